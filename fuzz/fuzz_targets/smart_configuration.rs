@@ -0,0 +1,34 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Feeds arbitrary input to the SMART configuration parser.
+//!
+//! SMART configurations are fetched from external servers, which are not always
+//! conformant. Parsing a configuration, and using the parsed configuration, must
+//! never panic; malformed configurations should only produce errors.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_smart_fhir::smart::configuration::SmartConfiguration;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(config) = serde_json::from_slice::<SmartConfiguration>(data) {
+        config.capabilities_set();
+        config.resolve_endpoint_for("Observation", "https://fhir.example.com/r4");
+        config.supports_scope("patient/Observation.read");
+    }
+});
@@ -0,0 +1,311 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use log::info;
+use maud::{html, Markup, DOCTYPE};
+use serde::Deserialize;
+
+use crate::context::LaunchContext;
+use crate::practitioner::{logged_in_practitioner, LoggedInPractitioner};
+use crate::state::State;
+
+#[derive(Deserialize)]
+pub struct InvalidateConfigQuery {
+    // The issuer whose SMART configuration should be dropped.
+    iss: String,
+}
+
+/**
+ * Admin: landing page for launches without patient context
+ * --------------------------------------------------------
+ * When the app is configured for administrative launches, the EHR does not select a
+ * patient, and our `/callback` endpoint redirects the user here instead of to a
+ * patient summary. This page shows who the user is logged in as (including their
+ * role, if the EHR identified them as a practitioner), the server we are
+ * connected to, and the scopes that the EHR granted.
+ */
+#[get("/admin/{session_key}")]
+pub async fn admin(data: web::Data<State>, session_key: web::Path<String>) -> HttpResponse {
+    match data.get_token(&session_key) {
+        Some(client) => {
+            let practitioner = logged_in_practitioner(&client).await;
+            HttpResponse::Ok()
+                .body(render_admin(&client.context, practitioner.as_ref()).into_string())
+        }
+        None => HttpResponse::Unauthorized()
+            .body(format!("Failed to find token for session {session_key}.")),
+    }
+}
+
+/**
+ * Admin: invalidate a cached SMART configuration
+ * ----------------------------------------------
+ * Drops the SMART configuration that we cached for the issuer named by the `iss`
+ * query parameter, so that the next launch from the issuer fetches it again. This is
+ * needed when an EHR rotates its endpoints while we hold a stale configuration.
+ *
+ * Requests must present the configured admin key as a `Bearer` token. If no admin key
+ * is configured, this endpoint is disabled. Responds with a 204 if a configuration
+ * was dropped, or a 404 if none was cached for the issuer.
+ */
+#[post("/admin/config/invalidate")]
+pub async fn invalidate_config(
+    req: HttpRequest,
+    data: web::Data<State>,
+    query: web::Query<InvalidateConfigQuery>,
+) -> HttpResponse {
+    if data.admin_key.is_none() {
+        return HttpResponse::NotFound().body("Administrative endpoints are disabled.");
+    }
+
+    let key = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "));
+    match key {
+        Some(key) if data.is_admin_key(key) => {
+            if data.invalidate_config(&query.iss) {
+                info!(
+                    "Invalidated cached SMART configuration for issuer {}",
+                    query.iss
+                );
+                HttpResponse::NoContent().finish()
+            } else {
+                HttpResponse::NotFound().body(format!(
+                    "No SMART configuration is cached for issuer {}.",
+                    query.iss
+                ))
+            }
+        }
+        _ => HttpResponse::Unauthorized().body("Invalid admin key."),
+    }
+}
+
+// Renders the admin landing page.
+//
+// # Arguments
+// * `context` The context of the launch.
+// * `practitioner` The practitioner who is logged in, if the EHR identified one.
+#[rustfmt::skip::macros(html)]
+fn render_admin(context: &LaunchContext, practitioner: Option<&LoggedInPractitioner>) -> Markup {
+    let user_name = practitioner
+        .and_then(|practitioner| practitioner.name.as_deref())
+        .or(context.user_name.as_deref());
+    let user_role = practitioner.and_then(|practitioner| practitioner.role.as_deref());
+
+    html! {
+	(DOCTYPE);
+	html lang="en" {
+            head {
+		title {
+		    "Example SMART-on-FHIR app: admin"
+		}
+            }
+            body {
+		div #holder {
+		    h1 {
+			"Example SMART-on-FHIR app"
+		    }
+		    p #brand {
+			"Connected to "
+			(context.connected_to())
+		    }
+		    p #user {
+			@if let Some(user) = user_name {
+			    "Logged in as "
+			    (user)
+			    @if let Some(role) = user_role {
+				", "
+				(role)
+			    }
+			    " ("
+			} @else {
+			    "("
+			}
+			a #logout href=(context.logout_path()) {
+			    "log out"
+			}
+			")"
+		    }
+		    section #scopes {
+			h2 {
+			    "Granted scopes"
+			}
+			ul {
+			    @for scope in &context.scopes {
+				li {
+				    code {
+					(scope)
+				    }
+				}
+			    }
+			}
+		    }
+		}
+            }
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::header::LOCATION;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use url::Url;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::callback::callback;
+    use crate::launch::launch;
+    use crate::test_support::{
+        encode, mock_token_endpoint, smart_configuration, test_state, token_response,
+    };
+
+    use std::time::Duration;
+
+    // Builds the state of an app that caches SMART configurations and accepts the
+    // admin key "secret".
+    fn caching_state() -> State {
+        test_state()
+            .with_smart_configuration_max_age(Duration::from_secs(3600))
+            .with_admin_key(Some(String::from("secret")))
+    }
+
+    // Serves a SMART configuration from a mock EHR, expecting it to be fetched a
+    // number of times.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `fetches` The number of times the configuration should be fetched.
+    async fn expect_configuration_fetches(ehr: &MockServer, fetches: u64) {
+        Mock::given(method("GET"))
+            .and(path("/.well-known/smart-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(smart_configuration(&ehr.uri())))
+            .expect(fetches)
+            .mount(ehr)
+            .await;
+    }
+
+    // Builds a request launching the app from a mock EHR.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    fn launch_request(ehr: &MockServer) -> test::TestRequest {
+        test::TestRequest::get().uri(&format!("/launch?iss={}&launch=abc", encode(&ehr.uri())))
+    }
+
+    // Builds a request invalidating the configuration cached for a mock EHR.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `key` The admin key to present.
+    fn invalidate_request(ehr: &MockServer, key: &str) -> test::TestRequest {
+        test::TestRequest::post()
+            .uri(&format!(
+                "/admin/config/invalidate?iss={}",
+                encode(&ehr.uri())
+            ))
+            .insert_header((AUTHORIZATION, format!("Bearer {key}")))
+    }
+
+    #[actix_web::test]
+    async fn invalidation_makes_next_launch_refetch_configuration() {
+        let ehr = MockServer::start().await;
+        expect_configuration_fetches(&ehr, 2).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(caching_state()))
+                .service(launch)
+                .service(callback)
+                .service(invalidate_config),
+        )
+        .await;
+
+        let first = test::call_service(&app, launch_request(&ehr).to_request()).await;
+        let cached = test::call_service(&app, launch_request(&ehr).to_request()).await;
+        let invalidated =
+            test::call_service(&app, invalidate_request(&ehr, "secret").to_request()).await;
+        let refetched = test::call_service(&app, launch_request(&ehr).to_request()).await;
+
+        assert_eq!(first.status(), StatusCode::SEE_OTHER);
+        assert_eq!(cached.status(), StatusCode::SEE_OTHER);
+        assert_eq!(invalidated.status(), StatusCode::NO_CONTENT);
+        assert_eq!(refetched.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[actix_web::test]
+    async fn invalidation_requires_admin_key() {
+        let ehr = MockServer::start().await;
+        expect_configuration_fetches(&ehr, 1).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(caching_state()))
+                .service(launch)
+                .service(callback)
+                .service(invalidate_config),
+        )
+        .await;
+
+        test::call_service(&app, launch_request(&ehr).to_request()).await;
+        let resp = test::call_service(&app, invalidate_request(&ehr, "guess").to_request()).await;
+        let cached = test::call_service(&app, launch_request(&ehr).to_request()).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(cached.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[actix_web::test]
+    async fn invalidation_does_not_break_launch_in_flight() {
+        let ehr = MockServer::start().await;
+        expect_configuration_fetches(&ehr, 1).await;
+        mock_token_endpoint(&ehr, token_response()).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(caching_state()))
+                .service(launch)
+                .service(callback)
+                .service(invalidate_config),
+        )
+        .await;
+
+        let launched = test::call_service(&app, launch_request(&ehr).to_request()).await;
+        let location = launched.headers().get(LOCATION).unwrap().to_str().unwrap();
+        let launch_state = Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .find(|(name, _)| name == "state")
+            .unwrap()
+            .1
+            .into_owned();
+        let invalidated =
+            test::call_service(&app, invalidate_request(&ehr, "secret").to_request()).await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={launch_state}"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(invalidated.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp.headers().get(LOCATION).unwrap().to_str().unwrap();
+        assert!(location.starts_with("https://app.example.com/123/index.html?iss="));
+    }
+}
@@ -0,0 +1,124 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::error::Unspecified;
+use ring::rand::{SecureRandom, SystemRandom};
+
+// Encrypts and authenticates small secrets with AES-256-GCM under a key from our
+// configuration.
+//
+// Sealed values are the random nonce followed by the ciphertext and its tag. The
+// associated data is not part of the sealed value, and has to be passed again when
+// opening it, so that a value sealed for one purpose cannot be opened for another.
+pub struct Aead {
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl Aead {
+    // Creates a cipher from a 256-bit key.
+    //
+    // Returns an error if the key has the wrong length.
+    //
+    // # Arguments
+    // * `key` The encryption key.
+    pub fn new(key: &[u8]) -> Result<Aead, Unspecified> {
+        UnboundKey::new(&AES_256_GCM, key).map(|key| Aead {
+            key: LessSafeKey::new(key),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    // The length of the keys we accept, in bytes.
+    pub fn key_len() -> usize {
+        AES_256_GCM.key_len()
+    }
+
+    // Encrypts a plaintext.
+    //
+    // Returns an error if we fail to generate a nonce or to encrypt the plaintext.
+    //
+    // # Arguments
+    // * `aad` The associated data to bind the ciphertext to.
+    // * `plaintext` The plaintext to encrypt.
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Unspecified> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce)?;
+
+        let mut ciphertext = plaintext.to_vec();
+        self.key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut ciphertext,
+        )?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.append(&mut ciphertext);
+        Ok(sealed)
+    }
+
+    // Decrypts a sealed value.
+    //
+    // Returns an empty option if the value was not sealed with our key and the
+    // same associated data, or was tampered with.
+    //
+    // # Arguments
+    // * `aad` The associated data the value was sealed with.
+    // * `sealed` The sealed value.
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let mut ciphertext = ciphertext.to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(aad),
+                &mut ciphertext,
+            )
+            .ok()?;
+        Some(plaintext.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_value_round_trips() {
+        let aead = Aead::new(&[7u8; 32]).unwrap();
+
+        let sealed = aead.seal(b"purpose", b"secret").unwrap();
+
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(aead.open(b"purpose", &sealed), Some(b"secret".to_vec()));
+    }
+
+    #[test]
+    fn sealed_value_is_bound_to_its_associated_data() {
+        let aead = Aead::new(&[7u8; 32]).unwrap();
+
+        let sealed = aead.seal(b"purpose", b"secret").unwrap();
+
+        assert_eq!(aead.open(b"other purpose", &sealed), None);
+        assert_eq!(aead.open(b"purpose", &sealed[..4]), None);
+    }
+}
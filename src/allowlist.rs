@@ -0,0 +1,161 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use url::Url;
+
+// A single entry in the issuer allowlist.
+#[derive(Clone, Debug)]
+enum AllowedIssuer {
+    // Allows any issuer URL on this host, e.g. "fhir.example.com".
+    Host(String),
+
+    // Allows any issuer URL under this URL, e.g. "https://fhir.example.com/r4".
+    Prefix(Url),
+}
+
+impl AllowedIssuer {
+    fn parse(entry: &str) -> Option<AllowedIssuer> {
+        if entry.contains("://") {
+            Url::parse(entry).ok().map(AllowedIssuer::Prefix)
+        } else {
+            Some(AllowedIssuer::Host(entry.to_lowercase()))
+        }
+    }
+
+    fn allows(&self, iss: &Url) -> bool {
+        match self {
+            AllowedIssuer::Host(host) => iss.host_str() == Some(host.as_str()),
+            AllowedIssuer::Prefix(prefix) => {
+                // Compare path segments rather than raw strings, so that an entry for
+                // "https://fhir.example.com/r4" does not allow "https://fhir.example.com/r4-internal".
+                let prefix_path = prefix.path().trim_end_matches('/');
+                let iss_path = iss.path();
+
+                iss.scheme() == prefix.scheme()
+                    && iss.host_str() == prefix.host_str()
+                    && iss.port_or_known_default() == prefix.port_or_known_default()
+                    && iss_path.starts_with(prefix_path)
+                    && (iss_path.len() == prefix_path.len()
+                        || iss_path[prefix_path.len()..].starts_with('/'))
+            }
+        }
+    }
+}
+
+// The set of issuers that are allowed to launch this app.
+//
+// The `/launch` endpoint makes a server-side HTTP request to the `iss` URL that
+// it is provided. To avoid this being used to make requests against arbitrary
+// (e.g., internal) services, we check the `iss` against this allowlist before
+// fetching its SMART configuration.
+//
+// Entries are either hosts (e.g., "fhir.example.com"), which allow any issuer
+// on that host, or URL prefixes (e.g., "https://fhir.example.com/r4"), which
+// allow any issuer with the same scheme, host, and port, whose path is under
+// the prefix path. An empty allowlist allows all issuers.
+#[derive(Clone, Debug, Default)]
+pub struct IssuerAllowlist {
+    entries: Vec<AllowedIssuer>,
+}
+
+impl IssuerAllowlist {
+    // Parses an allowlist from a list of entries.
+    //
+    // Blank entries and entries starting with '#' are ignored. Returns the
+    // invalid entry as an error if a URL prefix entry cannot be parsed.
+    //
+    // # Arguments
+    // * `entries` The allowed hosts and URL prefixes.
+    pub fn parse<'a, I>(entries: I) -> Result<IssuerAllowlist, String>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut allowlist = IssuerAllowlist::default();
+
+        for entry in entries.into_iter().map(str::trim) {
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            match AllowedIssuer::parse(entry) {
+                Some(allowed) => allowlist.entries.push(allowed),
+                None => return Err(entry.to_string()),
+            }
+        }
+
+        Ok(allowlist)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Checks whether an issuer is allowed to launch this app.
+    //
+    // Issuers that are not valid URLs are never allowed, even if the allowlist is
+    // empty.
+    //
+    // # Arguments
+    // * `iss` The URL of the server that issued the launch.
+    pub fn allows(&self, iss: &str) -> bool {
+        match Url::parse(iss) {
+            Ok(iss) => self.is_empty() || self.entries.iter().any(|entry| entry.allows(&iss)),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_allowlist_allows_all_issuers() {
+        let allowlist = IssuerAllowlist::default();
+
+        assert!(allowlist.allows("https://fhir.example.com/r4"));
+        assert!(!allowlist.allows("not a url"));
+    }
+
+    #[test]
+    fn host_entry_allows_any_issuer_on_host() {
+        let allowlist = IssuerAllowlist::parse(["fhir.example.com"]).unwrap();
+
+        assert!(allowlist.allows("https://fhir.example.com/r4"));
+        assert!(allowlist.allows("http://fhir.example.com"));
+        assert!(!allowlist.allows("https://internal.example.com/r4"));
+    }
+
+    #[test]
+    fn prefix_entry_allows_issuers_under_path() {
+        let allowlist =
+            IssuerAllowlist::parse(["# EHRs", "", "https://fhir.example.com/r4"]).unwrap();
+
+        assert!(allowlist.allows("https://fhir.example.com/r4"));
+        assert!(allowlist.allows("https://fhir.example.com/r4/tenant"));
+        assert!(!allowlist.allows("https://fhir.example.com/r4-internal"));
+        assert!(!allowlist.allows("http://fhir.example.com/r4"));
+        assert!(!allowlist.allows("https://fhir.example.com:8443/r4"));
+    }
+
+    #[test]
+    fn invalid_prefix_entry_is_rejected() {
+        assert_eq!(
+            IssuerAllowlist::parse(["https://"]).unwrap_err(),
+            "https://"
+        );
+    }
+}
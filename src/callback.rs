@@ -14,25 +14,45 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use actix_web::{get, web, HttpResponse};
-use log::{debug, error};
+use actix_web::rt::time::timeout;
+use actix_web::{get, web, HttpRequest, HttpResponse, ResponseError};
+use log::{debug, error, warn};
+use maud::{html, Markup, DOCTYPE};
 use serde::Deserialize;
 use uuid::Uuid;
 
-use crate::smart::token::Token;
-use crate::state::State;
+use crate::context::LaunchContext;
+use crate::context_cookie::context_cookie;
+use crate::error::AppError;
+use crate::fetch::is_valid_id;
+use crate::launch::restart_launch;
+use crate::session::{session_cookie, session_id};
+use crate::smart::configuration::SmartConfiguration;
+use crate::smart::token::{Token, TokenError};
+use crate::state::{PendingLaunch, State};
 
-#[allow(dead_code)]
 #[derive(Deserialize)]
 struct CallbackQuery {
     // The authorization code generated by the authorization server.
     // The authorization code needs to expire shortly after it is issued to mitigate the risk of leaks.
-    code: String,
+    // Omitted if authorization failed.
+    code: Option<String>,
 
     // The exact state value received from the client on the authorization call.
     state: String,
+
+    // The error code, if authorization failed, as described in
+    // [RFC 6749](https://datatracker.ietf.org/doc/html/rfc6749#section-4.1.2.1).
+    error: Option<String>,
+
+    // A human-readable description of the error, if authorization failed.
+    error_description: Option<String>,
 }
 
+// The maximum number of times we restart a launch to re-authenticate the user,
+// so that we do not loop forever if the EHR keeps asking the user to log in.
+const MAX_REAUTH_ATTEMPTS: u32 = 1;
+
 /**
  * SMART-on-FHIR EHR launch sequence: step 2 (acquiring token)
  * -----------------------------------------------------------
@@ -49,64 +69,1289 @@ struct CallbackQuery {
  * To exchange the code for a token, we need to POST to the FHIR server's token endpoint as
  * described [here](https://build.fhir.org/ig/HL7/smart-app-launch/app-launch.html#step-5-access-token).
  * Once we have the token, we can call against the core FHIR APIs.
+ *
+ * If the EHR redirects back to us after the launch has timed out, we have already
+ * dropped the PKCE verifier for the launch, so we cannot exchange the code. In that
+ * case, we respond with a `440 Login Time-out` page that asks the user to relaunch.
+ *
+ * If the EHR returns an id_token, it must carry the nonce that we sent to the authorization
+ * endpoint for this launch; otherwise, we reject the token. If the EHR publishes its keys
+ * at a `jwks_uri`, we log a warning if the id_token is not signed with one of them.
+ *
+ * If configured, a successful launch first shows a page listing the scopes that the EHR
+ * granted, with a link to continue to the landing page.
+ *
+ * Each authorization code may only be exchanged once; a replayed code is rejected with a
+ * `403 Forbidden` page, even if the launch it was issued for is still pending.
+ *
+ * If the token endpoint rejects the `aud` that we sent to the authorization endpoint, we
+ * restart the launch once, sending the EHR's OpenID Connect issuer as the audience instead.
+ *
+ * If the EHR could not authorize the app without the user logging in again (i.e., it
+ * returns a `login_required` or `interaction_required` error), we restart the launch
+ * with `prompt=login`, keeping the original launch ID. We only do this once per launch.
  */
 #[get("/callback")]
-pub async fn callback(data: web::Data<State>, query: web::Query<CallbackQuery>) -> HttpResponse {
+pub async fn callback(
+    req: HttpRequest,
+    data: web::Data<State>,
+    query: web::Query<CallbackQuery>,
+) -> HttpResponse {
     // parse state value to get transaction uuid
     match Uuid::parse_str(&query.state) {
         Ok(state) => {
-            // get PKCE challenge / verifier pair for this transaction
-            match data.get_pkce(&state) {
-                Some((_challenge, verifier)) => {
-                    // get smart configuration for this transaction
-                    let configuration = data.get_iss_and_config(&state);
-
-                    match configuration {
-                        Some((iss, smart_configuration)) => {
-                            // call to the FHIR server to request a token
-                            let token =
-                                Token::post(&smart_configuration, &query.code, &verifier, &data)
-                                    .await;
-
-                            match token {
-                                Ok(token) => {
-                                    let patient = token.patient.clone();
-
-                                    // if we've received a token, store it
-                                    data.put_token(token).await;
-
-                                    debug!("Successfully exchanged a token with iss {iss} for state {state}");
-
-                                    // now that we have received a token, redirect to index.html
-                                    HttpResponse::SeeOther()
-                                        .insert_header((
-                                            actix_web::http::header::LOCATION,
-                                            format!("{}/{}/index.html", data.app_domain, patient),
-                                        ))
-                                        .finish()
+            // check that the launch has not timed out
+            if data.launch_expired(&state) {
+                warn!("Received callback for launch {state}, which has expired");
+                return data.error_page(AppError::LaunchExpired).error_response();
+            }
+
+            // whatever the outcome, this launch is over. if we restart it, the restarted
+            // launch has a state of its own.
+            let response = exchange_code(req, data.clone(), state, &query).await;
+            data.finish_launch(&state);
+            response
+        }
+        Err(e) => {
+            error!("Failed to parse state UUID {} due to {}", query.state, e);
+            HttpResponse::BadRequest().body("Failed to parse state parameter provided by EHR.")
+        }
+    }
+}
+
+// Exchanges the authorization code that the EHR sent to the callback for a token.
+//
+// # Arguments
+// * `req` The callback request.
+// * `data` The application state.
+// * `state` The UUID for the launch.
+// * `query` The parameters that the EHR sent to the callback.
+async fn exchange_code(
+    req: HttpRequest,
+    data: web::Data<State>,
+    state: Uuid,
+    query: &CallbackQuery,
+) -> HttpResponse {
+    // the EHR reports authorization errors in place of a code
+    if let Some(error) = &query.error {
+        return authorization_error(
+            data.clone(),
+            &state,
+            error,
+            query.error_description.as_deref(),
+        )
+        .await;
+    }
+
+    let Some(code) = &query.code else {
+        error!("Received callback for launch {state} without a code or an error");
+        return HttpResponse::BadRequest().body("Received no authorization code from EHR.");
+    };
+
+    // get PKCE challenge / verifier pair for this transaction
+    match data.get_pkce(&state) {
+        Some((_challenge, verifier)) => {
+            // take the nonce along with the verifier, so that both are consumed
+            // however the exchange ends
+            let nonce = data.get_nonce(&state);
+
+            // refuse to exchange a code twice, in case it was intercepted
+            if !data.claim_code(code) {
+                warn!("Rejecting replayed authorization code for launch {state}");
+                return data
+                    .error_page(AppError::Forbidden(String::from(
+                        "This authorization code was already used.",
+                    )))
+                    .error_response();
+            }
+
+            // we will not need to restart this launch
+            let pending = data.get_pending_launch(&state);
+
+            // get smart configuration for this transaction
+            let configuration = data.get_iss_and_config(&state);
+
+            match configuration {
+                Some((iss, smart_configuration)) => {
+                    // call to the FHIR server to request a token
+                    let token = timeout(
+                        data.token_timeout,
+                        Token::post(&iss, &smart_configuration, code, &verifier, &data),
+                    )
+                    .await;
+                    let Ok(token) = token else {
+                        error!("Exchanging a token for state {state} with issuer {iss} timed out after {:?}", data.token_timeout);
+                        return data
+                            .error_page(AppError::Internal(String::from(
+                                "The EHR took too long to issue a token.",
+                            )))
+                            .error_response();
+                    };
+
+                    match token {
+                        Ok(mut token) => {
+                            // the id_token must echo the nonce we sent with this launch.
+                            // if the EHR granted `openid` but sent no id_token, or one
+                            // that we cannot decode, we cannot verify the user either.
+                            let verified = match &token.user {
+                                Some(user) => user.nonce == nonce,
+                                None => nonce.is_none() || !token.expects_id_token(),
+                            };
+                            if !verified {
+                                error!("Received no id_token with a matching nonce for state {state} and issuer {iss}");
+                                return data
+                                    .error_page(AppError::Forbidden(String::from(
+                                        "Failed to verify the identity token.",
+                                    )))
+                                    .error_response();
+                            }
+
+                            // if the EHR publishes its keys, check that the id_token is signed
+                            // with one of them
+                            data.check_id_token_signature(&token, &smart_configuration)
+                                .await;
+
+                            // resolve how the EHR presents itself, for display
+                            token.brand = data.get_brand(&iss, &smart_configuration).await;
+                            token.ehr_launch = pending
+                                .as_ref()
+                                .is_some_and(|pending| pending.launch_id.is_some());
+
+                            // servers may ignore the patient we asked to preselect,
+                            // in which case the user selected a patient as usual
+                            if let Some(hint) = pending.and_then(|pending| pending.patient_hint) {
+                                if token.patient.as_deref() != Some(hint.as_str()) {
+                                    warn!("Issuer {iss} did not preselect patient {hint} for state {state}; the user selected a patient");
                                 }
-                                Err(e) => {
-                                    error!("Failed to exchange a token for state {state} and issuer {iss} due to {e}");
-                                    HttpResponse::Forbidden().body("Failed to exchange token.")
+                            }
+
+                            // if we've received a token, store it
+                            let Some(context) = data.put_token(token).await else {
+                                error!("Failed to build a FHIR client for state {state} and issuer {iss}");
+                                return data
+                                    .error_page(AppError::Internal(String::from(
+                                        "Failed to connect to the FHIR server.",
+                                    )))
+                                    .error_response();
+                            };
+                            data.put_completed_launch(&state, &context.session_key);
+
+                            debug!(
+                                "Successfully exchanged a token with iss {iss} for state {state}"
+                            );
+
+                            // now that we have received a token, redirect to index.html,
+                            // to a resource in the launch context, or to the admin page
+                            // if the launch has no patient context, optionally showing the
+                            // granted scopes first. if the browser does not have a session
+                            // yet, start one, so that we can track the patients it views.
+                            let mut response = if data.show_granted_scopes {
+                                HttpResponse::Ok()
+                                    .content_type("text/html; charset=utf-8")
+                                    .body(
+                                        render_granted_scopes(
+                                            &context,
+                                            &landing_url(&data, &context),
+                                        )
+                                        .into_string(),
+                                    )
+                            } else {
+                                redirect_to_landing(&data, &context)
+                            };
+                            if let Some((cipher, patient)) = data
+                                .context_cookie_cipher
+                                .as_ref()
+                                .zip(context.patient.as_ref())
+                            {
+                                match cipher.seal(&context.iss, patient) {
+                                    Ok(value) => {
+                                        let cookie =
+                                            context_cookie(value, data.app_scheme() == "https");
+                                        if let Err(e) = response.add_cookie(&cookie) {
+                                            warn!("Failed to set context cookie: {e}");
+                                        }
+                                    }
+                                    Err(e) => warn!("Failed to set context cookie: {e}"),
+                                }
+                            }
+                            if session_id(&req).is_none() {
+                                let cookie =
+                                    session_cookie(&Uuid::new_v4(), data.app_scheme() == "https");
+                                if let Err(e) = response.add_cookie(&cookie) {
+                                    warn!("Failed to set session cookie: {e}");
                                 }
                             }
+                            response
                         }
-                        None => {
-                            error!("Do not have a SMART configuration/issuer for state {state}");
-                            HttpResponse::InternalServerError()
-                                .body("Could not find SMART configuration for transaction.")
+                        Err(TokenError::Response(response)) if response.is_audience_mismatch() => {
+                            match alternate_audience(&iss, &smart_configuration, pending) {
+                                Some(launch) => {
+                                    warn!("Token endpoint for issuer {iss} rejected the audience for state {state}; restarting the launch with aud {}", launch.aud.as_deref().unwrap_or_default());
+                                    restart_launch(data, launch, None).await
+                                }
+                                None => {
+                                    error!("Failed to exchange a token for state {state} and issuer {iss} due to {}", TokenError::Response(response));
+                                    data.error_page(AppError::Forbidden(String::from(
+                                        "Failed to exchange token.",
+                                    )))
+                                    .error_response()
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            error!("Failed to exchange a token for state {state} and issuer {iss} due to {e}");
+                            data.error_page(AppError::Forbidden(String::from(
+                                "Failed to exchange token.",
+                            )))
+                            .error_response()
                         }
                     }
                 }
                 None => {
-                    error!("Received state parameter {state} which is not in our state store.");
-                    HttpResponse::BadRequest().body("Received unknown state parameter from EHR.")
+                    error!("Do not have a SMART configuration/issuer for state {state}");
+                    data.error_page(AppError::Internal(String::from(
+                        "Could not find SMART configuration for transaction.",
+                    )))
+                    .error_response()
                 }
             }
         }
-        Err(e) => {
-            error!("Failed to parse state UUID {} due to {}", query.state, e);
-            HttpResponse::BadRequest().body("Failed to parse state parameter provided by EHR.")
+        None => {
+            // the callback may be delivered twice, e.g. if the browser prefetches it.
+            // if the first delivery already completed the launch, send the user on to
+            // the summary rather than showing an error.
+            if let Some(client) = data
+                .get_completed_launch(&state)
+                .and_then(|session_key| data.get_token(&session_key))
+            {
+                debug!("Received duplicate callback for completed launch {state}");
+                return redirect_to_landing(&data, &client.context);
+            }
+
+            error!("Received state parameter {state} which is not in our state store.");
+            HttpResponse::BadRequest().body("Received unknown state parameter from EHR.")
+        }
+    }
+}
+
+// Handles an authorization error reported by the EHR.
+//
+// Restarts the launch with `prompt=login` if the user needs to log in again, and we
+// have not already done so for this launch. Otherwise, responds with an error.
+//
+// # Arguments
+// * `data` The application state.
+// * `state` The UUID for the launch.
+// * `error` The error code reported by the EHR.
+// * `error_description` The description of the error reported by the EHR, if any.
+async fn authorization_error(
+    data: web::Data<State>,
+    state: &Uuid,
+    error: &str,
+    error_description: Option<&str>,
+) -> HttpResponse {
+    let launch = data.get_pending_launch(state);
+
+    match (error, launch) {
+        ("login_required" | "interaction_required", Some(launch))
+            if launch.reauth_attempts < MAX_REAUTH_ATTEMPTS =>
+        {
+            warn!(
+                "EHR {} returned {error} for launch {state}; restarting the launch to re-authenticate",
+                launch.iss
+            );
+            let launch = PendingLaunch {
+                reauth_attempts: launch.reauth_attempts + 1,
+                ..launch
+            };
+            restart_launch(data, launch, Some("login")).await
+        }
+        (error, _) => {
+            error!(
+                "EHR returned authorization error {error} for launch {state}: {}",
+                error_description.unwrap_or("no description")
+            );
+            data.error_page(AppError::Forbidden(format!(
+                "The EHR did not authorize the app: {error}."
+            )))
+            .error_response()
+        }
+    }
+}
+
+// Gets the launch to restart with the alternate `aud`, after the token endpoint
+// rejected the audience that we sent.
+//
+// We send the issuer as the audience, but some EHRs expect their OpenID Connect
+// issuer instead. Returns an empty option if there is no alternate candidate, or
+// if we already retried the launch with it, so that we do not loop.
+//
+// # Arguments
+// * `iss` The URL of the server that issued the launch.
+// * `smart_configuration` The SMART configuration for the server.
+// * `pending` The launch that failed, if we can restart it.
+fn alternate_audience(
+    iss: &str,
+    smart_configuration: &SmartConfiguration,
+    pending: Option<PendingLaunch>,
+) -> Option<PendingLaunch> {
+    let pending = pending.filter(|pending| pending.aud.is_none())?;
+    let aud = smart_configuration
+        .issuer
+        .as_ref()
+        .filter(|issuer| issuer.trim_end_matches('/') != iss.trim_end_matches('/'))?;
+
+    Some(PendingLaunch {
+        aud: Some(aud.clone()),
+        ..pending
+    })
+}
+
+// Gets the path of the page that displays a resource in the launch context.
+//
+// Returns an empty option if we cannot display the resource, e.g. because we do not
+// have a page for its type, or because it is not a relative reference.
+//
+// # Arguments
+// * `session_key` The key the launch's token is stored under.
+// * `reference` The reference to the resource, e.g. "DiagnosticReport/123".
+fn context_resource_path(session_key: &str, reference: &str) -> Option<String> {
+    let (resource_type, id) = reference.split_once('/')?;
+    if !is_valid_id(id) {
+        return None;
+    }
+
+    match resource_type {
+        "DiagnosticReport" => Some(format!("/{session_key}/diagnostic-report/{id}.html")),
+        "Observation" => Some(format!("/{session_key}/observation/{id}.html")),
+        _ => None,
+    }
+}
+
+// Gets the URL of the landing page for a launch.
+//
+// Launches with patient context land on the page for the first resource in the
+// launch's FHIR context that we can display, or else on the summary page for the
+// patient; launches without patient context land on the admin page.
+//
+// # Arguments
+// * `data` The application state.
+// * `context` The context of the launch.
+fn landing_url(data: &State, context: &LaunchContext) -> String {
+    let session_key = &context.session_key;
+    let context_path = context
+        .fhir_context
+        .iter()
+        .find_map(|reference| context_resource_path(session_key, reference));
+    match (&context.patient, context_path) {
+        (Some(_), Some(path)) => format!("{}{}", data.app_domain, path),
+        (Some(_), None) => format!("{}{}", data.app_domain, context.summary_path("html")),
+        (None, _) => format!("{}/admin/{}", data.app_domain, session_key),
+    }
+}
+
+// Redirects the browser to the landing page for a launch; see `landing_url`.
+//
+// # Arguments
+// * `data` The application state.
+// * `context` The context of the launch.
+fn redirect_to_landing(data: &State, context: &LaunchContext) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((
+            actix_web::http::header::LOCATION,
+            landing_url(data, context),
+        ))
+        .finish()
+}
+
+// The types of resources that the app reads, with a description for users.
+const ACCESSED_RESOURCES: [(&str, &str); 9] = [
+    ("Patient", "Demographics and contact details"),
+    ("Practitioner", "General practitioners"),
+    ("Organization", "General practices"),
+    ("Observation", "Vital signs and lab results"),
+    ("MedicationRequest", "Medication requests"),
+    ("Medication", "Medications"),
+    ("DiagnosticReport", "Diagnostic reports"),
+    ("Goal", "Goals"),
+    ("CarePlan", "Care plans"),
+];
+
+// Generates the HTML for the page shown after a successful launch, listing the
+// scopes that the EHR granted and the data that they let the app read.
+//
+// # Arguments
+// * `context` The context of the launch.
+// * `continue_url` The URL of the launch's landing page.
+#[rustfmt::skip::macros(html)]
+fn render_granted_scopes(context: &LaunchContext, continue_url: &str) -> Markup {
+    html! {
+	(DOCTYPE);
+	html lang="en" {
+            head {
+		title {
+		    "Example SMART-on-FHIR app: connected"
+		}
+            }
+            body {
+		div #holder {
+		    h1 {
+			"Example SMART-on-FHIR app"
+		    }
+		    p #brand {
+			"Connected to "
+			(context.connected_to())
+		    }
+		    section #access {
+			h2 {
+			    "Data the app can read"
+			}
+			ul {
+			    @for (resource_type, description) in ACCESSED_RESOURCES {
+				li {
+				    (description) ": "
+				    @if context.can_read(resource_type) {
+					"yes"
+				    } @else {
+					"no"
+				    }
+				}
+			    }
+			}
+		    }
+		    section #scopes {
+			h2 {
+			    "Granted scopes"
+			}
+			ul {
+			    @for scope in &context.scopes {
+				li {
+				    code {
+					(scope)
+				    }
+				}
+			    }
+			}
+		    }
+		    p {
+			a #continue href=(continue_url) {
+			    "Continue"
+			}
+		    }
+		}
+            }
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::header::LOCATION;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use url::Url;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::launch::{launch, LaunchMode};
+    use crate::test_support::{
+        capture_warnings, encode, id_token, mock_smart_configuration, mock_token_endpoint,
+        smart_configuration, take_warnings, test_state, token_response, SigningKey,
+    };
+
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    // Gets the query parameters of the authorization URL that a response redirected to.
+    //
+    // # Arguments
+    // * `resp` The response redirecting to the authorization endpoint.
+    fn authorize_params(resp: &ServiceResponse) -> HashMap<String, String> {
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp.headers().get(LOCATION).unwrap().to_str().unwrap();
+        Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect()
+    }
+
+    // Gets the state that a launch sent to the authorization endpoint.
+    //
+    // # Arguments
+    // * `resp` The response to the launch, redirecting to the authorization endpoint.
+    fn launch_state(resp: ServiceResponse) -> String {
+        authorize_params(&resp).remove("state").unwrap()
+    }
+
+    // Builds a request launching the app from a mock EHR.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    fn launch_request(ehr: &MockServer) -> actix_web::test::TestRequest {
+        test::TestRequest::get().uri(&format!("/launch?iss={}&launch=abc", encode(&ehr.uri())))
+    }
+
+    #[actix_web::test]
+    async fn callback_after_launch_timeout_asks_to_relaunch() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        let state = test_state()
+            .with_launch_timeout(Duration::ZERO)
+            .with_relaunch_url(String::from("https://ehr.example.com/launch"));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let launch_state =
+            launch_state(test::call_service(&app, launch_request(&ehr).to_request()).await);
+        actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={launch_state}"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status().as_u16(), 440);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains(r#"<a id="relaunch" href="https://ehr.example.com/launch">"#));
+    }
+
+    // Completes a launch whose token endpoint responds after a delay, returning the
+    // callback's response.
+    //
+    // # Arguments
+    // * `state` The application state.
+    // * `delay` How long the token endpoint takes to respond.
+    async fn callback_with_slow_token_endpoint(
+        state: web::Data<State>,
+        delay: Duration,
+    ) -> ServiceResponse {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(token_response())
+                    .set_delay(delay),
+            )
+            .mount(&ehr)
+            .await;
+        let app =
+            test::init_service(App::new().app_data(state).service(launch).service(callback)).await;
+
+        let launch_state =
+            launch_state(test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={launch_state}"))
+            .to_request();
+        test::call_service(&app, req).await
+    }
+
+    #[actix_web::test]
+    async fn slow_token_exchange_times_out() {
+        let state = web::Data::new(test_state().with_token_timeout(Duration::from_millis(50)));
+
+        let resp =
+            callback_with_slow_token_endpoint(state.clone(), Duration::from_millis(500)).await;
+
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        // the nonce is dropped with the verifier, although no id_token arrived to check
+        assert_eq!(state.launch_entries(), 0);
+    }
+
+    #[actix_web::test]
+    async fn token_exchange_is_not_limited_by_other_timeouts() {
+        let state = web::Data::new(
+            test_state()
+                .with_discovery_timeout(Duration::from_secs(1))
+                .with_resource_timeout(Duration::from_millis(10)),
+        );
+
+        let resp = callback_with_slow_token_endpoint(state, Duration::from_millis(100)).await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    }
+
+    // Launches the app from a mock EHR and completes the callback, returning the
+    // redirect URIs sent to the authorization endpoint and the token endpoint.
+    //
+    // # Arguments
+    // * `state` The application state.
+    // * `ehr` The mock EHR.
+    // * `iss` The issuer URL that the EHR launches the app with.
+    // * `code` The authorization code that the EHR issues.
+    async fn redirect_uris_sent(
+        state: &web::Data<State>,
+        ehr: &MockServer,
+        iss: &str,
+        code: &str,
+    ) -> (String, String) {
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/launch?iss={}&launch=abc", encode(iss)))
+            .to_request();
+        let mut params = authorize_params(&test::call_service(&app, req).await);
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code={code}&state={}", params["state"]))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::SEE_OTHER
+        );
+
+        let requests = ehr.received_requests().await.unwrap();
+        let token_request = requests
+            .iter()
+            .find(|request| request.url.path() == "/token")
+            .unwrap();
+        let token_redirect_uri = url::form_urlencoded::parse(&token_request.body)
+            .find(|(name, _)| name == "redirect_uri")
+            .unwrap()
+            .1
+            .into_owned();
+        (params.remove("redirect_uri").unwrap(), token_redirect_uri)
+    }
+
+    #[actix_web::test]
+    async fn issuer_redirect_uri_is_used_throughout_launch() {
+        let first = MockServer::start().await;
+        let second = MockServer::start().await;
+        for ehr in [&first, &second] {
+            mock_smart_configuration(ehr).await;
+            mock_token_endpoint(ehr, token_response()).await;
         }
+        let state = web::Data::new(test_state().with_issuer_redirect_uris(HashMap::from([(
+            String::from("127.0.0.1"),
+            String::from("https://app.example.com/first/callback"),
+        )])));
+
+        // the second EHR launches with a different host, so the default applies to it
+        let second_iss = second.uri().replace("127.0.0.1", "localhost");
+        let (first_authorize, first_token) =
+            redirect_uris_sent(&state, &first, &first.uri(), "first-code").await;
+        let (second_authorize, second_token) =
+            redirect_uris_sent(&state, &second, &second_iss, "second-code").await;
+
+        assert_eq!(first_authorize, "https://app.example.com/first/callback");
+        assert_eq!(first_token, first_authorize);
+        assert_eq!(second_authorize, "https://app.example.com/callback");
+        assert_eq!(second_token, second_authorize);
+    }
+
+    #[actix_web::test]
+    async fn duplicate_callback_redirects_to_summary() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        mock_token_endpoint(&ehr, token_response()).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let launch_state =
+            launch_state(test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let callback_uri = format!("/callback?code=abc&state={launch_state}");
+        let first = test::call_service(
+            &app,
+            test::TestRequest::get().uri(&callback_uri).to_request(),
+        )
+        .await;
+        let second = test::call_service(
+            &app,
+            test::TestRequest::get().uri(&callback_uri).to_request(),
+        )
+        .await;
+
+        assert_eq!(first.status(), StatusCode::SEE_OTHER);
+        assert_eq!(second.status(), StatusCode::SEE_OTHER);
+        let location = second.headers().get(LOCATION).unwrap().to_str().unwrap();
+        assert!(location.starts_with("https://app.example.com/123/index.html?iss="));
+        assert_eq!(
+            second.headers().get(LOCATION),
+            first.headers().get(LOCATION)
+        );
+    }
+
+    #[actix_web::test]
+    async fn login_required_restarts_the_launch_once() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+        let login_required = |state: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/callback?error=login_required&state={state}"))
+                .to_request()
+        };
+
+        let launch_state =
+            launch_state(test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let restarted = test::call_service(&app, login_required(&launch_state)).await;
+        let params = authorize_params(&restarted);
+        let again = test::call_service(&app, login_required(&params["state"])).await;
+
+        assert_eq!(params["prompt"], "login");
+        assert_eq!(params["launch"], "abc");
+        assert_eq!(again.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn token_without_patient_lands_on_admin_page() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        let mut response = token_response();
+        response.as_object_mut().unwrap().remove("patient");
+        response["scope"] = serde_json::json!("launch user/*.read");
+        mock_token_endpoint(&ehr, response).await;
+        let state = test_state().with_launch_mode(LaunchMode::Administrative);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let launched = test::call_service(&app, launch_request(&ehr).to_request()).await;
+        let params = authorize_params(&launched);
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={}", params["state"]))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert!(!params["scope"].contains("launch/patient"));
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp.headers().get(LOCATION).unwrap().to_str().unwrap();
+        assert!(location.starts_with("https://app.example.com/admin/"));
+    }
+
+    // Completes a launch whose token response carries a FHIR context.
+    //
+    // Returns the URL that the callback redirected to.
+    //
+    // # Arguments
+    // * `fhir_context` The `fhirContext` of the token response.
+    async fn landing_for_fhir_context(fhir_context: serde_json::Value) -> String {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        let mut response = token_response();
+        response["fhirContext"] = fhir_context;
+        mock_token_endpoint(&ehr, response).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let launch_state =
+            launch_state(test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={launch_state}"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        resp.headers()
+            .get(LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[actix_web::test]
+    async fn diagnostic_report_in_fhir_context_is_landing_page() {
+        let location = landing_for_fhir_context(serde_json::json!([
+            { "reference": "ServiceRequest/order-1" },
+            { "reference": "DiagnosticReport/r1" }
+        ]))
+        .await;
+
+        assert_eq!(
+            location,
+            "https://app.example.com/123/diagnostic-report/r1.html"
+        );
+    }
+
+    #[actix_web::test]
+    async fn undisplayable_fhir_context_lands_on_summary() {
+        let location =
+            landing_for_fhir_context(serde_json::json!(["ServiceRequest/order-1"])).await;
+
+        assert!(location.starts_with("https://app.example.com/123/index.html"));
+    }
+
+    #[actix_web::test]
+    async fn interstitial_lists_granted_scopes() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        mock_token_endpoint(&ehr, token_response()).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state().with_show_granted_scopes(true)))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let launch_state =
+            launch_state(test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={launch_state}"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        for scope in ["launch", "patient/*.read"] {
+            assert!(body.contains(&format!("<li><code>{scope}</code></li>")));
+        }
+        assert!(body.contains("<li>Vital signs and lab results: yes</li>"));
+        assert!(body.contains(&format!(
+            r#"<a id="continue" href="https://app.example.com/123/index.html?iss={}">Continue</a>"#,
+            encode(&ehr.uri())
+        )));
+    }
+
+    #[actix_web::test]
+    async fn configured_issuer_name_is_shown() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        mock_token_endpoint(&ehr, token_response()).await;
+        let state = test_state()
+            .with_show_granted_scopes(true)
+            .with_issuer_names(HashMap::from([(ehr.uri(), String::from("Example Health"))]));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let launch_state =
+            launch_state(test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={launch_state}"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains(r#"<p id="brand">Connected to Example Health</p>"#));
+        assert!(!body.contains(&format!("Connected to {}", ehr.uri())));
+    }
+
+    // Completes a launch whose token response may carry an id_token.
+    //
+    // # Arguments
+    // * `response` Builds the token response from the nonce that the launch sent.
+    async fn callback_with_token_response(
+        response: impl FnOnce(&str) -> serde_json::Value,
+    ) -> ServiceResponse {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let params =
+            authorize_params(&test::call_service(&app, launch_request(&ehr).to_request()).await);
+        mock_token_endpoint(&ehr, response(&params["nonce"])).await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={}", params["state"]))
+            .to_request();
+        test::call_service(&app, req).await
+    }
+
+    // Builds a token response granting `openid`, with an id_token, if any.
+    //
+    // # Arguments
+    // * `id_token` The encoded id_token.
+    fn openid_token_response(id_token: Option<String>) -> serde_json::Value {
+        let mut response = token_response();
+        response["scope"] = serde_json::json!("launch openid profile patient/*.read");
+        if let Some(id_token) = id_token {
+            response["id_token"] = serde_json::json!(id_token);
+        }
+        response
+    }
+
+    #[actix_web::test]
+    async fn id_token_with_launch_nonce_is_accepted() {
+        let resp = callback_with_token_response(|nonce| {
+            openid_token_response(Some(id_token(
+                serde_json::json!({ "sub": "u1", "nonce": nonce }),
+            )))
+        })
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[actix_web::test]
+    async fn id_token_with_other_nonce_is_rejected() {
+        let resp = callback_with_token_response(|_| {
+            openid_token_response(Some(id_token(
+                serde_json::json!({ "sub": "u1", "nonce": "replayed" }),
+            )))
+        })
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    // Completes a launch with an EHR that publishes its signing keys, whose token
+    // response carries an id_token.
+    //
+    // # Arguments
+    // * `key` The key that the EHR publishes.
+    // * `id_token` Builds the id_token from the nonce that the launch sent.
+    async fn callback_with_published_key(
+        key: &SigningKey,
+        id_token: impl FnOnce(&str) -> String,
+    ) -> ServiceResponse {
+        let ehr = MockServer::start().await;
+        let mut configuration = smart_configuration(&ehr.uri());
+        configuration["jwks_uri"] = serde_json::json!(format!("{}/jwks", ehr.uri()));
+        Mock::given(method("GET"))
+            .and(path("/.well-known/smart-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(configuration))
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/jwks"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "keys": [key.jwk()] })),
+            )
+            .mount(&ehr)
+            .await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let params =
+            authorize_params(&test::call_service(&app, launch_request(&ehr).to_request()).await);
+        mock_token_endpoint(
+            &ehr,
+            openid_token_response(Some(id_token(&params["nonce"]))),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={}", params["state"]))
+            .to_request();
+        test::call_service(&app, req).await
+    }
+
+    #[actix_web::test]
+    async fn id_token_signed_with_published_key_is_accepted() {
+        let key = SigningKey::generate("k1");
+        let resp = callback_with_published_key(&key, |nonce| {
+            key.sign(serde_json::json!({ "sub": "u1", "nonce": nonce }))
+        })
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[actix_web::test]
+    async fn unsigned_id_token_is_logged_if_keys_are_published() {
+        let key = SigningKey::generate("k1");
+        capture_warnings();
+        let resp = callback_with_published_key(&key, |nonce| {
+            id_token(serde_json::json!({ "sub": "u1", "nonce": nonce }))
+        })
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert!(take_warnings()
+            .iter()
+            .any(|warning| warning.contains("not signed with any of the keys")));
+    }
+
+    #[actix_web::test]
+    async fn id_token_signed_with_other_key_is_logged() {
+        let key = SigningKey::generate("k1");
+        capture_warnings();
+        let resp = callback_with_published_key(&key, |nonce| {
+            SigningKey::generate("k1").sign(serde_json::json!({ "sub": "u1", "nonce": nonce }))
+        })
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert!(take_warnings()
+            .iter()
+            .any(|warning| warning.contains("not signed with any of the keys")));
+    }
+
+    #[actix_web::test]
+    async fn unavailable_key_set_does_not_block_login() {
+        let ehr = MockServer::start().await;
+        let mut configuration = smart_configuration(&ehr.uri());
+        configuration["jwks_uri"] = serde_json::json!(format!("{}/jwks", ehr.uri()));
+        Mock::given(method("GET"))
+            .and(path("/.well-known/smart-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(configuration))
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/jwks"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&ehr)
+            .await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let params =
+            authorize_params(&test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let key = SigningKey::generate("k1");
+        mock_token_endpoint(
+            &ehr,
+            openid_token_response(Some(
+                key.sign(serde_json::json!({ "sub": "u1", "nonce": params["nonce"] })),
+            )),
+        )
+        .await;
+        capture_warnings();
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={}", params["state"]))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert!(take_warnings()
+            .iter()
+            .any(|warning| warning.contains("Fetching keys from")));
+    }
+
+    #[actix_web::test]
+    async fn openid_grant_without_id_token_is_rejected() {
+        let resp = callback_with_token_response(|_| openid_token_response(None)).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn undecodable_id_token_is_rejected() {
+        let resp = callback_with_token_response(|_| {
+            openid_token_response(Some(String::from("not-a-jwt")))
+        })
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    // Starts a mock EHR whose OpenID Connect issuer differs from its FHIR base URL,
+    // and whose token endpoint rejects the audience of the first `rejections`
+    // exchanges.
+    //
+    // # Arguments
+    // * `rejections` The number of exchanges to reject.
+    async fn ehr_rejecting_audience(rejections: u64) -> MockServer {
+        let ehr = MockServer::start().await;
+        let mut configuration = smart_configuration(&ehr.uri());
+        configuration["issuer"] = serde_json::json!("https://login.example.com/oidc");
+        Mock::given(method("GET"))
+            .and(path("/.well-known/smart-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(configuration))
+            .mount(&ehr)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "error": "invalid_grant",
+                "error_description": "Token aud does not match"
+            })))
+            .up_to_n_times(rejections)
+            .mount(&ehr)
+            .await;
+        mock_token_endpoint(&ehr, token_response()).await;
+        ehr
+    }
+
+    #[actix_web::test]
+    async fn audience_mismatch_restarts_launch_with_alternate_aud() {
+        let ehr = ehr_rejecting_audience(1).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let first =
+            authorize_params(&test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let rejected = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/callback?code=first&state={}", first["state"]))
+                .to_request(),
+        )
+        .await;
+        let retry = authorize_params(&rejected);
+        let accepted = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/callback?code=second&state={}", retry["state"]))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(first["aud"], ehr.uri());
+        assert_eq!(retry["aud"], "https://login.example.com/oidc");
+        assert_ne!(retry["state"], first["state"]);
+        assert_eq!(accepted.status(), StatusCode::SEE_OTHER);
+        let location = accepted.headers().get(LOCATION).unwrap().to_str().unwrap();
+        assert!(location.starts_with("https://app.example.com/123/index.html"));
+    }
+
+    #[actix_web::test]
+    async fn audience_mismatch_is_retried_only_once() {
+        let ehr = ehr_rejecting_audience(2).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        let first =
+            authorize_params(&test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let rejected = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/callback?code=first&state={}", first["state"]))
+                .to_request(),
+        )
+        .await;
+        let retry = authorize_params(&rejected);
+        let rejected_again = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri(&format!("/callback?code=second&state={}", retry["state"]))
+                .to_request(),
+        )
+        .await;
+
+        assert_eq!(rejected_again.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn replayed_code_is_rejected_for_another_launch() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(token_response()))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        // the second launch's state is still pending when the code is replayed
+        let first_state =
+            launch_state(test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let second_state =
+            launch_state(test::call_service(&app, launch_request(&ehr).to_request()).await);
+        let mut responses = Vec::new();
+        for state in [first_state, second_state] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/callback?code=intercepted&state={state}"))
+                .to_request();
+            responses.push(test::call_service(&app, req).await.status());
+        }
+
+        assert_eq!(responses, [StatusCode::SEE_OTHER, StatusCode::FORBIDDEN]);
+    }
+
+    #[actix_web::test]
+    async fn failed_callbacks_drop_the_launch() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("code=rejected"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "error": "invalid_grant"
+            })))
+            .mount(&ehr)
+            .await;
+        mock_token_endpoint(&ehr, token_response()).await;
+        let data = web::Data::new(test_state());
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(launch)
+                .service(callback),
+        )
+        .await;
+
+        // the code is exchanged for the first launch, then replayed for the last
+        let mut statuses = Vec::new();
+        for query in ["code=abc", "", "code=rejected", "code=abc"] {
+            let state =
+                launch_state(test::call_service(&app, launch_request(&ehr).to_request()).await);
+            let req = test::TestRequest::get()
+                .uri(&format!("/callback?{query}&state={state}"))
+                .to_request();
+            statuses.push(test::call_service(&app, req).await.status());
+        }
+
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::SEE_OTHER,
+                StatusCode::BAD_REQUEST,
+                StatusCode::FORBIDDEN,
+                StatusCode::FORBIDDEN
+            ]
+        );
+        assert_eq!(data.launch_entries(), 0);
     }
 }
@@ -0,0 +1,174 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fhir_sdk::client::Error;
+use fhir_sdk::r4b::resources::{CarePlan, Goal};
+use log::error;
+use serde::Serialize;
+
+use crate::display::display_codeable_concept;
+
+// Placeholder displayed for a goal that has no description.
+pub const UNDESCRIBED_GOAL: &str = "Undescribed goal";
+
+// Placeholder displayed for a care plan that has neither a title nor a category.
+pub const UNTITLED_CARE_PLAN: &str = "Untitled care plan";
+
+// A summary of a goal, for display.
+#[derive(Serialize)]
+pub struct GoalSummary {
+    pub description: String,
+    pub status: String,
+}
+
+// A summary of a care plan, for display.
+#[derive(Serialize)]
+pub struct CarePlanSummary {
+    pub title: String,
+    pub status: String,
+}
+
+// Summarizes a goal.
+//
+// A [Goal](http://hl7.org/fhir/R4B/goal.html) is displayed using the text of its
+// [description](http://hl7.org/fhir/R4B/goal-definitions.html#Goal.description),
+// along with its lifecycle status (e.g., "active").
+//
+// # Arguments
+// * `goal` The goal to summarize.
+pub fn summarize_goal(goal: &Goal) -> GoalSummary {
+    GoalSummary {
+        description: display_codeable_concept(&goal.description)
+            .unwrap_or_else(|| UNDESCRIBED_GOAL.to_string()),
+        status: goal.lifecycle_status.to_string(),
+    }
+}
+
+// Summarizes a care plan.
+//
+// A [CarePlan](http://hl7.org/fhir/R4B/careplan.html) is displayed using its title.
+// Care plans do not need a title, so we fall back to its categories (e.g.,
+// "Diabetes self management plan").
+//
+// # Arguments
+// * `care_plan` The care plan to summarize.
+pub fn summarize_care_plan(care_plan: &CarePlan) -> CarePlanSummary {
+    let title = care_plan.title.clone().or_else(|| {
+        let categories: Vec<String> = care_plan
+            .category
+            .iter()
+            .flatten()
+            .filter_map(display_codeable_concept)
+            .collect();
+        (!categories.is_empty()).then(|| categories.join(", "))
+    });
+
+    CarePlanSummary {
+        title: title.unwrap_or_else(|| UNTITLED_CARE_PLAN.to_string()),
+        status: care_plan.status.to_string(),
+    }
+}
+
+// Summarizes the goals for a patient.
+//
+// If the search for goals failed, returns an empty list.
+//
+// # Arguments
+// * `search_query` The result of a query searching for goals.
+pub fn summarize_goals(search_query: Result<Vec<Goal>, Error>) -> Vec<GoalSummary> {
+    match search_query {
+        Ok(goals) => goals.iter().map(summarize_goal).collect(),
+        Err(e) => {
+            error!("Fetching goals failed with error: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+// Summarizes the care plans for a patient.
+//
+// If the search for care plans failed, returns an empty list.
+//
+// # Arguments
+// * `search_query` The result of a query searching for care plans.
+pub fn summarize_care_plans(search_query: Result<Vec<CarePlan>, Error>) -> Vec<CarePlanSummary> {
+    match search_query {
+        Ok(care_plans) => care_plans.iter().map(summarize_care_plan).collect(),
+        Err(e) => {
+            error!("Fetching care plans failed with error: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn goal_shows_description_and_lifecycle_status() {
+        let goal: Goal = serde_json::from_value(json!({
+            "resourceType": "Goal",
+            "lifecycleStatus": "active",
+            "description": { "text": "Walk daily" },
+            "subject": { "reference": "Patient/123" }
+        }))
+        .unwrap();
+
+        let summary = summarize_goal(&goal);
+
+        assert_eq!(summary.description, "Walk daily");
+        assert_eq!(summary.status, "active");
+    }
+
+    #[test]
+    fn care_plan_falls_back_to_categories_without_title() {
+        let care_plan: CarePlan = serde_json::from_value(json!({
+            "resourceType": "CarePlan",
+            "status": "active",
+            "intent": "plan",
+            "category": [
+                { "text": "Diabetes self management plan" },
+                { "text": "Weight management" }
+            ],
+            "subject": { "reference": "Patient/123" }
+        }))
+        .unwrap();
+
+        let summary = summarize_care_plan(&care_plan);
+
+        assert_eq!(
+            summary.title,
+            "Diabetes self management plan, Weight management"
+        );
+        assert_eq!(summary.status, "active");
+    }
+
+    #[test]
+    fn care_plan_without_title_or_category_is_untitled() {
+        let care_plan: CarePlan = serde_json::from_value(json!({
+            "resourceType": "CarePlan",
+            "status": "draft",
+            "intent": "plan",
+            "subject": { "reference": "Patient/123" }
+        }))
+        .unwrap();
+
+        assert_eq!(summarize_care_plan(&care_plan).title, UNTITLED_CARE_PLAN);
+    }
+}
@@ -0,0 +1,306 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::{get, web, HttpResponse};
+use fhir_sdk::client::SearchParameters;
+use fhir_sdk::r4b::resources::Observation;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+use crate::fetch::{fetch_for_patient, is_session_expired, is_valid_id};
+use crate::render::to_json;
+use crate::state::State;
+
+// The maximum number of distinct codes we list for a patient.
+const MAX_CODES: usize = 200;
+
+#[derive(Deserialize)]
+pub struct CodesQuery {
+    // Whether to indent the document. Defaults to the `pretty_json` setting.
+    pretty: Option<bool>,
+}
+
+// A code that a patient's observations are coded with, and how often it is used.
+#[derive(Serialize)]
+struct ObservedCode {
+    system: Option<String>,
+    code: String,
+    display: Option<String>,
+    count: usize,
+}
+
+// The codes that a patient's observations are coded with.
+#[derive(Serialize)]
+struct ObservedCodes {
+    codes: Vec<ObservedCode>,
+
+    // Whether the patient has more observations than we searched, or more distinct
+    // codes than we list, in which case some codes or counts are missing.
+    truncated: bool,
+}
+
+/**
+ * Observation codes
+ * -----------------
+ * Lists the codes that a patient's [observations](http://hl7.org/fhir/R4B/observation.html)
+ * are coded with, along with a display name for each code and the number of
+ * observations using it. This lets a UI offer the data that exists for the patient,
+ * rather than a fixed list of measurements.
+ *
+ * We search for the patient's observations with `_elements=code`, so that the FHIR
+ * server only returns their codes, and count the codings of each observation. The
+ * search is bounded by the search limit, and at most 200 codes are listed, most used
+ * first; if either bound is reached, the document is marked as `truncated`.
+ *
+ * The document is compact; pass `?pretty=true` to indent it for debugging.
+ */
+#[get("/{patient_id}/codes.json")]
+pub async fn codes_json(
+    data: web::Data<State>,
+    patient_id: web::Path<String>,
+    query: web::Query<CodesQuery>,
+) -> HttpResponse {
+    let patient_id = patient_id.into_inner();
+    if !is_valid_id(&patient_id) {
+        return HttpResponse::BadRequest().body("Invalid patient ID.");
+    }
+
+    let Some(client) = data.get_token(&patient_id) else {
+        return HttpResponse::Unauthorized()
+            .body(format!("Failed to find token for {patient_id}."));
+    };
+    if client.patient.is_none() {
+        return HttpResponse::NotFound().body(format!(
+            "Session {patient_id} does not have a patient in context."
+        ));
+    }
+    if !client.can_read("Observation") {
+        return HttpResponse::Forbidden().body(format!(
+            "Session for {patient_id} is not authorized to read Observation resources."
+        ));
+    }
+
+    // we take one more observation than the limit, so that we can tell whether
+    // there are more than we searched
+    let observations = client
+        .limiter
+        .run_request(fetch_for_patient::<Observation>(
+            client.client_for("Observation"),
+            &patient_id,
+            SearchParameters::empty().and_raw("_elements", "code"),
+            data.search_limit.saturating_add(1),
+        ))
+        .await;
+
+    match observations {
+        Ok(mut observations) => {
+            let mut truncated = observations.len() > data.search_limit;
+            observations.truncate(data.search_limit);
+            let mut codes = count_codes(&observations);
+            if codes.len() > MAX_CODES {
+                truncated = true;
+                codes.truncate(MAX_CODES);
+            }
+
+            match to_json(
+                &ObservedCodes { codes, truncated },
+                query.pretty.unwrap_or(data.pretty_json),
+            ) {
+                Ok(json) => HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(json),
+                Err(e) => {
+                    error!(
+                        "Serializing observation codes for {patient_id} failed with error: {:?}",
+                        e
+                    );
+                    HttpResponse::InternalServerError().finish()
+                }
+            }
+        }
+        Err(e) if is_session_expired(&e) => {
+            HttpResponse::Unauthorized().body(format!("Session for {patient_id} has expired."))
+        }
+        Err(e) => {
+            error!(
+                "Searching for observation codes for {patient_id} failed with error: {:?}",
+                e
+            );
+            HttpResponse::BadGateway().finish()
+        }
+    }
+}
+
+// Counts the codes that observations are coded with.
+//
+// Each coding with a code counts once per observation, keyed by its system and code.
+// The display name is taken from the first coding that has one. Returns the codes
+// ordered by how often they are used, and then by code.
+//
+// # Arguments
+// * `observations` The observations to count the codes of.
+fn count_codes(observations: &[Observation]) -> Vec<ObservedCode> {
+    let mut counts: HashMap<(Option<String>, String), ObservedCode> = HashMap::new();
+    for observation in observations {
+        let mut seen = Vec::new();
+        for coding in observation.code.coding.iter().flatten() {
+            let Some(code) = &coding.code else {
+                continue;
+            };
+            let key = (coding.system.clone(), code.clone());
+            if seen.contains(&key) {
+                continue;
+            }
+            seen.push(key.clone());
+
+            let entry = counts.entry(key).or_insert_with(|| ObservedCode {
+                system: coding.system.clone(),
+                code: code.clone(),
+                display: None,
+                count: 0,
+            });
+            entry.count += 1;
+            if entry.display.is_none() {
+                entry.display = coding
+                    .display
+                    .clone()
+                    .or_else(|| observation.code.text.clone());
+            }
+        }
+    }
+
+    let mut codes: Vec<ObservedCode> = counts.into_values().collect();
+    codes.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.code.cmp(&b.code))
+            .then_with(|| a.system.cmp(&b.system))
+    });
+    codes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::test_support::{put_session, search_bundle, test_state};
+
+    // Builds an observation coded with LOINC codes.
+    //
+    // # Arguments
+    // * `codes` The codes and their display names.
+    fn observation(codes: &[(&str, &str)]) -> Value {
+        let coding: Vec<Value> = codes
+            .iter()
+            .map(|(code, display)| {
+                json!({ "system": "http://loinc.org", "code": code, "display": display })
+            })
+            .collect();
+        json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "coding": coding }
+        })
+    }
+
+    // Requests the observation codes of patient 123 from a mock EHR.
+    //
+    // # Arguments
+    // * `observations` The observations that the EHR returns.
+    // * `state` The application state.
+    async fn get_codes(observations: Vec<Value>, state: State) -> (StatusCode, Value) {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Observation"))
+            .and(query_param("_elements", "code"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(observations)))
+            .mount(&ehr)
+            .await;
+        put_session(&state, &ehr, "123", &["patient/Observation.read"]).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(codes_json),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/123/codes.json").to_request();
+        let resp = test::call_service(&app, req).await;
+        let status = resp.status();
+        (status, test::read_body_json(resp).await)
+    }
+
+    #[actix_web::test]
+    async fn codes_reflect_observations() {
+        let (status, body) = get_codes(
+            vec![
+                observation(&[("8302-2", "Body height")]),
+                observation(&[("8302-2", "Body height")]),
+                observation(&[("29463-7", "Body weight"), ("29463-7", "Weight")]),
+            ],
+            test_state(),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({
+                "codes": [
+                    {
+                        "system": "http://loinc.org",
+                        "code": "8302-2",
+                        "display": "Body height",
+                        "count": 2
+                    },
+                    {
+                        "system": "http://loinc.org",
+                        "code": "29463-7",
+                        "display": "Body weight",
+                        "count": 1
+                    }
+                ],
+                "truncated": false
+            })
+        );
+    }
+
+    #[actix_web::test]
+    async fn codes_are_truncated_at_search_limit() {
+        let (status, body) = get_codes(
+            vec![
+                observation(&[("8302-2", "Body height")]),
+                observation(&[("8302-2", "Body height")]),
+                observation(&[("29463-7", "Body weight")]),
+            ],
+            test_state().with_search_limit(2),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["truncated"], true);
+        assert_eq!(body["codes"].as_array().unwrap().len(), 1);
+        assert_eq!(body["codes"][0]["count"], 2);
+    }
+}
@@ -0,0 +1,186 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use url::form_urlencoded::byte_serialize;
+use url::Url;
+
+use crate::smart::brand::Brand;
+use crate::smart::style::SmartStyle;
+
+// Gets the path to the summary of a patient, in a format.
+//
+// The path names the issuer, as patient IDs are only unique within an issuer.
+//
+// # Arguments
+// * `session_key` The key the session is stored under.
+// * `iss` The URL that issued the launch.
+// * `extension` The extension for the format, e.g. "html".
+pub fn summary_path(session_key: &str, iss: &str, extension: &str) -> String {
+    format!(
+        "/{session_key}/index.{extension}?iss={}",
+        byte_serialize(iss.as_bytes()).collect::<String>()
+    )
+}
+
+// Gets a relative reference to a practitioner from a `fhirUser` claim.
+//
+// The claim is a URL for the FHIR resource representing the user, which may be
+// absolute (e.g. "https://ehr.example.com/fhir/Practitioner/123") or relative. Returns
+// an empty option if the user is not a practitioner, e.g. if they are a patient.
+//
+// # Arguments
+// * `fhir_user` The `fhirUser` claim.
+pub fn practitioner_reference(fhir_user: &str) -> Option<String> {
+    let mut segments = fhir_user.trim_end_matches('/').rsplit('/');
+    let id = segments.next().filter(|id| !id.is_empty())?;
+    match segments.next() {
+        Some("Practitioner") => Some(format!("Practitioner/{id}")),
+        _ => None,
+    }
+}
+
+// What we know about a launch, for display.
+//
+// Built once, when we exchange the authorization code for a token, so that pages
+// can be rendered from a single description of the launch.
+#[derive(Clone, Debug)]
+pub struct LaunchContext {
+    // The key the session is stored under. Used to link to session-specific pages,
+    // e.g. logging out.
+    pub session_key: String,
+
+    // The URL that issued the launch.
+    pub iss: String,
+
+    // The ID of the patient in context, if the launch has patient context.
+    pub patient: Option<String>,
+
+    // The ID of the encounter in context, if the EHR provided one.
+    pub encounter: Option<String>,
+
+    // References to other resources in context, e.g. an order that the user
+    // launched the app from.
+    pub fhir_context: Vec<String>,
+
+    // The name of the logged in user, if the `openid` scope was granted.
+    pub user_name: Option<String>,
+
+    // A reference to the logged in user's Practitioner resource, e.g.
+    // "Practitioner/123", if the EHR identified the user as a practitioner.
+    pub practitioner: Option<String>,
+
+    // The scopes that the EHR granted.
+    pub scopes: Vec<String>,
+
+    // How the EHR presents itself to users, if known.
+    pub brand: Option<Brand>,
+
+    // Whether the app needs to display a patient banner, because the EHR does not.
+    pub need_patient_banner: bool,
+
+    // The URL of the styles the EHR asks apps to use, if it provided one.
+    pub style_url: Option<String>,
+
+    // The styles fetched from `style_url`. Not set when the session is created, but
+    // filled in from the cache of styles when rendering pages (see `State::get_style`).
+    pub style: Option<SmartStyle>,
+
+    // Whether the EHR launched the app, rather than the user launching it standalone.
+    pub ehr_launch: bool,
+}
+
+impl LaunchContext {
+    // Gets the name of the EHR that we are connected to, for display.
+    //
+    // Prefers the EHR's brand name, falling back to its issuer URL.
+    pub fn connected_to(&self) -> &str {
+        self.brand
+            .as_ref()
+            .map(|brand| brand.name.as_str())
+            .unwrap_or(&self.iss)
+    }
+
+    // Gets the URL of the EHR's logo, if it has one.
+    pub fn logo(&self) -> Option<&str> {
+        self.brand.as_ref().and_then(|brand| brand.logo.as_deref())
+    }
+
+    // Checks whether the granted scopes allow reading a type of resource.
+    //
+    // Accepts both [SMART v1](https://hl7.org/fhir/smart-app-launch/1.0.0/scopes-and-launch-context/)
+    // scopes (e.g., `patient/Patient.read`) and
+    // [SMART v2](https://build.fhir.org/ig/HL7/smart-app-launch/scopes-and-launch-context.html)
+    // scopes (e.g., `patient/Patient.rs`), as well as wildcards. SMART v2 scopes that
+    // are restricted by search parameters (e.g., `patient/Observation.rs?category=laboratory`)
+    // count, as they allow reading some resources of the type.
+    //
+    // # Arguments
+    // * `resource_type` The type of resource to read, e.g. "Patient".
+    pub fn can_read(&self, resource_type: &str) -> bool {
+        self.scopes.iter().any(|scope| {
+            let Some((context, rest)) = scope.split_once('/') else {
+                return false;
+            };
+            let Some((scope_type, permissions)) = rest.split_once('.') else {
+                return false;
+            };
+            let permissions = permissions
+                .split_once('?')
+                .map_or(permissions, |(permissions, _)| permissions);
+
+            matches!(context, "patient" | "user")
+                && (scope_type == resource_type || scope_type == "*")
+                && match permissions {
+                    "read" | "*" => true,
+                    v2 => v2.contains('r') && v2.chars().all(|c| "cruds".contains(c)),
+                }
+        })
+    }
+
+    // Gets the path to the summary for this session, in a format.
+    //
+    // # Arguments
+    // * `extension` The extension for the format, e.g. "html".
+    pub fn summary_path(&self, extension: &str) -> String {
+        summary_path(&self.session_key, &self.iss, extension)
+    }
+
+    // Gets the `Content-Security-Policy` that controls who may frame our pages.
+    //
+    // EHRs often display apps that they launch in an iframe, so for EHR launches we
+    // permit framing by the issuer's origin. Otherwise, we deny framing entirely, to
+    // prevent clickjacking.
+    //
+    // # Arguments
+    // * `allow_ehr_framing` Whether EHRs may frame the app that they launched.
+    pub fn frame_ancestors(&self, allow_ehr_framing: bool) -> String {
+        let origin = Url::parse(&self.iss)
+            .ok()
+            .map(|iss| iss.origin())
+            .filter(|origin| origin.is_tuple());
+        match origin {
+            Some(origin) if allow_ehr_framing && self.ehr_launch => {
+                format!("frame-ancestors {}", origin.ascii_serialization())
+            }
+            _ => String::from("frame-ancestors 'none'"),
+        }
+    }
+
+    // Gets the path to log out of this session.
+    pub fn logout_path(&self) -> String {
+        format!("/logout/{}", self.session_key)
+    }
+}
@@ -0,0 +1,154 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::HttpRequest;
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+
+use crate::aead::Aead;
+
+// The name of the cookie holding the launch context of a browser session.
+pub const CONTEXT_COOKIE: &str = "rust_smart_fhir_context";
+
+// Encrypts the launch context that we hand to the browser in a cookie.
+//
+// After a launch, we can tell the browser which issuer and patient it launched with,
+// so that the summary page can pick the right session without asking the user, even
+// if the patient has sessions from several issuers. The context is encrypted and
+// authenticated with AES-256-GCM under a key from our configuration, so that it can
+// neither be read nor forged by the browser. The token itself stays in the state store.
+pub struct ContextCookieCipher {
+    aead: Aead,
+}
+
+impl ContextCookieCipher {
+    // Creates a cipher from a 256-bit key.
+    //
+    // Returns an error if the key has the wrong length.
+    //
+    // # Arguments
+    // * `key` The encryption key.
+    pub fn new(key: &[u8]) -> Result<ContextCookieCipher, String> {
+        Aead::new(key)
+            .map(|aead| ContextCookieCipher { aead })
+            .map_err(|_| format!("Context cookie key must be {} bytes long", Aead::key_len()))
+    }
+
+    // Encrypts a launch context into a cookie value.
+    //
+    // Returns an error if we fail to generate a nonce or to encrypt the context.
+    //
+    // # Arguments
+    // * `iss` The URL of the server that issued the launch.
+    // * `patient` The ID of the patient in context.
+    pub fn seal(&self, iss: &str, patient: &str) -> Result<String, String> {
+        let plaintext = serde_json::to_vec(&(iss, patient)).map_err(|e| e.to_string())?;
+        self.aead
+            .seal(CONTEXT_COOKIE.as_bytes(), &plaintext)
+            .map(|sealed| BASE64_URL_SAFE_NO_PAD.encode(sealed))
+            .map_err(|_| String::from("Failed to encrypt the launch context"))
+    }
+
+    // Decrypts a launch context from a cookie value.
+    //
+    // Returns the issuer and patient ID, or an empty option if the value was not
+    // sealed with our key, or was tampered with.
+    //
+    // # Arguments
+    // * `value` The cookie value.
+    pub fn open(&self, value: &str) -> Option<(String, String)> {
+        let sealed = BASE64_URL_SAFE_NO_PAD.decode(value).ok()?;
+        let plaintext = self.aead.open(CONTEXT_COOKIE.as_bytes(), &sealed)?;
+        serde_json::from_slice(&plaintext).ok()
+    }
+
+    // Gets the issuer that a browser launched a patient from, if its context cookie
+    // names the patient.
+    //
+    // # Arguments
+    // * `req` The request.
+    // * `patient_id` The ID of the patient.
+    pub fn issuer_for(&self, req: &HttpRequest, patient_id: &str) -> Option<String> {
+        let cookie = req.cookie(CONTEXT_COOKIE)?;
+        self.open(cookie.value())
+            .filter(|(_, patient)| patient == patient_id)
+            .map(|(iss, _)| iss)
+    }
+}
+
+// Builds the cookie holding the launch context of a browser session.
+//
+// # Arguments
+// * `value` The sealed launch context; see `ContextCookieCipher::seal`.
+// * `secure` Whether the cookie should only be sent over HTTPS.
+pub fn context_cookie(value: String, secure: bool) -> Cookie<'static> {
+    Cookie::build(CONTEXT_COOKIE, value)
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_context_round_trips() {
+        let cipher = ContextCookieCipher::new(&[7u8; 32]).unwrap();
+
+        let value = cipher.seal("https://ehr.example.com/fhir", "123").unwrap();
+
+        assert!(!value.contains("ehr.example.com"));
+        assert_eq!(
+            cipher.open(&value),
+            Some((
+                String::from("https://ehr.example.com/fhir"),
+                String::from("123")
+            ))
+        );
+    }
+
+    #[test]
+    fn tampered_context_is_rejected() {
+        let cipher = ContextCookieCipher::new(&[7u8; 32]).unwrap();
+        let mut value = BASE64_URL_SAFE_NO_PAD
+            .decode(cipher.seal("https://ehr.example.com/fhir", "123").unwrap())
+            .unwrap();
+        let last = value.len() - 1;
+        value[last] ^= 1;
+
+        assert_eq!(cipher.open(&BASE64_URL_SAFE_NO_PAD.encode(value)), None);
+        assert_eq!(cipher.open("not base64!"), None);
+        assert_eq!(cipher.open(""), None);
+    }
+
+    #[test]
+    fn context_sealed_with_other_key_is_rejected() {
+        let cipher = ContextCookieCipher::new(&[7u8; 32]).unwrap();
+        let other = ContextCookieCipher::new(&[8u8; 32]).unwrap();
+
+        let value = other.seal("https://ehr.example.com/fhir", "123").unwrap();
+
+        assert_eq!(cipher.open(&value), None);
+    }
+
+    #[test]
+    fn short_key_is_rejected() {
+        assert!(ContextCookieCipher::new(&[7u8; 16]).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use maud::{html, Markup, DOCTYPE};
+
+use crate::session::{session_id, RecentPatient};
+use crate::state::State;
+
+/**
+ * Dashboard: recently viewed patients
+ * -----------------------------------
+ * Providers often move between several patients. Each time the browser views a
+ * patient summary, we remember the patient for the browser's session; this page
+ * lists the patients viewed in the session, most recent first, with links back to
+ * their summaries.
+ *
+ * Only patients that we still hold a token for are listed. We remember at most
+ * `RECENT_PATIENTS_LIMIT` patients per session.
+ */
+#[get("/dashboard")]
+pub async fn dashboard(req: HttpRequest, data: web::Data<State>) -> HttpResponse {
+    let recent_patients = match session_id(&req) {
+        Some(session) => data.get_recent_patients(&session),
+        None => Vec::new(),
+    };
+
+    HttpResponse::Ok().body(render_dashboard(&data, &recent_patients).into_string())
+}
+
+// Renders the dashboard page.
+//
+// # Arguments
+// * `data` The application state.
+// * `recent_patients` The recently viewed patients, most recent first.
+#[rustfmt::skip::macros(html)]
+fn render_dashboard(data: &State, recent_patients: &[RecentPatient]) -> Markup {
+    html! {
+	(DOCTYPE);
+	html lang="en" {
+            head {
+		title {
+		    "Example SMART-on-FHIR app: dashboard"
+		}
+            }
+            body {
+		div #holder {
+		    h1 {
+			"Example SMART-on-FHIR app"
+		    }
+		    section #recent-patients {
+			h2 {
+			    "Recently viewed patients"
+			}
+			@if recent_patients.is_empty() {
+			    p {
+				"You have not viewed any patients yet. To view a patient, "
+				a #relaunch href=(data.relaunch_url) {
+				    "launch the app"
+				}
+				" from your EHR."
+			    }
+			} @else {
+			    ul {
+				@for patient in recent_patients {
+				    li {
+					a href=(format!("{}/{}/index.html", data.app_domain, patient.id)) {
+					    @if let Some(name) = &patient.name {
+						(name)
+					    } @else {
+						"Patient "
+						(patient.id)
+					    }
+					}
+				    }
+				}
+			    }
+			}
+		    }
+		}
+            }
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{test, App};
+    use serde_json::json;
+    use uuid::Uuid;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::index::index;
+    use crate::session::session_cookie;
+    use crate::test_support::{put_session, search_bundle, test_state};
+
+    // Serves a patient from a mock EHR.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `id` The ID of the patient.
+    // * `family` The family name of the patient.
+    async fn mock_patient(ehr: &MockServer, id: &str, family: &str) {
+        Mock::given(method("GET"))
+            .and(path(format!("/Patient/{id}")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Patient",
+                "id": id,
+                "name": [{ "family": family, "given": ["Pat"] }]
+            })))
+            .mount(ehr)
+            .await;
+    }
+
+    #[actix_web::test]
+    async fn dashboard_lists_viewed_patients_most_recent_first() {
+        let ehr = MockServer::start().await;
+        mock_patient(&ehr, "1", "First").await;
+        mock_patient(&ehr, "2", "Second").await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(Vec::new())))
+            .mount(&ehr)
+            .await;
+        let state = test_state();
+        put_session(&state, &ehr, "1", &["patient/*.read"]).await;
+        put_session(&state, &ehr, "2", &["patient/*.read"]).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(index)
+                .service(dashboard),
+        )
+        .await;
+        let cookie = session_cookie(&Uuid::new_v4(), false);
+
+        for patient in ["1", "2"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/{patient}/index.html"))
+                .cookie(cookie.clone())
+                .to_request();
+            assert!(test::call_service(&app, req).await.status().is_success());
+        }
+        let req = test::TestRequest::get()
+            .uri("/dashboard")
+            .cookie(cookie)
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        let second = body.find("Pat Second").unwrap();
+        let first = body.find("Pat First").unwrap();
+        assert!(second < first);
+    }
+}
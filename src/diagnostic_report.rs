@@ -0,0 +1,252 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono_tz::Tz;
+use fhir_sdk::client::Client as FhirClient;
+use fhir_sdk::client::FhirR4B;
+use fhir_sdk::r4b::resources::{
+    DiagnosticReport, DiagnosticReportEffective, Observation, Resource,
+};
+use fhir_sdk::r4b::types::Reference;
+use fhir_sdk::ParsedReference;
+use futures::future::join_all;
+use log::error;
+use serde::Serialize;
+
+use crate::display::{display_codeable_concept, display_date_time, display_period};
+use crate::limit::RequestLimiter;
+use crate::observation::{observation_value, ValuePrecision};
+
+// Placeholder displayed when a result cannot be resolved, or has no name.
+pub const UNKNOWN_RESULT: &str = "Unknown result";
+
+// Placeholder displayed when a result's value cannot be resolved.
+pub const UNAVAILABLE_RESULT: &str = "Result unavailable";
+
+// A single result in a diagnostic report, resolved from an Observation.
+#[derive(Serialize)]
+pub struct ReportResult {
+    pub name: String,
+    pub value: String,
+}
+
+// A summary of a diagnostic report, for display.
+#[derive(Serialize)]
+pub struct ReportSummary {
+    pub name: String,
+    pub effective: Option<String>,
+    pub results: Vec<ReportResult>,
+}
+
+// Summarizes a diagnostic report.
+//
+// A [DiagnosticReport](http://hl7.org/fhir/R4B/diagnosticreport.html) groups the
+// results of a set of tests (e.g., a lab panel), which are referenced as
+// [Observation](http://hl7.org/fhir/R4B/observation.html) resources in its
+// [result](http://hl7.org/fhir/R4B/diagnosticreport-definitions.html#DiagnosticReport.result)
+// field. We resolve each result to its value; results that cannot be resolved are
+// displayed using placeholders.
+//
+// # Arguments
+// * `client` The FHIR client to use to resolve results.
+// * `limiter` The limit on requests to the FHIR server.
+// * `report` The diagnostic report to summarize.
+// * `timezone` The timezone to display times in.
+// * `precision` The number of decimal places to display result values with, keyed by code.
+pub async fn summarize_report(
+    client: &FhirClient<FhirR4B>,
+    limiter: &RequestLimiter,
+    report: &DiagnosticReport,
+    timezone: &Tz,
+    precision: &ValuePrecision,
+) -> ReportSummary {
+    let effective = report.effective.as_ref().map(|effective| match effective {
+        DiagnosticReportEffective::DateTime(date_time) => display_date_time(date_time, timezone),
+        DiagnosticReportEffective::Period(period) => display_period(period, timezone),
+    });
+
+    let results = join_all(
+        report
+            .result
+            .iter()
+            .flatten()
+            .map(|reference| resolve_result(client, limiter, report, reference, precision)),
+    )
+    .await;
+
+    ReportSummary {
+        name: display_codeable_concept(&report.code)
+            .unwrap_or_else(|| String::from(UNKNOWN_RESULT)),
+        effective,
+        results,
+    }
+}
+
+// Resolves a reference to a result in a diagnostic report.
+//
+// Checks the resources contained in the report first. Otherwise, if the reference
+// is relative to the FHIR server, reads the Observation resource. As with
+// medications, we do not follow absolute references.
+//
+// # Arguments
+// * `client` The FHIR client to use.
+// * `limiter` The limit on requests to the FHIR server.
+// * `report` The diagnostic report that holds the reference.
+// * `reference` The reference to resolve.
+// * `precision` The number of decimal places to display the result's value with.
+async fn resolve_result(
+    client: &FhirClient<FhirR4B>,
+    limiter: &RequestLimiter,
+    report: &DiagnosticReport,
+    reference: &Reference,
+    precision: &ValuePrecision,
+) -> ReportResult {
+    let observation = match reference.parse() {
+        Some(ParsedReference::Local { id }) => {
+            report.contained.iter().find_map(|resource| match resource {
+                Resource::Observation(observation) if observation.id.as_deref() == Some(id) => {
+                    Some(observation.clone())
+                }
+                _ => None,
+            })
+        }
+        Some(ParsedReference::Relative {
+            resource_type: "Observation",
+            ..
+        }) => match limiter.run_request(client.read_referenced(reference)).await {
+            Ok(Resource::Observation(observation)) => Some(observation),
+            Ok(resource) => {
+                error!(
+                    "Result reference {:?} resolved to a {} resource",
+                    reference.reference,
+                    resource.resource_type()
+                );
+                None
+            }
+            Err(e) => {
+                error!(
+                    "Resolving result reference {:?} failed with error: {:?}",
+                    reference.reference, e
+                );
+                None
+            }
+        },
+        _ => None,
+    };
+
+    match observation {
+        Some(observation) => result_from_observation(&observation, precision),
+        None => ReportResult {
+            name: reference
+                .display
+                .clone()
+                .unwrap_or_else(|| String::from(UNKNOWN_RESULT)),
+            value: String::from(UNAVAILABLE_RESULT),
+        },
+    }
+}
+
+fn result_from_observation(observation: &Observation, precision: &ValuePrecision) -> ReportResult {
+    ReportResult {
+        name: display_codeable_concept(&observation.code)
+            .unwrap_or_else(|| String::from(UNKNOWN_RESULT)),
+        value: observation_value(observation, precision)
+            .unwrap_or_else(|| String::from(UNAVAILABLE_RESULT)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::observation::default_value_precision;
+    use crate::test_support::fhir_client;
+
+    // Serves an Observation from a mock FHIR server.
+    //
+    // # Arguments
+    // * `server` The mock FHIR server.
+    // * `id` The ID of the observation.
+    // * `name` The name of the observation.
+    // * `value` The value of the observation, in mg/dL.
+    async fn mock_observation(server: &MockServer, id: &str, name: &str, value: f64) {
+        Mock::given(method("GET"))
+            .and(path(format!("/Observation/{id}")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Observation",
+                "id": id,
+                "status": "final",
+                "code": { "text": name },
+                "valueQuantity": { "value": value, "unit": "mg/dL" }
+            })))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    #[actix_web::test]
+    async fn summarize_report_resolves_result_observations() {
+        let server = MockServer::start().await;
+        mock_observation(&server, "ldl", "LDL cholesterol", 100.0).await;
+        mock_observation(&server, "hdl", "HDL cholesterol", 50.0).await;
+        Mock::given(method("GET"))
+            .and(path("/Observation/missing"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        let report: DiagnosticReport = serde_json::from_value(json!({
+            "resourceType": "DiagnosticReport",
+            "status": "final",
+            "code": { "text": "Lipid panel" },
+            "effectiveDateTime": "2024-03-01",
+            "result": [
+                { "reference": "Observation/ldl" },
+                { "reference": "Observation/hdl" },
+                { "reference": "Observation/missing", "display": "Triglycerides" }
+            ]
+        }))
+        .unwrap();
+
+        let summary = summarize_report(
+            &fhir_client(&server),
+            &RequestLimiter::default(),
+            &report,
+            &Tz::UTC,
+            &default_value_precision(),
+        )
+        .await;
+
+        assert_eq!(summary.name, "Lipid panel");
+        assert!(summary.effective.is_some());
+        let results: Vec<(&str, &str)> = summary
+            .results
+            .iter()
+            .map(|result| (result.name.as_str(), result.value.as_str()))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                ("LDL cholesterol", "100 mg/dL"),
+                ("HDL cholesterol", "50 mg/dL"),
+                ("Triglycerides", UNAVAILABLE_RESULT),
+            ]
+        );
+    }
+}
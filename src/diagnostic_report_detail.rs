@@ -0,0 +1,171 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::{get, web, HttpResponse, ResponseError};
+use fhir_sdk::r4b::resources::DiagnosticReport;
+use log::error;
+use maud::{html, Markup, DOCTYPE};
+
+use crate::context::LaunchContext;
+use crate::diagnostic_report::{summarize_report, ReportSummary};
+use crate::error::AppError;
+use crate::fetch::{is_session_expired, is_valid_id, references_patient};
+use crate::state::State;
+
+/**
+ * Diagnostic report details
+ * -------------------------
+ * Shows a single [diagnostic report](http://hl7.org/fhir/R4B/diagnosticreport.html),
+ * with the values of the observations it references as results. EHRs can launch the
+ * app straight into this page by including the report in the launch's `fhirContext`.
+ *
+ * Both IDs in the path must be valid FHIR IDs. We only show reports about the patient
+ * in context for the session.
+ */
+#[get("/{patient_id}/diagnostic-report/{report_id}.html")]
+pub async fn diagnostic_report_detail(
+    data: web::Data<State>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (patient_id, report_id) = path.into_inner();
+    if !is_valid_id(&patient_id) || !is_valid_id(&report_id) {
+        return HttpResponse::BadRequest().body("Invalid patient or diagnostic report ID.");
+    }
+
+    let Some(client) = data.get_token(&patient_id) else {
+        return HttpResponse::Unauthorized()
+            .body(format!("Failed to find token for {patient_id}."));
+    };
+    let Some(patient) = client.patient.clone() else {
+        return data
+            .error_page(AppError::NotFound(String::from(
+                "This session does not have a patient in context.",
+            )))
+            .error_response();
+    };
+    if !client.can_read("DiagnosticReport") {
+        return data
+            .error_page(AppError::Forbidden(String::from(
+                "This session is not authorized to read diagnostic reports.",
+            )))
+            .error_response();
+    }
+
+    let report = client
+        .limiter
+        .run_request(
+            client
+                .client_for("DiagnosticReport")
+                .read::<DiagnosticReport>(&report_id),
+        )
+        .await;
+
+    match report {
+        // do not show reports about other patients, even if the token allows it
+        Ok(Some(report)) if references_patient(report.subject.as_ref(), &patient) => {
+            let summary = summarize_report(
+                client.client_for("Observation"),
+                &client.limiter,
+                &report,
+                &data.timezone,
+                &data.value_precision,
+            )
+            .await;
+            HttpResponse::Ok().body(render_report(&summary, &client.context).into_string())
+        }
+        Ok(_) => data
+            .error_page(AppError::NotFound(format!(
+                "Diagnostic report {report_id} was not found for this patient."
+            )))
+            .error_response(),
+        Err(e) if is_session_expired(&e) => {
+            HttpResponse::Unauthorized().body(format!("Session for {patient_id} has expired."))
+        }
+        Err(e) => {
+            error!(
+                "Reading diagnostic report {report_id} failed with error: {:?}",
+                e
+            );
+            data.error_page(AppError::Internal(String::from(
+                "Failed to read the diagnostic report from the FHIR server.",
+            )))
+            .error_response()
+        }
+    }
+}
+
+// Renders a diagnostic report and its results.
+//
+// # Arguments
+// * `summary` The summary of the report to render.
+// * `context` The context of the launch, e.g. the EHR and user.
+#[rustfmt::skip::macros(html)]
+fn render_report(summary: &ReportSummary, context: &LaunchContext) -> Markup {
+    html! {
+	(DOCTYPE);
+	html lang="en" {
+            head {
+		title {
+		    "Example SMART-on-FHIR app: " (summary.name)
+		}
+            }
+            body {
+		div #holder {
+		    h1 {
+			"Example SMART-on-FHIR app"
+		    }
+		    p #brand {
+			"Connected to "
+			(context.connected_to())
+		    }
+		    section #diagnostic-report {
+			h2 {
+			    (summary.name)
+			    @if let Some(effective) = &summary.effective {
+				" (" (effective) ")"
+			    }
+			}
+			@if summary.results.is_empty() {
+			    p {
+				"This report has no results."
+			    }
+			} @else {
+			    table {
+				tbody {
+				    @for result in &summary.results {
+					tr {
+					    th {
+						(result.name) ":"
+					    }
+					    td {
+						(result.value)
+					    }
+					}
+				    }
+				}
+			    }
+			}
+		    }
+		    p {
+			a #back href=(context.summary_path("html")) {
+			    "Back to the summary"
+			}
+		    }
+		}
+            }
+	}
+    }
+}
@@ -0,0 +1,164 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono_tz::Tz;
+use fhir_sdk::r4b::resources::Patient;
+use fhir_sdk::r4b::types::{CodeableConcept, HumanName, Period};
+use fhir_sdk::{Date, DateTime};
+
+// Formats a FHIR date for display.
+//
+// # Arguments
+// * `date` The date to display.
+pub fn display_date(date: &Date) -> String {
+    match date {
+        Date::Year(year) => format!("{}", year),
+        Date::YearMonth(year, month) => format!("{month} {year}"),
+        Date::Date(date) => format!("{} {}, {}", date.month(), date.day(), date.year()),
+    }
+}
+
+// Formats a FHIR date/time for display.
+//
+// Values with a time component are converted to the display timezone, and shown
+// with the time and the timezone's abbreviation. Values without a time component
+// are displayed as dates, as there is nothing to convert.
+//
+// # Arguments
+// * `date_time` The date/time to display.
+// * `timezone` The timezone to display times in.
+pub fn display_date_time(date_time: &DateTime, timezone: &Tz) -> String {
+    match date_time {
+        DateTime::Date(date) => display_date(date),
+        DateTime::DateTime(instant) => {
+            match chrono::DateTime::from_timestamp(
+                instant.0.unix_timestamp(),
+                instant.0.nanosecond(),
+            ) {
+                Some(timestamp) => timestamp
+                    .with_timezone(timezone)
+                    .format("%B %-d, %Y, %H:%M %Z")
+                    .to_string(),
+                // fall back to the date, as written by the FHIR server
+                None => display_date(&Date::Date(instant.0.date())),
+            }
+        }
+    }
+}
+
+// Formats a FHIR period for display.
+//
+// # Arguments
+// * `period` The period to display.
+// * `timezone` The timezone to display times in.
+pub fn display_period(period: &Period, timezone: &Tz) -> String {
+    let display = |date_time| display_date_time(date_time, timezone);
+
+    match (&period.start, &period.end) {
+        (Some(start), Some(end)) => format!("{} - {}", display(start), display(end)),
+        (Some(start), None) => format!("from {}", display(start)),
+        (None, Some(end)) => format!("until {}", display(end)),
+        (None, None) => String::new(),
+    }
+}
+
+// Formats a FHIR codeable concept for display.
+//
+// Prefers the concept's text, falling back to the display name of the first
+// coding that has one, and then to the first code. Returns an empty option if
+// the concept has none of these.
+//
+// # Arguments
+// * `concept` The codeable concept to display.
+pub fn display_codeable_concept(concept: &CodeableConcept) -> Option<String> {
+    let codings = || concept.coding.iter().flatten();
+
+    concept
+        .text
+        .clone()
+        .or_else(|| codings().find_map(|coding| coding.display.clone()))
+        .or_else(|| codings().find_map(|coding| coding.code.clone()))
+}
+
+// Formats the name of a patient for display.
+//
+// Uses the given and family names from the first name on the patient. Returns an
+// empty option if the patient has no name.
+//
+// # Arguments
+// * `patient` The patient whose name to display.
+pub fn display_patient_name(patient: &Patient) -> Option<String> {
+    patient
+        .name
+        .iter()
+        .flatten()
+        .next()
+        .and_then(display_human_name)
+}
+
+// Formats a person's name for display, joining the given and family names.
+//
+// Returns an empty option if the name has neither.
+//
+// # Arguments
+// * `name` The name to display.
+pub fn display_human_name(name: &HumanName) -> Option<String> {
+    let parts: Vec<&str> = name
+        .given
+        .iter()
+        .flatten()
+        .chain(name.family.as_ref())
+        .map(String::as_str)
+        .collect();
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    // Parses a FHIR dateTime value.
+    //
+    // # Arguments
+    // * `value` The value, as written in FHIR JSON.
+    fn date_time(value: &str) -> DateTime {
+        serde_json::from_value(json!(value)).unwrap()
+    }
+
+    #[test]
+    fn utc_date_time_renders_in_configured_timezone() {
+        let display = display_date_time(
+            &date_time("2024-03-01T15:30:00Z"),
+            &chrono_tz::America::New_York,
+        );
+
+        assert_eq!(display, "March 1, 2024, 10:30 EST");
+    }
+
+    #[test]
+    fn date_renders_without_timezone() {
+        let display = display_date_time(&date_time("2024-03-01"), &chrono_tz::Asia::Tokyo);
+
+        assert_eq!(display, "March 1, 2024");
+    }
+}
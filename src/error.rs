@@ -0,0 +1,418 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::body::BoxBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::ErrorHandlerResponse;
+use actix_web::{HttpMessage, HttpResponse, ResponseError};
+use log::error;
+use maud::{html, Markup, DOCTYPE};
+
+use std::fmt;
+
+use crate::request_id::RequestId;
+
+// An error that we show to the user as an error page.
+#[derive(Debug)]
+pub enum AppError {
+    // The user is not allowed to do what they asked, e.g. because the EHR did
+    // not authorize the app.
+    Forbidden(String),
+
+    // The thing that the user asked for does not exist.
+    NotFound(String),
+
+    // Something went wrong on our side, or while talking to the EHR.
+    Internal(String),
+
+    // The launch took too long to complete, and must be restarted from the EHR.
+    LaunchExpired,
+}
+
+impl AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::LaunchExpired => {
+                StatusCode::from_u16(440).expect("440 is a valid status code")
+            }
+        }
+    }
+
+    // Gets the details of this error, if any, to show below the page's message.
+    fn detail(&self) -> Option<&str> {
+        match self {
+            AppError::Forbidden(detail)
+            | AppError::NotFound(detail)
+            | AppError::Internal(detail) => Some(detail),
+            AppError::LaunchExpired => None,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::Forbidden(detail) => write!(f, "forbidden: {detail}"),
+            AppError::NotFound(detail) => write!(f, "not found: {detail}"),
+            AppError::Internal(detail) => write!(f, "internal error: {detail}"),
+            AppError::LaunchExpired => write!(f, "launch expired"),
+        }
+    }
+}
+
+// The text shown on each kind of error page.
+//
+// Deployments can override the message for each page, e.g. to point users at
+// their own support channels. Internal errors have no page; see
+// `server_error_response`.
+#[derive(Clone, Debug)]
+pub struct ErrorPageText {
+    pub forbidden: String,
+    pub not_found: String,
+    pub launch_expired: String,
+}
+
+impl Default for ErrorPageText {
+    fn default() -> ErrorPageText {
+        ErrorPageText {
+            forbidden: String::from("The app is not allowed to access this page."),
+            not_found: String::from("The page you asked for could not be found."),
+            launch_expired: String::from("The app took too long to finish launching."),
+        }
+    }
+}
+
+impl ErrorPageText {
+    // Overrides the message for the error page with a status code.
+    //
+    // Returns false if there is no error page for the status code.
+    //
+    // # Arguments
+    // * `status` The status code of the error page, e.g. 404.
+    // * `message` The message to show on the page.
+    pub fn set(&mut self, status: u16, message: String) -> bool {
+        match status {
+            403 => self.forbidden = message,
+            404 => self.not_found = message,
+            440 => self.launch_expired = message,
+            _ => return false,
+        }
+        true
+    }
+
+    fn message(&self, error: &AppError) -> &str {
+        match error {
+            AppError::Forbidden(_) => &self.forbidden,
+            AppError::NotFound(_) => &self.not_found,
+            AppError::Internal(_) => "",
+            AppError::LaunchExpired => &self.launch_expired,
+        }
+    }
+}
+
+// An error, along with what we need to render its page.
+#[derive(Debug)]
+pub struct ErrorPage {
+    error: AppError,
+    message: String,
+    relaunch_url: String,
+}
+
+impl ErrorPage {
+    // Creates an error page.
+    //
+    // # Arguments
+    // * `error` The error to show.
+    // * `text` The text for each kind of error page.
+    // * `relaunch_url` The URL to link to for relaunching the app.
+    pub fn new(error: AppError, text: &ErrorPageText, relaunch_url: &str) -> ErrorPage {
+        ErrorPage {
+            message: text.message(&error).to_string(),
+            error,
+            relaunch_url: relaunch_url.to_string(),
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self.error {
+            AppError::Forbidden(_) => "Access denied",
+            AppError::NotFound(_) => "Not found",
+            AppError::Internal(_) => "Something went wrong",
+            AppError::LaunchExpired => "Launch expired",
+        }
+    }
+}
+
+// Generates the HTML for an error page.
+//
+// # Arguments
+// * `page` The error page to render.
+#[rustfmt::skip::macros(html)]
+fn render_error_page(page: &ErrorPage) -> Markup {
+    html! {
+	(DOCTYPE);
+	html lang="en" {
+            head {
+		title {
+		    "Example SMART-on-FHIR app: " (page.title().to_lowercase())
+		}
+            }
+            body {
+		div #holder {
+		    h1 {
+			"Example SMART-on-FHIR app"
+		    }
+		    section #error {
+			h2 {
+			    (page.title())
+			}
+			p #message {
+			    (page.message)
+			}
+			@if let Some(detail) = page.error.detail() {
+			    p #detail {
+				(detail)
+			    }
+			}
+			p {
+			    "To start over, "
+			    a #relaunch href=(page.relaunch_url) {
+				"relaunch the app"
+			    }
+			    " from your EHR."
+			}
+		    }
+		}
+            }
+	}
+    }
+}
+
+impl fmt::Display for ErrorPage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl ResponseError for ErrorPage {
+    fn status_code(&self) -> StatusCode {
+        self.error.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // the details of server errors go to our logs only; `server_error_response`
+        // fills in the body
+        if self.status_code().is_server_error() {
+            error!("Responding with an error page for {}", self.error);
+            return HttpResponse::build(self.status_code()).finish();
+        }
+
+        HttpResponse::build(self.status_code())
+            .content_type("text/html; charset=utf-8")
+            .body(render_error_page(self).into_string())
+    }
+}
+
+// Replaces the body of a 5xx response with a generic JSON document.
+//
+// Handlers may describe errors in their response bodies, which could leak internal
+// types or details of the EHR. We replace every server error's body with
+// `{"error": "internal_error", "request_id": "..."}`, so that users can quote the
+// request ID when reporting the error, and we can find its details in our logs.
+//
+// Used as the default server error handler of the `ErrorHandlers` middleware, which
+// must run inside the request ID middleware.
+//
+// # Arguments
+// * `res` The server error response.
+pub fn server_error_response<B>(
+    res: ServiceResponse<B>,
+) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let request_id = res
+        .request()
+        .extensions()
+        .get::<RequestId>()
+        .map(|request_id| request_id.0.clone());
+    let body = serde_json::json!({
+        "error": "internal_error",
+        "request_id": request_id,
+    });
+
+    let (req, res) = res.into_parts();
+    let mut res = res.set_body(BoxBody::new(body.to_string()));
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    Ok(ErrorHandlerResponse::Response(
+        ServiceResponse::new(req, res).map_into_right_body(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::body::MessageBody;
+    use actix_web::middleware::{from_fn, ErrorHandlers};
+    use actix_web::{web, App};
+
+    use crate::request_id::{request_id, X_REQUEST_ID};
+
+    // Renders the response for an error page, returning its status and body.
+    //
+    // # Arguments
+    // * `error` The error to show.
+    // * `text` The text for each kind of error page.
+    fn respond(error: AppError, text: &ErrorPageText) -> (StatusCode, String) {
+        let response =
+            ErrorPage::new(error, text, "https://ehr.example.com/launch").error_response();
+        let status = response.status();
+        let body = response.into_body().try_into_bytes().unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn each_error_renders_its_page() {
+        let text = ErrorPageText::default();
+        for (error, status, title, message) in [
+            (
+                AppError::Forbidden(String::from("Missing scope")),
+                403,
+                "Access denied",
+                &text.forbidden,
+            ),
+            (
+                AppError::NotFound(String::from("No such patient")),
+                404,
+                "Not found",
+                &text.not_found,
+            ),
+            (
+                AppError::LaunchExpired,
+                440,
+                "Launch expired",
+                &text.launch_expired,
+            ),
+        ] {
+            let detail = error.detail().map(str::to_string);
+
+            let (actual_status, body) = respond(error, &text);
+
+            assert_eq!(actual_status.as_u16(), status);
+            assert!(body.contains(&format!("<h2>{title}</h2>")));
+            assert!(body.contains(&format!(r#"<p id="message">{message}</p>"#)));
+            assert!(body.contains(r#"<a id="relaunch" href="https://ehr.example.com/launch">"#));
+            if let Some(detail) = detail {
+                assert!(body.contains(&format!(r#"<p id="detail">{detail}</p>"#)));
+            }
+        }
+    }
+
+    #[test]
+    fn internal_error_has_no_page() {
+        let (status, body) = respond(
+            AppError::Internal(String::from("Token endpoint unreachable")),
+            &ErrorPageText::default(),
+        );
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn error_page_text_can_be_overridden() {
+        let mut text = ErrorPageText::default();
+        assert!(text.set(404, String::from("Ask the help desk for this page.")));
+        assert!(!text.set(500, String::from("Unused")));
+
+        let (_, body) = respond(AppError::NotFound(String::new()), &text);
+
+        assert!(body.contains("Ask the help desk for this page."));
+    }
+
+    #[actix_web::test]
+    async fn server_error_body_is_sanitized() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(ErrorHandlers::new().default_handler_server(server_error_response))
+                .wrap(from_fn(request_id))
+                .route(
+                    "/",
+                    web::get().to(|| async {
+                        let e = serde_json::from_str::<Vec<u8>>("{").unwrap_err();
+                        HttpResponse::InternalServerError().body(format!("{e:?}"))
+                    }),
+                ),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/")
+            .insert_header((X_REQUEST_ID, "req-170"))
+            .to_request();
+
+        let resp = actix_web::test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let body = String::from_utf8(actix_web::test::read_body(resp).await.to_vec()).unwrap();
+        assert!(!body.contains("Error("));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({ "error": "internal_error", "request_id": "req-170" })
+        );
+    }
+
+    #[actix_web::test]
+    async fn internal_error_page_is_sanitized() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(ErrorHandlers::new().default_handler_server(server_error_response))
+                .wrap(from_fn(request_id))
+                .route(
+                    "/",
+                    web::get().to(|| async {
+                        ErrorPage::new(
+                            AppError::Internal(String::from("reqwest::Error { kind: Connect }")),
+                            &ErrorPageText::default(),
+                            "https://ehr.example.com/launch",
+                        )
+                        .error_response()
+                    }),
+                ),
+        )
+        .await;
+
+        let resp = actix_web::test::call_service(
+            &app,
+            actix_web::test::TestRequest::get().uri("/").to_request(),
+        )
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body["error"], "internal_error");
+        assert!(body["request_id"].is_string());
+        assert!(!body.to_string().contains("reqwest"));
+    }
+}
@@ -0,0 +1,408 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fhir_sdk::client::Client as FhirClient;
+use fhir_sdk::client::{Error, FhirR4B, SearchParameters};
+use fhir_sdk::r4b::resources::{NamedResource, Patient, Resource};
+use fhir_sdk::r4b::types::Reference;
+use fhir_sdk::{TryStreamExt, WrongResourceType};
+use futures::{join, StreamExt};
+use log::{error, warn};
+use reqwest::StatusCode;
+use url::Url;
+
+use crate::limit::RequestLimiter;
+
+// Fetches a patient resource.
+//
+// Fetches the [patient](http://hl7.org/fhir/R4B/patient.html) resource corresponding
+// to a specific patient ID.
+//
+// Equivalent to:
+//
+// ```
+// GET [base]/Patient/[patient_id]
+// ```
+//
+// # Arguments
+// * `client` The FHIR client to use.
+// * `patient_id` The patient ID to fetch.
+pub async fn fetch_patient(
+    client: &FhirClient<FhirR4B>,
+    patient_id: &str,
+) -> Result<Option<Patient>, Error> {
+    client.read::<Patient>(patient_id).await
+}
+
+// Checks whether a string is a valid FHIR [resource ID](http://hl7.org/fhir/R4B/datatypes.html#id).
+//
+// IDs are 1 to 64 characters long, made up of letters, digits, '-', and '.'. We
+// check IDs taken from request paths before using them in requests to the FHIR
+// server.
+//
+// # Arguments
+// * `id` The ID to check.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+// Checks whether a reference (e.g., the subject of an observation) refers to a patient.
+//
+// # Arguments
+// * `reference` The reference to check.
+// * `patient_id` The ID of the patient.
+pub fn references_patient(reference: Option<&Reference>, patient_id: &str) -> bool {
+    reference
+        .and_then(|reference| reference.reference.as_deref())
+        .is_some_and(|reference| reference == format!("Patient/{patient_id}"))
+}
+
+// Checks whether a FHIR request failed because our session with the FHIR server expired.
+//
+// This is the case if our access token expired and could not be refreshed, or if the
+// server rejected our access token.
+//
+// # Arguments
+// * `error` The error that the request failed with.
+pub fn is_session_expired(error: &Error) -> bool {
+    match error {
+        Error::AuthCallback(_) => true,
+        Error::Response(status, _) | Error::OperationOutcomeR4B(status, _) => {
+            *status == StatusCode::UNAUTHORIZED
+        }
+        _ => false,
+    }
+}
+
+// Builds the search parameters for resources belonging to a specific patient.
+//
+// Adds a `subject` filter for the patient to the provided search parameters.
+//
+// # Arguments
+// * `patient_id` The patient ID to filter on.
+// * `extra_params` Additional search parameters, e.g. a code to filter on.
+pub fn patient_search(patient_id: &str, extra_params: SearchParameters) -> SearchParameters {
+    extra_params.and_raw("subject", format!("Patient/{patient_id}"))
+}
+
+// Fetches all resources of a given type for a specific patient.
+//
+// Runs a search for resources of type `R` whose subject is the patient, and collects
+// all pages of results, up to `limit` resources. Once the limit is reached, we stop
+// paging and log a warning, so that a server returning an unbounded number of results
+// cannot exhaust our memory.
+//
+// Each page is requested with the client's current authorization header, rather than
+// the one the search started with. If our token expires or is revoked partway
+// through, the server rejects the next page, the client refreshes the token through
+// `Token`'s `LoginManager` implementation, and the page is retried with the new
+// token, so the search does not lose its place.
+//
+// Equivalent to:
+//
+// ```
+// GET [base]/[R]?[extra_params]&subject=Patient/[patient_id]
+// ```
+//
+// # Arguments
+// * `client` The FHIR client to use.
+// * `patient_id` The patient ID to fetch resources for.
+// * `extra_params` Additional search parameters, e.g. a code to filter on.
+// * `limit` The maximum number of resources to collect.
+pub async fn fetch_for_patient<R>(
+    client: &FhirClient<FhirR4B>,
+    patient_id: &str,
+    extra_params: SearchParameters,
+    limit: usize,
+) -> Result<Vec<R>, Error>
+where
+    R: NamedResource + TryFrom<Resource, Error = WrongResourceType>,
+{
+    // we take one more resource than the limit, so that we can tell whether the
+    // results were truncated
+    let mut resources: Vec<R> = client
+        .search::<R>(patient_search(patient_id, extra_params))
+        .take(limit.saturating_add(1))
+        .try_collect()
+        .await?;
+
+    if resources.len() > limit {
+        warn!(
+            "Search for {} resources for patient {} returned more than {} results; ignoring the rest",
+            R::TYPE.as_str(),
+            patient_id,
+            limit
+        );
+        resources.truncate(limit);
+    }
+
+    Ok(resources)
+}
+
+// Counts the resources of a given type for a specific patient.
+//
+// Runs a search with `_summary=count`, which asks the FHIR server to return only the
+// [total](http://hl7.org/fhir/R4B/bundle-definitions.html#Bundle.total) number of
+// matches. Returns an empty option if the server does not report a total.
+//
+// Equivalent to:
+//
+// ```
+// GET [base]/[R]?[extra_params]&subject=Patient/[patient_id]&_summary=count
+// ```
+//
+// # Arguments
+// * `client` The FHIR client to use.
+// * `base_url` The base URL of the FHIR server.
+// * `patient_id` The patient ID to count resources for.
+// * `extra_params` Additional search parameters, e.g. a code to filter on.
+pub async fn fetch_count_for_patient<R: NamedResource>(
+    client: &FhirClient<FhirR4B>,
+    base_url: &str,
+    patient_id: &str,
+    extra_params: &[(&str, &str)],
+) -> Result<Option<u32>, Error> {
+    let mut url = Url::parse(&format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        R::TYPE.as_str()
+    ))
+    .map_err(|_| Error::UrlParse(base_url.to_string()))?;
+    url.query_pairs_mut()
+        .extend_pairs(extra_params)
+        .append_pair("subject", &format!("Patient/{patient_id}"))
+        .append_pair("_summary", "count");
+
+    // The FHIR client does not expose the bundle returned by a search, so we read the
+    // search URL as an absolute reference, which returns the raw bundle.
+    let reference = Reference::builder()
+        .reference(url.to_string())
+        .build()
+        .map_err(|_| Error::UrlParse(url.to_string()))?;
+    match client.read_referenced(&reference).await? {
+        Resource::Bundle(bundle) => Ok(bundle.total),
+        _ => Ok(None),
+    }
+}
+
+// Fetches all resources of a given type for a specific patient, along with their total.
+//
+// Behaves akin to `fetch_for_patient`, but also counts the matching resources using
+// `fetch_count_for_patient`. Failing to count the resources is not treated as an error;
+// instead, the total is returned as an empty option.
+//
+// # Arguments
+// * `client` The FHIR client to use.
+// * `base_url` The base URL of the FHIR server.
+// * `patient_id` The patient ID to fetch resources for.
+// * `extra_params` Additional search parameters, e.g. a code to filter on.
+// * `limit` The maximum number of resources to collect.
+// * `limiter` The limiter for concurrent requests to the FHIR server.
+pub async fn fetch_for_patient_with_total<R>(
+    client: &FhirClient<FhirR4B>,
+    base_url: &str,
+    patient_id: &str,
+    extra_params: &[(&str, &str)],
+    limit: usize,
+    limiter: &RequestLimiter,
+) -> Result<(Vec<R>, Option<u32>), Error>
+where
+    R: NamedResource + TryFrom<Resource, Error = WrongResourceType>,
+{
+    let search_params = extra_params
+        .iter()
+        .fold(SearchParameters::empty(), |params, (key, value)| {
+            params.and_raw(*key, value)
+        });
+
+    let (resources, total) = join!(
+        limiter.run_request(fetch_for_patient::<R>(
+            client,
+            patient_id,
+            search_params,
+            limit
+        )),
+        limiter.run_request(fetch_count_for_patient::<R>(
+            client,
+            base_url,
+            patient_id,
+            extra_params
+        ))
+    );
+
+    let total = total.unwrap_or_else(|e| {
+        error!(
+            "Counting {} resources failed with error: {:?}",
+            R::TYPE.as_str(),
+            e
+        );
+        None
+    });
+
+    resources.map(|resources| (resources, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fhir_sdk::r4b::resources::{Condition, Observation};
+    use serde_json::json;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::limit::RequestLimiter;
+    use crate::test_support::{fhir_client, search_bundle};
+
+    #[actix_web::test]
+    async fn fetch_for_patient_searches_by_subject() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Condition"))
+            .and(query_param("subject", "Patient/123"))
+            .and(query_param("clinical-status", "active"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(search_bundle(vec![json!({
+                    "resourceType": "Condition",
+                    "id": "c1",
+                    "subject": { "reference": "Patient/123" }
+                })])),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let conditions = fetch_for_patient::<Condition>(
+            &fhir_client(&server),
+            "123",
+            SearchParameters::empty().and_raw("clinical-status", "active"),
+            10,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(conditions.len(), 1);
+        assert_eq!(conditions[0].id.as_deref(), Some("c1"));
+    }
+
+    #[actix_web::test]
+    async fn fetch_for_patient_with_total_parses_bundle_total() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Observation"))
+            .and(query_param("_summary", "count"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Bundle",
+                "type": "searchset",
+                "total": 7
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Observation"))
+            .and(query_param("subject", "Patient/123"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(search_bundle(vec![json!({
+                    "resourceType": "Observation",
+                    "id": "o1",
+                    "status": "final",
+                    "code": { "text": "Height" }
+                })])),
+            )
+            .mount(&server)
+            .await;
+
+        let (observations, total) = fetch_for_patient_with_total::<Observation>(
+            &fhir_client(&server),
+            &server.uri(),
+            "123",
+            &[("code", "http://loinc.org|8302-2")],
+            1,
+            &RequestLimiter::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(observations.len(), 1);
+        assert_eq!(total, Some(7));
+    }
+
+    // Builds a page of search results holding two conditions, linking to the next page.
+    //
+    // # Arguments
+    // * `server` The mock FHIR server.
+    // * `page` The number of this page.
+    fn condition_page(server: &MockServer, page: u32) -> serde_json::Value {
+        let mut bundle = search_bundle(
+            (0..2)
+                .map(|i| {
+                    json!({
+                        "resourceType": "Condition",
+                        "id": format!("c{page}-{i}"),
+                        "subject": { "reference": "Patient/123" }
+                    })
+                })
+                .collect(),
+        );
+        bundle["link"] = json!([{
+            "relation": "next",
+            "url": format!("{}/Condition?page={}", server.uri(), page + 1)
+        }]);
+        bundle
+    }
+
+    #[actix_web::test]
+    async fn fetch_for_patient_stops_paging_at_limit() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Condition"))
+            .and(query_param("subject", "Patient/123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(condition_page(&server, 1)))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Condition"))
+            .and(query_param("page", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(condition_page(&server, 2)))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Condition"))
+            .and(query_param("page", "3"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(condition_page(&server, 3)))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let conditions = fetch_for_patient::<Condition>(
+            &fhir_client(&server),
+            "123",
+            SearchParameters::empty(),
+            3,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(conditions.len(), 3);
+    }
+}
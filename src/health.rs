@@ -14,9 +14,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use actix_web::{get, Result};
+use actix_web::{get, web, HttpResponse, Result};
 use maud::{html, Markup, DOCTYPE};
 
+use crate::state::State;
+
 #[get("/healthcheck.html")]
 pub async fn check() -> Result<Markup> {
     Ok(html! {
@@ -35,3 +37,101 @@ pub async fn check() -> Result<Markup> {
     }
     })
 }
+
+/**
+ * Liveness probe
+ * --------------
+ * Responds with 200 as long as the process is up and serving requests. This endpoint
+ * does not check any dependencies, so that a liveness probe (e.g., in Kubernetes)
+ * does not restart the app because of an outage elsewhere.
+ */
+#[get("/livez")]
+pub async fn livez() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/**
+ * Health probe
+ * ------------
+ * Responds with 200 if the app's internal state is consistent, and 503 otherwise,
+ * e.g. if a thread panicked while holding a lock on the state store. Suitable for
+ * readiness probes. `/health` is an alias of this endpoint.
+ */
+#[get("/healthz")]
+pub async fn healthz(data: web::Data<State>) -> HttpResponse {
+    health_response(&data)
+}
+
+#[get("/health")]
+pub async fn health(data: web::Data<State>) -> HttpResponse {
+    health_response(&data)
+}
+
+// Responds with the health of the app's internal state.
+//
+// # Arguments
+// * `data` The application state.
+fn health_response(data: &State) -> HttpResponse {
+    if data.is_healthy() {
+        HttpResponse::Ok().body("ok")
+    } else {
+        HttpResponse::ServiceUnavailable().body("degraded")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    use crate::test_support::test_state;
+
+    // Requests each probe endpoint, returning the statuses in the order `/livez`,
+    // `/healthz`, `/health`.
+    //
+    // # Arguments
+    // * `state` The application state.
+    async fn probe(state: State) -> Vec<StatusCode> {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(livez)
+                .service(healthz)
+                .service(health),
+        )
+        .await;
+
+        let mut statuses = Vec::new();
+        for path in ["/livez", "/healthz", "/health"] {
+            let req = test::TestRequest::get().uri(path).to_request();
+            statuses.push(test::call_service(&app, req).await.status());
+        }
+        statuses
+    }
+
+    #[actix_web::test]
+    async fn probes_succeed_when_state_is_healthy() {
+        let statuses = probe(test_state()).await;
+
+        assert_eq!(statuses, vec![StatusCode::OK; 3]);
+    }
+
+    #[actix_web::test]
+    async fn only_liveness_succeeds_when_state_is_degraded() {
+        let state = test_state();
+        state.poison();
+
+        let statuses = probe(state).await;
+
+        assert_eq!(
+            statuses,
+            vec![
+                StatusCode::OK,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::SERVICE_UNAVAILABLE
+            ]
+        );
+    }
+}
@@ -0,0 +1,175 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use reqwest::Client;
+
+use crate::metrics::{ConnectionMetrics, ConnectionMetricsLayer};
+
+use std::time::Duration;
+
+// Settings for the HTTP client used to call EHR and FHIR servers.
+#[derive(Clone, Debug)]
+pub struct HttpClientConfig {
+    // The maximum number of idle connections kept open to each host.
+    pub pool_max_idle_per_host: usize,
+
+    // How long an idle connection is kept open before it is closed.
+    pub pool_idle_timeout: Duration,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> HttpClientConfig {
+        HttpClientConfig {
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+impl HttpClientConfig {
+    // Builds a HTTP client with these settings.
+    //
+    // The client asks servers to compress responses, and transparently decompresses
+    // gzip and Brotli encoded bodies. FHIR servers often compress large bundles, and
+    // since the FHIR client sends its requests through this client, search results
+    // are parsed from the decompressed body.
+    //
+    // # Arguments
+    // * `metrics` The metrics to record the client's connections into.
+    pub fn build(&self, metrics: &ConnectionMetrics) -> Result<Client, reqwest::Error> {
+        Client::builder()
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .gzip(true)
+            .brotli(true)
+            .connector_layer(ConnectionMetricsLayer::new(metrics))
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fhir_sdk::client::SearchParameters;
+    use fhir_sdk::r4b::resources::Condition;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use futures::future::join_all;
+    use wiremock::matchers::{header_regex, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::fetch::fetch_for_patient;
+    use crate::test_support::{put_session, search_bundle, test_state};
+
+    use std::io::Write;
+
+    // Starts a server that answers every request slowly, so that concurrent requests
+    // each need their own connection.
+    async fn slow_server() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(100)))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    // Sends concurrent requests to a server, and waits for all of them to complete.
+    //
+    // # Arguments
+    // * `client` The client to send the requests with.
+    // * `server` The server to send the requests to.
+    // * `count` The number of requests to send.
+    async fn send_concurrently(client: &Client, server: &MockServer, count: usize) {
+        let requests = (0..count).map(|_| client.get(server.uri()).send());
+        for response in join_all(requests).await {
+            assert!(response.unwrap().status().is_success());
+        }
+    }
+
+    #[actix_web::test]
+    async fn pool_keeps_at_most_max_idle_connections() {
+        let server = slow_server().await;
+        let metrics = ConnectionMetrics::default();
+        let config = HttpClientConfig {
+            pool_max_idle_per_host: 2,
+            ..HttpClientConfig::default()
+        };
+        let client = config.build(&metrics).unwrap();
+
+        send_concurrently(&client, &server, 4).await;
+        assert_eq!(metrics.connections(), 4);
+
+        // only two of the four connections were kept, so two more are opened
+        send_concurrently(&client, &server, 4).await;
+        assert_eq!(metrics.connections(), 6);
+    }
+
+    #[actix_web::test]
+    async fn pool_closes_connections_after_idle_timeout() {
+        let server = slow_server().await;
+        let metrics = ConnectionMetrics::default();
+        let config = HttpClientConfig {
+            pool_idle_timeout: Duration::from_millis(50),
+            ..HttpClientConfig::default()
+        };
+        let client = config.build(&metrics).unwrap();
+
+        send_concurrently(&client, &server, 2).await;
+        send_concurrently(&client, &server, 2).await;
+        assert_eq!(metrics.connections(), 2);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        send_concurrently(&client, &server, 2).await;
+        assert_eq!(metrics.connections(), 4);
+    }
+
+    #[actix_web::test]
+    async fn gzip_encoded_bundle_is_parsed() {
+        let ehr = MockServer::start().await;
+        let bundle = search_bundle(vec![serde_json::json!({
+            "resourceType": "Condition",
+            "id": "c1",
+            "subject": { "reference": "Patient/123" }
+        })]);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bundle.to_string().as_bytes()).unwrap();
+        Mock::given(method("GET"))
+            .and(path("/Condition"))
+            .and(header_regex("accept-encoding", "gzip"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("content-encoding", "gzip")
+                    .insert_header("content-type", "application/fhir+json")
+                    .set_body_bytes(encoder.finish().unwrap()),
+            )
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        let state = test_state();
+        put_session(&state, &ehr, "123", &["patient/*.read"]).await;
+        let client = state.get_token("123").unwrap();
+
+        let conditions: Vec<Condition> =
+            fetch_for_patient(&client.client, "123", SearchParameters::empty(), 10)
+                .await
+                .unwrap();
+
+        assert_eq!(conditions.len(), 1);
+        assert_eq!(conditions[0].id.as_deref(), Some("c1"));
+    }
+}
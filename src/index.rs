@@ -14,37 +14,94 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use actix_web::{get, web, HttpResponse, Result};
-use fhir_sdk::client::Client as FhirClient;
-use fhir_sdk::client::{Error, FhirR4B, SearchParameters};
-use fhir_sdk::r4b::resources::{Observation, ObservationComponentValue, ObservationValue, Patient};
-use fhir_sdk::{Date, TryStreamExt};
-use log::error;
+use actix_web::http::header::{
+    ContentDisposition, ETag, EntityTag, IfNoneMatch, ACCEPT, CONTENT_SECURITY_POLICY,
+};
+use actix_web::rt::time::timeout;
+use actix_web::{get, web, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use chrono_tz::Tz;
+use fhir_sdk::client::{Error, SearchParameters};
+use fhir_sdk::r4b::resources::{CarePlan, DiagnosticReport, Goal, MedicationRequest};
+use log::{error, warn};
 use maud::{html, Markup, DOCTYPE};
+use ring::digest::{Context, SHA256};
+use serde::Deserialize;
+use url::form_urlencoded::byte_serialize;
 
-use crate::state::State;
+use crate::care_plan::{summarize_care_plans, summarize_goals};
+use crate::context::LaunchContext;
+use crate::diagnostic_report::{summarize_report, ReportSummary};
+use crate::display::display_patient_name;
+use crate::error::AppError;
+use crate::fetch::{
+    fetch_for_patient, fetch_for_patient_with_total, fetch_patient, is_session_expired,
+};
+use crate::loinc::LoincCode;
+use crate::medication::MedicationResolver;
+use crate::observation::ValuePrecision;
+use crate::practitioner::{logged_in_practitioner, PractitionerResolver};
+use crate::render::{renderer_for_accept, renderer_for_extension, SummaryRenderer};
+use crate::search_support::SearchSupport;
+use crate::session::{session_id, RecentPatient};
+use crate::smart::token::TokenClient;
+use crate::state::{SessionLookup, State};
+use crate::summary::{ObservationSearch, PatientSummary};
 
+use futures::future::join_all;
 use futures::join;
 
-// Fetches a patient resource.
-//
-// Fetches the [patient](http://hl7.org/fhir/R4B/patient.html) resource corresponding
-// to a specific patient ID.
-//
-// Equivalent to:
-//
-// ```
-// GET [base]/Patient?id=[patient_id]
-// ```
-//
-// # Arguments
-// * `client` The FHIR client to use.
-// * `patient_id` The patient ID to fetch.
-async fn fetch_patient(
-    client: &FhirClient<FhirR4B>,
-    patient_id: &str,
-) -> Result<Option<Patient>, Error> {
-    client.read::<Patient>(patient_id).await
+use std::collections::HashMap;
+
+#[derive(Deserialize)]
+pub struct SummaryQuery {
+    // The issuer of the session to summarize. Patient IDs are only unique within an
+    // issuer, so this is needed if the patient has sessions from several issuers.
+    iss: Option<String>,
+
+    // Whether to indent JSON summaries. Defaults to the `pretty_json` setting.
+    pretty: Option<bool>,
+}
+
+// The code system URL for SNOMED CT codes.
+const SNOMED_SYSTEM: &str = "http://snomed.info/sct";
+
+// An observation that we summarize.
+struct ObservationSpec {
+    // The name of the measurement, e.g. "height".
+    name: &'static str,
+
+    // The codes of the observation, as tokens qualified with their code system (e.g.,
+    // "http://loinc.org|8302-2"). Servers code some measurements in different code
+    // systems, so we search for observations carrying any of these codes.
+    codes: Vec<String>,
+
+    // The [category](http://hl7.org/fhir/R4B/valueset-observation-category.html) to
+    // narrow the search to (e.g., "vital-signs" or "laboratory"), as some codes are
+    // used across categories. If empty, observations in any category are returned.
+    category: Option<&'static str>,
+}
+
+impl ObservationSpec {
+    // Creates a spec for a measurement, searching for its built-in codes along with
+    // any codes configured for it.
+    //
+    // # Arguments
+    // * `name` The name of the measurement, e.g. "height".
+    // * `codes` The built-in codes of the measurement, qualified with their code system.
+    // * `observation_codes` The additional codes configured per measurement.
+    fn new(
+        name: &'static str,
+        codes: &[String],
+        observation_codes: &HashMap<String, Vec<String>>,
+    ) -> ObservationSpec {
+        let mut codes = codes.to_vec();
+        codes.extend(observation_codes.get(name).into_iter().flatten().cloned());
+        ObservationSpec {
+            name,
+            codes,
+            category: None,
+        }
+    }
 }
 
 // Fetches all observations for a specific code for a specific patient.
@@ -55,117 +112,190 @@ async fn fetch_patient(
 // Equivalent to:
 //
 // ```
-// GET [base]/Observation?subject=Patient/[patient_id]&code=[loinc]
+// GET [base]/Observation?code=[system|code],[system|code]&subject=Patient/[patient_id]
 // ```
 //
+// where the codes are ORed together, so that we find the observation regardless of
+// which of the spec's code systems the server used.
+//
+// If the spec has a category, the search is narrowed with `&category=[category]`.
+//
+// Also fetches the total number of matching observations, so that we can show how many
+// observations we are summarizing.
+//
+// If we know that the server does not support searching observations by `code` or
+// `subject`, we skip the search and log a warning, rather than sending a request that
+// the server will reject or that would return observations for other codes. If it does
+// not support searching by `category`, we search without the category.
+//
 // # Arguments
 // * `client` The FHIR client to use.
 // * `patient_id` The patient ID to fetch.
-// * `loinc` The LOINC code to search for.
+// * `spec` The observation to search for.
+// * `limit` The maximum number of observations to fetch.
+// * `search_support` The search parameters that the server supports, if known.
 async fn fetch_observations(
-    client: &FhirClient<FhirR4B>,
+    client: &TokenClient,
     patient_id: &str,
-    loinc: &str,
-) -> Result<Vec<Observation>, Error> {
-    client
-        .search(
-            SearchParameters::empty()
-                .and_raw("code", loinc)
-                .and_raw("subject", format!("Patient/{patient_id}")),
-        )
-        .try_collect()
-        .await
+    spec: &ObservationSpec,
+    limit: usize,
+    search_support: Option<&SearchSupport>,
+) -> ObservationSearch {
+    let name = spec.name;
+    let mut category = spec.category;
+
+    if let Some(search_support) = search_support {
+        let unsupported = search_support.unsupported("Observation", &["code", "subject"]);
+        if !unsupported.is_empty() {
+            warn!(
+                "Skipping search for {name} observations, as the server does not support searching by {}",
+                unsupported.join(", ")
+            );
+            return Ok((Vec::new(), None));
+        }
+
+        if category.is_some() && !search_support.supports("Observation", "category") {
+            warn!("Searching for {name} observations without a category, as the server does not support searching by category");
+            category = None;
+        }
+    }
+
+    let codes = spec.codes.join(",");
+    let mut params = vec![("code", codes.as_str())];
+    params.extend(category.map(|category| ("category", category)));
+
+    fetch_for_patient_with_total(
+        client.client_for("Observation"),
+        client.base_url_for("Observation"),
+        patient_id,
+        &params,
+        limit,
+        &client.limiter,
+    )
+    .await
 }
 
-// Extracts the observed value for an observation from a query.
+// Computes a weak ETag for a rendered summary.
 //
-// Handles observations with [quantity](http://hl7.org/fhir/R4B/datatypes.html#Quantity)
-// types. If at least one observation with a quantity type _and_ both value and unit is
-// available, returns a string concatenting the value and unit. If no observations are
-// found, an empty option is returned.
+// The ETag is a hash over the content type and the rendered summary, so that it
+// changes whenever the data we display changes. It is weak, as we do not guarantee
+// that equal summaries are byte-for-byte identical across releases.
 //
-// If the query returned multiple valid Observation resources, we select one of the results.
-// We do not use any specific logic to choose what to return.
+// # Arguments
+// * `content_type` The content type of the rendered summary.
+// * `body` The rendered summary.
+fn summary_etag(content_type: &str, body: &[u8]) -> EntityTag {
+    let mut context = Context::new(&SHA256);
+    context.update(content_type.as_bytes());
+    context.update(body);
+    let digest = context.finish();
+
+    let tag: String = digest.as_ref()[..16]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    EntityTag::new_weak(tag)
+}
+
+// Checks whether a request's `If-None-Match` header matches an ETag.
 //
 // # Arguments
-// * `search_query` The result of a query searching for observations.
-fn extract_observation(search_query: Result<Vec<Observation>, Error>) -> Option<String> {
-    match search_query {
-        Ok(observations) => {
-            // TODO: rewrite loop
-            // right now, we are looping over all elements, extracting the measurement if
-            // it exists, appending that into a vec, and then returning the first entry in
-            // the vec. it would be more efficient to either loop until we find the first
-            // valid entry, or to have smarter logic for selecting an entry to return (e.g.,
-            // sort and return latest entry)
-            let mut values: Vec<String> = Vec::new();
-
-            for observation in observations {
-                if let Some(ObservationValue::Quantity(quantity)) = &observation.value {
-                    if let (Some(value), Some(unit)) = (&quantity.value, &quantity.unit) {
-                        values.push(format!("{value} {unit}"));
-                    }
-                }
-            }
+// * `req` The request for the summary.
+// * `etag` The ETag for the current summary.
+fn not_modified(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(etag)),
+        None => false,
+    }
+}
 
-            values.pop()
+// Resolves the names of the medications requested for a patient.
+//
+// Medications that cannot be resolved are displayed using a placeholder; see
+// `MedicationResolver`. If the search for medication requests failed, returns an
+// empty list.
+//
+// # Arguments
+// * `client` The FHIR client to use to resolve referenced medications.
+// * `search_query` The result of a query searching for medication requests.
+async fn resolve_medications(
+    client: &TokenClient,
+    search_query: Result<Vec<MedicationRequest>, Error>,
+) -> Vec<String> {
+    match search_query {
+        Ok(requests) => {
+            let resolver = MedicationResolver::new(
+                client.client_for("Medication"),
+                &client.limiter,
+                &client.medications,
+            );
+            join_all(requests.iter().map(|request| resolver.resolve(request))).await
         }
         Err(e) => {
-            error!("Fetching observation failed with error: {:?}", e);
-            None
+            error!("Fetching medication requests failed with error: {:?}", e);
+            Vec::new()
         }
     }
 }
 
-// Extracts the observed value for a specific component from a multi-component observation query.
-//
-// Handles observations that bundle multiple measurement components together. For example,
-// observations corresponding to the blood pressure [LOINC 55284-4](https://loinc.org/55284-4)
-// code SHOULD contain separate observations of systolic ([LOINC 8480-6](https://loinc.org/8480-6)
-// and diastolic ([LOINC 8462-4](https://loinc.org/8462-4)) pressure measurements, nested under
-// the [Observation.component](http://hl7.org/fhir/R4B/observation-definitions.html#Observation.component)
-// field.
+// Summarizes the diagnostic reports for a patient.
 //
-// Otherwise, behaves akin to `extract_observation`.
+// If the search for diagnostic reports failed, returns an empty list.
 //
 // # Arguments
-// * `search_query` The result of a query searching for observations.
-// * `code` The code to use to filter observation components. Should be provided without
-//   the LOINC prefix; e.g., if filtering on [LOINC 8462-4](https://loinc.org/8462-4), provide
-//   "8462-4", instead of "http://loinc.org|8462-4".
-fn extract_observation_component(
-    search_query: &Result<Vec<Observation>, Error>,
-    code: String,
-) -> Option<String> {
+// * `client` The FHIR client to use to resolve report results.
+// * `search_query` The result of a query searching for diagnostic reports.
+// * `timezone` The timezone to display report times in.
+// * `precision` The number of decimal places to display result values with, keyed by code.
+async fn summarize_reports(
+    client: &TokenClient,
+    search_query: Result<Vec<DiagnosticReport>, Error>,
+    timezone: &Tz,
+    precision: &ValuePrecision,
+) -> Vec<ReportSummary> {
     match search_query {
-        Ok(observations) => {
-            // TODO: rewrite loop
-            //
-            // see note in `extract_observation`
-            let mut values: Vec<String> = Vec::new();
-
-            for observation in observations {
-                for component in observation.component.iter().flatten() {
-                    for coding in component.code.coding.iter().flatten() {
-                        if Some(code.clone()) == coding.code {
-                            if let Some(ObservationComponentValue::Quantity(quantity)) =
-                                &component.value
-                            {
-                                if let (Some(value), Some(unit)) = (&quantity.value, &quantity.unit)
-                                {
-                                    values.push(format!("{value} {unit}"));
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-
-            values.pop()
+        Ok(reports) => {
+            join_all(reports.iter().map(|report| {
+                summarize_report(
+                    client.client_for("Observation"),
+                    &client.limiter,
+                    report,
+                    timezone,
+                    precision,
+                )
+            }))
+            .await
         }
         Err(e) => {
-            error!("Fetching observation failed with error: {:?}", e);
-            None
+            error!("Fetching diagnostic reports failed with error: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+// Gets the result of a summary section that was given a deadline.
+//
+// If the section did not complete in time, records it as timed out, and returns a
+// fallback value instead.
+//
+// # Arguments
+// * `result` The result of the section, or an empty option if it timed out.
+// * `fallback` The value to use if the section timed out.
+// * `section` The name of the section, for display.
+// * `timed_out` The names of the sections that timed out.
+fn unless_timed_out<T>(
+    result: Option<T>,
+    fallback: T,
+    section: &'static str,
+    timed_out: &mut Vec<&'static str>,
+) -> T {
+    match result {
+        Some(result) => result,
+        None => {
+            warn!("Fetching {section} for the summary timed out");
+            timed_out.push(section);
+            fallback
         }
     }
 }
@@ -178,92 +308,483 @@ fn extract_observation_component(
  * token corresponding to a patient ID (encoded in the path) to display a simple summary
  * about the patient we have selected. This summary shows:
  *
- * - Patient name and birthdate, taken from the [FHIR patient resource](http://hl7.org/fhir/R4B/patient.html)
+ * - Patient name, birthdate, and contact details, taken from the [FHIR patient resource](http://hl7.org/fhir/R4B/patient.html)
+ * - The patient's general practitioners, resolved from the [practitioner](http://hl7.org/fhir/R4B/practitioner.html)
+ *   or [organization](http://hl7.org/fhir/R4B/organization.html) resources that the patient refers to.
  * - Several measurements, taken from [FHIR observations](http://hl7.org/fhir/R4B/observation.html) associated with the patient. These measurements may not be available for all patients.
- *   - A blood pressure measurement, using the combined measurement code [LOINC 55284-4](https://loinc.org/55284-4).
- *     Systolic/diastolic measurements are broken out by processing the individual
- *     [observation components](http://hl7.org/fhir/R4B/observation-definitions.html#Observation.component).
- *   - Height, using the code [LOINC 8302-2](https://loinc.org/8302-2).
+ *   - A blood pressure measurement, using the combined measurement code [LOINC 55284-4](https://loinc.org/55284-4)
+ *   or [SNOMED CT 75367002](http://snomed.info/id/75367002).
+ *   Systolic/diastolic measurements are broken out by processing the individual
+ *   [observation components](http://hl7.org/fhir/R4B/observation-definitions.html#Observation.component).
+ *   - Height, using the code [LOINC 8302-2](https://loinc.org/8302-2) or [SNOMED CT 50373000](http://snomed.info/id/50373000).
  *   - LDL, using the code [LOINC 2089-1](https://loinc.org/2089-1).
  *   - HDL, using the code [LOINC 2085-9](https://loinc.org/2085-9).
+ *
+ *   Further codes can be configured for each measurement, e.g. for local code systems.
+ *   If the latest observation has no value because of a data absent reason (e.g., the
+ *   test was not performed), the reason is shown in place of the value.
+ * - Requested medications, taken from [FHIR medication requests](http://hl7.org/fhir/R4B/medicationrequest.html).
+ *   Medications that are referenced rather than coded inline are resolved from the
+ *   [medication resources](http://hl7.org/fhir/R4B/medication.html) they refer to.
+ * - Diagnostic reports (e.g., lab panels), taken from [FHIR diagnostic reports](http://hl7.org/fhir/R4B/diagnosticreport.html).
+ *   Each report lists the values of the observations it references as results.
+ * - Goals and care plans, taken from [FHIR goals](http://hl7.org/fhir/R4B/goal.html)
+ *   and [FHIR care plans](http://hl7.org/fhir/R4B/careplan.html), with their statuses.
+ *
+ * The HTML page's header shows who is logged in. If the EHR identifies the user as a
+ * practitioner through the `fhirUser` claim, we read their practitioner resource to
+ * show their name and role.
+ *
+ * The summary is rendered in the format given by the path's extension: an HTML page
+ * (`index.html`), a JSON document (`index.json`), CSV for spreadsheets (`index.csv`),
+ * or a PDF document for sharing (`index.pdf`).
+ * Responses carry a weak `ETag`; clients that send it back in `If-None-Match` receive a
+ * 304 if the summary has not changed.
+ *
+ * Sections whose searches do not complete within the summary timeout are left out, and
+ * the summary lists them as timed out, so that one slow search does not hang the page.
+ *
+ * JSON summaries are compact; pass `?pretty=true` to indent them for debugging.
+ *
+ * If the session has expired, JSON clients receive a 401 with a body like
+ * `{"error": "session_expired", "relaunch_url": "..."}`, so that they can send the user
+ * to relaunch the app.
+ * Likewise, if the FHIR server does not return the patient, HTML clients receive a page
+ * linking to relaunch the app, and JSON clients a 404 with a body like
+ * `{"error": "patient_not_found", "patient_id": "...", "relaunch_url": "..."}`.
+ *
+ * Patient IDs are only unique within a FHIR server. If the patient ID has sessions from
+ * more than one issuer, the `iss` query parameter selects which one to use. Without it,
+ * we use the issuer from the browser's encrypted launch context cookie, if configured;
+ * otherwise, we respond with a 300 page linking to each issuer's summary.
+ *
+ * EHRs often display the app in an iframe. For EHR launches, a `Content-Security-Policy`
+ * header permits the issuer's origin to frame the summary; otherwise, framing is denied.
  */
-#[get("/{patient_id}/index.html")]
-pub async fn index(data: web::Data<State>, patient_id: web::Path<String>) -> HttpResponse {
-    if let Some(client) = data.get_token(&patient_id) {
-        let patient_id = client.patient;
-
-        // fetch the core patient data
-        let patient_request = fetch_patient(&client.client, &patient_id);
-
-        // loinc codes - these need to have a lifetime that persists until the `join!`
-        let bp_loinc = String::from("http://loinc.org|55284-4");
-        let height_loinc = String::from("http://loinc.org|8302-2");
-        let ldl_loinc = String::from("http://loinc.org|2089-1");
-        let hdl_loinc = String::from("http://loinc.org|2085-9");
-
-        // fetch observations from FHIR server
-        // TODO:
-        // - we are currently collecting all observations. this is fine for test data,
-        //   but is not the ideal way to handle the data.
-        // - the LDL code seems to not fetch any data from the SMART test server...
-        //   are we using an incorrect code? needs more exploration...
-        let blood_pressure_request = fetch_observations(&client.client, &patient_id, &bp_loinc);
-        let height_request = fetch_observations(&client.client, &patient_id, &height_loinc);
-        let ldl_request = fetch_observations(&client.client, &patient_id, &ldl_loinc);
-        let hdl_request = fetch_observations(&client.client, &patient_id, &hdl_loinc);
-        let (patient, blood_pressure, height, ldl, hdl) = join!(
-            patient_request,
-            blood_pressure_request,
-            height_request,
-            ldl_request,
-            hdl_request
-        );
-
-        // if we have received a valid patient resource, then render the page.
-        // we are more lenient with error checking for the observations, as we do not
-        // expect to find observations for all codes for all patients.
-        match patient {
-            Ok(Some(patient)) => HttpResponse::Ok()
-                .body(render_page(patient, blood_pressure, height, ldl, hdl).into_string()),
-            Ok(None) => {
-                HttpResponse::NotFound().body(format!("No search results found for {}", patient_id))
-            }
-            Err(e) => HttpResponse::InternalServerError()
-                .body(format!("Searching for patient failed with error: {:?}", e)),
+#[get("/{patient_id}/index.{extension}")]
+pub async fn index(
+    req: HttpRequest,
+    data: web::Data<State>,
+    path: web::Path<(String, String)>,
+    query: web::Query<SummaryQuery>,
+) -> HttpResponse {
+    let (patient_id, extension) = path.into_inner();
+    let pretty = query.pretty.unwrap_or(data.pretty_json);
+    match renderer_for_extension(&extension, pretty) {
+        Some(renderer) => {
+            render_summary(
+                &req,
+                &data,
+                &patient_id,
+                query.iss.as_deref(),
+                renderer.as_ref(),
+            )
+            .await
         }
-    } else {
-        HttpResponse::InternalServerError().body(format!("Failed to find token for {patient_id}."))
+        None => data
+            .error_page(AppError::NotFound(format!(
+                "Patient summaries cannot be rendered as {extension}."
+            )))
+            .error_response(),
     }
 }
 
-// Formats a FHIR date for display.
+/**
+ * Patient summary, negotiated by content type
+ * -------------------------------------------
+ * Serves the same summary as `/{patient_id}/index.{extension}`, in the format requested
+ * by the `Accept` header: `text/html`, `application/json`, `text/csv`, or
+ * `application/pdf`. Defaults to HTML.
+ */
+#[get("/{patient_id}/summary")]
+pub async fn summary(
+    req: HttpRequest,
+    data: web::Data<State>,
+    patient_id: web::Path<String>,
+    query: web::Query<SummaryQuery>,
+) -> HttpResponse {
+    let accept = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok());
+    let renderer = renderer_for_accept(accept, query.pretty.unwrap_or(data.pretty_json));
+    render_summary(
+        &req,
+        &data,
+        &patient_id,
+        query.iss.as_deref(),
+        renderer.as_ref(),
+    )
+    .await
+}
+
+// Fetches the data for a patient summary, and renders it.
 //
 // # Arguments
-// * `date` The date to display.
-fn display_date(date: &Date) -> String {
-    // TODO: move this function into a centralized location
-    match date {
-        Date::Year(year) => format!("{}", year),
-        Date::YearMonth(year, month) => format!("{month} {year}"),
-        Date::Date(date) => format!("{} {}, {}", date.month(), date.day(), date.year()),
+// * `req` The request for the summary.
+// * `data` The application state.
+// * `patient_id` The patient ID to summarize.
+// * `iss` The issuer of the session to use, if the request names one.
+// * `renderer` The renderer for the requested format.
+async fn render_summary(
+    req: &HttpRequest,
+    data: &State,
+    patient_id: &str,
+    iss: Option<&str>,
+    renderer: &dyn SummaryRenderer,
+) -> HttpResponse {
+    // unless the request names an issuer, use the one that this browser launched the
+    // patient from, if its context cookie tells us, and only search the server-side
+    // sessions for the patient if the cookie does not lead to one
+    let lookup = match iss {
+        Some(iss) => data.lookup_token(patient_id, Some(iss)),
+        None => match data
+            .context_cookie_cipher
+            .as_ref()
+            .and_then(|cipher| cipher.issuer_for(req, patient_id))
+            .map(|cookie_iss| data.lookup_token(patient_id, Some(&cookie_iss)))
+        {
+            Some(SessionLookup::Found(client)) => SessionLookup::Found(client),
+            _ => data.lookup_token(patient_id, None),
+        },
+    };
+
+    let client = match lookup {
+        SessionLookup::Found(client) => client,
+        SessionLookup::Ambiguous(contexts) => {
+            return HttpResponse::MultipleChoices()
+                .content_type("text/html; charset=utf-8")
+                .body(render_issuer_choice(req.path(), &contexts).into_string());
+        }
+        SessionLookup::Missing => {
+            return renderer
+                .render_session_expired(&data.relaunch_url)
+                .unwrap_or_else(|| {
+                    error!("Failed to find token for {patient_id}");
+                    HttpResponse::InternalServerError().finish()
+                });
+        }
+    };
+
+    let Some(patient_id) = client.patient.clone() else {
+        return HttpResponse::NotFound().body("This session does not have a patient in context.");
+    };
+
+    // fetch the core patient data
+    let patient_request = client
+        .limiter
+        .run_request(fetch_patient(client.client_for("Patient"), &patient_id));
+
+    // observation specs - these need to have a lifetime that persists until the `join!`
+    let bp_spec = ObservationSpec::new(
+        "blood-pressure",
+        &[
+            LoincCode::bare("55284-4").token(),
+            format!("{SNOMED_SYSTEM}|75367002"),
+        ],
+        &data.observation_codes,
+    );
+    let height_spec = ObservationSpec::new(
+        "height",
+        &[
+            LoincCode::bare("8302-2").token(),
+            format!("{SNOMED_SYSTEM}|50373000"),
+        ],
+        &data.observation_codes,
+    );
+    let ldl_spec = ObservationSpec::new(
+        "ldl",
+        &[LoincCode::bare("2089-1").token()],
+        &data.observation_codes,
+    );
+    let hdl_spec = ObservationSpec::new(
+        "hdl",
+        &[LoincCode::bare("2085-9").token()],
+        &data.observation_codes,
+    );
+
+    // fetch observations from FHIR server
+    // TODO:
+    // - we are currently collecting all observations, up to the search limit. this
+    //   is fine for test data, but is not the ideal way to handle the data.
+    // - the LDL code seems to not fetch any data from the SMART test server...
+    //   are we using an incorrect code? needs more exploration...
+    let search_support = data.get_search_support(&client, "Observation").await;
+    let observations = |spec| {
+        fetch_observations(
+            &client,
+            &patient_id,
+            spec,
+            data.search_limit,
+            search_support.as_ref(),
+        )
+    };
+    let blood_pressure_request = observations(&bp_spec);
+    let height_request = observations(&height_spec);
+    let ldl_request = observations(&ldl_spec);
+    let hdl_request = observations(&hdl_spec);
+
+    // fetch medication requests from FHIR server, and resolve the medications
+    let medication_request = async {
+        let medication_requests = client
+            .limiter
+            .run_request(fetch_for_patient::<MedicationRequest>(
+                client.client_for("MedicationRequest"),
+                &patient_id,
+                SearchParameters::empty(),
+                data.search_limit,
+            ))
+            .await;
+        resolve_medications(&client, medication_requests).await
+    };
+
+    // fetch diagnostic reports from FHIR server, and summarize their results
+    let diagnostic_report_request = async {
+        let diagnostic_reports = client
+            .limiter
+            .run_request(fetch_for_patient::<DiagnosticReport>(
+                client.client_for("DiagnosticReport"),
+                &patient_id,
+                SearchParameters::empty(),
+                data.search_limit,
+            ))
+            .await;
+        summarize_reports(
+            &client,
+            diagnostic_reports,
+            &data.timezone,
+            &data.value_precision,
+        )
+        .await
+    };
+
+    // fetch goals and care plans from FHIR server
+    let goal_request = async {
+        let goals = client
+            .limiter
+            .run_request(fetch_for_patient::<Goal>(
+                client.client_for("Goal"),
+                &patient_id,
+                SearchParameters::empty(),
+                data.search_limit,
+            ))
+            .await;
+        summarize_goals(goals)
+    };
+    let care_plan_request = async {
+        let care_plans = client
+            .limiter
+            .run_request(fetch_for_patient::<CarePlan>(
+                client.client_for("CarePlan"),
+                &patient_id,
+                SearchParameters::empty(),
+                data.search_limit,
+            ))
+            .await;
+        summarize_care_plans(care_plans)
+    };
+
+    // the EHR's styles are fetched alongside the data, so that a slow style server
+    // holds up the page no longer than a slow search
+    let style_request = data.get_style(&client.context);
+
+    // a slow search should not hold up the whole page, so we give every section
+    // the same window to complete, and leave out the sections that miss it
+    let deadline = data.summary_timeout;
+    let (
+        style,
+        patient,
+        blood_pressure,
+        height,
+        ldl,
+        hdl,
+        medications,
+        diagnostic_reports,
+        goals,
+        care_plans,
+    ) = join!(
+        timeout(deadline, style_request),
+        timeout(deadline, patient_request),
+        timeout(deadline, blood_pressure_request),
+        timeout(deadline, height_request),
+        timeout(deadline, ldl_request),
+        timeout(deadline, hdl_request),
+        timeout(deadline, medication_request),
+        timeout(deadline, diagnostic_report_request),
+        timeout(deadline, goal_request),
+        timeout(deadline, care_plan_request)
+    );
+
+    let Ok(patient) = patient else {
+        error!("Fetching patient {patient_id} timed out after {deadline:?}");
+        return data
+            .error_page(AppError::Internal(String::from(
+                "The FHIR server took too long to return the patient.",
+            )))
+            .error_response();
+    };
+
+    // some FHIR servers only serve resources under a version path segment; if so, the
+    // session now uses the versioned base URL, and we fetch the summary again from it
+    if matches!(patient, Ok(None)) {
+        if let Ok(Some(versioned)) = timeout(deadline, data.detect_fhir_version(&client)).await {
+            return Box::pin(render_summary(
+                req,
+                data,
+                &patient_id,
+                Some(&versioned.context.iss),
+                renderer,
+            ))
+            .await;
+        }
+    }
+
+    let mut timed_out = Vec::new();
+    let blood_pressure = unless_timed_out(
+        blood_pressure.ok(),
+        Ok((Vec::new(), None)),
+        "Blood pressure",
+        &mut timed_out,
+    );
+    let height = unless_timed_out(
+        height.ok(),
+        Ok((Vec::new(), None)),
+        "Height",
+        &mut timed_out,
+    );
+    let ldl = unless_timed_out(ldl.ok(), Ok((Vec::new(), None)), "LDL", &mut timed_out);
+    let hdl = unless_timed_out(hdl.ok(), Ok((Vec::new(), None)), "HDL", &mut timed_out);
+    let medications = unless_timed_out(
+        medications.ok(),
+        Vec::new(),
+        "Medication requests",
+        &mut timed_out,
+    );
+    let diagnostic_reports = unless_timed_out(
+        diagnostic_reports.ok(),
+        Vec::new(),
+        "Diagnostic reports",
+        &mut timed_out,
+    );
+    let goals = unless_timed_out(goals.ok(), Vec::new(), "Goals", &mut timed_out);
+    let care_plans = unless_timed_out(care_plans.ok(), Vec::new(), "Care plans", &mut timed_out);
+
+    // if we have received a valid patient resource, then render the page.
+    // we are more lenient with error checking for the observations, as we do not
+    // expect to find observations for all codes for all patients.
+    match patient {
+        Ok(Some(patient)) => {
+            // the general practitioners are referenced from the patient, so we can
+            // only resolve them once we have it
+            let general_practitioners = if client.can_read("Practitioner") {
+                let resolver = PractitionerResolver::new(&client, &client.practitioners);
+                unless_timed_out(
+                    timeout(deadline, resolver.resolve(&patient)).await.ok(),
+                    Vec::new(),
+                    "General practitioners",
+                    &mut timed_out,
+                )
+            } else {
+                Vec::new()
+            };
+            let practitioner = unless_timed_out(
+                timeout(deadline, logged_in_practitioner(&client))
+                    .await
+                    .ok(),
+                None,
+                "Logged in practitioner",
+                &mut timed_out,
+            );
+
+            // remember that this browser viewed the patient, for the dashboard
+            if let Some(session) = session_id(req) {
+                data.put_recent_patient(
+                    &session,
+                    RecentPatient {
+                        id: patient_id.clone(),
+                        name: display_patient_name(&patient),
+                    },
+                );
+            }
+
+            let patient_summary = PatientSummary {
+                patient_id: patient_id.clone(),
+                patient,
+                general_practitioners,
+                practitioner,
+                blood_pressure,
+                height,
+                ldl,
+                hdl,
+                medications,
+                diagnostic_reports,
+                goals,
+                care_plans,
+                timed_out,
+                value_precision: data.value_precision.clone(),
+            };
+            let mut context = client.context.clone();
+            context.style = style.ok().flatten();
+            let body = match renderer.render(&patient_summary, &context) {
+                Ok(body) => body,
+                Err(e) => return data.error_page(e).error_response(),
+            };
+            let etag = summary_etag(renderer.content_type(), &body);
+            let csp = client.context.frame_ancestors(data.allow_ehr_framing);
+
+            // polling clients can send the ETag they last received, so that we
+            // do not resend a summary that has not changed
+            if not_modified(req, &etag) {
+                return HttpResponse::NotModified()
+                    .insert_header(ETag(etag))
+                    .insert_header((CONTENT_SECURITY_POLICY, csp))
+                    .finish();
+            }
+
+            let mut response = HttpResponse::Ok();
+            response
+                .content_type(renderer.content_type())
+                .insert_header(ETag(etag))
+                .insert_header((CONTENT_SECURITY_POLICY, csp));
+            if let Some(filename) = renderer.filename(&patient_summary) {
+                response.insert_header(ContentDisposition::attachment(filename));
+            }
+            response.body(body)
+        }
+        Ok(None) => renderer
+            .render_patient_not_found(&patient_id, &data.relaunch_url)
+            .unwrap_or_else(|| {
+                data.error_page(AppError::NotFound(format!(
+                    "The FHIR server did not return a patient resource for patient ID {patient_id}. The patient may have been removed, or you may not have access to their record."
+                )))
+                .error_response()
+            }),
+        Err(e) if is_session_expired(&e) => renderer
+            .render_session_expired(&data.relaunch_url)
+            .unwrap_or_else(|| {
+                HttpResponse::Unauthorized()
+                    .body(format!("Session for {patient_id} has expired."))
+            }),
+        Err(e) => {
+            error!("Searching for patient {patient_id} failed with error: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
     }
 }
 
-// Generates the HTML for the queried patient and observations.
+// Renders a page asking the user which issuer's session to use for a patient.
+//
+// # Arguments
+// * `path` The path of the requested summary.
+// * `contexts` The context of each session for the patient.
 #[rustfmt::skip::macros(html)]
-fn render_page(
-    patient: Patient,
-    blood_pressure: Result<Vec<Observation>, Error>,
-    height: Result<Vec<Observation>, Error>,
-    ldl: Result<Vec<Observation>, Error>,
-    hdl: Result<Vec<Observation>, Error>,
-) -> Markup {
+fn render_issuer_choice(path: &str, contexts: &[LaunchContext]) -> Markup {
     html! {
 	(DOCTYPE);
 	html lang="en" {
             head {
 		title {
-		    "Example SMART-on-FHIR app"
+		    "Example SMART-on-FHIR app: choose a server"
 		}
             }
             body {
@@ -271,115 +792,18 @@ fn render_page(
 		    h1 {
 			"Example SMART-on-FHIR app"
 		    }
-		    section #patient {
+		    section #issuer-choice {
 			h2 {
-			    "Patient resource"
-			}
-			table {
-			    tbody {
-				@if !patient.name.is_empty() {
-				    @if let Some(name) = &patient.name[0] {
-					tr {
-					    th {
-						"First name:"
-					    }
-					    td #fname {
-						@if !name.given.is_empty() {
-						    @if let Some(given_name) = &name.given[0] {
-							(given_name)
-						    }
-						}
-					    }
-					}
-					tr {
-					    th {
-						"Last name:"
-					    }
-					    td #lname {
-						@if let Some(family_name) = &name.family {
-						    (family_name)
-						}
-					    }
-					}
-				    }
-				}
-			    }
-			    @if let Some(gender) = &patient.gender {
-				tr {
-				    th {
-					"Gender:"
-				    }
-				    td #gender {
-					(gender)
-				    }
-				}
-			    }
-			    @if let Some(birth_date) = &patient.birth_date {
-				tr {
-				    th {
-					"Date of birth:"
-				    }
-				    td #birthdate {
-					(display_date(birth_date))
-				    }
-				}
-			    }
+			    "Choose a server"
 			}
-		    }
-		    section #observation {
-			h2 {
-			    "Observation resource"
+			p {
+			    "This patient ID was launched from more than one server. Choose which server's record to view:"
 			}
-			table {
-			    tbody {
-				@if let Some(height) = extract_observation(height) {
-				    tr {
-					th {
-					    "Height:"
-					}
-					td #height {
-					    (height)
-					}
-				    }
-				}
-				@if let Some(systolic_blood_pressure) = extract_observation_component(&blood_pressure, String::from("8480-6")) {
-				    tr {
-					th {
-					    "Systolic blood pressure:"
-					}
-					td #systolicbp {
-					    (systolic_blood_pressure)
-					}
-				    }
-				}
-				@if let Some(diastolic_blood_pressure) = extract_observation_component(&blood_pressure, String::from("8462-4")) {
-				    tr {
-					th {
-					    "Diastolic blood pressure:"
-					}
-					td #disatolicbp {
-					    (diastolic_blood_pressure)
-					}
-				    }
-				}
-				@if let Some(ldl) = extract_observation(ldl) {
-				    tr {
-					th {
-					    "LDL:"
-					}
-					td #ldl {
-					    (ldl)
-					}
-				    }
-				}
-				@if let Some(hdl) = extract_observation(hdl) {
-				    tr {
-					th {
-					    "HDL:"
-					}
-					td #hdl {
-					    (hdl)
-					}
+			ul {
+			    @for context in contexts {
+				li {
+				    a href=(format!("{path}?iss={}", byte_serialize(context.iss.as_bytes()).collect::<String>())) {
+					(context.connected_to())
 				    }
 				}
 			    }
@@ -390,3 +814,840 @@ fn render_page(
 	}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::header::{ETAG, IF_NONE_MATCH};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::context_cookie::{context_cookie, ContextCookieCipher};
+    use crate::smart::id_token::IdTokenClaims;
+    use crate::smart::token::Token;
+    use crate::test_support::{encode, id_token, put_session, search_bundle, test_state};
+
+    // Requests the summary of a patient whom the mock EHR does not have.
+    //
+    // # Arguments
+    // * `extension` The extension for the format of the summary, e.g. "html".
+    async fn get_missing_patient_summary(extension: &str) -> actix_web::dev::ServiceResponse {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Patient/123"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&ehr)
+            .await;
+        let state = test_state().with_relaunch_url(String::from("https://ehr.example.com/launch"));
+        put_session(&state, &ehr, "123", &["patient/*.read"]).await;
+
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(index)).await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/123/index.{extension}"))
+            .to_request();
+        test::call_service(&app, req).await
+    }
+
+    #[actix_web::test]
+    async fn missing_patient_renders_page_with_relaunch_link() {
+        let resp = get_missing_patient_summary("html").await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains(r#"<a id="relaunch" href="https://ehr.example.com/launch">"#));
+        assert!(body.contains("patient ID 123"));
+    }
+
+    #[actix_web::test]
+    async fn missing_patient_is_a_json_error_for_json_summary() {
+        let resp = get_missing_patient_summary("json").await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "patient_not_found");
+        assert_eq!(body["relaunch_url"], "https://ehr.example.com/launch");
+    }
+
+    // Requests the HTML summary of a patient, launched with a token.
+    //
+    // The mock EHR has the patient, but no other resources.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR that issued the token.
+    // * `token` The token for the launch.
+    async fn get_summary_page(ehr: &MockServer, token: Token) -> String {
+        get_summary_page_with_state(ehr, token, web::Data::new(test_state())).await
+    }
+
+    // Requests the HTML summary of a patient, launched with a token, from an app
+    // with specific settings.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR that issued the token.
+    // * `token` The token for the launch.
+    // * `state` The application state.
+    async fn get_summary_page_with_state(
+        ehr: &MockServer,
+        token: Token,
+        state: web::Data<State>,
+    ) -> String {
+        Mock::given(method("GET"))
+            .and(path("/Patient/123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "resourceType": "Patient",
+                "id": "123",
+                "name": [{ "family": "Chalmers", "given": ["Peter"] }]
+            })))
+            .mount(ehr)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(Vec::new())))
+            .mount(ehr)
+            .await;
+        state.put_token(token).await.unwrap();
+
+        let app = test::init_service(App::new().app_data(state).service(index)).await;
+        let req = test::TestRequest::get().uri("/123/index.html").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        String::from_utf8(test::read_body(resp).await.to_vec()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn id_token_name_is_shown_as_logged_in_user() {
+        let ehr = MockServer::start().await;
+        let mut token = Token::for_test(&ehr.uri(), Some("123"), &["openid", "profile"]);
+        token.user = IdTokenClaims::decode(&id_token(serde_json::json!({
+            "sub": "u1",
+            "name": "Dr. Jane Smith"
+        })));
+
+        let body = get_summary_page(&ehr, token).await;
+
+        assert!(body.contains("Logged in as Dr. Jane Smith"));
+    }
+
+    #[actix_web::test]
+    async fn launch_without_user_identity_has_no_logged_in_banner() {
+        let ehr = MockServer::start().await;
+        let token = Token::for_test(&ehr.uri(), Some("123"), &["launch/patient"]);
+
+        let body = get_summary_page(&ehr, token).await;
+
+        assert!(body.contains("Chalmers"));
+        assert!(!body.contains("Logged in as"));
+    }
+
+    #[actix_web::test]
+    async fn observation_total_is_rendered() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Observation"))
+            .and(query_param("_summary", "count"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "resourceType": "Bundle",
+                "type": "searchset",
+                "total": 5
+            })))
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Observation"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(vec![
+                serde_json::json!({
+                    "resourceType": "Observation",
+                    "id": "o1",
+                    "status": "final",
+                    "code": { "text": "Body height" },
+                    "valueQuantity": { "value": 180, "unit": "cm" }
+                }),
+            ])))
+            .mount(&ehr)
+            .await;
+        let token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]);
+
+        let body = get_summary_page(&ehr, token).await;
+
+        assert!(body.contains("180 cm"));
+        assert!(body.contains("(showing 1 of 5 measurements)"));
+    }
+
+    #[actix_web::test]
+    async fn patient_banner_is_shown_when_ehr_needs_one() {
+        let ehr = MockServer::start().await;
+        let mut token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]);
+        token.need_patient_banner = true;
+
+        let body = get_summary_page(&ehr, token).await;
+
+        assert!(body.contains(r#"<div id="patient-banner"><strong>Peter Chalmers</strong>"#));
+    }
+
+    #[actix_web::test]
+    async fn patient_banner_is_hidden_when_ehr_shows_one() {
+        let ehr = MockServer::start().await;
+        let mut token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]);
+        token.need_patient_banner = false;
+
+        let body = get_summary_page(&ehr, token).await;
+
+        assert!(!body.contains("patient-banner"));
+    }
+
+    #[actix_web::test]
+    async fn observation_searches_are_skipped_when_code_is_unsupported() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/metadata"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "resourceType": "CapabilityStatement",
+                "status": "active",
+                "date": "2024-01-01",
+                "kind": "instance",
+                "fhirVersion": "4.3.0",
+                "format": ["json"],
+                "rest": [{
+                    "mode": "server",
+                    "resource": [{
+                        "type": "Observation",
+                        "searchParam": [
+                            { "name": "subject", "type": "reference" },
+                            { "name": "date", "type": "date" }
+                        ]
+                    }]
+                }]
+            })))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        let token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]);
+
+        get_summary_page_with_state(
+            &ehr,
+            token,
+            web::Data::new(test_state().with_check_search_support(true)),
+        )
+        .await;
+
+        let requests = ehr.received_requests().await.unwrap();
+        assert!(!requests
+            .iter()
+            .any(|request| request.url.path() == "/Observation"));
+    }
+
+    #[actix_web::test]
+    async fn repeated_request_with_etag_is_not_modified() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Patient/123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "resourceType": "Patient",
+                "id": "123",
+                "name": [{ "family": "Chalmers", "given": ["Peter"] }]
+            })))
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(Vec::new())))
+            .mount(&ehr)
+            .await;
+        let state = test_state();
+        put_session(&state, &ehr, "123", &["patient/*.read"]).await;
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(index)).await;
+
+        for extension in ["html", "json"] {
+            let uri = format!("/123/index.{extension}");
+            let first =
+                test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+            assert_eq!(first.status(), StatusCode::OK);
+            let etag = first.headers().get(ETAG).unwrap().clone();
+            let req = test::TestRequest::get()
+                .uri(&uri)
+                .insert_header((IF_NONE_MATCH, etag))
+                .to_request();
+
+            let second = test::call_service(&app, req).await;
+
+            assert_eq!(
+                second.status(),
+                StatusCode::NOT_MODIFIED,
+                "{extension} summary"
+            );
+            assert!(test::read_body(second).await.is_empty());
+        }
+    }
+
+    #[actix_web::test]
+    async fn expired_session_is_a_json_error_for_json_summary() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&ehr)
+            .await;
+        let state = test_state().with_relaunch_url(String::from("https://ehr.example.com/launch"));
+        let token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]).expired();
+        state.put_token(token).await.unwrap();
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(index)).await;
+
+        let req = test::TestRequest::get().uri("/123/index.json").to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "session_expired");
+        assert_eq!(body["relaunch_url"], "https://ehr.example.com/launch");
+    }
+
+    // Searches a mock EHR for height observations, optionally narrowed to a category.
+    //
+    // Returns the query parameters of the search that the EHR received, leaving out
+    // the search counting the matches.
+    //
+    // # Arguments
+    // * `category` The category to narrow the search to, if any.
+    async fn search_heights(category: Option<&'static str>) -> HashMap<String, String> {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Observation"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(Vec::new())))
+            .mount(&ehr)
+            .await;
+        let token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]);
+        let client = TokenClient::new(reqwest::Client::new(), token, &ehr.uri())
+            .await
+            .unwrap();
+        let spec = ObservationSpec {
+            name: "height",
+            codes: vec![LoincCode::bare("8302-2").token()],
+            category,
+        };
+
+        fetch_observations(&client, "123", &spec, 10, None)
+            .await
+            .unwrap();
+
+        ehr.received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|request| request.url.query_pairs().into_owned().collect())
+            .find(|params: &HashMap<String, String>| !params.contains_key("_summary"))
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn observation_search_includes_category_when_specified() {
+        let params = search_heights(Some("vital-signs")).await;
+
+        assert_eq!(params["category"], "vital-signs");
+        assert_eq!(params["code"], "http://loinc.org|8302-2");
+    }
+
+    #[actix_web::test]
+    async fn observation_search_has_no_category_by_default() {
+        let params = search_heights(None).await;
+
+        assert!(!params.contains_key("category"));
+    }
+
+    #[actix_web::test]
+    async fn slow_search_is_left_out_of_summary() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/MedicationRequest"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(search_bundle(Vec::new()))
+                    .set_delay(std::time::Duration::from_secs(5)),
+            )
+            .mount(&ehr)
+            .await;
+        let token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]);
+        let state = test_state().with_summary_timeout(std::time::Duration::from_millis(500));
+
+        let body = get_summary_page_with_state(&ehr, token, web::Data::new(state)).await;
+
+        assert!(body.contains("Peter"));
+        assert!(body.contains(
+            r#"<p id="timed-out">Some data took too long to load, and is not shown: Medication requests."#
+        ));
+    }
+
+    // Starts a mock EHR that has patient 123, under a family name of its own, and no
+    // other resources.
+    //
+    // # Arguments
+    // * `family_name` The family name of the patient.
+    async fn ehr_with_patient(family_name: &str) -> MockServer {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Patient/123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "resourceType": "Patient",
+                "id": "123",
+                "name": [{ "family": family_name, "given": ["Peter"] }]
+            })))
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(Vec::new())))
+            .mount(&ehr)
+            .await;
+        ehr
+    }
+
+    #[actix_web::test]
+    async fn patient_from_several_issuers_needs_issuer_choice() {
+        let first = ehr_with_patient("Chalmers").await;
+        let second = ehr_with_patient("Levin").await;
+        let state = test_state();
+        put_session(&state, &first, "123", &["patient/*.read"]).await;
+        put_session(&state, &second, "123", &["patient/*.read"]).await;
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(index)).await;
+        let get = |uri: String| test::TestRequest::get().uri(&uri).to_request();
+
+        let choice = test::call_service(&app, get(String::from("/123/index.html"))).await;
+        let chosen = test::call_service(
+            &app,
+            get(format!("/123/index.html?iss={}", encode(&second.uri()))),
+        )
+        .await;
+
+        assert_eq!(choice.status(), StatusCode::MULTIPLE_CHOICES);
+        let choice = String::from_utf8(test::read_body(choice).await.to_vec()).unwrap();
+        assert!(choice.contains("Choose a server"));
+        for ehr in [&first, &second] {
+            assert!(choice.contains(&format!(
+                r#"<a href="/123/index.html?iss={}">"#,
+                encode(&ehr.uri())
+            )));
+        }
+        assert_eq!(chosen.status(), StatusCode::OK);
+        let chosen = String::from_utf8(test::read_body(chosen).await.to_vec()).unwrap();
+        assert!(chosen.contains("Levin"));
+        assert!(!chosen.contains("Chalmers"));
+    }
+
+    #[actix_web::test]
+    async fn summary_may_be_framed_only_by_launching_ehr() {
+        let ehr = ehr_with_patient("Chalmers").await;
+        let state = test_state();
+        let mut standalone = Token::for_test(&ehr.uri(), Some("456"), &["patient/*.read"]);
+        standalone.ehr_launch = false;
+        state
+            .put_token(Token::for_test(
+                &ehr.uri(),
+                Some("123"),
+                &["patient/*.read"],
+            ))
+            .await
+            .unwrap();
+        state.put_token(standalone).await.unwrap();
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(index)).await;
+        let csp = |resp: &actix_web::dev::ServiceResponse| {
+            resp.headers()
+                .get(CONTENT_SECURITY_POLICY)
+                .map(|csp| csp.to_str().unwrap().to_string())
+        };
+
+        let framed = test::TestRequest::get().uri("/123/index.html").to_request();
+        let framed = test::call_service(&app, framed).await;
+        let unframed = test::TestRequest::get().uri("/456/index.html").to_request();
+        let unframed = test::call_service(&app, unframed).await;
+
+        assert_eq!(framed.status(), StatusCode::OK);
+        assert_eq!(
+            csp(&framed),
+            Some(format!(
+                "frame-ancestors {}",
+                ehr.uri().trim_end_matches('/')
+            ))
+        );
+        assert_eq!(unframed.status(), StatusCode::OK);
+        assert_eq!(csp(&unframed), Some(String::from("frame-ancestors 'none'")));
+    }
+
+    #[actix_web::test]
+    async fn pretty_param_indents_json_summary() {
+        let ehr = ehr_with_patient("Chalmers").await;
+        let state = test_state();
+        put_session(&state, &ehr, "123", &["patient/*.read"]).await;
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(index)).await;
+        let mut bodies = Vec::new();
+
+        for uri in ["/123/index.json?pretty=true", "/123/index.json"] {
+            let resp =
+                test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            bodies.push(String::from_utf8(test::read_body(resp).await.to_vec()).unwrap());
+        }
+
+        let (pretty, compact) = (&bodies[0], &bodies[1]);
+        assert!(pretty.lines().count() > 1);
+        assert!(pretty.contains("\n  \""));
+        assert_eq!(compact.lines().count(), 1);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(pretty).unwrap(),
+            serde_json::from_str::<serde_json::Value>(compact).unwrap()
+        );
+    }
+
+    #[actix_web::test]
+    async fn goals_and_care_plans_are_summarized() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Goal"))
+            .and(query_param("subject", "Patient/123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(vec![
+                serde_json::json!({
+                    "resourceType": "Goal",
+                    "lifecycleStatus": "active",
+                    "description": { "text": "Walk daily" },
+                    "subject": { "reference": "Patient/123" }
+                }),
+            ])))
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/CarePlan"))
+            .and(query_param("subject", "Patient/123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(vec![
+                serde_json::json!({
+                    "resourceType": "CarePlan",
+                    "status": "active",
+                    "intent": "plan",
+                    "title": "Cardiac rehabilitation",
+                    "subject": { "reference": "Patient/123" }
+                }),
+            ])))
+            .mount(&ehr)
+            .await;
+
+        let body = get_summary_page(
+            &ehr,
+            Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]),
+        )
+        .await;
+
+        assert!(body.contains(r#"<section id="goals">"#));
+        assert!(body.contains("<li>Walk daily (active)</li>"));
+        assert!(body.contains(r#"<section id="care-plans">"#));
+        assert!(body.contains("<li>Cardiac rehabilitation (active)</li>"));
+    }
+
+    #[actix_web::test]
+    async fn empty_goals_and_care_plans_are_omitted() {
+        let ehr = MockServer::start().await;
+
+        let body = get_summary_page(
+            &ehr,
+            Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]),
+        )
+        .await;
+
+        assert!(!body.contains(r#"<section id="goals">"#));
+        assert!(!body.contains(r#"<section id="care-plans">"#));
+    }
+
+    #[actix_web::test]
+    async fn context_cookie_selects_issuer_for_shared_patient_id() {
+        let first = ehr_with_patient("Chalmers").await;
+        let second = ehr_with_patient("Levin").await;
+        let cipher = ContextCookieCipher::new(&[7u8; 32]).unwrap();
+        let cookie = context_cookie(cipher.seal(&second.uri(), "123").unwrap(), false);
+        let state = test_state().with_context_cookie_cipher(cipher);
+        put_session(&state, &first, "123", &["patient/*.read"]).await;
+        put_session(&state, &second, "123", &["patient/*.read"]).await;
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(index)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/123/index.html")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("Levin"));
+    }
+
+    #[actix_web::test]
+    async fn stale_context_cookie_falls_back_to_server_side_sessions() {
+        let ehr = ehr_with_patient("Chalmers").await;
+        let cipher = ContextCookieCipher::new(&[7u8; 32]).unwrap();
+        let cookie = context_cookie(
+            cipher.seal("https://gone.example.com/fhir", "123").unwrap(),
+            false,
+        );
+        let state = test_state().with_context_cookie_cipher(cipher);
+        put_session(&state, &ehr, "123", &["patient/*.read"]).await;
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(index)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/123/index.html")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("Chalmers"));
+    }
+
+    #[actix_web::test]
+    async fn height_coded_in_snomed_is_found() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Observation"))
+            .and(query_param(
+                "code",
+                "http://loinc.org|8302-2,http://snomed.info/sct|50373000",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(vec![
+                serde_json::json!({
+                    "resourceType": "Observation",
+                    "status": "final",
+                    "code": {
+                        "coding": [{ "system": "http://snomed.info/sct", "code": "50373000" }]
+                    },
+                    "subject": { "reference": "Patient/123" },
+                    "valueQuantity": { "value": 180, "unit": "cm" }
+                }),
+            ])))
+            .mount(&ehr)
+            .await;
+
+        let body = get_summary_page(
+            &ehr,
+            Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]),
+        )
+        .await;
+
+        assert!(body.contains("180 cm"));
+    }
+
+    #[actix_web::test]
+    async fn logged_in_practitioner_is_shown_with_role() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Practitioner/dr1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "resourceType": "Practitioner",
+                "id": "dr1",
+                "name": [{ "family": "Smith", "given": ["Jane"] }],
+                "qualification": [{ "code": { "text": "Cardiologist" } }]
+            })))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        let token = Token::for_test(
+            &ehr.uri(),
+            Some("123"),
+            &["patient/*.read", "openid", "fhirUser"],
+        )
+        .with_id_token(&id_token(serde_json::json!({
+            "sub": "dr1",
+            "fhirUser": format!("{}/Practitioner/dr1", ehr.uri())
+        })));
+
+        let body = get_summary_page(&ehr, token).await;
+
+        assert!(body.contains("Logged in as Jane Smith, Cardiologist"));
+    }
+
+    #[actix_web::test]
+    async fn pdf_summary_is_a_pdf_attachment() {
+        let ehr = ehr_with_patient("Chalmers").await;
+        let state = test_state();
+        put_session(&state, &ehr, "123", &["patient/*.read"]).await;
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(index)).await;
+
+        let req = test::TestRequest::get().uri("/123/index.pdf").to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/pdf"
+        );
+        assert_eq!(
+            resp.headers().get("content-disposition").unwrap(),
+            r#"attachment; filename="patient-summary-123.pdf""#
+        );
+        let body = test::read_body(resp).await;
+        assert!(body.starts_with(b"%PDF-"));
+        assert!(body.len() > 1000);
+    }
+
+    // A renderer that always fails, e.g. as if the PDF library could not save the
+    // document.
+    struct FailingRenderer;
+
+    impl SummaryRenderer for FailingRenderer {
+        fn content_type(&self) -> &'static str {
+            "application/pdf"
+        }
+
+        fn render(
+            &self,
+            _summary: &PatientSummary,
+            _context: &LaunchContext,
+        ) -> Result<Vec<u8>, AppError> {
+            Err(AppError::Internal(String::from("Failed to render.")))
+        }
+    }
+
+    #[actix_web::test]
+    async fn failure_to_render_is_a_server_error() {
+        let ehr = ehr_with_patient("Chalmers").await;
+        let state = test_state();
+        put_session(&state, &ehr, "123", &["patient/*.read"]).await;
+        let req = test::TestRequest::get()
+            .uri("/123/index.pdf")
+            .to_http_request();
+
+        // the summary's future is large, so we keep it off the test's stack
+        let resp = Box::pin(render_summary(&req, &state, "123", None, &FailingRenderer)).await;
+
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn data_absent_reason_is_shown_for_attempted_observation() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Observation"))
+            .and(query_param("code", "http://loinc.org|2085-9"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(vec![
+                serde_json::json!({
+                    "resourceType": "Observation",
+                    "status": "cancelled",
+                    "code": {
+                        "coding": [{ "system": "http://loinc.org", "code": "2085-9" }]
+                    },
+                    "subject": { "reference": "Patient/123" },
+                    "dataAbsentReason": {
+                        "coding": [{
+                            "system": "http://terminology.hl7.org/CodeSystem/data-absent-reason",
+                            "code": "not-performed",
+                            "display": "Not Performed"
+                        }]
+                    }
+                }),
+            ])))
+            .mount(&ehr)
+            .await;
+
+        let body = get_summary_page(
+            &ehr,
+            Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]),
+        )
+        .await;
+
+        assert!(body.contains("No value (Not Performed)"));
+        // the LDL search found nothing, so there is nothing to explain
+        assert_eq!(body.matches("No value").count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn missing_version_segment_is_detected_from_patient_read() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Patient/123"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/R4/Patient/123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "resourceType": "Patient",
+                "id": "123",
+                "name": [{ "family": "Chalmers", "given": ["Peter"] }]
+            })))
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(search_bundle(Vec::new())))
+            .mount(&ehr)
+            .await;
+        let state = web::Data::new(test_state());
+        put_session(&state, &ehr, "123", &["patient/*.read"]).await;
+        let app = test::init_service(App::new().app_data(state.clone()).service(index)).await;
+
+        // the session keeps the versioned base URL, so the second summary does not read
+        // the patient under the issuer URL again
+        for _ in 0..2 {
+            let req = test::TestRequest::get().uri("/123/index.html").to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+            assert!(body.contains("Chalmers"));
+        }
+
+        // later sessions with the same issuer use the versioned base URL from the start
+        put_session(&state, &ehr, "456", &["patient/*.read"]).await;
+        let SessionLookup::Found(client) = state.lookup_token("456", None) else {
+            panic!("Expected a session for patient 456");
+        };
+        assert_eq!(client.base_url(), format!("{}/R4", ehr.uri()));
+    }
+
+    #[actix_web::test]
+    async fn styles_are_fetched_once_for_repeated_renders() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/style.json"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "color_background": "#fafafa" })),
+            )
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        let mut token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]);
+        token.style_url = Some(format!("{}/style.json", ehr.uri()));
+        let state = web::Data::new(test_state());
+
+        for _ in 0..2 {
+            let body = get_summary_page_with_state(&ehr, token.clone(), state.clone()).await;
+            assert!(body.contains("body { background-color: #fafafa; }"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn slow_styles_do_not_hold_up_summary() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/style.json"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "color_background": "#fafafa" }))
+                    .set_delay(std::time::Duration::from_secs(5)),
+            )
+            .mount(&ehr)
+            .await;
+        let mut token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]);
+        token.style_url = Some(format!("{}/style.json", ehr.uri()));
+        let state = test_state().with_summary_timeout(std::time::Duration::from_millis(500));
+
+        let started = std::time::Instant::now();
+        let body = get_summary_page_with_state(&ehr, token, web::Data::new(state)).await;
+
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+        assert!(body.contains("Peter"));
+        assert!(!body.contains("background-color"));
+    }
+}
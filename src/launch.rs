@@ -14,23 +14,66 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use actix_web::{get, web, HttpResponse};
-use log::{debug, error};
+use actix_web::rt::time::timeout;
+use actix_web::{get, post, web, HttpResponse, ResponseError};
+use log::{debug, error, warn};
 use oauth2::PkceCodeChallenge;
 use serde::Deserialize;
+use url::form_urlencoded::byte_serialize;
 use url::Url;
 use url_builder::URLBuilder;
 use uuid::Uuid;
 
+use crate::error::AppError;
+use crate::fetch::is_valid_id;
+use crate::smart::capability::Capability;
 use crate::smart::configuration::SmartConfiguration;
-use crate::state::State;
+use crate::state::{PendingLaunch, State};
 
+// Parameters for the launch endpoint, provided either as query parameters (GET)
+// or as a form-encoded body (POST).
 #[derive(Deserialize)]
 struct LaunchQuery {
     // URL of the FHIR server
     iss: String,
-    // Unique launch ID parameter received from the launching EHR
-    launch: String,
+    // Unique launch ID parameter received from the launching EHR. Absent (or empty) for
+    // standalone launches
+    launch: Option<String>,
+    // OPTIONAL, OpenID Connect `prompt` parameter, passed through to the authorization endpoint
+    prompt: Option<String>,
+    // OPTIONAL, OpenID Connect `login_hint` parameter, passed through to the authorization endpoint
+    login_hint: Option<String>,
+    // OPTIONAL, the ID of a patient to preselect in standalone launches, passed to servers
+    // that support standalone patient context
+    patient: Option<String>,
+    // OPTIONAL, if true, respond with the authorization URL as JSON rather than redirecting to it.
+    // Only honored in dev mode.
+    #[serde(default)]
+    dry_run: bool,
+    // The number of times this launch has been restarted to re-authenticate the user
+    #[serde(skip)]
+    reauth_attempts: u32,
+    // The `aud` to send in place of the issuer, if the EHR rejected the issuer as the audience
+    #[serde(skip)]
+    aud: Option<String>,
+}
+
+// The values of the `prompt` parameter that we pass through to the authorization endpoint,
+// as defined by [OpenID Connect](https://openid.net/specs/openid-connect-core-1_0.html#AuthRequest).
+const ALLOWED_PROMPTS: [&str; 4] = ["none", "login", "consent", "select_account"];
+
+impl LaunchQuery {
+    // Checks that the `prompt` parameter, if any, only contains allowed values.
+    //
+    // The `prompt` parameter is a space separated list of values.
+    fn has_valid_prompt(&self) -> bool {
+        match &self.prompt {
+            Some(prompt) => prompt
+                .split(' ')
+                .all(|value| ALLOWED_PROMPTS.contains(&value)),
+            None => true,
+        }
+    }
 }
 
 /**
@@ -56,24 +99,165 @@ struct LaunchQuery {
  *
  * The EHR will then redirect to the redirect URL ("/callback", in our case), which
  * continues the authorization flow by requesting a token.
+ *
+ * The launch may also provide the OpenID Connect `prompt` (one or more of `none`,
+ * `login`, `consent`, and `select_account`) and `login_hint` parameters, e.g. to
+ * force the user to re-authenticate. These are passed through to the authorization
+ * endpoint.
+ *
+ * Standalone launches, which omit `launch` or send it empty, may pass a `patient` ID to
+ * preselect the patient, so that the user can skip patient selection. The ID is passed
+ * to the authorization endpoint if the server advertises the
+ * `context-standalone-patient` capability. Otherwise, or if the server does not honor
+ * it, the user selects a patient as usual.
+ *
+ * In dev mode, passing `dry_run=true` makes the launch respond with the authorization
+ * URL and the generated state as JSON, rather than redirecting to the authorization
+ * URL, so that test harnesses can inspect the URL without following it to an EHR.
  */
 #[get("/launch")]
 pub async fn launch(data: web::Data<State>, query: web::Query<LaunchQuery>) -> HttpResponse {
-    // Get the .well-known/smart-configuration from the FHIR server.
-    let smart_configuration = SmartConfiguration::get(&query.iss, &data.reqwest_client).await;
+    start_launch(data, query.into_inner()).await
+}
+
+/**
+ * SMART-on-FHIR EHR launch sequence: step 1 (launching), via POST
+ * ---------------------------------------------------------------
+ * Some EHRs launch SMART-on-FHIR apps by POSTing the `iss` and `launch`
+ * arguments as a form-encoded body, rather than by passing them as query
+ * parameters. This endpoint behaves identically to the GET `launch` endpoint.
+ */
+#[post("/launch")]
+pub async fn launch_post(data: web::Data<State>, form: web::Form<LaunchQuery>) -> HttpResponse {
+    start_launch(data, form.into_inner()).await
+}
+
+// Restarts the SMART-on-FHIR launch sequence.
+//
+// Used when the EHR's authorization endpoint tells us that the user needs to log
+// in again, or when the token endpoint rejects the `aud` that we sent; see `callback`.
+//
+// # Arguments
+// * `data` The application state.
+// * `pending` The launch that is being restarted.
+// * `prompt` The OpenID Connect `prompt` parameter to send, e.g. "login", if any.
+pub async fn restart_launch(
+    data: web::Data<State>,
+    pending: PendingLaunch,
+    prompt: Option<&str>,
+) -> HttpResponse {
+    let query = LaunchQuery {
+        iss: pending.iss,
+        launch: pending.launch_id,
+        prompt: prompt.map(String::from),
+        login_hint: None,
+        patient: pending.patient_hint,
+        dry_run: false,
+        reauth_attempts: pending.reauth_attempts,
+        aud: pending.aud,
+    };
+
+    start_launch(data, query).await
+}
+
+// Starts the SMART-on-FHIR launch sequence.
+//
+// Shared between the GET and POST `launch` endpoints; see `launch` for a
+// description of the launch sequence.
+//
+// The issuer URL is normalized first (see `State::normalize_iss`), so that the form
+// we store for the launch matches later comparisons.
+//
+// # Arguments
+// * `data` The application state.
+// * `query` The launch parameters received from the launching EHR.
+async fn start_launch(data: web::Data<State>, mut query: LaunchQuery) -> HttpResponse {
+    query.iss = data.normalize_iss(&query.iss);
+    query.launch = query.launch.filter(|launch_id| !launch_id.is_empty());
+    let query = &query;
+    let iss = query.iss.as_str();
+
+    // Check that the issuer is allowed before making any requests to it.
+    if !data.iss_allowlist.allows(iss) {
+        error!(
+            "Rejecting launch from issuer {} that is not in the allowlist",
+            iss
+        );
+        return HttpResponse::Forbidden()
+            .body(format!("EHR {} is not allowed to launch this app.", iss));
+    }
+
+    if query.dry_run && !data.dev_mode {
+        error!("Rejecting dry run launch from issuer {iss}, as dev mode is disabled");
+        return HttpResponse::Forbidden().body("Dry run launches are only available in dev mode.");
+    }
+
+    if !query.has_valid_prompt() {
+        error!("Rejecting launch from issuer {iss} with invalid prompt parameter");
+        return HttpResponse::BadRequest().body(format!(
+            "Invalid prompt parameter. Allowed values are: {}.",
+            ALLOWED_PROMPTS.join(", ")
+        ));
+    }
+
+    if query
+        .patient
+        .as_deref()
+        .is_some_and(|patient| !is_valid_id(patient))
+    {
+        error!("Rejecting launch from issuer {iss} with invalid patient parameter");
+        return HttpResponse::BadRequest().body("Invalid patient parameter.");
+    }
+
+    // Get the .well-known/smart-configuration from the FHIR server, unless we fetched
+    // it recently.
+    let smart_configuration = match data.get_cached_config(iss) {
+        Some(smart_configuration) => Ok(smart_configuration),
+        None => match timeout(
+            data.discovery_timeout,
+            SmartConfiguration::get(
+                iss,
+                &data.reqwest_client,
+                data.allows_host_root_discovery(iss),
+            ),
+        )
+        .await
+        {
+            Ok(smart_configuration) => smart_configuration,
+            Err(_) => {
+                error!(
+                    "Fetching SMART configuration from EHR {iss} timed out after {:?}",
+                    data.discovery_timeout
+                );
+                return HttpResponse::GatewayTimeout().body(format!(
+                    "EHR {iss} took too long to provide its SMART configuration."
+                ));
+            }
+        },
+    };
 
     match smart_configuration {
         Ok(smart_configuration) => {
             debug!(
                 "Successfully retrieved SMART configuration from issuer {}",
-                query.iss
+                iss
             );
 
+            if data.strict_smart_configuration {
+                check_unknown_fields(iss, &smart_configuration);
+            }
+
             if let Some(authorization_endpoint) = &smart_configuration.authorization_endpoint {
                 let auth_url = Url::parse(authorization_endpoint);
 
                 match auth_url {
                     Ok(auth_url) => {
+                        // Check that the browser will be able to follow the redirect.
+                        if let Err(err) = check_endpoint_scheme(&data, &auth_url) {
+                            error!("{err}");
+                            return data.error_page(AppError::Forbidden(err)).error_response();
+                        }
+
                         // Create a PKCE S256 code verifier / challenge pair.
                         let (pkce_challenge, pkce_verifier) =
                             PkceCodeChallenge::new_random_sha256();
@@ -81,15 +265,39 @@ pub async fn launch(data: web::Data<State>, query: web::Query<LaunchQuery>) -> H
                         // Create a UUID to use as state.
                         let state = Uuid::new_v4();
 
+                        // Record when the launch started, so that we can expire it, and
+                        // what we need to restart it
+                        data.put_launch_started(&state);
+                        data.put_pending_launch(
+                            &state,
+                            PendingLaunch {
+                                iss: iss.to_string(),
+                                launch_id: query.launch.clone(),
+                                reauth_attempts: query.reauth_attempts,
+                                aud: query.aud.clone(),
+                                patient_hint: query.patient.clone(),
+                            },
+                        );
+
                         // Insert smart configuration and issuer for state
-                        data.put_iss_and_config(&state, &query.iss, &smart_configuration);
+                        data.put_iss_and_config(&state, iss, &smart_configuration);
 
                         // Insert PKCE into app state for use from callback endpoint
-                        data.put_pkce(&state, pkce_challenge.clone(), pkce_verifier);
+                        if let Err(err) =
+                            data.put_pkce(&state, pkce_challenge.clone(), pkce_verifier)
+                        {
+                            error!("{err}");
+                            data.finish_launch(&state);
+                            return data.error_page(AppError::Internal(err)).error_response();
+                        }
+
+                        // Create a nonce to bind the id_token to this launch
+                        let nonce = Uuid::new_v4().simple().to_string();
+                        data.put_nonce(&state, &nonce);
 
                         debug!(
                             "Redirecting launch from issuer {} with state {} to {}",
-                            query.iss, state, auth_url
+                            iss, state, auth_url
                         );
 
                         // Create a HTTP response that redirects the web browser to the EHR authorization endpoint.
@@ -101,33 +309,33 @@ pub async fn launch(data: web::Data<State>, query: web::Query<LaunchQuery>) -> H
                         // HTTP response, and are setting the ["Location"
                         // header](https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Location) to the authorization
                         // endpoint on the EHR.
+                        let authorization_url = authorize_url(
+                            data,
+                            &auth_url,
+                            &smart_configuration,
+                            query,
+                            pkce_challenge.as_str(),
+                            &state,
+                            &nonce,
+                        );
+                        if query.dry_run {
+                            return HttpResponse::Ok().json(serde_json::json!({
+                                "authorization_url": authorization_url,
+                                "state": state.to_string(),
+                            }));
+                        }
+
                         HttpResponse::SeeOther()
-                            .insert_header((
-                                actix_web::http::header::LOCATION,
-                                authorize_url(
-                                    data,
-                                    &auth_url,
-                                    &query.iss,
-                                    &query.launch,
-                                    pkce_challenge.as_str(),
-                                    &state,
-                                ),
-                            ))
+                            .insert_header((actix_web::http::header::LOCATION, authorization_url))
                             .finish()
                     }
                     Err(e) => {
                         error!("Failed to parse authorization server URL {authorization_endpoint} due to error {e}");
-                        HttpResponse::InternalServerError().body(format!(
-			    "Failed to parse authorization URL provided by EHR: {}\nError was: {:?}",
-			    authorization_endpoint, e
-			))
+                        HttpResponse::InternalServerError().finish()
                     }
                 }
             } else {
-                let err = format!(
-                    "EHR {} does not provide an authorization endpoint.",
-                    &query.iss
-                );
+                let err = format!("EHR {} does not provide an authorization endpoint.", iss);
                 error!("{err}");
                 HttpResponse::NotImplemented().body(err)
             }
@@ -135,48 +343,815 @@ pub async fn launch(data: web::Data<State>, query: web::Query<LaunchQuery>) -> H
         Err(e) => {
             error!(
                 "Fetching SMART configuration from EHR {} failed due to {:?}",
-                query.iss, e
+                iss, e
             );
             HttpResponse::InternalServerError().body(format!(
                 "Failed to parse SMART configuration provided by EHR {}.",
-                &query.iss
+                iss
             ))
         }
     }
 }
 
+// Checks that an EHR's SMART configuration only contains fields that we recognize.
+//
+// Unknown fields are ignored when parsing the SMART configuration, so we log a
+// warning for each one, to surface specification features that we do not yet handle.
+//
+// # Arguments
+// * `iss` The URL of the server that issued the launch.
+// * `smart_configuration` The SMART configuration for the server.
+fn check_unknown_fields(iss: &str, smart_configuration: &SmartConfiguration) {
+    for field in smart_configuration.unknown_fields.keys() {
+        warn!("SMART configuration for EHR {iss} contains unrecognized field {field}");
+    }
+}
+
+// Checks that the scheme of an EHR endpoint is compatible with the app's scheme.
+//
+// If the app is served over HTTPS and redirects the browser to an `http://`
+// endpoint, browsers will block the mixed-content redirect. More generally,
+// sending authorization parameters over plain HTTP is insecure. We log a warning
+// whenever an `http://` endpoint is about to be used; in strict mode, we reject
+// the endpoint instead.
+//
+// # Arguments
+// * `data` The application state.
+// * `endpoint` The EHR endpoint we are about to redirect to.
+fn check_endpoint_scheme(data: &State, endpoint: &Url) -> Result<(), String> {
+    if endpoint.scheme() != "http" {
+        return Ok(());
+    }
+
+    if data.strict_schemes {
+        return Err(format!(
+            "EHR endpoint {endpoint} uses an insecure http:// scheme, which is not allowed in strict mode."
+        ));
+    }
+
+    if data.app_scheme() == "https" {
+        warn!("EHR endpoint {endpoint} uses http:// while this app is served over https://; browsers may block the redirect as mixed content.");
+    } else {
+        warn!("EHR endpoint {endpoint} uses an insecure http:// scheme.");
+    }
+
+    Ok(())
+}
+
+// Whether the app launches with a patient in context.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LaunchMode {
+    // The app summarizes the patient selected in the EHR.
+    Patient,
+
+    // The app operates at the system or organization level, without patient context.
+    Administrative,
+}
+
+impl LaunchMode {
+    // Parses a launch mode from its name, e.g. "patient".
+    //
+    // # Arguments
+    // * `mode` The name of the launch mode.
+    pub fn parse(mode: &str) -> Option<LaunchMode> {
+        match mode {
+            "patient" => Some(LaunchMode::Patient),
+            "administrative" => Some(LaunchMode::Administrative),
+            _ => None,
+        }
+    }
+
+    // The scopes that the app requests in this launch mode.
+    //
+    // Administrative launches do not request `launch/patient`, and request access to
+    // resources through the user's permissions rather than the patient's; instead, they
+    // request `launch/practitioner`, for the practitioner context of provider apps. Both
+    // modes request `fhirUser`, so that the EHR identifies the practitioner using the app.
+    //
+    // # Arguments
+    // * `syntax` The syntax to request resource scopes in.
+    // * `refresh_scope` The scope that grants refresh tokens; see
+    //   `SmartConfiguration::refresh_scope`.
+    pub fn desired_scopes(&self, syntax: ScopeSyntax, refresh_scope: &str) -> Vec<String> {
+        let context = match self {
+            LaunchMode::Patient => "patient",
+            LaunchMode::Administrative => "user",
+        };
+        let resource_scopes: Vec<String> = match syntax {
+            ScopeSyntax::V1 => [
+                "Patient",
+                "Practitioner",
+                "Organization",
+                "Observation",
+                "MedicationRequest",
+                "Medication",
+                "DiagnosticReport",
+                "Goal",
+                "CarePlan",
+            ]
+            .into_iter()
+            .map(|resource_type| format!("{context}/{resource_type}.read"))
+            .collect(),
+            // we only read resources, and only read the categories of observations
+            // that we summarize
+            ScopeSyntax::V2 => [
+                "Patient.rs",
+                "Practitioner.rs",
+                "Organization.rs",
+                "Observation.rs?category=vital-signs",
+                "Observation.rs?category=laboratory",
+                "MedicationRequest.rs",
+                "Medication.rs",
+                "DiagnosticReport.rs",
+                "Goal.rs",
+                "CarePlan.rs",
+            ]
+            .into_iter()
+            .map(|scope| format!("{context}/{scope}"))
+            .collect(),
+        };
+        let launch_scopes: &[&'static str] = match self {
+            LaunchMode::Patient => &["launch", "launch/patient", "fhirUser"],
+            LaunchMode::Administrative => &["launch", "launch/practitioner", "fhirUser"],
+        };
+
+        resource_scopes
+            .into_iter()
+            .chain(launch_scopes.iter().map(|scope| scope.to_string()))
+            .chain([refresh_scope, "openid", "profile"].map(String::from))
+            .collect()
+    }
+}
+
+// The syntax of the resource scopes that the app requests.
+//
+// [SMART v1](https://hl7.org/fhir/smart-app-launch/1.0.0/scopes-and-launch-context/)
+// scopes grant access by `.read` or `.write`, while
+// [SMART v2](https://build.fhir.org/ig/HL7/smart-app-launch/scopes-and-launch-context.html)
+// scopes grant granular permissions (e.g., `.rs` to read and search, or `.cruds` for
+// full access), optionally restricted by search parameters (e.g.,
+// `patient/Observation.rs?category=vital-signs`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScopeSyntax {
+    V1,
+    V2,
+}
+
+// Which scope syntax the app requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScopeVersion {
+    // Always request SMART v1 scopes. Understood by older servers.
+    V1,
+
+    // Always request SMART v2 scopes.
+    V2,
+
+    // Request SMART v2 scopes from servers that advertise the `permission-v2`
+    // capability, and SMART v1 scopes otherwise.
+    Auto,
+}
+
+impl ScopeVersion {
+    // Parses a scope version from its name, e.g. "v2".
+    //
+    // # Arguments
+    // * `version` The name of the scope version.
+    pub fn parse(version: &str) -> Option<ScopeVersion> {
+        match version {
+            "v1" => Some(ScopeVersion::V1),
+            "v2" => Some(ScopeVersion::V2),
+            "auto" => Some(ScopeVersion::Auto),
+            _ => None,
+        }
+    }
+
+    // Gets the scope syntax to request from a server.
+    //
+    // # Arguments
+    // * `smart_configuration` The SMART configuration of the server.
+    pub fn syntax_for(&self, smart_configuration: &SmartConfiguration) -> ScopeSyntax {
+        match self {
+            ScopeVersion::V1 => ScopeSyntax::V1,
+            ScopeVersion::V2 => ScopeSyntax::V2,
+            ScopeVersion::Auto => {
+                if smart_configuration
+                    .capabilities_set()
+                    .contains(&Capability::PermissionV2)
+                {
+                    ScopeSyntax::V2
+                } else {
+                    ScopeSyntax::V1
+                }
+            }
+        }
+    }
+}
+
 fn authorize_url(
     data: web::Data<State>,
     base_url: &Url,
-    iss: &str,
-    launch_id: &str,
+    smart_configuration: &SmartConfiguration,
+    query: &LaunchQuery,
     code_challenge: &str,
     state: &Uuid,
+    nonce: &str,
 ) -> String {
-    let desired_scopes = [
-        "patient/Patient.read",
-        "patient/Observation.read",
-        "launch",
-        "launch/patient",
-        "online_access",
-        "openid",
-        "profile",
-    ];
+    let syntax = data.scope_version.syntax_for(smart_configuration);
+    let desired_scopes = data.scopes_for(&query.iss).unwrap_or_else(|| {
+        data.launch_mode
+            .desired_scopes(syntax, smart_configuration.refresh_scope())
+    });
+
+    // strict servers reject launches that request scopes they do not support
+    let (desired_scopes, unsupported_scopes): (Vec<String>, Vec<String>) = desired_scopes
+        .into_iter()
+        .partition(|scope| smart_configuration.supports_scope(scope));
+    if !unsupported_scopes.is_empty() {
+        warn!(
+            "Not requesting scopes {} from issuer {}, as it does not list them as supported",
+            unsupported_scopes.join(" "),
+            query.iss
+        );
+    }
 
     let mut ub = URLBuilder::new();
 
+    // the port is only known if it is not the scheme's default
+    if let Some(port) = base_url.port() {
+        ub.set_port(port);
+    }
     ub.set_protocol(base_url.scheme())
         .set_host(base_url.host_str().unwrap_or(""))
         .add_route(base_url.path().trim_matches('/'))
         .add_param("response_type", "code")
-        .add_param("client_id", &data.client_id)
-        .add_param("redirect_uri", &data.callback())
-        .add_param("launch", launch_id)
+        .add_param("client_id", &data.credentials_for(&query.iss).client_id)
+        .add_param("redirect_uri", &data.redirect_uri_for(&query.iss))
         .add_param("state", &state.to_string())
-        .add_param("aud", iss)
+        .add_param("aud", query.aud.as_deref().unwrap_or(&query.iss))
         .add_param("code_challenge", code_challenge)
         .add_param("code_challenge_method", "S256")
-        .add_param("scope", &desired_scopes.join("+"));
+        .add_param("nonce", nonce)
+        .add_param(
+            "scope",
+            // SMART v2 scopes may contain search parameters, which need to be encoded
+            &desired_scopes
+                .iter()
+                .map(|scope| byte_serialize(scope.as_bytes()).collect::<String>())
+                .collect::<Vec<String>>()
+                .join("+"),
+        );
+
+    // standalone launches have no launch ID to pass on
+    if let Some(launch_id) = &query.launch {
+        ub.add_param("launch", launch_id);
+    }
+
+    // pass through the optional OpenID Connect parameters, which may contain
+    // characters that need to be encoded
+    if let Some(prompt) = &query.prompt {
+        ub.add_param(
+            "prompt",
+            &byte_serialize(prompt.as_bytes()).collect::<String>(),
+        );
+    }
+    if let Some(login_hint) = &query.login_hint {
+        ub.add_param(
+            "login_hint",
+            &byte_serialize(login_hint.as_bytes()).collect::<String>(),
+        );
+    }
+
+    // patient IDs are validated when the launch starts, and need no encoding
+    if let Some(patient) = &query.patient {
+        if query.launch.is_some() {
+            warn!(
+                "Ignoring patient hint for EHR launch from issuer {}, as the EHR selects the patient",
+                query.iss
+            );
+        } else if smart_configuration
+            .capabilities_set()
+            .contains(&Capability::ContextStandalonePatient)
+        {
+            ub.add_param("patient", patient);
+        } else {
+            warn!(
+                "Not passing patient hint to issuer {}, as it does not advertise the {} capability",
+                query.iss,
+                Capability::ContextStandalonePatient
+            );
+        }
+    }
 
     ub.build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::header::LOCATION;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::ErrorHandlers;
+    use actix_web::{test, App};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::allowlist::IssuerAllowlist;
+    use crate::error::server_error_response;
+    use crate::test_support::{
+        capture_warnings, encode, mock_smart_configuration, smart_configuration, take_warnings,
+        test_state,
+    };
+
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    // Sends a GET request to the launch endpoint.
+    //
+    // # Arguments
+    // * `state` The application state.
+    // * `query` The query string, without the leading '?'.
+    async fn get_launch(state: State, query: &str) -> actix_web::dev::ServiceResponse {
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(launch)).await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/launch?{query}"))
+            .to_request();
+        test::call_service(&app, req).await
+    }
+
+    // Gets the query parameters of the authorization URL that a launch redirected to.
+    //
+    // # Arguments
+    // * `resp` The response to the launch.
+    fn authorize_params(resp: &actix_web::dev::ServiceResponse) -> HashMap<String, String> {
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp.headers().get(LOCATION).unwrap().to_str().unwrap();
+        Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn launch_from_allowed_issuer_redirects_to_ehr() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        let state =
+            test_state().with_iss_allowlist(IssuerAllowlist::parse([ehr.uri().as_str()]).unwrap());
+
+        let resp = get_launch(state, &format!("iss={}&launch=abc", encode(&ehr.uri()))).await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp.headers().get(LOCATION).unwrap().to_str().unwrap();
+        assert!(location.starts_with(&format!("{}/authorize?", ehr.uri())));
+    }
+
+    // Mounts a SMART configuration that the mock EHR serves after a delay.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `delay` How long the EHR takes to respond.
+    async fn mock_slow_smart_configuration(ehr: &MockServer, delay: Duration) {
+        Mock::given(method("GET"))
+            .and(path("/.well-known/smart-configuration"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(smart_configuration(&ehr.uri()))
+                    .set_delay(delay),
+            )
+            .mount(ehr)
+            .await;
+    }
+
+    #[actix_web::test]
+    async fn slow_discovery_times_out() {
+        let ehr = MockServer::start().await;
+        mock_slow_smart_configuration(&ehr, Duration::from_millis(500)).await;
+        let state = test_state().with_discovery_timeout(Duration::from_millis(50));
+
+        let resp = get_launch(state, &format!("iss={}&launch=abc", encode(&ehr.uri()))).await;
+
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[actix_web::test]
+    async fn discovery_is_not_limited_by_other_timeouts() {
+        let ehr = MockServer::start().await;
+        mock_slow_smart_configuration(&ehr, Duration::from_millis(100)).await;
+        let state = test_state()
+            .with_token_timeout(Duration::from_millis(10))
+            .with_resource_timeout(Duration::from_millis(10));
+
+        let resp = get_launch(state, &format!("iss={}&launch=abc", encode(&ehr.uri()))).await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    }
+
+    #[actix_web::test]
+    async fn dry_run_returns_authorization_url() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        let state = test_state().with_dev_mode(true);
+
+        let resp = get_launch(
+            state,
+            &format!("iss={}&launch=abc&dry_run=true", encode(&ehr.uri())),
+        )
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let authorization_url = Url::parse(body["authorization_url"].as_str().unwrap()).unwrap();
+        let params: HashMap<String, String> =
+            authorization_url.query_pairs().into_owned().collect();
+        assert_eq!(
+            authorization_url.as_str().split('?').next(),
+            Some(format!("{}/authorize", ehr.uri()).as_str())
+        );
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["client_id"], "test-client");
+        assert_eq!(params["launch"], "abc");
+        assert_eq!(params["aud"], ehr.uri());
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert_eq!(params["state"], body["state"].as_str().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn dry_run_is_forbidden_outside_dev_mode() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+
+        let resp = get_launch(
+            test_state(),
+            &format!("iss={}&launch=abc&dry_run=true", encode(&ehr.uri())),
+        )
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn launch_from_disallowed_issuer_is_forbidden_without_fetching() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        let state = test_state()
+            .with_iss_allowlist(IssuerAllowlist::parse(["https://fhir.example.com"]).unwrap());
+
+        let resp = get_launch(state, &format!("iss={}&launch=abc", encode(&ehr.uri()))).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(ehr.received_requests().await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn launch_with_http_authorization_endpoint_is_rejected_in_strict_mode() {
+        // the mock EHR is served over http://, as is its authorization endpoint
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        let state = test_state().with_strict_schemes(true);
+
+        // the app's error handlers sanitize server errors, so the rejection must not be one
+        let app = test::init_service(
+            App::new()
+                .wrap(ErrorHandlers::new().default_handler_server(server_error_response))
+                .app_data(web::Data::new(state))
+                .service(launch),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/launch?iss={}&launch=abc", encode(&ehr.uri())))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(&body).contains("insecure http:// scheme"));
+    }
+
+    #[actix_web::test]
+    async fn post_launch_redirects_like_get_launch() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state()))
+                .service(launch_post),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/launch")
+            .set_form([("iss", ehr.uri().as_str()), ("launch", "abc")])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = resp.headers().get(LOCATION).unwrap().to_str().unwrap();
+        assert!(location.starts_with(&format!("{}/authorize?", ehr.uri())));
+        let location = Url::parse(location).unwrap();
+        assert!(location
+            .query_pairs()
+            .any(|(key, value)| key == "launch" && value == "abc"));
+    }
+
+    #[actix_web::test]
+    async fn launch_passes_prompt_and_login_hint_to_ehr() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+
+        let resp = get_launch(
+            test_state(),
+            &format!(
+                "iss={}&launch=abc&prompt={}&login_hint={}",
+                encode(&ehr.uri()),
+                encode("login consent"),
+                encode("dr.smith@example.com")
+            ),
+        )
+        .await;
+
+        let params = authorize_params(&resp);
+        assert_eq!(params["prompt"], "login consent");
+        assert_eq!(params["login_hint"], "dr.smith@example.com");
+    }
+
+    #[actix_web::test]
+    async fn launch_with_invalid_prompt_is_rejected() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+
+        let resp = get_launch(
+            test_state(),
+            &format!("iss={}&launch=abc&prompt=bogus", encode(&ehr.uri())),
+        )
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // Launches the app from an EHR whose SMART configuration has an unrecognized
+    // field, returning the warnings logged during the launch.
+    //
+    // # Arguments
+    // * `strict` Whether to warn about unrecognized fields.
+    async fn launch_with_unknown_field(strict: bool) -> Vec<String> {
+        let ehr = MockServer::start().await;
+        let mut config = smart_configuration(&ehr.uri());
+        config["future_feature"] = serde_json::json!(true);
+        Mock::given(method("GET"))
+            .and(path("/.well-known/smart-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(config))
+            .mount(&ehr)
+            .await;
+        let state = test_state().with_strict_smart_configuration(strict);
+
+        capture_warnings();
+        let resp = get_launch(state, &format!("iss={}&launch=abc", encode(&ehr.uri()))).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        take_warnings()
+    }
+
+    #[actix_web::test]
+    async fn unknown_configuration_field_is_logged_in_strict_mode() {
+        let warnings = launch_with_unknown_field(true).await;
+
+        assert!(warnings
+            .iter()
+            .any(|warning| warning.contains("unrecognized field future_feature")));
+    }
+
+    #[actix_web::test]
+    async fn unknown_configuration_field_is_ignored_by_default() {
+        let warnings = launch_with_unknown_field(false).await;
+
+        assert!(!warnings
+            .iter()
+            .any(|warning| warning.contains("future_feature")));
+    }
+
+    // Gets the scopes that a launch requests from a mock EHR.
+    //
+    // # Arguments
+    // * `scope_version` The scope version the app is configured with.
+    // * `capabilities` The capabilities that the mock EHR advertises.
+    async fn requested_scopes(scope_version: ScopeVersion, capabilities: &[&str]) -> Vec<String> {
+        requested_scopes_with(
+            test_state().with_scope_version(scope_version),
+            serde_json::json!({ "capabilities": capabilities }),
+        )
+        .await
+    }
+
+    // Gets the scopes that an app requests from a mock EHR with a customized SMART
+    // configuration.
+    //
+    // # Arguments
+    // * `state` The application state.
+    // * `overrides` The fields to replace in the mock EHR's SMART configuration.
+    async fn requested_scopes_with(state: State, overrides: serde_json::Value) -> Vec<String> {
+        let ehr = MockServer::start().await;
+        let mut configuration = smart_configuration(&ehr.uri());
+        for (field, value) in overrides.as_object().unwrap() {
+            configuration[field] = value.clone();
+        }
+        Mock::given(method("GET"))
+            .and(path("/.well-known/smart-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(configuration))
+            .mount(&ehr)
+            .await;
+        let resp = get_launch(state, &format!("iss={}&launch=abc", encode(&ehr.uri()))).await;
+
+        authorize_params(&resp)["scope"]
+            .split(' ')
+            .map(str::to_string)
+            .collect()
+    }
+
+    // Gets the authorization parameters of a standalone launch from a mock EHR.
+    //
+    // # Arguments
+    // * `capabilities` The capabilities that the mock EHR advertises.
+    // * `query` The query string of the launch, after the issuer.
+    async fn standalone_authorize_params(
+        capabilities: &[&str],
+        query: &str,
+    ) -> HashMap<String, String> {
+        let ehr = MockServer::start().await;
+        let mut configuration = smart_configuration(&ehr.uri());
+        configuration["capabilities"] = serde_json::json!(capabilities);
+        Mock::given(method("GET"))
+            .and(path("/.well-known/smart-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(configuration))
+            .mount(&ehr)
+            .await;
+        let resp = get_launch(test_state(), &format!("iss={}&{query}", encode(&ehr.uri()))).await;
+
+        authorize_params(&resp)
+    }
+
+    #[actix_web::test]
+    async fn patient_hint_is_passed_to_standalone_launch() {
+        let params = standalone_authorize_params(
+            &["launch-standalone", "context-standalone-patient"],
+            "patient=123",
+        )
+        .await;
+
+        assert_eq!(params["patient"], "123");
+        assert!(!params.contains_key("launch"));
+    }
+
+    #[actix_web::test]
+    async fn patient_hint_needs_standalone_patient_capability() {
+        let params = standalone_authorize_params(&["launch-standalone"], "patient=123").await;
+
+        assert!(!params.contains_key("patient"));
+    }
+
+    #[actix_web::test]
+    async fn patient_hint_is_ignored_for_ehr_launch() {
+        let params = standalone_authorize_params(
+            &["launch-ehr", "context-standalone-patient"],
+            "launch=abc&patient=123",
+        )
+        .await;
+
+        assert_eq!(params["launch"], "abc");
+        assert!(!params.contains_key("patient"));
+    }
+
+    #[actix_web::test]
+    async fn malformed_patient_hint_is_rejected() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+
+        let resp = get_launch(
+            test_state(),
+            &format!("iss={}&patient=a%2Fb", encode(&ehr.uri())),
+        )
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn v2_scopes_are_granular() {
+        let scopes = requested_scopes(ScopeVersion::V2, &["launch-ehr"]).await;
+
+        assert!(scopes.contains(&String::from("patient/Patient.rs")));
+        assert!(scopes.contains(&String::from("patient/Observation.rs?category=vital-signs")));
+        assert!(!scopes.iter().any(|scope| scope.ends_with(".read")));
+    }
+
+    #[actix_web::test]
+    async fn v1_scopes_are_read_scopes() {
+        let scopes = requested_scopes(ScopeVersion::V1, &["launch-ehr", "permission-v2"]).await;
+
+        assert!(scopes.contains(&String::from("patient/Patient.read")));
+        assert!(scopes.contains(&String::from("patient/Observation.read")));
+        assert!(!scopes.iter().any(|scope| scope.contains(".rs")));
+    }
+
+    #[actix_web::test]
+    async fn auto_scopes_follow_permission_v2_capability() {
+        let v2 = requested_scopes(ScopeVersion::Auto, &["launch-ehr", "permission-v2"]).await;
+        let v1 = requested_scopes(ScopeVersion::Auto, &["launch-ehr"]).await;
+
+        assert!(v2.contains(&String::from("patient/Patient.rs")));
+        assert!(v1.contains(&String::from("patient/Patient.read")));
+    }
+
+    #[actix_web::test]
+    async fn practitioner_context_is_requested_only_for_administrative_launches() {
+        let patient_scopes = LaunchMode::Patient.desired_scopes(ScopeSyntax::V1, "online_access");
+        let administrative_scopes =
+            LaunchMode::Administrative.desired_scopes(ScopeSyntax::V1, "online_access");
+
+        assert!(!patient_scopes.contains(&String::from("launch/practitioner")));
+        assert!(administrative_scopes.contains(&String::from("launch/practitioner")));
+        assert!(administrative_scopes.contains(&String::from("fhirUser")));
+    }
+
+    #[actix_web::test]
+    async fn offline_access_is_requested_when_supported() {
+        let scopes = requested_scopes_with(
+            test_state(),
+            serde_json::json!({
+                "scopes_supported": [
+                    "launch", "launch/patient", "fhirUser", "openid", "profile",
+                    "patient/*.read", "offline_access", "online_access"
+                ]
+            }),
+        )
+        .await;
+
+        assert!(scopes.contains(&String::from("offline_access")));
+        assert!(!scopes.contains(&String::from("online_access")));
+        assert!(scopes.contains(&String::from("patient/Observation.read")));
+    }
+
+    #[actix_web::test]
+    async fn online_access_is_requested_otherwise() {
+        let scopes = requested_scopes(ScopeVersion::V1, &["launch-ehr"]).await;
+
+        assert!(scopes.contains(&String::from("online_access")));
+        assert!(!scopes.contains(&String::from("offline_access")));
+    }
+
+    #[actix_web::test]
+    async fn trailing_slash_issuer_is_normalized() {
+        let ehr = MockServer::start().await;
+        mock_smart_configuration(&ehr).await;
+
+        let resp = get_launch(
+            test_state(),
+            &format!("iss={}&launch=abc", encode(&format!("{}/", ehr.uri()))),
+        )
+        .await;
+
+        assert_eq!(authorize_params(&resp)["aud"], ehr.uri());
+        let requests = ehr.received_requests().await.unwrap();
+        assert_eq!(requests[0].url.path(), "/.well-known/smart-configuration");
+    }
+
+    #[actix_web::test]
+    async fn issuer_scopes_override_defaults() {
+        let state = test_state().with_issuer_scopes(HashMap::from([(
+            String::from("127.0.0.1"),
+            vec![
+                String::from("launch"),
+                String::from("patient/Patient.read"),
+                String::from("patient/Condition.read"),
+            ],
+        )]));
+
+        let scopes = requested_scopes_with(state, serde_json::json!({})).await;
+
+        assert_eq!(
+            scopes,
+            ["launch", "patient/Patient.read", "patient/Condition.read"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn issuer_scopes_are_limited_to_supported_scopes() {
+        let state = test_state().with_issuer_scopes(HashMap::from([(
+            String::from("127.0.0.1"),
+            vec![
+                String::from("launch"),
+                String::from("patient/Patient.read"),
+                String::from("patient/Condition.read"),
+            ],
+        )]));
+
+        let scopes = requested_scopes_with(
+            state,
+            serde_json::json!({ "scopes_supported": ["launch", "patient/Patient.read"] }),
+        )
+        .await;
+
+        assert_eq!(scopes, ["launch", "patient/Patient.read"]);
+    }
+}
@@ -14,9 +14,43 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod admin;
+pub mod aead;
+pub mod allowlist;
 pub mod callback;
+pub mod care_plan;
+pub mod codes;
+pub mod context;
+pub mod context_cookie;
+pub mod dashboard;
+pub mod diagnostic_report;
+pub mod diagnostic_report_detail;
+pub mod display;
+pub mod error;
+pub mod fetch;
 pub mod health;
+pub mod http;
 pub mod index;
 pub mod launch;
+pub mod limit;
+pub mod logging;
+pub mod logout;
+pub mod loinc;
+pub mod medication;
+pub mod metrics;
+pub mod observation;
+pub mod observation_detail;
+pub mod patient;
+pub mod pkce;
+pub mod practitioner;
+pub mod proxy;
+pub mod render;
+pub mod request_id;
+pub mod search_support;
+pub mod session;
 pub mod smart;
 pub mod state;
+pub mod static_files;
+pub mod summary;
+#[cfg(test)]
+mod test_support;
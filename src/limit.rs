@@ -0,0 +1,340 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::rt::time::timeout;
+use fhir_sdk::client::Error;
+use log::warn;
+use reqwest::StatusCode;
+use tokio::sync::Semaphore;
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::fetch::is_session_expired;
+
+// Stops a session from sending requests after the FHIR server repeatedly rejects
+// its token.
+//
+// If a token is revoked at the EHR, every request fails, and retrying each one is
+// wasteful and may trip the server's abuse protections. After `threshold`
+// consecutive rejections, the breaker opens, and requests fail immediately until
+// the cooldown passes. A successful request closes the breaker again.
+#[derive(Clone, Debug, Default)]
+pub struct AuthBreaker {
+    // The number of consecutive rejections that open the breaker. Zero disables
+    // the breaker.
+    threshold: u32,
+
+    // How long the breaker stays open.
+    cooldown: Duration,
+
+    // The number of consecutive rejections, and when the breaker closes, if open.
+    state: Arc<Mutex<(u32, Option<Instant>)>>,
+}
+
+impl AuthBreaker {
+    // Creates a breaker.
+    //
+    // # Arguments
+    // * `threshold` The number of consecutive rejections that open the breaker. If
+    //   zero, the breaker never opens.
+    // * `cooldown` How long the breaker stays open.
+    pub fn new(threshold: u32, cooldown: Duration) -> AuthBreaker {
+        AuthBreaker {
+            threshold,
+            cooldown,
+            state: Arc::default(),
+        }
+    }
+
+    // Checks whether the breaker is open, i.e., requests should not be sent.
+    pub fn is_open(&self) -> bool {
+        let (_, open_until) = *self.state.lock().unwrap();
+        open_until.is_some_and(|open_until| Instant::now() < open_until)
+    }
+
+    // Records a successful request, closing the breaker.
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = (0, None);
+    }
+
+    // Records a request that the FHIR server rejected our token for.
+    //
+    // Once the cooldown passes, the next request is let through; if it is rejected
+    // too, the breaker opens again right away.
+    pub fn record_rejection(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = state.0.saturating_add(1);
+        if self.threshold > 0 && state.0 >= self.threshold {
+            warn!(
+                "FHIR server rejected our token {} times in a row; pausing requests for {} seconds",
+                state.0,
+                self.cooldown.as_secs()
+            );
+            state.1 = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+// Limits how many requests a session sends to the FHIR server at once.
+//
+// Protects EHRs with strict rate limits from bursts of requests, e.g. when a
+// summary page fetches many resources concurrently. The limit is shared between
+// clones, so that all requests made for a given `TokenClient` count against it.
+#[derive(Clone, Debug, Default)]
+pub struct RequestLimiter {
+    semaphore: Option<Arc<Semaphore>>,
+    breaker: AuthBreaker,
+
+    // How long a request may take once it is sent. If empty, requests may take
+    // as long as they need.
+    timeout: Option<Duration>,
+}
+
+impl RequestLimiter {
+    // Creates a limiter.
+    //
+    // # Arguments
+    // * `limit` The maximum number of concurrent requests. If empty, requests are
+    //   not limited.
+    // * `breaker` The breaker that stops requests once our token is rejected.
+    // * `timeout` How long a request may take once it is sent.
+    pub fn new(limit: Option<usize>, breaker: AuthBreaker, timeout: Duration) -> RequestLimiter {
+        RequestLimiter {
+            semaphore: limit.map(|limit| Arc::new(Semaphore::new(limit))),
+            breaker,
+            timeout: Some(timeout),
+        }
+    }
+
+    // Runs a FHIR request, waiting until the limit allows it to run.
+    //
+    // Fails immediately with an `AuthCallback` error, which callers treat as an
+    // expired session, if the breaker is open. Otherwise, records whether the request
+    // succeeded or the FHIR server rejected our token. A request that takes longer
+    // than the timeout fails with a `504 Gateway Timeout` response error; the time
+    // spent waiting for the limit does not count towards the timeout.
+    //
+    // # Arguments
+    // * `request` The request to run.
+    pub async fn run_request<T, F>(&self, request: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        if self.breaker.is_open() {
+            return Err(Error::AuthCallback(String::from(
+                "the FHIR server repeatedly rejected our token",
+            )));
+        }
+
+        // the timeout only starts once the limit lets the request run
+        let result = self
+            .run(async {
+                match self.timeout {
+                    Some(limit) => timeout(limit, request).await.unwrap_or_else(|_| {
+                        Err(Error::Response(
+                            StatusCode::GATEWAY_TIMEOUT,
+                            format!("the FHIR server did not respond within {limit:?}"),
+                        ))
+                    }),
+                    None => request.await,
+                }
+            })
+            .await;
+        match &result {
+            Ok(_) => self.breaker.record_success(),
+            Err(e) if is_session_expired(e) => self.breaker.record_rejection(),
+            Err(_) => {}
+        }
+        result
+    }
+
+    // Runs a request, waiting until the limit allows it to run.
+    //
+    // # Arguments
+    // * `request` The request to run.
+    pub async fn run<F: Future>(&self, request: F) -> F::Output {
+        match &self.semaphore {
+            Some(semaphore) => {
+                let _permit = semaphore
+                    .acquire()
+                    .await
+                    .expect("Request limiter semaphore was closed.");
+                request.await
+            }
+            None => request.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    // Sends concurrent requests to a slow server through a limiter, returning the
+    // largest number of requests that were in flight at once.
+    //
+    // # Arguments
+    // * `limiter` The limiter to send the requests through.
+    async fn max_in_flight(limiter: &RequestLimiter) -> usize {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(50)))
+            .expect(3)
+            .mount(&server)
+            .await;
+        let client = reqwest::Client::new();
+        let in_flight = AtomicUsize::new(0);
+        let max = AtomicUsize::new(0);
+
+        let requests = (0..3).map(|_| {
+            limiter.run_request(async {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max.fetch_max(current, Ordering::SeqCst);
+                let response = client.get(server.uri()).send().await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                response.map(|_| ()).map_err(Error::Request)
+            })
+        });
+        for result in join_all(requests).await {
+            result.unwrap();
+        }
+
+        max.load(Ordering::SeqCst)
+    }
+
+    #[actix_web::test]
+    async fn limit_of_one_serializes_requests() {
+        let limiter = RequestLimiter::new(Some(1), AuthBreaker::default(), Duration::from_secs(5));
+
+        assert_eq!(max_in_flight(&limiter).await, 1);
+    }
+
+    #[actix_web::test]
+    async fn unlimited_requests_run_concurrently() {
+        let limiter = RequestLimiter::new(None, AuthBreaker::default(), Duration::from_secs(5));
+
+        assert_eq!(max_in_flight(&limiter).await, 3);
+    }
+
+    // Sends a request to a server through a limiter, failing with a response error if
+    // the server does not respond with success.
+    //
+    // # Arguments
+    // * `limiter` The limiter to send the request through.
+    // * `server` The server to send the request to.
+    async fn send(limiter: &RequestLimiter, server: &MockServer) -> Result<(), Error> {
+        limiter
+            .run_request(async {
+                let response = reqwest::get(server.uri()).await.map_err(Error::Request)?;
+                match response.status() {
+                    status if status.is_success() => Ok(()),
+                    status => Err(Error::Response(status, String::new())),
+                }
+            })
+            .await
+    }
+
+    #[actix_web::test]
+    async fn consecutive_rejections_open_breaker() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(2)
+            .mount(&server)
+            .await;
+        let breaker = AuthBreaker::new(2, Duration::from_secs(60));
+        let limiter = RequestLimiter::new(None, breaker.clone(), Duration::from_secs(5));
+
+        for _ in 0..2 {
+            let e = send(&limiter, &server).await.unwrap_err();
+            assert!(matches!(e, Error::Response(StatusCode::UNAUTHORIZED, _)));
+        }
+        let e = send(&limiter, &server).await.unwrap_err();
+
+        assert!(breaker.is_open());
+        assert!(matches!(e, Error::AuthCallback(_)));
+    }
+
+    #[actix_web::test]
+    async fn success_resets_rejection_count() {
+        let server = MockServer::start().await;
+        let limiter = RequestLimiter::new(
+            None,
+            AuthBreaker::new(2, Duration::from_secs(60)),
+            Duration::from_secs(5),
+        );
+
+        for status in [401, 200, 401] {
+            server.reset().await;
+            Mock::given(method("GET"))
+                .respond_with(ResponseTemplate::new(status))
+                .mount(&server)
+                .await;
+            let _ = send(&limiter, &server).await;
+        }
+
+        assert!(!limiter.breaker.is_open());
+    }
+
+    #[actix_web::test]
+    async fn slow_request_times_out() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(500)))
+            .mount(&server)
+            .await;
+        let limiter = RequestLimiter::new(None, AuthBreaker::default(), Duration::from_millis(50));
+
+        let e = send(&limiter, &server).await.unwrap_err();
+
+        assert!(matches!(e, Error::Response(StatusCode::GATEWAY_TIMEOUT, _)));
+    }
+
+    #[actix_web::test]
+    async fn waiting_for_limit_does_not_count_towards_timeout() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(400)))
+            .expect(2)
+            .mount(&server)
+            .await;
+        // the second request waits about 400ms for the first, then takes 400ms itself
+        let limiter =
+            RequestLimiter::new(Some(1), AuthBreaker::default(), Duration::from_millis(700));
+
+        let results = join_all([send(&limiter, &server), send(&limiter, &server)]).await;
+
+        for result in results {
+            result.unwrap();
+        }
+    }
+
+    #[test]
+    fn breaker_closes_after_cooldown() {
+        let breaker = AuthBreaker::new(1, Duration::ZERO);
+
+        breaker.record_rejection();
+
+        assert!(!breaker.is_open());
+    }
+}
@@ -0,0 +1,144 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use env_logger::{Builder, Env};
+use log::Record;
+
+use std::io::Write;
+
+// The filter used if `RUST_LOG` is not set.
+const DEFAULT_FILTER: &str = "actix_web::middleware::logger=info,rust_smart_fhir=info";
+
+// How log lines are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    // Human-readable lines, in `env_logger`'s default format.
+    Text,
+
+    // One JSON object per line, for log aggregation.
+    Json,
+}
+
+impl LogFormat {
+    // Parses a log format from its name, e.g. "json".
+    //
+    // # Arguments
+    // * `format` The name of the log format.
+    pub fn parse(format: &str) -> Option<LogFormat> {
+        match format {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+
+    // Installs a logger that writes lines in this format.
+    //
+    // Log levels are filtered using `RUST_LOG`, as usual for `env_logger`.
+    pub fn init(&self) {
+        let mut builder = Builder::from_env(Env::new().default_filter_or(DEFAULT_FILTER));
+        if *self == LogFormat::Json {
+            builder.format(|buf, record| writeln!(buf, "{}", json_record(record, Utc::now())));
+        }
+        builder.init();
+    }
+}
+
+// Formats a log record as a JSON object.
+//
+// The object holds the record's timestamp, level, target, and message. Access log
+// lines end with the ID of the request (see `request_id`), which is moved into a
+// `request_id` field, so that log aggregators can correlate lines without parsing
+// messages.
+//
+// # Arguments
+// * `record` The log record.
+// * `timestamp` When the record was logged.
+pub fn json_record(record: &Record, timestamp: DateTime<Utc>) -> serde_json::Value {
+    let message = record.args().to_string();
+    let (message, request_id) = match message.rsplit_once(" request_id=") {
+        Some((message, request_id)) if !request_id.contains(char::is_whitespace) => {
+            (message.to_string(), Some(request_id.to_string()))
+        }
+        _ => (message, None),
+    };
+
+    let mut object = serde_json::json!({
+        "timestamp": timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": message,
+    });
+    if let Some(request_id) = request_id {
+        object["request_id"] = serde_json::Value::String(request_id);
+    }
+    object
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use log::Level;
+
+    #[test]
+    fn json_records_are_parseable_json_lines() {
+        let timestamp: DateTime<Utc> = "2024-05-01T12:00:00Z".parse().unwrap();
+        let line = json_record(
+            &Record::builder()
+                .args(format_args!(
+                    "127.0.0.1 \"GET /launch HTTP/1.1\" 303 request_id=abc-123"
+                ))
+                .level(Level::Info)
+                .target("actix_web::middleware::logger")
+                .build(),
+            timestamp,
+        )
+        .to_string();
+
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(!line.contains('\n'));
+        assert_eq!(record["timestamp"], "2024-05-01T12:00:00Z");
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["target"], "actix_web::middleware::logger");
+        assert_eq!(record["message"], "127.0.0.1 \"GET /launch HTTP/1.1\" 303");
+        assert_eq!(record["request_id"], "abc-123");
+    }
+
+    #[test]
+    fn json_records_without_request_id_keep_message() {
+        let record = json_record(
+            &Record::builder()
+                .args(format_args!("Fetching SMART configuration\nfailed"))
+                .level(Level::Warn)
+                .target("rust_smart_fhir::launch")
+                .build(),
+            Utc::now(),
+        );
+
+        assert_eq!(record["message"], "Fetching SMART configuration\nfailed");
+        assert!(record.get("request_id").is_none());
+        assert!(!record.to_string().contains('\n'));
+    }
+
+    #[test]
+    fn log_formats_parse_by_name() {
+        assert_eq!(LogFormat::parse("json"), Some(LogFormat::Json));
+        assert_eq!(LogFormat::parse("text"), Some(LogFormat::Text));
+        assert_eq!(LogFormat::parse("xml"), None);
+    }
+}
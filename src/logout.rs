@@ -0,0 +1,201 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::{get, web, HttpResponse};
+use log::{debug, error, warn};
+use url::Url;
+
+use crate::smart::token::Logout;
+use crate::state::State;
+
+/**
+ * Logging out
+ * -----------
+ * Ends the session for a patient: the session's token is removed from our state
+ * store and, if the EHR provides a [revocation endpoint](https://www.rfc-editor.org/rfc/rfc7009),
+ * revoked at the EHR.
+ *
+ * If the EHR provides an OpenID Connect end-session endpoint and we hold an
+ * id_token, we then redirect the browser to the end-session endpoint to perform
+ * [RP-initiated logout](https://openid.net/specs/openid-connect-rpinitiated-1_0.html),
+ * so that the user is also logged out of the EHR. The EHR redirects the browser to
+ * the configured post-logout URL afterwards, if there is one.
+ *
+ * Otherwise, we redirect the browser to the configured post-logout URL, or, if
+ * none is configured, simply confirm that the user has been logged out.
+ */
+#[get("/logout/{patient_id}")]
+pub async fn logout(data: web::Data<State>, path: web::Path<String>) -> HttpResponse {
+    let patient_id = path.into_inner();
+
+    match data.remove_token(&patient_id) {
+        Some(client) => {
+            if let Some(revocation) = &client.logout.revocation {
+                match revocation.revoke(&data.reqwest_client).await {
+                    Ok(()) => debug!("Revoked token for patient {patient_id}"),
+                    Err(e) => warn!("Failed to revoke token for patient {patient_id}: {e}"),
+                }
+            }
+
+            match end_session_url(&data, &client.logout) {
+                Some(Ok(end_session_url)) => redirect(end_session_url.as_str()),
+                Some(Err(e)) => {
+                    error!(
+                        "Failed to parse end-session endpoint for issuer {} due to error {e}",
+                        client.context.iss
+                    );
+                    post_logout_response(&data)
+                }
+                None => post_logout_response(&data),
+            }
+        }
+        None => {
+            debug!("Logout requested for patient {patient_id}, who has no session");
+            post_logout_response(&data)
+        }
+    }
+}
+
+// Builds the URL of the EHR's end-session endpoint, for RP-initiated logout.
+//
+// Returns an empty option if the EHR does not provide an end-session endpoint,
+// or if we do not hold an id_token to pass as `id_token_hint`.
+//
+// # Arguments
+// * `data` The application state.
+// * `session` What we need to end the session with the EHR.
+fn end_session_url(data: &State, session: &Logout) -> Option<Result<Url, url::ParseError>> {
+    let endpoint = session.end_session_endpoint.as_ref()?;
+    let id_token = session.id_token.as_ref()?;
+
+    Some(Url::parse(endpoint).map(|mut url| {
+        {
+            let mut query = url.query_pairs_mut();
+            query
+                .append_pair("id_token_hint", id_token)
+                .append_pair("client_id", &session.client_id);
+            if let Some(post_logout_url) = &data.post_logout_url {
+                query.append_pair("post_logout_redirect_uri", post_logout_url);
+            }
+        }
+        url
+    }))
+}
+
+// Responds to a logout that did not end the session at the EHR.
+//
+// Redirects to the post-logout URL if one is configured.
+//
+// # Arguments
+// * `data` The application state.
+fn post_logout_response(data: &State) -> HttpResponse {
+    match &data.post_logout_url {
+        Some(post_logout_url) => redirect(post_logout_url),
+        None => HttpResponse::Ok().body("You have been logged out."),
+    }
+}
+
+// Redirects the browser with a "303 See Other" response.
+//
+// # Arguments
+// * `location` The URL to redirect to.
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((actix_web::http::header::LOCATION, location))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::header::LOCATION;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::json;
+    use wiremock::MockServer;
+
+    use crate::smart::token::Token;
+    use crate::test_support::{id_token, put_session, smart_configuration, test_state};
+
+    // Logs out of a patient's session, returning the response.
+    //
+    // # Arguments
+    // * `state` The application state.
+    async fn get_logout(state: State) -> actix_web::dev::ServiceResponse {
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(logout)).await;
+        let req = test::TestRequest::get().uri("/logout/123").to_request();
+        test::call_service(&app, req).await
+    }
+
+    #[actix_web::test]
+    async fn logout_redirects_to_post_logout_url() {
+        let ehr = MockServer::start().await;
+        let state = test_state()
+            .with_post_logout_url(Some(String::from("https://app.example.com/goodbye")));
+        put_session(&state, &ehr, "123", &["patient/*.read"]).await;
+        let state = web::Data::new(state);
+
+        let app = test::init_service(App::new().app_data(state.clone()).service(logout)).await;
+        let req = test::TestRequest::get().uri("/logout/123").to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            "https://app.example.com/goodbye"
+        );
+        assert!(state.get_token("123").is_none());
+    }
+
+    #[actix_web::test]
+    async fn logout_redirects_to_end_session_endpoint() {
+        let ehr = MockServer::start().await;
+        let mut config = smart_configuration(&ehr.uri());
+        config["end_session_endpoint"] = json!(format!("{}/logout", ehr.uri()));
+        let id_token = id_token(json!({ "sub": "u1" }));
+        let token = Token::for_test(&ehr.uri(), Some("123"), &["openid", "patient/*.read"])
+            .with_smart_configuration(serde_json::from_value(config).unwrap())
+            .with_id_token(&id_token);
+        let state = test_state()
+            .with_post_logout_url(Some(String::from("https://app.example.com/goodbye")));
+        state.put_token(token).await.unwrap();
+
+        let resp = get_logout(state).await;
+
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let location = Url::parse(resp.headers().get(LOCATION).unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(
+            location.as_str().split('?').next(),
+            Some(format!("{}/logout", ehr.uri()).as_str())
+        );
+        let query: Vec<(String, String)> = location.query_pairs().into_owned().collect();
+        assert!(query.contains(&(String::from("id_token_hint"), id_token)));
+        assert!(query.contains(&(String::from("client_id"), String::from("test-client"))));
+        assert!(query.contains(&(
+            String::from("post_logout_redirect_uri"),
+            String::from("https://app.example.com/goodbye")
+        )));
+    }
+
+    #[actix_web::test]
+    async fn logout_without_session_confirms_logout() {
+        let resp = get_logout(test_state()).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,108 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fhir_sdk::r4b::types::Coding;
+
+use std::fmt;
+
+// The code system URL for LOINC codes.
+pub const LOINC_SYSTEM: &str = "http://loinc.org";
+
+// A [LOINC](https://loinc.org/) code.
+//
+// FHIR APIs expect LOINC codes in two forms: search parameters take a token
+// qualified with the code system (e.g., "http://loinc.org|8462-4"), while codings
+// carry the bare code (e.g., "8462-4") and the system separately. This type stores
+// the bare code, and renders each form on request.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LoincCode(String);
+
+impl LoincCode {
+    // Creates a LOINC code from a bare code, e.g. "8462-4".
+    pub fn bare(code: &str) -> LoincCode {
+        LoincCode(code.to_string())
+    }
+
+    // Creates a LOINC code from a token qualified with the LOINC system, e.g.
+    // "http://loinc.org|8462-4".
+    //
+    // Returns an empty option if the token is for a different code system.
+    pub fn with_system(token: &str) -> Option<LoincCode> {
+        match token.split_once('|') {
+            Some((LOINC_SYSTEM, code)) => Some(LoincCode::bare(code)),
+            _ => None,
+        }
+    }
+
+    // Gets the bare code, e.g. "8462-4".
+    pub fn code(&self) -> &str {
+        &self.0
+    }
+
+    // Gets the code qualified with the LOINC system, e.g. "http://loinc.org|8462-4",
+    // as used in search parameters.
+    pub fn token(&self) -> String {
+        format!("{LOINC_SYSTEM}|{}", self.0)
+    }
+
+    // Checks whether a coding carries this code.
+    //
+    // Codings that do not specify a system are assumed to be LOINC codings.
+    //
+    // # Arguments
+    // * `coding` The coding to check.
+    pub fn matches(&self, coding: &Coding) -> bool {
+        coding.code.as_deref() == Some(self.code())
+            && coding
+                .system
+                .as_deref()
+                .is_none_or(|system| system == LOINC_SYSTEM)
+    }
+}
+
+impl fmt::Display for LoincCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LOINC {}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_code_renders_both_forms() {
+        let code = LoincCode::bare("8462-4");
+
+        assert_eq!(code.code(), "8462-4");
+        assert_eq!(code.token(), "http://loinc.org|8462-4");
+    }
+
+    #[test]
+    fn system_qualified_code_renders_both_forms() {
+        let code = LoincCode::with_system("http://loinc.org|8462-4").unwrap();
+
+        assert_eq!(code, LoincCode::bare("8462-4"));
+        assert_eq!(code.code(), "8462-4");
+        assert_eq!(code.token(), "http://loinc.org|8462-4");
+    }
+
+    #[test]
+    fn code_from_other_system_is_rejected() {
+        assert!(LoincCode::with_system("http://snomed.info/sct|271649006").is_none());
+        assert!(LoincCode::with_system("8462-4").is_none());
+    }
+}
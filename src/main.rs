@@ -14,17 +14,44 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use actix_files as fs;
-use actix_web::middleware::Logger;
+use actix_web::middleware::{from_fn, ErrorHandlers, Logger};
 use actix_web::{web::Data, App, HttpServer};
 
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono_tz::Tz;
+use log::{info, warn};
+use url::Url;
+
+use std::collections::HashMap;
 use std::env;
+use std::fs::read_to_string;
+use std::time::Duration;
 
+use rust_smart_fhir::admin::{admin, invalidate_config};
+use rust_smart_fhir::allowlist::IssuerAllowlist;
 use rust_smart_fhir::callback::callback;
-use rust_smart_fhir::health::check;
-use rust_smart_fhir::index::index;
-use rust_smart_fhir::launch::launch;
+use rust_smart_fhir::codes::codes_json;
+use rust_smart_fhir::context_cookie::ContextCookieCipher;
+use rust_smart_fhir::dashboard::dashboard;
+use rust_smart_fhir::diagnostic_report_detail::diagnostic_report_detail;
+use rust_smart_fhir::error::{server_error_response, ErrorPageText};
+use rust_smart_fhir::health::{check, health, healthz, livez};
+use rust_smart_fhir::http::HttpClientConfig;
+use rust_smart_fhir::index::{index, summary};
+use rust_smart_fhir::launch::{launch, launch_post, LaunchMode, ScopeVersion};
+use rust_smart_fhir::logging::LogFormat;
+use rust_smart_fhir::logout::logout;
+use rust_smart_fhir::metrics::metrics;
+use rust_smart_fhir::observation_detail::observation_detail;
+use rust_smart_fhir::patient::patient_json;
+use rust_smart_fhir::pkce::VerifierCipher;
+use rust_smart_fhir::proxy::proxy;
+use rust_smart_fhir::request_id::request_id;
+use rust_smart_fhir::smart::client_auth::{
+    ClientAuthMethod, ClientType, DEFAULT_CLIENT_AUTH_METHODS,
+};
 use rust_smart_fhir::state::State;
+use rust_smart_fhir::static_files::StaticFilesConfig;
 
 fn hostname() -> String {
     let default_hostname = String::from("127.0.0.1");
@@ -84,29 +111,1022 @@ fn client_secret() -> String {
     }
 }
 
+fn http_client_config() -> HttpClientConfig {
+    let default_config = HttpClientConfig::default();
+
+    let pool_max_idle_per_host = match env::var_os("FHIR_EXAMPLE_POOL_MAX_IDLE_PER_HOST") {
+        Some(max_idle_ostr) => match max_idle_ostr.into_string() {
+            Ok(max_idle_str) => max_idle_str
+                .parse::<usize>()
+                .unwrap_or(default_config.pool_max_idle_per_host),
+            Err(_) => default_config.pool_max_idle_per_host,
+        },
+        None => default_config.pool_max_idle_per_host,
+    };
+
+    let pool_idle_timeout = match env::var_os("FHIR_EXAMPLE_POOL_IDLE_TIMEOUT_SECS") {
+        Some(timeout_ostr) => match timeout_ostr.into_string() {
+            Ok(timeout_str) => timeout_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(default_config.pool_idle_timeout),
+            Err(_) => default_config.pool_idle_timeout,
+        },
+        None => default_config.pool_idle_timeout,
+    };
+
+    HttpClientConfig {
+        pool_max_idle_per_host,
+        pool_idle_timeout,
+    }
+}
+
+fn relaunch_url() -> String {
+    let default_relaunch_url = String::from("https://launch.smarthealthit.org/");
+
+    match env::var_os("FHIR_EXAMPLE_RELAUNCH_URL") {
+        Some(relaunch_ostr) => match relaunch_ostr.into_string() {
+            Ok(relaunch_str) => relaunch_str,
+            Err(_) => default_relaunch_url,
+        },
+        None => default_relaunch_url,
+    }
+}
+
+fn smart_configuration_max_age() -> Duration {
+    match env::var_os("FHIR_EXAMPLE_SMART_CONFIGURATION_MAX_AGE_SECS") {
+        Some(max_age_ostr) => match max_age_ostr.into_string() {
+            Ok(max_age_str) => max_age_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(Duration::ZERO),
+            Err(_) => Duration::ZERO,
+        },
+        None => Duration::ZERO,
+    }
+}
+
+fn style_max_age() -> Duration {
+    let style_max_age = Duration::from_secs(60 * 60);
+
+    match env::var_os("FHIR_EXAMPLE_STYLE_MAX_AGE_SECS") {
+        Some(max_age_ostr) => match max_age_ostr.into_string() {
+            Ok(max_age_str) => max_age_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(style_max_age),
+            Err(_) => style_max_age,
+        },
+        None => style_max_age,
+    }
+}
+
+fn jwks_max_key_sets() -> usize {
+    let max_key_sets = 64;
+
+    match env::var_os("FHIR_EXAMPLE_JWKS_MAX_KEY_SETS") {
+        Some(max_ostr) => match max_ostr.into_string() {
+            Ok(max_str) => max_str.parse::<usize>().unwrap_or(max_key_sets),
+            Err(_) => max_key_sets,
+        },
+        None => max_key_sets,
+    }
+}
+
+fn jwks_max_age() -> Duration {
+    let jwks_max_age = Duration::from_secs(60 * 60);
+
+    match env::var_os("FHIR_EXAMPLE_JWKS_MAX_AGE_SECS") {
+        Some(max_age_ostr) => match max_age_ostr.into_string() {
+            Ok(max_age_str) => max_age_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(jwks_max_age),
+            Err(_) => jwks_max_age,
+        },
+        None => jwks_max_age,
+    }
+}
+
+fn admin_key() -> Option<String> {
+    match env::var_os("FHIR_EXAMPLE_ADMIN_KEY") {
+        // an empty key would be too easy to guess, so we treat it as unset
+        Some(admin_key_ostr) => admin_key_ostr
+            .into_string()
+            .ok()
+            .filter(|key| !key.is_empty()),
+        None => None,
+    }
+}
+
+fn post_logout_url() -> Option<String> {
+    match env::var_os("FHIR_EXAMPLE_POST_LOGOUT_URL") {
+        Some(post_logout_ostr) => post_logout_ostr.into_string().ok(),
+        None => None,
+    }
+}
+
+fn log_format() -> std::io::Result<LogFormat> {
+    match env::var_os("FHIR_EXAMPLE_LOG_FORMAT") {
+        Some(format_ostr) => match format_ostr.into_string() {
+            Ok(format_str) => LogFormat::parse(format_str.trim()).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid log format: {format_str}"),
+                )
+            }),
+            Err(_) => Ok(LogFormat::Text),
+        },
+        None => Ok(LogFormat::Text),
+    }
+}
+
+fn client_type() -> std::io::Result<ClientType> {
+    match env::var_os("FHIR_EXAMPLE_CLIENT_TYPE") {
+        Some(type_ostr) => match type_ostr.into_string() {
+            Ok(type_str) => ClientType::parse(type_str.trim()).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid client type: {type_str}"),
+                )
+            }),
+            Err(_) => Ok(ClientType::Confidential),
+        },
+        None => Ok(ClientType::Confidential),
+    }
+}
+
+fn launch_mode() -> std::io::Result<LaunchMode> {
+    match env::var_os("FHIR_EXAMPLE_LAUNCH_MODE") {
+        Some(mode_ostr) => match mode_ostr.into_string() {
+            Ok(mode_str) => LaunchMode::parse(mode_str.trim()).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid launch mode: {mode_str}"),
+                )
+            }),
+            Err(_) => Ok(LaunchMode::Patient),
+        },
+        None => Ok(LaunchMode::Patient),
+    }
+}
+
+fn scope_version() -> std::io::Result<ScopeVersion> {
+    match env::var_os("FHIR_EXAMPLE_SCOPE_VERSION") {
+        Some(version_ostr) => match version_ostr.into_string() {
+            Ok(version_str) => ScopeVersion::parse(version_str.trim()).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid scope version: {version_str}"),
+                )
+            }),
+            Err(_) => Ok(ScopeVersion::V1),
+        },
+        None => Ok(ScopeVersion::V1),
+    }
+}
+
+fn client_auth_methods() -> std::io::Result<Vec<ClientAuthMethod>> {
+    match env::var_os("FHIR_EXAMPLE_CLIENT_AUTH_METHODS") {
+        Some(methods_ostr) => match methods_ostr.into_string() {
+            Ok(methods_str) => methods_str
+                .split(',')
+                .map(str::trim)
+                .map(|method| {
+                    ClientAuthMethod::parse(method).ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Invalid client authentication method: {method}"),
+                        )
+                    })
+                })
+                .collect(),
+            Err(_) => Ok(DEFAULT_CLIENT_AUTH_METHODS.to_vec()),
+        },
+        None => Ok(DEFAULT_CLIENT_AUTH_METHODS.to_vec()),
+    }
+}
+
+fn idle_timeout() -> Duration {
+    let idle_timeout = Duration::from_secs(30 * 60);
+
+    match env::var_os("FHIR_EXAMPLE_IDLE_TIMEOUT_SECS") {
+        Some(timeout_ostr) => match timeout_ostr.into_string() {
+            Ok(timeout_str) => timeout_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(idle_timeout),
+            Err(_) => idle_timeout,
+        },
+        None => idle_timeout,
+    }
+}
+
+fn launch_timeout() -> Duration {
+    let launch_timeout = Duration::from_secs(10 * 60);
+
+    match env::var_os("FHIR_EXAMPLE_LAUNCH_TIMEOUT_SECS") {
+        Some(timeout_ostr) => match timeout_ostr.into_string() {
+            Ok(timeout_str) => timeout_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(launch_timeout),
+            Err(_) => launch_timeout,
+        },
+        None => launch_timeout,
+    }
+}
+
+fn discovery_timeout() -> Duration {
+    let discovery_timeout = Duration::from_secs(10);
+
+    match env::var_os("FHIR_EXAMPLE_DISCOVERY_TIMEOUT_SECS") {
+        Some(timeout_ostr) => match timeout_ostr.into_string() {
+            Ok(timeout_str) => timeout_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(discovery_timeout),
+            Err(_) => discovery_timeout,
+        },
+        None => discovery_timeout,
+    }
+}
+
+fn token_clock_skew() -> Duration {
+    match env::var_os("FHIR_EXAMPLE_TOKEN_CLOCK_SKEW_SECS") {
+        Some(skew_ostr) => match skew_ostr.into_string() {
+            Ok(skew_str) => skew_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(Duration::ZERO),
+            Err(_) => Duration::ZERO,
+        },
+        None => Duration::ZERO,
+    }
+}
+
+fn token_refresh_lead() -> Duration {
+    match env::var_os("FHIR_EXAMPLE_TOKEN_REFRESH_LEAD_SECS") {
+        Some(lead_ostr) => match lead_ostr.into_string() {
+            Ok(lead_str) => lead_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(Duration::ZERO),
+            Err(_) => Duration::ZERO,
+        },
+        None => Duration::ZERO,
+    }
+}
+
+fn token_timeout() -> Duration {
+    let token_timeout = Duration::from_secs(30);
+
+    match env::var_os("FHIR_EXAMPLE_TOKEN_TIMEOUT_SECS") {
+        Some(timeout_ostr) => match timeout_ostr.into_string() {
+            Ok(timeout_str) => timeout_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(token_timeout),
+            Err(_) => token_timeout,
+        },
+        None => token_timeout,
+    }
+}
+
+fn resource_timeout() -> Duration {
+    let resource_timeout = Duration::from_secs(60);
+
+    match env::var_os("FHIR_EXAMPLE_RESOURCE_TIMEOUT_SECS") {
+        Some(timeout_ostr) => match timeout_ostr.into_string() {
+            Ok(timeout_str) => timeout_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(resource_timeout),
+            Err(_) => resource_timeout,
+        },
+        None => resource_timeout,
+    }
+}
+
+fn summary_timeout() -> Duration {
+    let summary_timeout = Duration::from_secs(15);
+
+    match env::var_os("FHIR_EXAMPLE_SUMMARY_TIMEOUT_SECS") {
+        Some(timeout_ostr) => match timeout_ostr.into_string() {
+            Ok(timeout_str) => timeout_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(summary_timeout),
+            Err(_) => summary_timeout,
+        },
+        None => summary_timeout,
+    }
+}
+
+fn timezone() -> std::io::Result<Tz> {
+    match env::var_os("FHIR_EXAMPLE_TIMEZONE") {
+        Some(timezone_ostr) => match timezone_ostr.into_string() {
+            Ok(timezone_str) => timezone_str.parse::<Tz>().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid timezone {timezone_str}: {e}"),
+                )
+            }),
+            Err(_) => Ok(Tz::UTC),
+        },
+        None => Ok(Tz::UTC),
+    }
+}
+
+fn search_limit() -> usize {
+    let search_limit = 1000;
+
+    match env::var_os("FHIR_EXAMPLE_SEARCH_LIMIT") {
+        Some(limit_ostr) => match limit_ostr.into_string() {
+            Ok(limit_str) => limit_str.parse::<usize>().unwrap_or(search_limit),
+            Err(_) => search_limit,
+        },
+        None => search_limit,
+    }
+}
+
+fn max_concurrent_requests() -> Option<usize> {
+    match env::var_os("FHIR_EXAMPLE_MAX_CONCURRENT_REQUESTS") {
+        Some(max_ostr) => match max_ostr.into_string() {
+            // a limit of zero would block all requests, so we treat it as unlimited
+            Ok(max_str) => max_str.parse::<usize>().ok().filter(|max| *max > 0),
+            Err(_) => None,
+        },
+        None => None,
+    }
+}
+
+fn proxy_resource_types() -> Vec<String> {
+    match env::var_os("FHIR_EXAMPLE_PROXY_RESOURCE_TYPES") {
+        Some(types_ostr) => match types_ostr.into_string() {
+            Ok(types_str) => types_str
+                .split(',')
+                .map(str::trim)
+                .filter(|resource_type| !resource_type.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(_) => Vec::new(),
+        },
+        None => Vec::new(),
+    }
+}
+
+fn state_shards() -> Option<usize> {
+    match env::var_os("FHIR_EXAMPLE_STATE_SHARDS") {
+        Some(shards_ostr) => match shards_ostr.into_string() {
+            Ok(shards_str) => match shards_str.parse::<usize>() {
+                Ok(shards) if shards > 1 && shards.is_power_of_two() => Some(shards),
+                _ => {
+                    warn!("Ignoring FHIR_EXAMPLE_STATE_SHARDS={shards_str}, as it is not a power of two greater than one; using the default number of shards");
+                    None
+                }
+            },
+            Err(_) => None,
+        },
+        None => None,
+    }
+}
+
+fn max_sessions() -> Option<usize> {
+    match env::var_os("FHIR_EXAMPLE_MAX_SESSIONS") {
+        Some(max_ostr) => match max_ostr.into_string() {
+            // a limit of zero would drop every session as it is stored, so we treat it
+            // as unlimited
+            Ok(max_str) => max_str.parse::<usize>().ok().filter(|max| *max > 0),
+            Err(_) => None,
+        },
+        None => None,
+    }
+}
+
+fn auth_failure_threshold() -> u32 {
+    let auth_failure_threshold = 3;
+
+    match env::var_os("FHIR_EXAMPLE_AUTH_FAILURE_THRESHOLD") {
+        Some(threshold_ostr) => match threshold_ostr.into_string() {
+            Ok(threshold_str) => threshold_str
+                .parse::<u32>()
+                .unwrap_or(auth_failure_threshold),
+            Err(_) => auth_failure_threshold,
+        },
+        None => auth_failure_threshold,
+    }
+}
+
+fn auth_failure_cooldown() -> Duration {
+    let auth_failure_cooldown = Duration::from_secs(60);
+
+    match env::var_os("FHIR_EXAMPLE_AUTH_FAILURE_COOLDOWN_SECS") {
+        Some(cooldown_ostr) => match cooldown_ostr.into_string() {
+            Ok(cooldown_str) => cooldown_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(auth_failure_cooldown),
+            Err(_) => auth_failure_cooldown,
+        },
+        None => auth_failure_cooldown,
+    }
+}
+
+fn strict_schemes() -> bool {
+    match env::var_os("FHIR_EXAMPLE_STRICT_SCHEMES") {
+        Some(strict_ostr) => match strict_ostr.into_string() {
+            Ok(strict_str) => strict_str.parse::<bool>().unwrap_or(false),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+fn trim_iss_trailing_slash() -> bool {
+    match env::var_os("FHIR_EXAMPLE_TRIM_ISS_TRAILING_SLASH") {
+        Some(trim_ostr) => match trim_ostr.into_string() {
+            Ok(trim_str) => trim_str.parse::<bool>().unwrap_or(true),
+            Err(_) => true,
+        },
+        None => true,
+    }
+}
+
+fn strict_smart_configuration() -> bool {
+    match env::var_os("FHIR_EXAMPLE_STRICT_SMART_CONFIGURATION") {
+        Some(strict_ostr) => match strict_ostr.into_string() {
+            Ok(strict_str) => strict_str.parse::<bool>().unwrap_or(false),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+fn host_root_discovery() -> bool {
+    match env::var_os("FHIR_EXAMPLE_HOST_ROOT_DISCOVERY") {
+        Some(discovery_ostr) => match discovery_ostr.into_string() {
+            Ok(discovery_str) => discovery_str.parse::<bool>().unwrap_or(false),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+fn allow_unsupported_token_types() -> bool {
+    match env::var_os("FHIR_EXAMPLE_ALLOW_UNSUPPORTED_TOKEN_TYPES") {
+        Some(allow_ostr) => match allow_ostr.into_string() {
+            Ok(allow_str) => allow_str.parse::<bool>().unwrap_or(false),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+fn allow_ehr_framing() -> bool {
+    match env::var_os("FHIR_EXAMPLE_ALLOW_EHR_FRAMING") {
+        Some(allow_ostr) => match allow_ostr.into_string() {
+            Ok(allow_str) => allow_str.parse::<bool>().unwrap_or(true),
+            Err(_) => true,
+        },
+        None => true,
+    }
+}
+
+fn show_granted_scopes() -> bool {
+    match env::var_os("FHIR_EXAMPLE_SHOW_GRANTED_SCOPES") {
+        Some(show_ostr) => match show_ostr.into_string() {
+            Ok(show_str) => show_str.parse::<bool>().unwrap_or(false),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+fn pretty_json() -> bool {
+    match env::var_os("FHIR_EXAMPLE_PRETTY_JSON") {
+        Some(pretty_ostr) => match pretty_ostr.into_string() {
+            Ok(pretty_str) => pretty_str.parse::<bool>().unwrap_or(false),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+fn check_search_support() -> bool {
+    match env::var_os("FHIR_EXAMPLE_CHECK_SEARCH_SUPPORT") {
+        Some(check_ostr) => match check_ostr.into_string() {
+            Ok(check_str) => check_str.parse::<bool>().unwrap_or(false),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+fn dev_mode() -> bool {
+    match env::var_os("FHIR_EXAMPLE_DEV_MODE") {
+        Some(dev_ostr) => match dev_ostr.into_string() {
+            Ok(dev_str) => dev_str.parse::<bool>().unwrap_or(false),
+            Err(_) => false,
+        },
+        None => false,
+    }
+}
+
+fn static_files_config() -> StaticFilesConfig {
+    let default_config = StaticFilesConfig::default();
+
+    let max_age = match env::var_os("FHIR_EXAMPLE_STATIC_MAX_AGE_SECS") {
+        Some(max_age_ostr) => match max_age_ostr.into_string() {
+            Ok(max_age_str) => max_age_str
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .unwrap_or(default_config.max_age),
+            Err(_) => default_config.max_age,
+        },
+        None => default_config.max_age,
+    };
+
+    let favicon_path = match env::var_os("FHIR_EXAMPLE_FAVICON_PATH") {
+        Some(favicon_ostr) => favicon_ostr
+            .into_string()
+            .unwrap_or(default_config.favicon_path.clone()),
+        None => default_config.favicon_path.clone(),
+    };
+
+    StaticFilesConfig {
+        max_age,
+        // directory listings are useful while developing, but should not be
+        // exposed in production
+        show_files_listing: dev_mode(),
+        favicon_path,
+    }
+}
+
+fn verifier_cipher() -> std::io::Result<Option<VerifierCipher>> {
+    match env::var_os("FHIR_EXAMPLE_PKCE_ENCRYPTION_KEY") {
+        Some(key_ostr) => match key_ostr.into_string() {
+            Ok(key_str) => BASE64_STANDARD
+                .decode(key_str.trim())
+                .map_err(|e| e.to_string())
+                .and_then(|key| VerifierCipher::new(&key))
+                .map(Some)
+                .map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid PKCE encryption key: {e}"),
+                    )
+                }),
+            Err(_) => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+fn context_cookie_cipher() -> std::io::Result<Option<ContextCookieCipher>> {
+    match env::var_os("FHIR_EXAMPLE_CONTEXT_COOKIE_KEY") {
+        Some(key_ostr) => match key_ostr.into_string() {
+            Ok(key_str) => BASE64_STANDARD
+                .decode(key_str.trim())
+                .map_err(|e| e.to_string())
+                .and_then(|key| ContextCookieCipher::new(&key))
+                .map(Some)
+                .map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid context cookie key: {e}"),
+                    )
+                }),
+            Err(_) => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+fn issuer_credentials() -> std::io::Result<HashMap<String, (String, String)>> {
+    // each line of the file holds an issuer host, client ID, and client secret,
+    // separated by whitespace
+    let contents = match env::var_os("FHIR_EXAMPLE_ISSUER_CREDENTIALS_FILE") {
+        Some(path) => read_to_string(path)?,
+        None => String::new(),
+    };
+
+    let mut credentials = HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [host, client_id, client_secret] => {
+                credentials.insert(
+                    host.to_string(),
+                    (client_id.to_string(), client_secret.to_string()),
+                );
+            }
+            _ => {
+                // do not echo the line, as it may contain a secret
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Invalid issuer credentials entry: expected host, client ID, and client secret",
+                ));
+            }
+        }
+    }
+
+    Ok(credentials)
+}
+
+fn issuer_scopes() -> std::io::Result<HashMap<String, Vec<String>>> {
+    // each line of the file holds an issuer host, followed by the scopes to request
+    // from it, separated by whitespace
+    let contents = match env::var_os("FHIR_EXAMPLE_ISSUER_SCOPES_FILE") {
+        Some(path) => read_to_string(path)?,
+        None => String::new(),
+    };
+
+    let mut scopes = HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [host, ref host_scopes @ ..] if !host_scopes.is_empty() => {
+                scopes.insert(
+                    host.to_string(),
+                    host_scopes.iter().map(|scope| scope.to_string()).collect(),
+                );
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid issuer scopes entry: {line}"),
+                ));
+            }
+        }
+    }
+
+    Ok(scopes)
+}
+
+fn issuer_redirect_uris() -> std::io::Result<HashMap<String, String>> {
+    // each line of the file holds an issuer host and the redirect URI registered with
+    // it, separated by whitespace
+    let contents = match env::var_os("FHIR_EXAMPLE_ISSUER_REDIRECT_URIS_FILE") {
+        Some(path) => read_to_string(path)?,
+        None => String::new(),
+    };
+
+    let mut redirect_uris = HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [host, redirect_uri] if Url::parse(redirect_uri).is_ok() => {
+                redirect_uris.insert(host.to_string(), redirect_uri.to_string());
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid issuer redirect URI entry: {line}"),
+                ));
+            }
+        }
+    }
+
+    Ok(redirect_uris)
+}
+
+fn issuer_fhir_versions() -> std::io::Result<HashMap<String, String>> {
+    // each line of the file holds an issuer host and the FHIR version path segment to
+    // append to its base URL, separated by whitespace
+    let contents = match env::var_os("FHIR_EXAMPLE_ISSUER_FHIR_VERSIONS_FILE") {
+        Some(path) => read_to_string(path)?,
+        None => String::new(),
+    };
+
+    let mut versions = HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [host, version]
+                if !version.trim_matches('/').is_empty()
+                    && !version.trim_matches('/').contains('/') =>
+            {
+                versions.insert(host.to_string(), version.to_string());
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid issuer FHIR version entry: {line}"),
+                ));
+            }
+        }
+    }
+
+    Ok(versions)
+}
+
+fn issuer_names() -> std::io::Result<HashMap<String, String>> {
+    // each line of the file holds an issuer URL, followed by the name to display for
+    // it, separated by whitespace
+    let contents = match env::var_os("FHIR_EXAMPLE_ISSUER_NAMES_FILE") {
+        Some(path) => read_to_string(path)?,
+        None => String::new(),
+    };
+
+    let mut names = HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_once(char::is_whitespace) {
+            Some((iss, name)) if !name.trim().is_empty() => {
+                names.insert(iss.to_string(), name.trim().to_string());
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid issuer name entry: {line}"),
+                ));
+            }
+        }
+    }
+
+    Ok(names)
+}
+
+fn observation_codes() -> std::io::Result<HashMap<String, Vec<String>>> {
+    // each line of the file holds a measurement name (e.g., "height"), followed by a
+    // code qualified with its code system (e.g., "http://snomed.info/sct|50373000"),
+    // separated by whitespace
+    let contents = match env::var_os("FHIR_EXAMPLE_OBSERVATION_CODES_FILE") {
+        Some(path) => read_to_string(path)?,
+        None => String::new(),
+    };
+
+    let mut codes: HashMap<String, Vec<String>> = HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [name, code] if code.contains('|') => {
+                codes
+                    .entry(name.to_string())
+                    .or_default()
+                    .push(code.to_string());
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid observation code entry: {line}"),
+                ));
+            }
+        }
+    }
+
+    Ok(codes)
+}
+
+fn value_precision() -> std::io::Result<HashMap<String, usize>> {
+    // each line of the file holds a code qualified with its code system (e.g.,
+    // "http://loinc.org|8302-2"), followed by the number of decimal places to display
+    // its values with, separated by whitespace
+    let contents = match env::var_os("FHIR_EXAMPLE_VALUE_PRECISION_FILE") {
+        Some(path) => read_to_string(path)?,
+        None => String::new(),
+    };
+
+    let mut precision = HashMap::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [code, decimals] if code.contains('|') => match decimals.parse::<usize>() {
+                Ok(decimals) => {
+                    precision.insert(code.to_string(), decimals);
+                }
+                Err(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid value precision entry: {line}"),
+                    ));
+                }
+            },
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid value precision entry: {line}"),
+                ));
+            }
+        }
+    }
+
+    Ok(precision)
+}
+
+fn error_page_text() -> std::io::Result<ErrorPageText> {
+    // each line of the file holds the status code of an error page, followed by
+    // the message to show on it
+    let contents = match env::var_os("FHIR_EXAMPLE_ERROR_PAGES_FILE") {
+        Some(path) => read_to_string(path)?,
+        None => String::new(),
+    };
+
+    let mut text = ErrorPageText::default();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parsed = line
+            .split_once(char::is_whitespace)
+            .and_then(|(status, message)| Some((status.parse::<u16>().ok()?, message.trim())));
+        match parsed {
+            Some((status, message)) if text.set(status, message.to_string()) => {}
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid error page entry: {line}"),
+                ));
+            }
+        }
+    }
+
+    Ok(text)
+}
+
+fn iss_allowlist() -> std::io::Result<IssuerAllowlist> {
+    // entries can be provided inline as a comma separated list, or in a file
+    // with one entry per line
+    let mut entries = match env::var_os("FHIR_EXAMPLE_ISS_ALLOWLIST") {
+        Some(allowlist_ostr) => match allowlist_ostr.into_string() {
+            Ok(allowlist_str) => allowlist_str.replace(',', "\n"),
+            Err(_) => String::new(),
+        },
+        None => String::new(),
+    };
+
+    if let Some(path) = env::var_os("FHIR_EXAMPLE_ISS_ALLOWLIST_FILE") {
+        entries.push('\n');
+        entries.push_str(&read_to_string(path)?);
+    }
+
+    IssuerAllowlist::parse(entries.lines()).map_err(|entry| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid issuer allowlist entry: {entry}"),
+        )
+    })
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let hostname = hostname();
     let port = port();
     println!("Running on http://{}:{}", hostname, port);
 
-    let state = Data::new(State::new(domain(), client_id(), client_secret()));
+    log_format()?.init();
+
+    let iss_allowlist = iss_allowlist()?;
+    if iss_allowlist.is_empty() {
+        warn!("No issuer allowlist is configured: ALL issuers are allowed to launch this app. Set FHIR_EXAMPLE_ISS_ALLOWLIST or FHIR_EXAMPLE_ISS_ALLOWLIST_FILE to restrict issuers.");
+    }
 
-    env_logger::init_from_env(
-        env_logger::Env::new()
-            .default_filter_or("actix_web::middleware::logger=info,rust_fhir_example=error"),
+    let http_client_config = http_client_config();
+    info!(
+        "HTTP client pool: at most {} idle connections per host, idle timeout of {:?}",
+        http_client_config.pool_max_idle_per_host, http_client_config.pool_idle_timeout
     );
 
+    let mut state = State::new(domain(), client_id(), client_secret());
+    if let Some(cipher) = verifier_cipher()? {
+        info!("PKCE verifiers will be encrypted while stored");
+        state = state.with_verifier_cipher(cipher);
+    }
+    if let Some(cipher) = context_cookie_cipher()? {
+        info!("Launch contexts will be stored in encrypted cookies");
+        state = state.with_context_cookie_cipher(cipher);
+    }
+
+    let state = Data::new(
+        state
+            .with_http_client_config(&http_client_config)
+            .with_iss_allowlist(iss_allowlist)
+            .with_strict_schemes(strict_schemes())
+            .with_dev_mode(dev_mode())
+            .with_trim_iss_trailing_slash(trim_iss_trailing_slash())
+            .with_strict_smart_configuration(strict_smart_configuration())
+            .with_host_root_discovery(host_root_discovery())
+            .with_allow_unsupported_token_types(allow_unsupported_token_types())
+            .with_allow_ehr_framing(allow_ehr_framing())
+            .with_show_granted_scopes(show_granted_scopes())
+            .with_pretty_json(pretty_json())
+            .with_relaunch_url(relaunch_url())
+            .with_error_page_text(error_page_text()?)
+            .with_post_logout_url(post_logout_url())
+            .with_smart_configuration_max_age(smart_configuration_max_age())
+            .with_style_max_age(style_max_age())
+            .with_key_set_cache(jwks_max_key_sets(), jwks_max_age())
+            .with_admin_key(admin_key())
+            .with_idle_timeout(idle_timeout())
+            .with_launch_timeout(launch_timeout())
+            .with_discovery_timeout(discovery_timeout())
+            .with_token_timeout(token_timeout())
+            .with_token_clock_skew(token_clock_skew())
+            .with_token_refresh_lead(token_refresh_lead())
+            .with_resource_timeout(resource_timeout())
+            .with_summary_timeout(summary_timeout())
+            .with_timezone(timezone()?)
+            .with_search_limit(search_limit())
+            .with_max_concurrent_requests(max_concurrent_requests())
+            .with_max_sessions(max_sessions())
+            .with_state_shards(state_shards())
+            .with_auth_failure_threshold(auth_failure_threshold())
+            .with_auth_failure_cooldown(auth_failure_cooldown())
+            .with_client_auth_methods(client_auth_methods()?)
+            .with_credentials(issuer_credentials()?)
+            .with_issuer_names(issuer_names()?)
+            .with_issuer_scopes(issuer_scopes()?)
+            .with_issuer_redirect_uris(issuer_redirect_uris()?)
+            .with_issuer_fhir_versions(issuer_fhir_versions()?)
+            .with_proxy_resource_types(proxy_resource_types())
+            .with_observation_codes(observation_codes()?)
+            .with_value_precision(value_precision()?)
+            .with_client_type(client_type()?)
+            .with_launch_mode(launch_mode()?)
+            .with_scope_version(scope_version()?)
+            .with_check_search_support(check_search_support()),
+    );
+
+    // periodically drop idle sessions, so that their tokens are not kept in memory,
+    // and launches that were never completed
+    let sweep_state = state.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let evicted = sweep_state.evict_idle_sessions();
+            if evicted > 0 {
+                info!("Dropped {evicted} idle sessions");
+            }
+            let expired = sweep_state.evict_expired_launches();
+            if expired > 0 {
+                info!("Dropped {expired} expired launches");
+            }
+        }
+    });
+
+    let static_files_config = static_files_config();
+    if static_files_config.show_files_listing {
+        warn!("Running in dev mode: directory listings are enabled for static files.");
+    }
+
     HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
+            // the logger wraps the request ID middleware, so that it can log the
+            // request ID from the response headers
+            // server errors are sanitized inside the request ID middleware, so that
+            // their bodies can include the request ID
+            .wrap(ErrorHandlers::new().default_handler_server(server_error_response))
+            .wrap(from_fn(request_id))
+            .wrap(Logger::new(
+                r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{x-request-id}o"#,
+            ))
             .app_data(state.clone())
+            .service(admin)
+            .service(invalidate_config)
             .service(check)
+            .service(health)
+            .service(healthz)
+            .service(livez)
+            .service(metrics)
             .service(callback)
+            .service(dashboard)
             .service(index)
+            .service(summary)
+            .service(patient_json)
+            .service(codes_json)
+            .service(proxy)
+            .service(observation_detail)
+            .service(diagnostic_report_detail)
             .service(launch)
-            .service(fs::Files::new("/resources", "./resources").show_files_listing())
-            .service(fs::Files::new("/lib", "./lib").show_files_listing())
+            .service(launch_post)
+            .service(logout)
+            .service(static_files_config.favicon_service())
+            .service(static_files_config.service("/resources", "./resources"))
+            .service(static_files_config.service("/lib", "./lib"))
     })
     .bind((hostname, port))?
     .run()
@@ -0,0 +1,303 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fhir_sdk::client::Client as FhirClient;
+use fhir_sdk::client::FhirR4B;
+use fhir_sdk::r4b::resources::{
+    Medication, MedicationRequest, MedicationRequestMedication, Resource,
+};
+use fhir_sdk::r4b::types::Reference;
+use fhir_sdk::ParsedReference;
+use log::error;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::display::display_codeable_concept;
+use crate::limit::RequestLimiter;
+
+// Placeholder displayed when a medication cannot be resolved.
+pub const UNKNOWN_MEDICATION: &str = "Unknown medication";
+
+// A cache of resolved medication names, keyed by reference.
+//
+// The cache is shared between clones, so that all requests made with a
+// given `TokenClient` share a single cache.
+#[derive(Clone, Default)]
+pub struct MedicationCache(Arc<Mutex<HashMap<String, String>>>);
+
+impl MedicationCache {
+    fn get(&self, reference: &str) -> Option<String> {
+        let map = self.0.lock().unwrap();
+        map.get(reference).cloned()
+    }
+
+    fn put(&self, reference: &str, name: &str) {
+        let mut map = self.0.lock().unwrap();
+        map.insert(reference.to_string(), name.to_string());
+    }
+}
+
+// Resolves the medication that a MedicationRequest refers to.
+//
+// A [MedicationRequest](http://hl7.org/fhir/R4B/medicationrequest.html) identifies
+// the requested medication either inline, using a codeable concept, or with a
+// reference to a [Medication](http://hl7.org/fhir/R4B/medication.html) resource.
+// A referenced Medication may be contained in the MedicationRequest, or may need
+// to be read from the FHIR server.
+pub struct MedicationResolver<'a> {
+    client: &'a FhirClient<FhirR4B>,
+    limiter: &'a RequestLimiter,
+    cache: &'a MedicationCache,
+}
+
+impl<'a> MedicationResolver<'a> {
+    pub fn new(
+        client: &'a FhirClient<FhirR4B>,
+        limiter: &'a RequestLimiter,
+        cache: &'a MedicationCache,
+    ) -> MedicationResolver<'a> {
+        MedicationResolver {
+            client,
+            limiter,
+            cache,
+        }
+    }
+
+    // Gets the display name for the medication in a MedicationRequest.
+    //
+    // If the medication cannot be resolved, returns a placeholder.
+    //
+    // # Arguments
+    // * `request` The MedicationRequest to resolve the medication for.
+    pub async fn resolve(&self, request: &MedicationRequest) -> String {
+        let name = match &request.medication {
+            MedicationRequestMedication::CodeableConcept(concept) => {
+                display_codeable_concept(concept)
+            }
+            MedicationRequestMedication::Reference(reference) => {
+                self.resolve_reference(request, reference).await
+            }
+        };
+
+        name.unwrap_or_else(|| String::from(UNKNOWN_MEDICATION))
+    }
+
+    // Resolves a reference to a Medication resource.
+    //
+    // Checks the resources contained in the MedicationRequest first. Otherwise, if the
+    // reference is relative to the FHIR server, reads the Medication resource, caching
+    // the result. We do not follow absolute references, as they may point outside of the
+    // FHIR server that issued our token. If the reference cannot be resolved, falls back
+    // to the display text on the reference, if any.
+    //
+    // # Arguments
+    // * `request` The MedicationRequest that holds the reference.
+    // * `reference` The reference to resolve.
+    async fn resolve_reference(
+        &self,
+        request: &MedicationRequest,
+        reference: &Reference,
+    ) -> Option<String> {
+        let name = match reference.parse() {
+            Some(ParsedReference::Local { id }) => {
+                request
+                    .contained
+                    .iter()
+                    .find_map(|resource| match resource {
+                        Resource::Medication(medication)
+                            if medication.id.as_deref() == Some(id) =>
+                        {
+                            medication_name(medication)
+                        }
+                        _ => None,
+                    })
+            }
+            Some(ParsedReference::Relative {
+                resource_type: "Medication",
+                ..
+            }) => self.read_reference(reference).await,
+            _ => None,
+        };
+
+        name.or_else(|| reference.display.clone())
+    }
+
+    // Reads a Medication resource from the FHIR server, using the cache if possible.
+    //
+    // # Arguments
+    // * `reference` The relative reference to the Medication resource.
+    async fn read_reference(&self, reference: &Reference) -> Option<String> {
+        let key = reference.reference.as_deref()?;
+
+        if let Some(name) = self.cache.get(key) {
+            return Some(name);
+        }
+
+        match self
+            .limiter
+            .run_request(self.client.read_referenced(reference))
+            .await
+        {
+            Ok(Resource::Medication(medication)) => {
+                let name = medication_name(&medication);
+                if let Some(name) = &name {
+                    self.cache.put(key, name);
+                }
+                name
+            }
+            Ok(resource) => {
+                error!(
+                    "Medication reference {key} resolved to a {} resource",
+                    resource.resource_type()
+                );
+                None
+            }
+            Err(e) => {
+                error!(
+                    "Resolving medication reference {key} failed with error: {:?}",
+                    e
+                );
+                None
+            }
+        }
+    }
+}
+
+// Gets the display name for a Medication resource.
+fn medication_name(medication: &Medication) -> Option<String> {
+    medication.code.as_ref().and_then(display_codeable_concept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{json, Value};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::limit::AuthBreaker;
+    use crate::test_support::fhir_client;
+
+    use std::time::Duration;
+
+    // Builds a MedicationRequest for a medication reference.
+    //
+    // # Arguments
+    // * `reference` The reference to the medication.
+    // * `contained` The resources contained in the request.
+    fn medication_request(reference: &str, contained: Vec<Value>) -> MedicationRequest {
+        serde_json::from_value(json!({
+            "resourceType": "MedicationRequest",
+            "status": "active",
+            "intent": "order",
+            "subject": { "reference": "Patient/123" },
+            "medicationReference": { "reference": reference },
+            "contained": contained
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn resolves_contained_medication() {
+        let server = MockServer::start().await;
+        let client = fhir_client(&server);
+        let cache = MedicationCache::default();
+        let request = medication_request(
+            "#med",
+            vec![json!({
+                "resourceType": "Medication",
+                "id": "med",
+                "code": { "text": "Amoxicillin 250 mg" }
+            })],
+        );
+
+        let name = MedicationResolver::new(&client, &RequestLimiter::default(), &cache)
+            .resolve(&request)
+            .await;
+
+        assert_eq!(name, "Amoxicillin 250 mg");
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn reads_and_caches_referenced_medication() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Medication/m1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Medication",
+                "id": "m1",
+                "code": { "text": "Lisinopril 10 mg" }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = fhir_client(&server);
+        let cache = MedicationCache::default();
+        let limiter = RequestLimiter::default();
+        let resolver = MedicationResolver::new(&client, &limiter, &cache);
+        let request = medication_request("Medication/m1", Vec::new());
+
+        assert_eq!(resolver.resolve(&request).await, "Lisinopril 10 mg");
+        assert_eq!(resolver.resolve(&request).await, "Lisinopril 10 mg");
+    }
+
+    #[actix_web::test]
+    async fn unresolvable_medication_is_a_placeholder() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Medication/missing"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        let client = fhir_client(&server);
+        let cache = MedicationCache::default();
+        let limiter = RequestLimiter::default();
+        let resolver = MedicationResolver::new(&client, &limiter, &cache);
+
+        let missing = resolver
+            .resolve(&medication_request("Medication/missing", Vec::new()))
+            .await;
+        let absolute = resolver
+            .resolve(&medication_request(
+                "https://other.example.com/Medication/m1",
+                Vec::new(),
+            ))
+            .await;
+
+        assert_eq!(missing, UNKNOWN_MEDICATION);
+        assert_eq!(absolute, UNKNOWN_MEDICATION);
+    }
+
+    #[actix_web::test]
+    async fn medication_is_not_read_while_breaker_is_open() {
+        let server = MockServer::start().await;
+        let client = fhir_client(&server);
+        let cache = MedicationCache::default();
+        let breaker = AuthBreaker::new(1, Duration::from_secs(60));
+        breaker.record_rejection();
+        let limiter = RequestLimiter::new(None, breaker, Duration::from_secs(60));
+
+        let name = MedicationResolver::new(&client, &limiter, &cache)
+            .resolve(&medication_request("Medication/m1", Vec::new()))
+            .await;
+
+        assert_eq!(name, UNKNOWN_MEDICATION);
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,301 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::{get, web, HttpResponse};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use std::fmt::Write;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::state::State;
+
+// The upper bounds, in seconds, of the buckets of the connect time histogram.
+const CONNECT_TIME_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Debug, Default)]
+struct ConnectionCounters {
+    // The number of connections that failed to open.
+    failed: AtomicU64,
+
+    // The number of connections opened in each bucket of `CONNECT_TIME_BUCKETS`, plus
+    // a final bucket for connections slower than the largest bound.
+    buckets: [AtomicU64; CONNECT_TIME_BUCKETS.len() + 1],
+
+    // The total time spent opening connections, in microseconds.
+    total_micros: AtomicU64,
+}
+
+// Metrics about the connections that the HTTP client opens to EHR and FHIR servers.
+//
+// reqwest does not report whether a request reused a pooled connection, so we record
+// each new connection instead, along with how long it took to open (i.e., DNS
+// resolution, TCP connect, and the TLS handshake). If connections are being reused,
+// far fewer connections are opened than requests are sent.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionMetrics {
+    counters: Arc<ConnectionCounters>,
+}
+
+impl ConnectionMetrics {
+    // Records an attempt to open a connection.
+    //
+    // # Arguments
+    // * `elapsed` How long the attempt took.
+    // * `succeeded` Whether the connection was opened.
+    pub fn record_connect(&self, elapsed: Duration, succeeded: bool) {
+        if !succeeded {
+            self.counters.failed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        let bucket = CONNECT_TIME_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(CONNECT_TIME_BUCKETS.len());
+        self.counters.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .total_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // Gets the number of connections opened.
+    pub fn connections(&self) -> u64 {
+        self.counters
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    // Renders the metrics in the [Prometheus text format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        // writing to a string cannot fail
+        let _ = writeln!(
+            output,
+            "# HELP fhir_client_connect_seconds Time taken to open a new connection to an EHR or FHIR server."
+        );
+        let _ = writeln!(output, "# TYPE fhir_client_connect_seconds histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in CONNECT_TIME_BUCKETS.iter().zip(&self.counters.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                output,
+                "fhir_client_connect_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let connections = self.connections();
+        let _ = writeln!(
+            output,
+            "fhir_client_connect_seconds_bucket{{le=\"+Inf\"}} {connections}"
+        );
+        let total_seconds = self.counters.total_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(output, "fhir_client_connect_seconds_sum {total_seconds}");
+        let _ = writeln!(output, "fhir_client_connect_seconds_count {connections}");
+
+        let _ = writeln!(
+            output,
+            "# HELP fhir_client_connect_failures_total Connections to an EHR or FHIR server that failed to open."
+        );
+        let _ = writeln!(output, "# TYPE fhir_client_connect_failures_total counter");
+        let _ = writeln!(
+            output,
+            "fhir_client_connect_failures_total {}",
+            self.counters.failed.load(Ordering::Relaxed)
+        );
+
+        output
+    }
+}
+
+// A layer for the HTTP client's connector, which records connection metrics.
+//
+// The connector is only called when the client needs a new connection, so requests
+// sent over pooled connections are not recorded.
+#[derive(Clone)]
+pub struct ConnectionMetricsLayer {
+    metrics: ConnectionMetrics,
+}
+
+impl ConnectionMetricsLayer {
+    // Creates a layer that records into a set of metrics.
+    //
+    // # Arguments
+    // * `connection_metrics` The metrics to record into.
+    pub fn new(connection_metrics: &ConnectionMetrics) -> ConnectionMetricsLayer {
+        ConnectionMetricsLayer {
+            metrics: connection_metrics.clone(),
+        }
+    }
+}
+
+impl<S> Layer<S> for ConnectionMetricsLayer {
+    type Service = TimedConnector<S>;
+
+    fn layer(&self, inner: S) -> TimedConnector<S> {
+        TimedConnector {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+// A connector that times each connection it opens.
+#[derive(Clone)]
+pub struct TimedConnector<S> {
+    inner: S,
+    metrics: ConnectionMetrics,
+}
+
+impl<S, R> Service<R> for TimedConnector<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let started = Instant::now();
+        let connecting = self.inner.call(request);
+        let connection_metrics = self.metrics.clone();
+
+        Box::pin(async move {
+            let connection = connecting.await;
+            connection_metrics.record_connect(started.elapsed(), connection.is_ok());
+            connection
+        })
+    }
+}
+
+/**
+ * Metrics
+ * -------
+ * Exposes metrics about the app's connections to EHR and FHIR servers in the
+ * Prometheus text format, so that operators can check whether connection pooling
+ * is working: a histogram of how long new connections took to open, and a count of
+ * connections that failed to open.
+ */
+#[get("/metrics")]
+pub async fn metrics(data: web::Data<State>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(data.connection_metrics.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::http::HttpClientConfig;
+
+    // Sends requests to a mock server one after another, with a client that records
+    // its connections.
+    //
+    // Returns the number of connections that the client opened.
+    //
+    // # Arguments
+    // * `config` The settings of the client.
+    // * `count` The number of requests to send.
+    async fn connections_for_requests(config: HttpClientConfig, count: usize) -> u64 {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        let connection_metrics = ConnectionMetrics::default();
+        let client = config.build(&connection_metrics).unwrap();
+
+        for _ in 0..count {
+            let response = client.get(server.uri()).send().await.unwrap();
+            assert!(response.status().is_success());
+        }
+        connection_metrics.connections()
+    }
+
+    #[actix_web::test]
+    async fn connection_is_recorded_for_each_request_without_pooling() {
+        let config = HttpClientConfig {
+            pool_max_idle_per_host: 0,
+            ..HttpClientConfig::default()
+        };
+
+        assert_eq!(connections_for_requests(config, 3).await, 3);
+    }
+
+    #[actix_web::test]
+    async fn pooled_connection_is_recorded_once() {
+        assert_eq!(
+            connections_for_requests(HttpClientConfig::default(), 3).await,
+            1
+        );
+    }
+
+    #[actix_web::test]
+    async fn failed_connection_is_counted() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let connection_metrics = ConnectionMetrics::default();
+        let client = HttpClientConfig::default()
+            .build(&connection_metrics)
+            .unwrap();
+
+        assert!(client
+            .get(format!("http://{address}"))
+            .send()
+            .await
+            .is_err());
+
+        assert_eq!(connection_metrics.connections(), 0);
+        assert!(connection_metrics
+            .render()
+            .contains("fhir_client_connect_failures_total 1\n"));
+    }
+
+    #[test]
+    fn histogram_is_cumulative() {
+        let connection_metrics = ConnectionMetrics::default();
+        connection_metrics.record_connect(Duration::from_millis(3), true);
+        connection_metrics.record_connect(Duration::from_millis(200), true);
+        connection_metrics.record_connect(Duration::from_secs(10), true);
+
+        let output = connection_metrics.render();
+
+        assert!(output.contains("fhir_client_connect_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(output.contains("fhir_client_connect_seconds_bucket{le=\"0.25\"} 2\n"));
+        assert!(output.contains("fhir_client_connect_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(output.contains("fhir_client_connect_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(output.contains("fhir_client_connect_seconds_count 3\n"));
+    }
+}
@@ -0,0 +1,631 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fhir_sdk::r4b::resources::{
+    Observation, ObservationComponent, ObservationComponentValue, ObservationReferenceRange,
+    ObservationValue,
+};
+use fhir_sdk::r4b::types::{CodeableConcept, Quantity};
+
+use crate::loinc::{LoincCode, LOINC_SYSTEM};
+
+use std::collections::HashMap;
+
+// Common spellings of units, and the canonical form that we display them in.
+//
+// Spellings are matched case-insensitively, after removing whitespace.
+const CANONICAL_UNITS: [(&str, &str); 12] = [
+    ("mm[hg]", "mmHg"),
+    ("mmhg", "mmHg"),
+    ("millimeterofmercury", "mmHg"),
+    ("cm", "cm"),
+    ("centimeter", "cm"),
+    ("centimeters", "cm"),
+    ("kg", "kg"),
+    ("kilogram", "kg"),
+    ("kilograms", "kg"),
+    ("mg/dl", "mg/dL"),
+    ("mg/100ml", "mg/dL"),
+    ("milligramperdeciliter", "mg/dL"),
+];
+
+// Displayed after values whose unit we do not recognize.
+pub const UNKNOWN_UNIT_WARNING: &str = "(\u{26a0} unrecognized unit)";
+
+// Gets the canonical form of a unit, e.g. "mmHg" for "mm[Hg]" or "mm Hg".
+//
+// Units that we do not recognize are returned unchanged.
+//
+// # Arguments
+// * `raw` The unit, as provided by the FHIR server.
+pub fn canonical_unit(raw: &str) -> String {
+    let normalized: String = raw
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+
+    CANONICAL_UNITS
+        .iter()
+        .find(|(spelling, _)| *spelling == normalized)
+        .map(|(_, canonical)| canonical.to_string())
+        .unwrap_or_else(|| raw.to_string())
+}
+
+// [UCUM](https://ucum.org/) units that we expect to see in clinical observations.
+//
+// This is not the whole of UCUM, which allows arbitrary combinations of units, but
+// covers the vital signs and lab results that we display. Our canonical spellings
+// (see `CANONICAL_UNITS`) are included, as we check units after canonicalizing them.
+const KNOWN_UNITS: [&str; 48] = [
+    "1", "%", "m", "cm", "mm", "[in_i]", "[ft_i]", "kg", "g", "mg", "ug", "ng", "pg", "[lb_av]",
+    "[oz_av]", "L", "dL", "mL", "uL", "fL", "s", "min", "h", "d", "wk", "mo", "a", "/min", "/h",
+    "mL/min", "L/min", "mm[Hg]", "mmHg", "Cel", "[degF]", "kg/m2", "m2", "mg/dL", "g/dL", "g/L",
+    "mmol/L", "umol/L", "mol/L", "meq/L", "ng/mL", "pg/mL", "10*3/uL", "U/L",
+];
+
+// Checks whether a unit is a UCUM unit that we recognize.
+//
+// Annotations in curly braces (e.g., the "{beats}" in "{beats}/min") carry no meaning
+// in UCUM, so they are ignored.
+//
+// # Arguments
+// * `unit` The unit to check.
+pub fn is_known_unit(unit: &str) -> bool {
+    let mut stripped = String::with_capacity(unit.len());
+    let mut in_annotation = false;
+    for c in unit.chars() {
+        match c {
+            '{' => in_annotation = true,
+            '}' => in_annotation = false,
+            c if !in_annotation => stripped.push(c),
+            _ => {}
+        }
+    }
+
+    // a bare annotation, e.g. "{score}", stands for the unit 1
+    if stripped.is_empty() {
+        return unit.starts_with('{');
+    }
+
+    KNOWN_UNITS.contains(&stripped.as_str())
+}
+
+// The number of decimal places that we display values with, keyed by code, qualified
+// with its code system (e.g., "http://loinc.org|8302-2").
+pub type ValuePrecision = HashMap<String, usize>;
+
+// Gets the number of decimal places that we display values with, unless configured
+// otherwise.
+//
+// Vital signs are displayed with one decimal place. Counts, and measurements that are
+// recorded in whole units (e.g., blood pressure), are displayed without decimals.
+// Values for other codes are displayed as reported.
+pub fn default_value_precision() -> ValuePrecision {
+    [
+        // body height
+        ("8302-2", 1),
+        // body weight
+        ("29463-7", 1),
+        // body mass index
+        ("39156-5", 1),
+        // body temperature
+        ("8310-5", 1),
+        // systolic blood pressure
+        ("8480-6", 0),
+        // diastolic blood pressure
+        ("8462-4", 0),
+        // heart rate
+        ("8867-4", 0),
+        // respiratory rate
+        ("9279-1", 0),
+        // LDL cholesterol
+        ("2089-1", 0),
+        // HDL cholesterol
+        ("2085-9", 0),
+    ]
+    .into_iter()
+    .map(|(code, decimals)| (LoincCode::bare(code).token(), decimals))
+    .collect()
+}
+
+// Gets the number of decimal places to display values for a concept with.
+//
+// Returns an empty option if none of the concept's codings have a configured
+// precision. Codings that do not specify a system are assumed to be LOINC codings.
+//
+// # Arguments
+// * `concept` The code of the observation or component.
+// * `precision` The configured precision, keyed by code.
+fn value_precision(concept: &CodeableConcept, precision: &ValuePrecision) -> Option<usize> {
+    concept.coding.iter().flatten().find_map(|coding| {
+        let code = coding.code.as_deref()?;
+        let system = coding.system.as_deref().unwrap_or(LOINC_SYSTEM);
+        precision.get(&format!("{system}|{code}")).copied()
+    })
+}
+
+// Formats a value, rounded to a number of decimal places.
+//
+// Values keep all of the decimal places, so that e.g. a height of 170 cm is displayed
+// as "170.0" with one decimal place. If rounding would hide a non-zero value entirely
+// (e.g., 0.0043 with one decimal place), we display two significant digits instead.
+//
+// # Arguments
+// * `value` The value to format.
+// * `precision` The number of decimal places to round to. If empty, the value is
+//   formatted as reported.
+pub fn format_value(value: f64, precision: Option<usize>) -> String {
+    let Some(mut precision) = precision else {
+        return format!("{value}");
+    };
+
+    let rounded = format!("{value:.precision$}");
+    if value != 0.0 && rounded.parse::<f64>() == Ok(0.0) {
+        // keep enough decimal places for two significant digits
+        precision = (-value.abs().log10()).ceil() as usize + 1;
+    }
+
+    format!("{value:.precision$}")
+}
+
+// Formats a quantity, concatenating its value and canonical unit.
+//
+// If neither the quantity's unit nor its coded unit is a unit that we recognize (see
+// `is_known_unit`), the value is flagged, so that users do not mistake it for a value
+// in a unit that they expect.
+//
+// Quantities without a unit (e.g., counts or scores) fall back to their coded unit,
+// and are displayed as a bare value if they have neither. Returns an empty option if
+// the quantity is missing a value.
+//
+// # Arguments
+// * `quantity` The quantity to format.
+// * `precision` The number of decimal places to round the value to; see `format_value`.
+fn display_quantity(quantity: &Quantity, precision: Option<usize>) -> Option<String> {
+    match (
+        &quantity.value,
+        quantity.unit.as_ref().or(quantity.code.as_ref()),
+    ) {
+        (Some(value), None) => Some(format_value(*value, precision)),
+        (Some(value), Some(unit)) => {
+            let unit = canonical_unit(unit);
+            let known = is_known_unit(&unit) || quantity.code.as_deref().is_some_and(is_known_unit);
+            let value = format_value(*value, precision);
+
+            if known {
+                Some(format!("{value} {unit}"))
+            } else {
+                Some(format!("{value} {unit} {UNKNOWN_UNIT_WARNING}"))
+            }
+        }
+        _ => None,
+    }
+}
+
+// Gets a label for the [interpretation](http://hl7.org/fhir/R4B/valueset-observation-interpretation.html)
+// of an observation or component, e.g. "High" or "Critical".
+//
+// Uses the display of the first coding of the first interpretation, falling back to
+// its code. Returns an empty option if the value was not interpreted.
+//
+// # Arguments
+// * `interpretation` The interpretations of the observation or component.
+fn interpretation_label(interpretation: &[Option<CodeableConcept>]) -> Option<String> {
+    let coding = interpretation.first()?.as_ref()?.coding.first()?.as_ref()?;
+    coding.display.clone().or_else(|| coding.code.clone())
+}
+
+// Appends the interpretation of a value to it, e.g. "140 mg/dL (High)".
+//
+// # Arguments
+// * `value` The formatted value.
+// * `interpretation` The interpretations of the observation or component.
+fn with_interpretation(value: String, interpretation: &[Option<CodeableConcept>]) -> String {
+    match interpretation_label(interpretation) {
+        Some(label) => format!("{value} ({label})"),
+        None => value,
+    }
+}
+
+// Formats the value of an observation.
+//
+// Handles observations with [quantity](http://hl7.org/fhir/R4B/datatypes.html#Quantity)
+// types, returning a string concatenating the value and unit; units are displayed in
+// their canonical form (see `canonical_unit`), and values are rounded to the
+// precision configured for the observation's code (see `default_value_precision`). If the
+// observation is interpreted (e.g., as high or low), the interpretation follows the
+// value in parentheses. If the observation has no value because of a
+// [dataAbsentReason](http://hl7.org/fhir/R4B/observation-definitions.html#Observation.dataAbsentReason)
+// (e.g., the test was not performed), we describe the reason instead, so that users
+// know the observation was attempted. Returns an empty option if
+// the top-level value is absent, which is legitimately the case for multi-component
+// observations (e.g., blood pressure), or if the quantity is missing a value.
+//
+// # Arguments
+// * `observation` The observation to format.
+// * `precision` The number of decimal places to display values with, keyed by code.
+pub fn observation_value(observation: &Observation, precision: &ValuePrecision) -> Option<String> {
+    match &observation.value {
+        Some(ObservationValue::Quantity(quantity)) => {
+            display_quantity(quantity, value_precision(&observation.code, precision))
+                .map(|value| with_interpretation(value, &observation.interpretation))
+        }
+        Some(_) => None,
+        None => display_data_absent_reason(observation.data_absent_reason.as_ref()),
+    }
+}
+
+// Formats the value of a component of an observation.
+//
+// Handles components with quantity types, as in `observation_value`, including the
+// component's interpretation and data absent reason. Returns an empty option if the
+// component has neither a quantity value nor a reason that it is absent.
+//
+// # Arguments
+// * `component` The component to format.
+// * `precision` The number of decimal places to display values with, keyed by code.
+pub fn component_value(
+    component: &ObservationComponent,
+    precision: &ValuePrecision,
+) -> Option<String> {
+    match &component.value {
+        Some(ObservationComponentValue::Quantity(quantity)) => {
+            display_quantity(quantity, value_precision(&component.code, precision))
+                .map(|value| with_interpretation(value, &component.interpretation))
+        }
+        Some(_) => None,
+        None => display_data_absent_reason(component.data_absent_reason.as_ref()),
+    }
+}
+
+// Describes why an observation or component has no value, e.g. "No value (Not
+// performed)".
+//
+// Uses the display of the reason's first coding, falling back to its text and then
+// its code. Returns an empty option if there is no reason, in which case the value is
+// missing rather than explained.
+//
+// # Arguments
+// * `reason` The data absent reason of the observation or component.
+fn display_data_absent_reason(reason: Option<&CodeableConcept>) -> Option<String> {
+    let reason = reason?;
+    let coding = reason.coding.iter().flatten().next();
+    coding
+        .and_then(|coding| coding.display.clone())
+        .or_else(|| reason.text.clone())
+        .or_else(|| coding.and_then(|coding| coding.code.clone()))
+        .map(|reason| format!("No value ({reason})"))
+}
+
+// Formats the value of a specific component of an observation.
+//
+// Searches the [Observation.component](http://hl7.org/fhir/R4B/observation-definitions.html#Observation.component)
+// field for a component whose code matches `code`, and formats its quantity as in
+// `observation_value`.
+//
+// # Arguments
+// * `observation` The observation to format.
+// * `code` The LOINC code of the component to format.
+// * `precision` The number of decimal places to display values with, keyed by code.
+pub fn observation_component_value(
+    observation: &Observation,
+    code: &LoincCode,
+    precision: &ValuePrecision,
+) -> Option<String> {
+    observation
+        .component
+        .iter()
+        .flatten()
+        .filter(|component| {
+            component
+                .code
+                .coding
+                .iter()
+                .flatten()
+                .any(|coding| code.matches(coding))
+        })
+        .find_map(|component| component_value(component, precision))
+}
+
+// Gets the text of the latest note on an observation, e.g. a clinician's comment
+// about how it was measured.
+//
+// Notes are listed in the order they were added, so we take the last one with text.
+// Returns an empty option if the observation has no notes.
+//
+// # Arguments
+// * `observation` The observation to get the note of.
+pub fn observation_note(observation: &Observation) -> Option<String> {
+    observation
+        .note
+        .iter()
+        .flatten()
+        .map(|note| note.text.trim())
+        .rfind(|text| !text.is_empty())
+        .map(str::to_string)
+}
+
+// Formats a reference range of an observation, e.g. "40 - 60 mg/dL".
+//
+// Prefers the range's text, falling back to its low and high bounds. Returns an
+// empty option if the range has neither.
+//
+// # Arguments
+// * `range` The reference range to format.
+pub fn display_reference_range(range: &ObservationReferenceRange) -> Option<String> {
+    if let Some(text) = &range.text {
+        return Some(text.clone());
+    }
+
+    let low = range
+        .low
+        .as_ref()
+        .and_then(|low| display_quantity(low, None));
+    let high = range
+        .high
+        .as_ref()
+        .and_then(|high| display_quantity(high, None));
+    match (low, high) {
+        (Some(low), Some(high)) => Some(format!("{low} - {high}")),
+        (Some(low), None) => Some(format!(">= {low}")),
+        (None, Some(high)) => Some(format!("<= {high}")),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_unit_normalizes_spellings() {
+        for (raw, canonical) in [
+            ("mm[Hg]", "mmHg"),
+            ("mm Hg", "mmHg"),
+            ("MMHG", "mmHg"),
+            ("centimeters", "cm"),
+            ("Kilogram", "kg"),
+            ("mg/dl", "mg/dL"),
+            ("mg/100 mL", "mg/dL"),
+        ] {
+            assert_eq!(canonical_unit(raw), canonical, "canonical form of {raw}");
+        }
+    }
+
+    #[test]
+    fn data_absent_reason_renders_in_place_of_value() {
+        let observation: Observation = serde_json::from_value(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "2085-9" }]
+            },
+            "dataAbsentReason": {
+                "coding": [{
+                    "system": "http://terminology.hl7.org/CodeSystem/data-absent-reason",
+                    "code": "not-performed",
+                    "display": "Not Performed"
+                }]
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            observation_value(&observation, &default_value_precision()).unwrap(),
+            "No value (Not Performed)"
+        );
+    }
+
+    #[test]
+    fn observation_without_value_or_reason_has_no_value() {
+        let observation: Observation = serde_json::from_value(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "2085-9" }]
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            observation_value(&observation, &default_value_precision()),
+            None
+        );
+    }
+
+    #[test]
+    fn canonical_unit_keeps_unknown_units() {
+        assert_eq!(canonical_unit("mmol/L"), "mmol/L");
+        assert_eq!(canonical_unit("{beats}/min"), "{beats}/min");
+    }
+
+    #[test]
+    fn noisy_value_renders_at_configured_precision() {
+        let observation: Observation = serde_json::from_value(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "8310-5" }]
+            },
+            "valueQuantity": { "value": 37.250000000001, "unit": "Cel" }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            observation_value(&observation, &default_value_precision()).unwrap(),
+            "37.3 Cel"
+        );
+    }
+
+    #[test]
+    fn configured_precision_replaces_default() {
+        let observation: Observation = serde_json::from_value(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "2089-1" }]
+            },
+            "valueQuantity": { "value": 7, "unit": "mg/dL" }
+        }))
+        .unwrap();
+        let mut precision = default_value_precision();
+        precision.insert(String::from("http://loinc.org|2089-1"), 2);
+
+        assert_eq!(
+            observation_value(&observation, &precision).unwrap(),
+            "7.00 mg/dL"
+        );
+    }
+
+    #[test]
+    fn format_value_keeps_significant_digits() {
+        assert_eq!(format_value(98.60000000001, Some(0)), "99");
+        assert_eq!(format_value(170.0, Some(1)), "170.0");
+        assert_eq!(format_value(7.0, Some(1)), "7.0");
+        assert_eq!(format_value(0.0043, Some(1)), "0.0043");
+        assert_eq!(format_value(0.1234, None), "0.1234");
+    }
+
+    #[test]
+    fn is_known_unit_recognizes_ucum_units() {
+        for unit in [
+            "cm",
+            "kg",
+            "mmHg",
+            "mg/dL",
+            "mmol/L",
+            "{beats}/min",
+            "{score}",
+            "%",
+        ] {
+            assert!(is_known_unit(unit), "{unit} should be known");
+        }
+    }
+
+    #[test]
+    fn is_known_unit_rejects_unknown_units() {
+        for unit in ["furlongs", "mg/fortnight", "{beats}/fortnight", ""] {
+            assert!(!is_known_unit(unit), "{unit} should be unknown");
+        }
+    }
+
+    #[test]
+    fn value_with_unknown_unit_is_flagged() {
+        let observation: Observation = serde_json::from_value(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "text": "Distance walked" },
+            "valueQuantity": { "value": 3, "unit": "furlongs" }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            observation_value(&observation, &default_value_precision()).unwrap(),
+            format!("3 furlongs {UNKNOWN_UNIT_WARNING}")
+        );
+    }
+
+    // Builds an LDL observation with an interpretation.
+    //
+    // # Arguments
+    // * `interpretation` The observation's interpretations, as FHIR JSON.
+    fn ldl_with_interpretation(interpretation: serde_json::Value) -> Observation {
+        serde_json::from_value(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "18262-6" }]
+            },
+            "valueQuantity": { "value": 140, "unit": "mg/dL" },
+            "interpretation": interpretation
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn interpretation_follows_value() {
+        let observation = ldl_with_interpretation(serde_json::json!([{
+            "coding": [{
+                "system": "http://terminology.hl7.org/CodeSystem/v3-ObservationInterpretation",
+                "code": "H",
+                "display": "High"
+            }]
+        }]));
+
+        assert_eq!(
+            observation_value(&observation, &default_value_precision()).unwrap(),
+            "140 mg/dL (High)"
+        );
+    }
+
+    #[test]
+    fn interpretation_falls_back_to_code() {
+        let observation = ldl_with_interpretation(serde_json::json!([{
+            "coding": [{
+                "system": "http://terminology.hl7.org/CodeSystem/v3-ObservationInterpretation",
+                "code": "HH"
+            }]
+        }]));
+
+        assert_eq!(
+            observation_value(&observation, &default_value_precision()).unwrap(),
+            "140 mg/dL (HH)"
+        );
+    }
+
+    #[test]
+    fn value_without_interpretation_is_unchanged() {
+        let observation = ldl_with_interpretation(serde_json::json!([]));
+
+        assert_eq!(
+            observation_value(&observation, &default_value_precision()).unwrap(),
+            "140 mg/dL"
+        );
+    }
+
+    #[test]
+    fn latest_note_with_text_is_used() {
+        let observation: Observation = serde_json::from_value(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "text": "Height" },
+            "note": [
+                { "text": "Measured without shoes" },
+                { "text": "Measured standing" },
+                { "text": "  " }
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            observation_note(&observation).as_deref(),
+            Some("Measured standing")
+        );
+    }
+
+    #[test]
+    fn observation_without_notes_has_no_note() {
+        let observation: Observation = serde_json::from_value(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "text": "Height" }
+        }))
+        .unwrap();
+
+        assert_eq!(observation_note(&observation), None);
+    }
+}
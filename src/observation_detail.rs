@@ -0,0 +1,480 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::{get, web, HttpResponse, ResponseError};
+use chrono_tz::Tz;
+use fhir_sdk::r4b::resources::{Observation, ObservationEffective};
+use fhir_sdk::DateTime;
+use log::error;
+use maud::{html, Markup, DOCTYPE};
+
+use crate::context::LaunchContext;
+use crate::display::{display_codeable_concept, display_date_time, display_period};
+use crate::error::AppError;
+use crate::fetch::{is_session_expired, is_valid_id, references_patient};
+use crate::observation::{
+    component_value, display_reference_range, observation_value, ValuePrecision,
+};
+use crate::practitioner::PractitionerResolver;
+use crate::state::State;
+
+/**
+ * Observation details
+ * -------------------
+ * Shows everything we display about a single
+ * [observation](http://hl7.org/fhir/R4B/observation.html): its code, value, components,
+ * reference ranges, effective date, and performers. The observation is read from the
+ * FHIR server, so that users can inspect the source of a value shown in the summary.
+ *
+ * Performers that reference a Practitioner, Organization, or Patient are resolved to
+ * their names, if the session may read practitioners; otherwise we show the display
+ * text on each reference.
+ *
+ * Both IDs in the path must be valid FHIR IDs. We only show observations about the
+ * patient in context for the session.
+ */
+#[get("/{patient_id}/observation/{observation_id}.html")]
+pub async fn observation_detail(
+    data: web::Data<State>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (patient_id, observation_id) = path.into_inner();
+    if !is_valid_id(&patient_id) || !is_valid_id(&observation_id) {
+        return HttpResponse::BadRequest().body("Invalid patient or observation ID.");
+    }
+
+    let Some(client) = data.get_token(&patient_id) else {
+        return HttpResponse::Unauthorized()
+            .body(format!("Failed to find token for {patient_id}."));
+    };
+    let Some(patient) = client.patient.clone() else {
+        return data
+            .error_page(AppError::NotFound(String::from(
+                "This session does not have a patient in context.",
+            )))
+            .error_response();
+    };
+    if !client.can_read("Observation") {
+        return data
+            .error_page(AppError::Forbidden(String::from(
+                "This session is not authorized to read observations.",
+            )))
+            .error_response();
+    }
+
+    let observation = client
+        .limiter
+        .run_request(
+            client
+                .client_for("Observation")
+                .read::<Observation>(&observation_id),
+        )
+        .await;
+
+    match observation {
+        // do not show observations about other patients, even if the token allows it
+        Ok(Some(observation)) if references_patient(observation.subject.as_ref(), &patient) => {
+            let performers = if client.can_read("Practitioner") {
+                PractitionerResolver::new(&client, &client.practitioners)
+                    .resolve_performers(&observation)
+                    .await
+            } else {
+                display_performers(&observation)
+            };
+            HttpResponse::Ok().body(
+                render_observation(
+                    &observation,
+                    &performers,
+                    &client.context,
+                    &data.timezone,
+                    &data.value_precision,
+                )
+                .into_string(),
+            )
+        }
+        Ok(_) => data
+            .error_page(AppError::NotFound(format!(
+                "Observation {observation_id} was not found for this patient."
+            )))
+            .error_response(),
+        Err(e) if is_session_expired(&e) => {
+            HttpResponse::Unauthorized().body(format!("Session for {patient_id} has expired."))
+        }
+        Err(e) => {
+            error!(
+                "Reading observation {observation_id} failed with error: {:?}",
+                e
+            );
+            data.error_page(AppError::Internal(String::from(
+                "Failed to read the observation from the FHIR server.",
+            )))
+            .error_response()
+        }
+    }
+}
+
+// Formats when an observation was made, for display.
+//
+// Returns an empty option if the observation has no effective time, or if it is
+// given as a timing schedule.
+//
+// # Arguments
+// * `observation` The observation to format.
+// * `timezone` The timezone to display times in.
+fn display_effective(observation: &Observation, timezone: &Tz) -> Option<String> {
+    match observation.effective.as_ref()? {
+        ObservationEffective::DateTime(date_time) => Some(display_date_time(date_time, timezone)),
+        ObservationEffective::Period(period) => Some(display_period(period, timezone)),
+        ObservationEffective::Instant(instant) => Some(display_date_time(
+            &DateTime::DateTime(instant.clone()),
+            timezone,
+        )),
+        ObservationEffective::Timing(_) => None,
+    }
+}
+
+// Gets the performers of an observation for display, without resolving references.
+//
+// Uses the display text on each reference, falling back to the reference itself.
+//
+// # Arguments
+// * `observation` The observation whose performers to display.
+fn display_performers(observation: &Observation) -> Vec<String> {
+    observation
+        .performer
+        .iter()
+        .flatten()
+        .filter_map(|performer| {
+            performer
+                .display
+                .clone()
+                .or_else(|| performer.reference.clone())
+        })
+        .collect()
+}
+
+// Renders the details of an observation.
+//
+// # Arguments
+// * `observation` The observation to render.
+// * `performers` The display names of the observation's performers.
+// * `context` The context of the launch, e.g. the EHR and user.
+// * `timezone` The timezone to display times in.
+// * `precision` The number of decimal places to display values with, keyed by code.
+#[rustfmt::skip::macros(html)]
+fn render_observation(
+    observation: &Observation,
+    performers: &[String],
+    context: &LaunchContext,
+    timezone: &Tz,
+    precision: &ValuePrecision,
+) -> Markup {
+    let name = display_codeable_concept(&observation.code)
+        .unwrap_or_else(|| String::from("Unknown observation"));
+
+    html! {
+	(DOCTYPE);
+	html lang="en" {
+            head {
+		title {
+		    "Example SMART-on-FHIR app: " (name)
+		}
+            }
+            body {
+		div #holder {
+		    h1 {
+			"Example SMART-on-FHIR app"
+		    }
+		    p #brand {
+			"Connected to "
+			(context.connected_to())
+		    }
+		    section #observation {
+			h2 {
+			    (name)
+			}
+			table {
+			    tbody {
+				tr {
+				    th {
+					"Status:"
+				    }
+				    td #status {
+					(observation.status)
+				    }
+				}
+				@for coding in observation.code.coding.iter().flatten() {
+				    @if let Some(code) = &coding.code {
+					tr {
+					    th {
+						"Code:"
+					    }
+					    td .code {
+						(code)
+						@if let Some(system) = &coding.system {
+						    " (" (system) ")"
+						}
+					    }
+					}
+				    }
+				}
+				@if let Some(value) = observation_value(observation, precision) {
+				    tr {
+					th {
+					    "Value:"
+					}
+					td #value {
+					    (value)
+					}
+				    }
+				}
+				@for component in observation.component.iter().flatten() {
+				    tr {
+					th {
+					    (display_codeable_concept(&component.code).unwrap_or_default()) ":"
+					}
+					td .component {
+					    (component_value(component, precision).unwrap_or_default())
+					}
+				    }
+				}
+				@for range in observation.reference_range.iter().flatten() {
+				    @if let Some(range) = display_reference_range(range) {
+					tr {
+					    th {
+						"Reference range:"
+					    }
+					    td .reference-range {
+						(range)
+					    }
+					}
+				    }
+				}
+				@if let Some(effective) = display_effective(observation, timezone) {
+				    tr {
+					th {
+					    "Effective:"
+					}
+					td #effective {
+					    (effective)
+					}
+				    }
+				}
+				@if !performers.is_empty() {
+				    tr {
+					th {
+					    "Performed by:"
+					}
+					td #performer {
+					    ul {
+						@for performer in performers {
+						    li {
+							(performer)
+						    }
+						}
+					    }
+					}
+				    }
+				}
+			    }
+			}
+		    }
+		    p {
+			a #back href=(context.summary_path("html")) {
+			    "Back to the summary"
+			}
+		    }
+		}
+            }
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::test_support::{put_session, test_state};
+
+    // Requests the details page of an observation, served by a mock EHR.
+    //
+    // # Arguments
+    // * `observation` The observation that the EHR returns for `Observation/bp`.
+    // * `uri` The path to request.
+    async fn get_observation_page(observation: Value, uri: &str) -> (StatusCode, String) {
+        let ehr = MockServer::start().await;
+        get_observation_page_from(&ehr, observation, &["patient/Observation.read"], uri).await
+    }
+
+    // Requests the details page of an observation from a given mock EHR, which may serve
+    // other resources that the page refers to.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `observation` The observation that the EHR returns for `Observation/bp`.
+    // * `scopes` The scopes granted to the session.
+    // * `uri` The path to request.
+    async fn get_observation_page_from(
+        ehr: &MockServer,
+        observation: Value,
+        scopes: &[&str],
+        uri: &str,
+    ) -> (StatusCode, String) {
+        Mock::given(method("GET"))
+            .and(path("/Observation/bp"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(observation))
+            .mount(ehr)
+            .await;
+        let state = test_state();
+        put_session(&state, ehr, "123", scopes).await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(observation_detail),
+        )
+        .await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        let status = resp.status();
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        (status, body)
+    }
+
+    // Builds a blood pressure observation about a patient, with systolic and diastolic
+    // components.
+    //
+    // # Arguments
+    // * `patient_id` The ID of the patient that the observation is about.
+    fn blood_pressure(patient_id: &str) -> Value {
+        let component = |code: &str, display: &str, value: u32| {
+            json!({
+                "code": {
+                    "coding": [{ "system": "http://loinc.org", "code": code, "display": display }]
+                },
+                "valueQuantity": { "value": value, "unit": "mm[Hg]" }
+            })
+        };
+        json!({
+            "resourceType": "Observation",
+            "id": "bp",
+            "status": "final",
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "55284-4" }],
+                "text": "Blood pressure"
+            },
+            "subject": { "reference": format!("Patient/{patient_id}") },
+            "effectiveDateTime": "2024-03-01T15:30:00Z",
+            "performer": [{ "display": "Dr. Jane Smith" }],
+            "component": [
+                component("8480-6", "Systolic blood pressure", 120),
+                component("8462-4", "Diastolic blood pressure", 80)
+            ]
+        })
+    }
+
+    #[actix_web::test]
+    async fn multi_component_observation_is_rendered() {
+        let (status, body) =
+            get_observation_page(blood_pressure("123"), "/123/observation/bp.html").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<h2>Blood pressure</h2>"));
+        assert!(body.contains(r#"<td id="status">final</td>"#));
+        assert!(body.contains("55284-4 (http://loinc.org)"));
+        assert!(body
+            .contains(r#"<th>Systolic blood pressure:</th><td class="component">120 mmHg</td>"#));
+        assert!(body
+            .contains(r#"<th>Diastolic blood pressure:</th><td class="component">80 mmHg</td>"#));
+        assert!(body.contains(r#"<td id="effective">March 1, 2024, 15:30 UTC</td>"#));
+        assert!(body.contains("<li>Dr. Jane Smith</li>"));
+    }
+
+    #[actix_web::test]
+    async fn performer_references_are_resolved_to_names() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Practitioner/p1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Practitioner",
+                "id": "p1",
+                "name": [{ "given": ["Jane"], "family": "Smith" }]
+            })))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        let mut observation = blood_pressure("123");
+        observation["performer"] = json!([
+            { "reference": "Practitioner/p1" },
+            { "reference": "Practitioner/gone", "display": "Dr. Former" }
+        ]);
+
+        let (status, body) = get_observation_page_from(
+            &ehr,
+            observation,
+            &["patient/Observation.read", "user/Practitioner.read"],
+            "/123/observation/bp.html",
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<li>Jane Smith</li>"));
+        assert!(body.contains("<li>Dr. Former</li>"));
+    }
+
+    #[actix_web::test]
+    async fn performer_references_are_not_read_without_scope() {
+        let ehr = MockServer::start().await;
+        let mut observation = blood_pressure("123");
+        observation["performer"] = json!([{ "reference": "Practitioner/p1" }]);
+
+        let (status, body) = get_observation_page_from(
+            &ehr,
+            observation,
+            &["patient/Observation.read"],
+            "/123/observation/bp.html",
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<li>Practitioner/p1</li>"));
+        let requests = ehr.received_requests().await.unwrap();
+        assert!(!requests
+            .iter()
+            .any(|request| request.url.path().starts_with("/Practitioner")));
+    }
+
+    #[actix_web::test]
+    async fn observation_about_other_patient_is_not_found() {
+        let (status, _) =
+            get_observation_page(blood_pressure("456"), "/123/observation/bp.html").await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn invalid_observation_id_is_rejected() {
+        let (status, _) =
+            get_observation_page(blood_pressure("123"), "/123/observation/b%24p.html").await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
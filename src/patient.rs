@@ -0,0 +1,166 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::{get, web, HttpResponse};
+use log::error;
+use serde::Deserialize;
+
+use crate::fetch::fetch_patient;
+use crate::render::to_json;
+use crate::state::State;
+
+#[derive(Deserialize)]
+pub struct PatientQuery {
+    // Whether to indent the resource. Defaults to the `pretty_json` setting.
+    pretty: Option<bool>,
+}
+
+/**
+ * Raw patient resource
+ * --------------------
+ * Returns the [patient resource](http://hl7.org/fhir/R4B/patient.html) for a patient
+ * as FHIR JSON, without any of the processing that we apply for the summary page.
+ * This lets downstream tools work with the unprocessed resource.
+ *
+ * Like the summary page, this endpoint requires a session for the patient. The
+ * session's token must also have been granted a scope allowing us to read
+ * patient resources.
+ *
+ * The resource is compact; pass `?pretty=true` to indent it for debugging.
+ */
+#[get("/{patient_id}/Patient.json")]
+pub async fn patient_json(
+    data: web::Data<State>,
+    patient_id: web::Path<String>,
+    query: web::Query<PatientQuery>,
+) -> HttpResponse {
+    match data.get_token(&patient_id) {
+        Some(client) if client.patient.is_none() => HttpResponse::NotFound().body(format!(
+            "Session {patient_id} does not have a patient in context."
+        )),
+        Some(client) if !client.can_read("Patient") => HttpResponse::Forbidden().body(format!(
+            "Session for {patient_id} is not authorized to read Patient resources."
+        )),
+        Some(client) => match client
+            .limiter
+            .run_request(fetch_patient(client.client_for("Patient"), &patient_id))
+            .await
+        {
+            Ok(Some(patient)) => {
+                match to_json(&patient, query.pretty.unwrap_or(data.pretty_json)) {
+                    Ok(json) => HttpResponse::Ok()
+                        .content_type("application/fhir+json")
+                        .body(json),
+                    Err(e) => {
+                        error!(
+                            "Serializing patient {patient_id} failed with error: {:?}",
+                            e
+                        );
+                        HttpResponse::InternalServerError().finish()
+                    }
+                }
+            }
+            Ok(None) => HttpResponse::NotFound().body(format!("Patient {patient_id} not found.")),
+            Err(e) => {
+                error!(
+                    "Searching for patient {patient_id} failed with error: {:?}",
+                    e
+                );
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+        None => {
+            HttpResponse::Unauthorized().body(format!("Failed to find token for {patient_id}."))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::header::CONTENT_TYPE;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use serde_json::{json, Value};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::test_support::{put_session, test_state};
+
+    // Requests the raw Patient resource for patient 123.
+    //
+    // # Arguments
+    // * `state` The application state.
+    async fn get_patient_json(state: State) -> actix_web::dev::ServiceResponse {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(patient_json),
+        )
+        .await;
+        let req = test::TestRequest::get()
+            .uri("/123/Patient.json")
+            .to_request();
+        test::call_service(&app, req).await
+    }
+
+    #[actix_web::test]
+    async fn patient_json_is_fhir_json() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Patient/123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Patient",
+                "id": "123",
+                "gender": "female"
+            })))
+            .mount(&ehr)
+            .await;
+        let state = test_state();
+        put_session(&state, &ehr, "123", &["patient/Patient.read"]).await;
+
+        let resp = get_patient_json(state).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "application/fhir+json"
+        );
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["resourceType"], "Patient");
+        assert_eq!(body["id"], "123");
+        assert_eq!(body["gender"], "female");
+    }
+
+    #[actix_web::test]
+    async fn patient_json_requires_patient_read_scope() {
+        let ehr = MockServer::start().await;
+        let state = test_state();
+        put_session(&state, &ehr, "123", &["patient/Observation.read"]).await;
+
+        let resp = get_patient_json(state).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn patient_json_requires_session() {
+        let resp = get_patient_json(test_state()).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,130 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use oauth2::PkceCodeVerifier;
+use uuid::Uuid;
+
+use crate::aead::Aead;
+
+// A PKCE verifier, as kept in the state store between the launch and the callback.
+pub enum StoredVerifier {
+    // The verifier, in plaintext.
+    Plain(PkceCodeVerifier),
+
+    // The verifier, sealed with a `VerifierCipher`.
+    Encrypted(Vec<u8>),
+}
+
+// Encrypts PKCE verifiers while they are kept in the state store.
+//
+// The verifier is a secret, which sits in memory from the launch until the
+// callback. As defense in depth, it can be encrypted with AES-256-GCM under a
+// key from our configuration, and only decrypted when we exchange it for a token.
+// The launch UUID (`state`) is bound to the ciphertext as associated data, so that
+// a verifier cannot be used for a different launch.
+pub struct VerifierCipher {
+    aead: Aead,
+}
+
+impl VerifierCipher {
+    // Creates a cipher from a 256-bit key.
+    //
+    // Returns an error if the key has the wrong length.
+    //
+    // # Arguments
+    // * `key` The encryption key.
+    pub fn new(key: &[u8]) -> Result<VerifierCipher, String> {
+        Aead::new(key)
+            .map(|aead| VerifierCipher { aead })
+            .map_err(|_| format!("PKCE encryption key must be {} bytes long", Aead::key_len()))
+    }
+
+    // Encrypts a verifier.
+    //
+    // Returns an error if we fail to generate a nonce or to encrypt the verifier;
+    // we never fall back to keeping it in plaintext.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch the verifier belongs to.
+    // * `verifier` The verifier to encrypt.
+    pub fn seal(
+        &self,
+        state: &Uuid,
+        verifier: &PkceCodeVerifier,
+    ) -> Result<StoredVerifier, String> {
+        self.aead
+            .seal(state.as_bytes(), verifier.secret().as_bytes())
+            .map(StoredVerifier::Encrypted)
+            .map_err(|_| String::from("Failed to encrypt the PKCE verifier"))
+    }
+
+    // Decrypts a verifier.
+    //
+    // Returns an empty option if the verifier cannot be decrypted, e.g. because it
+    // was stored for a different launch.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch the verifier belongs to.
+    // * `stored` The stored verifier.
+    pub fn open(&self, state: &Uuid, stored: StoredVerifier) -> Option<PkceCodeVerifier> {
+        match stored {
+            StoredVerifier::Plain(verifier) => Some(verifier),
+            StoredVerifier::Encrypted(sealed) => {
+                let plaintext = self.aead.open(state.as_bytes(), &sealed)?;
+                String::from_utf8(plaintext).ok().map(PkceCodeVerifier::new)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Creates a cipher with a fixed key.
+    fn cipher() -> VerifierCipher {
+        VerifierCipher::new(&[7u8; 32]).unwrap()
+    }
+
+    #[test]
+    fn encrypted_verifier_round_trips() {
+        let state = Uuid::new_v4();
+        let verifier = PkceCodeVerifier::new(String::from("a-secret-verifier"));
+
+        let stored = cipher().seal(&state, &verifier).unwrap();
+
+        let StoredVerifier::Encrypted(ciphertext) = &stored else {
+            panic!("Verifier was stored in plaintext.");
+        };
+        assert!(!String::from_utf8_lossy(ciphertext).contains("a-secret-verifier"));
+        let opened = cipher().open(&state, stored).unwrap();
+        assert_eq!(opened.secret(), "a-secret-verifier");
+    }
+
+    #[test]
+    fn encrypted_verifier_is_bound_to_its_launch() {
+        let verifier = PkceCodeVerifier::new(String::from("a-secret-verifier"));
+
+        let stored = cipher().seal(&Uuid::new_v4(), &verifier).unwrap();
+
+        assert!(cipher().open(&Uuid::new_v4(), stored).is_none());
+    }
+
+    #[test]
+    fn key_of_wrong_length_is_rejected() {
+        assert!(VerifierCipher::new(&[7u8; 16]).is_err());
+    }
+}
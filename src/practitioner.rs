@@ -0,0 +1,369 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fhir_sdk::r4b::resources::{Observation, Patient, Practitioner, Resource};
+use fhir_sdk::r4b::types::Reference;
+use fhir_sdk::ParsedReference;
+use log::error;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::display::{display_codeable_concept, display_human_name, display_patient_name};
+use crate::smart::token::TokenClient;
+
+// A cache of practitioner, organization, and patient resources read from the FHIR
+// server, keyed by reference.
+//
+// The cache is shared between clones, so that all requests made with a
+// given `TokenClient` share a single cache.
+#[derive(Clone, Default)]
+pub struct PractitionerCache(Arc<Mutex<HashMap<String, Resource>>>);
+
+impl PractitionerCache {
+    fn get(&self, reference: &str) -> Option<Resource> {
+        let map = self.0.lock().unwrap();
+        map.get(reference).cloned()
+    }
+
+    fn put(&self, reference: &str, resource: &Resource) {
+        let mut map = self.0.lock().unwrap();
+        map.insert(reference.to_string(), resource.clone());
+    }
+}
+
+// The practitioner who is logged in, for display.
+#[derive(Clone, Debug)]
+pub struct LoggedInPractitioner {
+    // The practitioner's name.
+    pub name: Option<String>,
+
+    // The practitioner's role, taken from their first qualification.
+    pub role: Option<String>,
+}
+
+// Resolves the names of a patient's general practitioners.
+//
+// [Patient.generalPractitioner](http://hl7.org/fhir/R4B/patient-definitions.html#Patient.generalPractitioner)
+// refers to the patient's nominated care providers, each of which is a
+// [Practitioner](http://hl7.org/fhir/R4B/practitioner.html),
+// [PractitionerRole](http://hl7.org/fhir/R4B/practitionerrole.html), or
+// [Organization](http://hl7.org/fhir/R4B/organization.html) resource. We display the
+// names of practitioners and organizations; a referenced resource may be contained in
+// the Patient, or may need to be read from the FHIR server.
+pub struct PractitionerResolver<'a> {
+    client: &'a TokenClient,
+    cache: &'a PractitionerCache,
+}
+
+impl<'a> PractitionerResolver<'a> {
+    pub fn new(client: &'a TokenClient, cache: &'a PractitionerCache) -> PractitionerResolver<'a> {
+        PractitionerResolver { client, cache }
+    }
+
+    // Gets the display names of a patient's general practitioners.
+    //
+    // References that cannot be resolved, and that do not carry display text, are
+    // left out.
+    //
+    // # Arguments
+    // * `patient` The patient to resolve the general practitioners of.
+    pub async fn resolve(&self, patient: &Patient) -> Vec<String> {
+        let mut names = Vec::new();
+        for reference in patient.general_practitioner.iter().flatten() {
+            let name = self.resolve_reference(&patient.contained, reference).await;
+            names.extend(name);
+        }
+        names
+    }
+
+    // Gets the display names of the performers of an observation.
+    //
+    // [Observation.performer](http://hl7.org/fhir/R4B/observation-definitions.html#Observation.performer)
+    // refers to who is responsible for the observation, such as a Practitioner,
+    // Organization, or Patient. Performers are resolved in the same way as general
+    // practitioners; references that cannot be resolved, and that do not carry display
+    // text, are left out.
+    //
+    // # Arguments
+    // * `observation` The observation to resolve the performers of.
+    pub async fn resolve_performers(&self, observation: &Observation) -> Vec<String> {
+        let mut names = Vec::new();
+        for reference in observation.performer.iter().flatten() {
+            let name = self
+                .resolve_reference(&observation.contained, reference)
+                .await;
+            names.extend(name);
+        }
+        names
+    }
+
+    // Resolves a reference to a Practitioner, Organization, or Patient resource.
+    //
+    // Checks the contained resources of the referring resource first. Otherwise, if the reference
+    // is relative to the FHIR server, reads the resource, caching the result. We do not
+    // follow absolute references, as they may point outside of the FHIR server that
+    // issued our token. If the reference cannot be resolved, falls back to the display
+    // text on the reference, if any.
+    //
+    // # Arguments
+    // * `contained` The resources contained in the resource that holds the reference.
+    // * `reference` The reference to resolve.
+    async fn resolve_reference(
+        &self,
+        contained: &[Resource],
+        reference: &Reference,
+    ) -> Option<String> {
+        let name = match reference.parse() {
+            Some(ParsedReference::Local { id }) => contained
+                .iter()
+                .find(|resource| resource_id(resource) == Some(id))
+                .and_then(resource_name),
+            Some(ParsedReference::Relative {
+                resource_type: resource_type @ ("Practitioner" | "Organization" | "Patient"),
+                ..
+            }) => self
+                .read_reference(resource_type, reference)
+                .await
+                .as_ref()
+                .and_then(resource_name),
+            _ => None,
+        };
+
+        name.or_else(|| reference.display.clone())
+    }
+
+    // Reads a Practitioner, Organization, or Patient resource from the FHIR server,
+    // using the cache if possible.
+    //
+    // # Arguments
+    // * `resource_type` The type of the referenced resource.
+    // * `reference` The relative reference to the resource.
+    async fn read_reference(&self, resource_type: &str, reference: &Reference) -> Option<Resource> {
+        let key = reference.reference.as_deref()?;
+
+        if let Some(resource) = self.cache.get(key) {
+            return Some(resource);
+        }
+
+        match self
+            .client
+            .limiter
+            .run_request(
+                self.client
+                    .client_for(resource_type)
+                    .read_referenced(reference),
+            )
+            .await
+        {
+            Ok(resource) => {
+                self.cache.put(key, &resource);
+                Some(resource)
+            }
+            Err(e) => {
+                error!("Resolving reference {key} failed with error: {:?}", e);
+                None
+            }
+        }
+    }
+
+    // Gets the name and role of the practitioner who is logged in.
+    //
+    // Reads the Practitioner resource that the EHR identified as the user, using the
+    // cache if possible.
+    //
+    // # Arguments
+    // * `reference` The relative reference to the practitioner, e.g. "Practitioner/123".
+    pub async fn resolve_user(&self, reference: &str) -> Option<LoggedInPractitioner> {
+        let resource = match self.cache.get(reference) {
+            Some(resource) => resource,
+            None => {
+                let (_, id) = reference.split_once('/')?;
+                match self
+                    .client
+                    .limiter
+                    .run_request(
+                        self.client
+                            .client_for("Practitioner")
+                            .read::<Practitioner>(id),
+                    )
+                    .await
+                {
+                    Ok(Some(practitioner)) => {
+                        let resource = Resource::from(practitioner);
+                        self.cache.put(reference, &resource);
+                        resource
+                    }
+                    Ok(None) => {
+                        error!("Logged in practitioner {reference} not found");
+                        return None;
+                    }
+                    Err(e) => {
+                        error!(
+                            "Reading logged in practitioner {reference} failed with error: {:?}",
+                            e
+                        );
+                        return None;
+                    }
+                }
+            }
+        };
+
+        match &resource {
+            Resource::Practitioner(practitioner) => Some(LoggedInPractitioner {
+                name: resource_name(&resource),
+                role: practitioner
+                    .qualification
+                    .iter()
+                    .flatten()
+                    .find_map(|qualification| display_codeable_concept(&qualification.code)),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Gets the ID of a Practitioner, Organization, or Patient resource.
+fn resource_id(resource: &Resource) -> Option<&str> {
+    match resource {
+        Resource::Practitioner(practitioner) => practitioner.id.as_deref(),
+        Resource::Organization(organization) => organization.id.as_deref(),
+        Resource::Patient(patient) => patient.id.as_deref(),
+        _ => None,
+    }
+}
+
+// Gets the display name for a Practitioner, Organization, or Patient resource.
+//
+// Uses the first name of a practitioner or patient, and the name of an organization. Returns an empty option for other types of resources.
+fn resource_name(resource: &Resource) -> Option<String> {
+    match resource {
+        Resource::Practitioner(practitioner) => practitioner
+            .name
+            .iter()
+            .flatten()
+            .next()
+            .and_then(display_human_name),
+        Resource::Organization(organization) => organization.name.clone(),
+        Resource::Patient(patient) => display_patient_name(patient),
+        _ => None,
+    }
+}
+
+// Gets the name and role of the practitioner who is logged in, if the EHR identified
+// the user as a practitioner and we may read Practitioner resources.
+//
+// # Arguments
+// * `client` The client for the session.
+pub async fn logged_in_practitioner(client: &TokenClient) -> Option<LoggedInPractitioner> {
+    let reference = client.context.practitioner.as_deref()?;
+    if !client.can_read("Practitioner") {
+        return None;
+    }
+
+    PractitionerResolver::new(client, &client.practitioners)
+        .resolve_user(reference)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::test_support::{put_session, test_state};
+
+    // Gets a client for a session with a mock FHIR server.
+    //
+    // # Arguments
+    // * `server` The mock FHIR server.
+    async fn client_for(server: &MockServer) -> TokenClient {
+        let state = test_state();
+        put_session(&state, server, "123", &["patient/*.read"]).await;
+        state.get_token("123").unwrap()
+    }
+
+    // Builds a patient with general practitioners.
+    //
+    // # Arguments
+    // * `general_practitioner` The patient's general practitioner references, as FHIR JSON.
+    fn patient_with(general_practitioner: serde_json::Value) -> Patient {
+        serde_json::from_value(json!({
+            "resourceType": "Patient",
+            "id": "123",
+            "generalPractitioner": general_practitioner
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn general_practitioners_are_resolved_once() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Practitioner/p1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Practitioner",
+                "id": "p1",
+                "name": [{ "family": "Smith", "given": ["Jane"] }]
+            })))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Organization/o1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Organization",
+                "id": "o1",
+                "name": "Example Family Practice"
+            })))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        let client = client_for(&ehr).await;
+        let resolver = PractitionerResolver::new(&client, &client.practitioners);
+        let patient = patient_with(json!([
+            { "reference": "Practitioner/p1" },
+            { "reference": "Organization/o1" }
+        ]));
+
+        let first = resolver.resolve(&patient).await;
+        let second = resolver.resolve(&patient).await;
+
+        assert_eq!(first, ["Jane Smith", "Example Family Practice"]);
+        assert_eq!(second, first);
+    }
+
+    #[actix_web::test]
+    async fn unresolvable_general_practitioners_fall_back_to_display() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&ehr)
+            .await;
+        let client = client_for(&ehr).await;
+        let resolver = PractitionerResolver::new(&client, &client.practitioners);
+        let patient = patient_with(json!([
+            { "reference": "Practitioner/gone", "display": "Dr. Former" },
+            { "reference": "Practitioner/unknown" },
+            { "reference": "https://elsewhere.example.com/Practitioner/p2" }
+        ]));
+
+        assert_eq!(resolver.resolve(&patient).await, ["Dr. Former"]);
+    }
+}
@@ -0,0 +1,466 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use fhir_sdk::client::Error;
+use fhir_sdk::r4b::types::Reference;
+use log::error;
+use serde_json::Value;
+use url::form_urlencoded;
+
+use crate::fetch::{is_session_expired, is_valid_id, references_patient};
+use crate::state::{SessionLookup, State};
+
+/**
+ * FHIR proxy
+ * ----------
+ * Forwards a read or search to the FHIR server, using the token stored for the
+ * session, and returns the FHIR server's response. This lets downstream services that
+ * the user has consented to share data with work with FHIR resources without the
+ * token ever being exposed to the browser.
+ *
+ * The path is relative to the FHIR base URL, and may be a search (`Observation`), a
+ * read (`Observation/123`), or a version read (`Observation/123/_history/2`). Query
+ * parameters are passed through, e.g. `/{patient_id}/proxy/Observation?patient=123`.
+ * If the patient has sessions from several issuers, the `iss` parameter picks one,
+ * and is not passed through. Only resource types listed in
+ * `FHIR_EXAMPLE_PROXY_RESOURCE_TYPES` may be proxied, and only if the session's token
+ * was granted a scope to read them. If no resource types are listed, the proxy is
+ * disabled.
+ *
+ * The proxy only reaches the data of the patient in the path, whose session it uses:
+ * searches must filter on the patient with a `patient` or `subject` parameter (or
+ * `_id`, for Patient searches), and reads must return the patient, or a resource
+ * whose `subject` or `patient` is the patient. Search results are streamed back as
+ * the FHIR server sends them; reads are checked before they are sent back.
+ */
+#[get("/{patient_id}/proxy/{path:.*}")]
+pub async fn proxy(
+    req: HttpRequest,
+    data: web::Data<State>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (patient_id, path) = path.into_inner();
+    if data.proxy_resource_types.is_empty() {
+        return HttpResponse::NotFound().body("The FHIR proxy is disabled.");
+    }
+
+    let Some(proxied) = ProxiedPath::parse(&path) else {
+        return HttpResponse::BadRequest().body(format!("Cannot proxy FHIR path {path}."));
+    };
+    let resource_type = proxied.resource_type;
+    if !data
+        .proxy_resource_types
+        .iter()
+        .any(|allowed| allowed == resource_type)
+    {
+        return HttpResponse::Forbidden()
+            .body(format!("{resource_type} resources may not be proxied."));
+    }
+
+    // the issuer picks the session, and is not meant for the FHIR server
+    let (iss, params): (Vec<_>, Vec<_>) = form_urlencoded::parse(req.query_string().as_bytes())
+        .into_owned()
+        .partition(|(name, _)| name == "iss");
+    let client = match data.lookup_token(&patient_id, iss.first().map(|(_, iss)| iss.as_str())) {
+        SessionLookup::Found(client) => client,
+        SessionLookup::Ambiguous(_) => {
+            return HttpResponse::Conflict().body(format!(
+                "Patient {patient_id} has sessions with several servers; pass iss to choose one."
+            ));
+        }
+        SessionLookup::Missing => {
+            return HttpResponse::Unauthorized()
+                .body(format!("Failed to find token for {patient_id}."));
+        }
+    };
+    let Some(session_patient) = client.patient.as_deref() else {
+        return HttpResponse::Forbidden().body("This session does not have a patient in context.");
+    };
+    if !client.can_read(resource_type) {
+        return HttpResponse::Forbidden().body(format!(
+            "Session for {patient_id} is not authorized to read {resource_type} resources."
+        ));
+    }
+    if !proxied.is_limited_to(session_patient, &params) {
+        return HttpResponse::Forbidden().body(format!(
+            "Proxied requests must be limited to the data of patient {patient_id}."
+        ));
+    }
+
+    let mut url = format!(
+        "{}/{path}",
+        client.base_url_for(resource_type).trim_end_matches('/')
+    );
+    if !params.is_empty() {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(&params)
+            .finish();
+        url = format!("{url}?{query}");
+    }
+
+    // a rejected token counts towards the session's breaker
+    let request = async {
+        let response = client.get_streamed(&url).await?;
+        match response.status() {
+            reqwest::StatusCode::UNAUTHORIZED => Err(Error::Response(
+                response.status(),
+                String::from("the FHIR server rejected our token"),
+            )),
+            _ => Ok(response),
+        }
+    };
+    let response = match client.limiter.run_request(request).await {
+        Ok(response) => response,
+        Err(e) if is_session_expired(&e) => {
+            return HttpResponse::Unauthorized()
+                .body(format!("Session for {patient_id} has expired."));
+        }
+        Err(e) => {
+            error!("Proxying {path} failed with error: {:?}", e);
+            return HttpResponse::BadGateway()
+                .body(format!("Failed to read {path} from the FHIR server."));
+        }
+    };
+
+    let status =
+        StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    if status.is_server_error() || status.is_informational() {
+        error!("Proxying {path} failed with status {status}");
+        return HttpResponse::BadGateway()
+            .body(format!("Failed to read {path} from the FHIR server."));
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("application/fhir+json")
+        .to_string();
+
+    if !status.is_success() || !proxied.is_read() {
+        return HttpResponse::build(status)
+            .content_type(content_type)
+            .streaming(response.bytes_stream());
+    }
+
+    // a read names its resource directly, so we check that it is the patient's
+    // before sending it on
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Reading proxied {path} failed with error: {:?}", e);
+            return HttpResponse::BadGateway()
+                .body(format!("Failed to read {path} from the FHIR server."));
+        }
+    };
+    let belongs_to_patient = serde_json::from_slice::<Value>(&body)
+        .is_ok_and(|resource| belongs_to_patient(&resource, session_patient));
+    if !belongs_to_patient {
+        return HttpResponse::Forbidden()
+            .body(format!("{path} does not belong to patient {patient_id}."));
+    }
+    HttpResponse::build(status)
+        .content_type(content_type)
+        .body(body)
+}
+
+// A path that we may proxy.
+struct ProxiedPath<'a> {
+    resource_type: &'a str,
+
+    // The ID of the resource, if the path is a read rather than a search.
+    id: Option<&'a str>,
+}
+
+impl<'a> ProxiedPath<'a> {
+    // Parses a path that we may proxy.
+    //
+    // We only proxy searches (`Type`), reads (`Type/id`), and version reads
+    // (`Type/id/_history/vid`), so that a path cannot reach operations or other
+    // servers. Returns an empty option if the path has any other form.
+    //
+    // # Arguments
+    // * `path` The path, relative to the FHIR base URL.
+    fn parse(path: &'a str) -> Option<ProxiedPath<'a>> {
+        let mut segments = path.split('/');
+        let resource_type = segments.next()?;
+        if !resource_type.starts_with(|c: char| c.is_ascii_uppercase())
+            || !resource_type.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return None;
+        }
+
+        let id = match segments.collect::<Vec<&str>>()[..] {
+            [] => None,
+            [id] if is_valid_id(id) => Some(id),
+            [id, "_history", version] if is_valid_id(id) && is_valid_id(version) => Some(id),
+            _ => return None,
+        };
+        Some(ProxiedPath { resource_type, id })
+    }
+
+    // Checks whether the path reads a resource, rather than searching.
+    fn is_read(&self) -> bool {
+        self.id.is_some()
+    }
+
+    // Checks whether a request for this path is limited to a patient's data, before
+    // it is sent.
+    //
+    // Searches must filter on the patient, and may not filter on other patients,
+    // include other resources (`_include`, `_revinclude`), or filter on them (`_has`).
+    // Reads of the Patient resource must name the patient; other reads are checked
+    // once the resource is returned (see `belongs_to_patient`).
+    //
+    // # Arguments
+    // * `patient_id` The ID of the session's patient.
+    // * `params` The query parameters that will be sent to the FHIR server.
+    fn is_limited_to(&self, patient_id: &str, params: &[(String, String)]) -> bool {
+        if let Some(id) = self.id {
+            return self.resource_type != "Patient" || id == patient_id;
+        }
+
+        let patient_params: &[&str] = match self.resource_type {
+            "Patient" => &["_id"],
+            _ => &["patient", "subject"],
+        };
+        let mut filtered = false;
+        for (name, value) in params {
+            // modifiers and chains, e.g. `subject:missing` or `subject.name`, could
+            // reach other patients
+            let base_name = name.split([':', '.']).next().unwrap_or(name);
+            // so could resources that are included alongside the matches, or that
+            // the matches are filtered on
+            if ["_include", "_revinclude", "_has"].contains(&base_name) {
+                return false;
+            }
+            if !patient_params.contains(&base_name) {
+                continue;
+            }
+            if name != base_name
+                || !value
+                    .split(',')
+                    .all(|value| references_patient(patient_reference(value).as_ref(), patient_id))
+            {
+                return false;
+            }
+            filtered = true;
+        }
+        filtered
+    }
+}
+
+// Reads a search parameter value that names a patient as a reference, e.g. "123" or
+// "Patient/123" as `Patient/123`.
+//
+// # Arguments
+// * `value` The search parameter value.
+fn patient_reference(value: &str) -> Option<Reference> {
+    let reference = if value.contains('/') {
+        value.to_string()
+    } else {
+        format!("Patient/{value}")
+    };
+    Reference::builder().reference(reference).build().ok()
+}
+
+// Checks whether a resource that we read belongs to a patient: it is the patient, or
+// its `subject` or `patient` refers to them.
+//
+// # Arguments
+// * `resource` The resource, as FHIR JSON.
+// * `patient_id` The ID of the patient.
+fn belongs_to_patient(resource: &Value, patient_id: &str) -> bool {
+    if resource["resourceType"] == "Patient" {
+        return resource["id"] == patient_id;
+    }
+
+    ["subject", "patient"].iter().any(|field| {
+        let reference = serde_json::from_value::<Reference>(resource[field].clone()).ok();
+        references_patient(reference.as_ref(), patient_id)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::{test, App};
+    use serde_json::json;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::test_support::{put_session, search_bundle, test_state};
+
+    // Sends a request through the proxy, for a session with a mock EHR that may
+    // proxy Patient and Observation resources.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `uri` The URI of the request, e.g. "/123/proxy/Observation/ldl".
+    async fn get_proxied(ehr: &MockServer, uri: &str) -> actix_web::dev::ServiceResponse {
+        let state = test_state()
+            .with_proxy_resource_types(vec![String::from("Patient"), String::from("Observation")]);
+        put_session(&state, ehr, "123", &["patient/*.read"]).await;
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(proxy)).await;
+
+        let req = test::TestRequest::get().uri(uri).to_request();
+        test::call_service(&app, req).await
+    }
+
+    // Mounts an Observation for a patient on a mock EHR.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `id` The ID of the observation.
+    // * `patient_id` The ID of the patient the observation is about.
+    async fn mock_observation(ehr: &MockServer, id: &str, patient_id: &str) {
+        Mock::given(method("GET"))
+            .and(path(format!("/Observation/{id}")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Observation",
+                "id": id,
+                "status": "final",
+                "code": { "text": "LDL" },
+                "subject": { "reference": format!("Patient/{patient_id}") }
+            })))
+            .mount(ehr)
+            .await;
+    }
+
+    // Gets the requests that a mock EHR received for a resource type.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `resource_type` The resource type, e.g. "Observation".
+    async fn requests_for(ehr: &MockServer, resource_type: &str) -> Vec<wiremock::Request> {
+        ehr.received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|request| request.url.path().starts_with(&format!("/{resource_type}")))
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn proxied_observation_read_returns_upstream_body() {
+        let ehr = MockServer::start().await;
+        mock_observation(&ehr, "ldl", "123").await;
+
+        let resp = get_proxied(&ehr, "/123/proxy/Observation/ldl").await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["id"], "ldl");
+        assert_eq!(body["code"]["text"], "LDL");
+    }
+
+    #[actix_web::test]
+    async fn read_of_another_patients_observation_is_forbidden() {
+        let ehr = MockServer::start().await;
+        mock_observation(&ehr, "ldl", "456").await;
+
+        let resp = get_proxied(&ehr, "/123/proxy/Observation/ldl").await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn read_of_another_patient_is_forbidden_without_request() {
+        let ehr = MockServer::start().await;
+
+        let resp = get_proxied(&ehr, "/123/proxy/Patient/456").await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let requests = ehr.received_requests().await.unwrap();
+        assert!(!requests
+            .iter()
+            .any(|request| request.url.path() == "/Patient/456"));
+    }
+
+    #[actix_web::test]
+    async fn search_for_the_patient_is_streamed_without_issuer() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/Observation"))
+            .and(query_param("patient", "Patient/123"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(search_bundle(vec![json!({
+                    "resourceType": "Observation",
+                    "status": "final",
+                    "code": { "text": "LDL" },
+                    "subject": { "reference": "Patient/123" }
+                })])),
+            )
+            .mount(&ehr)
+            .await;
+
+        let resp = get_proxied(
+            &ehr,
+            &format!(
+                "/123/proxy/Observation?patient=Patient/123&iss={}",
+                ehr.uri()
+            ),
+        )
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["total"], 1);
+        let requests = requests_for(&ehr, "Observation").await;
+        assert_eq!(requests[0].url.query(), Some("patient=Patient%2F123"));
+    }
+
+    #[actix_web::test]
+    async fn search_must_filter_on_the_patient() {
+        let ehr = MockServer::start().await;
+
+        for uri in [
+            "/123/proxy/Observation?code=2085-9",
+            "/123/proxy/Observation?patient=456",
+            "/123/proxy/Observation?subject=Patient/123,Patient/456",
+            "/123/proxy/Observation?patient=123&subject:missing=true",
+            "/123/proxy/Observation?patient=123&_include=Observation:performer",
+            "/123/proxy/Observation?patient=123&_revinclude:iterate=Provenance:target",
+            "/123/proxy/Patient?_id=123&_has:Observation:patient:code=2085-9",
+        ] {
+            let resp = get_proxied(&ehr, uri).await;
+
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{uri}");
+        }
+        assert!(requests_for(&ehr, "Observation").await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn disallowed_path_is_rejected() {
+        let ehr = MockServer::start().await;
+
+        let disallowed_type = get_proxied(&ehr, "/123/proxy/Condition?patient=123").await;
+        let operation = get_proxied(&ehr, "/123/proxy/Patient/123/$everything").await;
+
+        assert_eq!(disallowed_type.status(), StatusCode::FORBIDDEN);
+        assert_eq!(operation.status(), StatusCode::BAD_REQUEST);
+        assert!(requests_for(&ehr, "Condition").await.is_empty());
+        assert!(!ehr
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .any(|request| request.url.path().contains("$everything")));
+    }
+}
@@ -0,0 +1,1043 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::HttpResponse;
+use fhir_sdk::r4b::resources::Patient;
+use log::error;
+use maud::{html, Markup, PreEscaped, DOCTYPE};
+use printpdf::{
+    BuiltinFont, IndirectFontRef, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference,
+};
+use serde::Serialize;
+
+use crate::care_plan::{CarePlanSummary, GoalSummary};
+use crate::context::LaunchContext;
+use crate::diagnostic_report::ReportSummary;
+use crate::display::display_date;
+use crate::error::AppError;
+use crate::smart::style::SmartStyle;
+use crate::summary::{medical_record_number, Measurement, PatientDetails, PatientSummary};
+
+// Renders a patient summary in a specific format.
+//
+// Each format shares the data fetched for the summary, so that the HTML page and
+// exports stay consistent with each other.
+pub trait SummaryRenderer {
+    // The content type of the rendered summary.
+    fn content_type(&self) -> &'static str;
+
+    // Renders a patient summary.
+    //
+    // Returns an error if the summary cannot be rendered in the format.
+    //
+    // # Arguments
+    // * `summary` The data to render.
+    // * `context` The context of the launch the summary was fetched for.
+    fn render(
+        &self,
+        summary: &PatientSummary,
+        context: &LaunchContext,
+    ) -> Result<Vec<u8>, AppError>;
+
+    // Renders the response for a session that has expired, so that clients can tell
+    // the user to relaunch the app.
+    //
+    // Returns an empty option if the format has no specific response for expired
+    // sessions.
+    //
+    // # Arguments
+    // * `relaunch_url` The URL to relaunch the app from.
+    fn render_session_expired(&self, _relaunch_url: &str) -> Option<HttpResponse> {
+        None
+    }
+
+    // Renders the response for a patient that the FHIR server did not return.
+    //
+    // Returns an empty option if the format has no specific response for missing
+    // patients, in which case we show the not found error page.
+    //
+    // # Arguments
+    // * `patient_id` The ID of the patient that was not found.
+    // * `relaunch_url` The URL to relaunch the app from.
+    fn render_patient_not_found(
+        &self,
+        _patient_id: &str,
+        _relaunch_url: &str,
+    ) -> Option<HttpResponse> {
+        None
+    }
+
+    // The filename to suggest when saving the rendered summary.
+    //
+    // Returns an empty option if the format is meant to be displayed in the browser
+    // rather than downloaded.
+    //
+    // # Arguments
+    // * `summary` The summary being rendered.
+    fn filename(&self, _summary: &PatientSummary) -> Option<String> {
+        None
+    }
+}
+
+// Renders a patient summary as an HTML page.
+pub struct HtmlRenderer;
+
+impl SummaryRenderer for HtmlRenderer {
+    fn content_type(&self) -> &'static str {
+        "text/html; charset=utf-8"
+    }
+
+    fn render(
+        &self,
+        summary: &PatientSummary,
+        context: &LaunchContext,
+    ) -> Result<Vec<u8>, AppError> {
+        Ok(render_page(summary, context).into_string().into_bytes())
+    }
+}
+
+// Generates the HTML for the queried patient and observations.
+//
+// # Arguments
+// * `summary` The data to render.
+// * `context` The context of the launch, e.g. the EHR and user.
+#[rustfmt::skip::macros(html)]
+fn render_page(summary: &PatientSummary, context: &LaunchContext) -> Markup {
+    let details = summary.details();
+    let measurements = summary.measurements();
+    let practitioner = summary.practitioner.as_ref();
+    let user_name = practitioner
+        .and_then(|practitioner| practitioner.name.as_deref())
+        .or(context.user_name.as_deref());
+    let user_role = practitioner.and_then(|practitioner| practitioner.role.as_deref());
+
+    let stylesheet = context.style.as_ref().and_then(SmartStyle::stylesheet);
+
+    html! {
+	(DOCTYPE);
+	html lang="en" {
+            head {
+		title {
+		    "Example SMART-on-FHIR app"
+		}
+		@if let Some(stylesheet) = stylesheet {
+		    style {
+			(PreEscaped(stylesheet))
+		    }
+		}
+            }
+            body {
+		div #holder {
+		    h1 {
+			"Example SMART-on-FHIR app"
+		    }
+		    p #brand {
+			@if let Some(logo) = context.logo() {
+			    img src=(logo) alt="" height="32";
+			    " "
+			}
+			"Connected to "
+			(context.connected_to())
+		    }
+		    @if let Some(user) = user_name {
+			p #user {
+			    "Logged in as "
+			    (user)
+			    @if let Some(role) = user_role {
+				", "
+				(role)
+			    }
+			    " ("
+			    a #logout href=(context.logout_path()) {
+				"log out"
+			    }
+			    ")"
+			}
+		    }
+		    @if context.need_patient_banner {
+			(render_patient_banner(&summary.patient))
+		    }
+		    @if !summary.timed_out.is_empty() {
+			p #timed-out {
+			    "Some data took too long to load, and is not shown: "
+			    (summary.timed_out.join(", "))
+			    ". Reload the page to try again."
+			}
+		    }
+		    p #export {
+			"Export: "
+			a href=(context.summary_path("json")) {
+			    "JSON"
+			}
+			" | "
+			a href=(context.summary_path("csv")) {
+			    "CSV"
+			}
+		    }
+		    section #patient {
+			h2 {
+			    "Patient resource"
+			}
+			table {
+			    tbody {
+				@if let Some(first_name) = &details.first_name {
+				    tr {
+					th {
+					    "First name:"
+					}
+					td #fname {
+					    (first_name)
+					}
+				    }
+				}
+				@if let Some(last_name) = &details.last_name {
+				    tr {
+					th {
+					    "Last name:"
+					}
+					td #lname {
+					    (last_name)
+					}
+				    }
+				}
+				@if let Some(gender) = &details.gender {
+				    tr {
+					th {
+					    "Gender:"
+					}
+					td #gender {
+					    (gender)
+					}
+				    }
+				}
+				@if let Some(phone) = &details.phone {
+				    tr {
+					th {
+					    "Phone:"
+					}
+					td #phone {
+					    (phone)
+					}
+				    }
+				}
+				@if let Some(email) = &details.email {
+				    tr {
+					th {
+					    "Email:"
+					}
+					td #email {
+					    (email)
+					}
+				    }
+				}
+				@if !details.address.is_empty() {
+				    tr {
+					th {
+					    "Address:"
+					}
+					td #address {
+					    @for (i, line) in details.address.iter().enumerate() {
+						@if i > 0 {
+						    br;
+						}
+						(line)
+					    }
+					}
+				    }
+				}
+				@if let Some(birth_date) = &details.birth_date {
+				    tr {
+					th {
+					    "Date of birth:"
+					}
+					td #birthdate {
+					    (birth_date)
+					}
+				    }
+				}
+				@if !summary.general_practitioners.is_empty() {
+				    tr {
+					th {
+					    "General practitioner:"
+					}
+					td #general-practitioner {
+					    (summary.general_practitioners.join(", "))
+					}
+				    }
+				}
+			    }
+			}
+		    }
+		    section #observation {
+			h2 {
+			    "Observation resource"
+			}
+			table {
+			    tbody {
+				@for measurement in &measurements {
+				    tr {
+					th {
+					    (measurement.name) ":"
+					}
+					td id=(measurement.id) {
+					    (measurement.value)
+					    @if let Some(total) = measurement.display_total() {
+						" "
+						small {
+						    (total)
+						}
+					    }
+					    @if let Some(note) = &measurement.note {
+						br;
+						small .note {
+						    (note)
+						}
+					    }
+					}
+				    }
+				}
+			    }
+			}
+		    }
+		    @if !summary.medications.is_empty() {
+			section #medications {
+			    h2 {
+				"Medication requests"
+			    }
+			    ul {
+				@for medication in &summary.medications {
+				    li {
+					(medication)
+				    }
+				}
+			    }
+			}
+		    }
+		    @if !summary.diagnostic_reports.is_empty() {
+			section #diagnostic-reports {
+			    h2 {
+				"Diagnostic reports"
+			    }
+			    @for report in &summary.diagnostic_reports {
+				h3 {
+				    (report.name)
+				    @if let Some(effective) = &report.effective {
+					" (" (effective) ")"
+				    }
+				}
+				@if !report.results.is_empty() {
+				    table {
+					tbody {
+					    @for result in &report.results {
+						tr {
+						    th {
+							(result.name) ":"
+						    }
+						    td {
+							(result.value)
+						    }
+						}
+					    }
+					}
+				    }
+				}
+			    }
+			}
+		    }
+		    @if !summary.goals.is_empty() {
+			section #goals {
+			    h2 {
+				"Goals"
+			    }
+			    ul {
+				@for goal in &summary.goals {
+				    li {
+					(goal.description) " (" (goal.status) ")"
+				    }
+				}
+			    }
+			}
+		    }
+		    @if !summary.care_plans.is_empty() {
+			section #care-plans {
+			    h2 {
+				"Care plans"
+			    }
+			    ul {
+				@for care_plan in &summary.care_plans {
+				    li {
+					(care_plan.title) " (" (care_plan.status) ")"
+				    }
+				}
+			    }
+			}
+		    }
+		}
+            }
+	}
+    }
+}
+
+// Generates the HTML for a patient banner.
+//
+// When an app is embedded in an EHR, the EHR tells us via the token response's
+// `need_patient_banner` field whether it already displays a banner identifying the
+// patient. If it does not, we display our own.
+#[rustfmt::skip::macros(html)]
+fn render_patient_banner(patient: &Patient) -> Markup {
+    html! {
+	div #patient-banner {
+	    @if let Some(Some(name)) = patient.name.first() {
+		strong {
+		    @for given_name in name.given.iter().flatten() {
+			(given_name) " "
+		    }
+		    @if let Some(family_name) = &name.family {
+			(family_name)
+		    }
+		}
+	    }
+	    @if let Some(birth_date) = &patient.birth_date {
+		" | DOB: " (display_date(birth_date))
+	    }
+	    @if let Some(gender) = &patient.gender {
+		" | Gender: " (gender)
+	    }
+	    @if let Some(mrn) = medical_record_number(patient) {
+		" | MRN: " (mrn)
+	    }
+	}
+    }
+}
+
+// The document produced when rendering a patient summary as JSON.
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    patient_id: &'a str,
+    patient: PatientDetails,
+    general_practitioners: &'a [String],
+    measurements: Vec<Measurement>,
+    medications: &'a [String],
+    diagnostic_reports: &'a [ReportSummary],
+    goals: &'a [GoalSummary],
+    care_plans: &'a [CarePlanSummary],
+    timed_out: &'a [&'static str],
+}
+
+// Serializes a value as JSON.
+//
+// Output is compact, unless `pretty` is set, in which case it is indented for
+// reading while debugging.
+//
+// # Arguments
+// * `value` The value to serialize.
+// * `pretty` Whether to indent the output.
+pub fn to_json<T: Serialize>(value: &T, pretty: bool) -> serde_json::Result<Vec<u8>> {
+    if pretty {
+        serde_json::to_vec_pretty(value)
+    } else {
+        serde_json::to_vec(value)
+    }
+}
+
+// Renders a patient summary as a JSON document.
+pub struct JsonRenderer {
+    // Whether to indent the document.
+    pub pretty: bool,
+}
+
+impl SummaryRenderer for JsonRenderer {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn render(
+        &self,
+        summary: &PatientSummary,
+        _context: &LaunchContext,
+    ) -> Result<Vec<u8>, AppError> {
+        let document = JsonSummary {
+            patient_id: &summary.patient_id,
+            patient: summary.details(),
+            general_practitioners: &summary.general_practitioners,
+            measurements: summary.measurements(),
+            medications: &summary.medications,
+            diagnostic_reports: &summary.diagnostic_reports,
+            goals: &summary.goals,
+            care_plans: &summary.care_plans,
+            timed_out: &summary.timed_out,
+        };
+
+        to_json(&document, self.pretty).map_err(|e| {
+            error!("Serializing JSON summary failed with error: {:?}", e);
+            AppError::Internal(String::from("Failed to render the summary."))
+        })
+    }
+
+    fn render_session_expired(&self, relaunch_url: &str) -> Option<HttpResponse> {
+        Some(HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "session_expired",
+            "relaunch_url": relaunch_url,
+        })))
+    }
+
+    fn render_patient_not_found(
+        &self,
+        patient_id: &str,
+        relaunch_url: &str,
+    ) -> Option<HttpResponse> {
+        Some(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "patient_not_found",
+            "patient_id": patient_id,
+            "relaunch_url": relaunch_url,
+        })))
+    }
+}
+
+// Renders a patient summary as CSV, for export to a spreadsheet.
+//
+// Each row holds a section of the summary, the name of a field, and its value.
+pub struct CsvRenderer;
+
+impl SummaryRenderer for CsvRenderer {
+    fn content_type(&self) -> &'static str {
+        "text/csv; charset=utf-8"
+    }
+
+    fn render(
+        &self,
+        summary: &PatientSummary,
+        _context: &LaunchContext,
+    ) -> Result<Vec<u8>, AppError> {
+        let details = summary.details();
+        let mut rows: Vec<[String; 3]> = vec![[
+            "section".to_string(),
+            "name".to_string(),
+            "value".to_string(),
+        ]];
+        let mut push = |section: &str, name: &str, value: &str| {
+            rows.push([section.to_string(), name.to_string(), value.to_string()])
+        };
+
+        push("patient", "Patient ID", &summary.patient_id);
+        let patient_fields = [
+            ("First name", details.first_name),
+            ("Last name", details.last_name),
+            ("Gender", details.gender),
+            ("Phone", details.phone),
+            ("Email", details.email),
+            ("Date of birth", details.birth_date),
+        ];
+        for (name, value) in patient_fields {
+            if let Some(value) = value {
+                push("patient", name, &value);
+            }
+        }
+        if !details.address.is_empty() {
+            push("patient", "Address", &details.address.join(", "));
+        }
+        for general_practitioner in &summary.general_practitioners {
+            push("patient", "General practitioner", general_practitioner);
+        }
+
+        for measurement in summary.measurements() {
+            push("observation", measurement.name, &measurement.value);
+            if let Some(note) = &measurement.note {
+                push("observation", &format!("{} note", measurement.name), note);
+            }
+        }
+        for medication in &summary.medications {
+            push("medication", "Medication request", medication);
+        }
+        for report in &summary.diagnostic_reports {
+            for result in &report.results {
+                push(&report.name, &result.name, &result.value);
+            }
+        }
+        for goal in &summary.goals {
+            push("goal", &goal.description, &goal.status);
+        }
+        for care_plan in &summary.care_plans {
+            push("care plan", &care_plan.title, &care_plan.status);
+        }
+        for section in &summary.timed_out {
+            push("timed out", section, "");
+        }
+
+        Ok(rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|field| csv_field(field))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            })
+            .collect::<Vec<String>>()
+            .join("\r\n")
+            .into_bytes())
+    }
+}
+
+// Escapes a field for inclusion in a CSV row.
+//
+// Fields containing commas, quotes, or line breaks are quoted, with quotes doubled,
+// as described in [RFC 4180](https://www.rfc-editor.org/rfc/rfc4180). Spreadsheets
+// evaluate fields starting with `=`, `+`, `-`, `@`, a tab, or a carriage return as
+// formulas, so we prefix these with `'`, as FHIR data may be entered by anyone.
+//
+// # Arguments
+// * `field` The field to escape.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_string()
+    };
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+// Renders a patient summary as a PDF document, for sharing.
+//
+// The document is laid out from the summary's data rather than from the HTML page,
+// and holds the patient's demographics and measurements.
+pub struct PdfRenderer;
+
+impl SummaryRenderer for PdfRenderer {
+    fn content_type(&self) -> &'static str {
+        "application/pdf"
+    }
+
+    fn render(
+        &self,
+        summary: &PatientSummary,
+        context: &LaunchContext,
+    ) -> Result<Vec<u8>, AppError> {
+        let details = summary.details();
+        let mut pdf = PdfWriter::new("Patient summary").map_err(|e| {
+            error!("Creating PDF document failed with error: {:?}", e);
+            AppError::Internal(String::from("Failed to render the summary as PDF."))
+        })?;
+
+        pdf.title("Patient summary");
+        pdf.text(&format!("Connected to {}", context.connected_to()));
+
+        pdf.heading("Patient");
+        pdf.row("Patient ID", &summary.patient_id);
+        let patient_fields = [
+            ("First name", details.first_name),
+            ("Last name", details.last_name),
+            ("Gender", details.gender),
+            ("Date of birth", details.birth_date),
+            (
+                "MRN",
+                medical_record_number(&summary.patient).map(str::to_string),
+            ),
+            ("Phone", details.phone),
+            ("Email", details.email),
+        ];
+        for (name, value) in patient_fields {
+            pdf.row(name, value.as_deref().unwrap_or("Not recorded"));
+        }
+        if details.address.is_empty() {
+            pdf.row("Address", "Not recorded");
+        }
+        for (line, address_line) in details.address.iter().enumerate() {
+            pdf.row(if line == 0 { "Address" } else { "" }, address_line);
+        }
+        for general_practitioner in &summary.general_practitioners {
+            pdf.row("General practitioner", general_practitioner);
+        }
+
+        pdf.heading("Measurements");
+        let measurements = summary.measurements();
+        if measurements.is_empty() {
+            pdf.text("No measurements are available for this patient.");
+        }
+        for measurement in measurements {
+            let value = match measurement.display_total() {
+                Some(total) => format!("{} {total}", measurement.value),
+                None => measurement.value.clone(),
+            };
+            pdf.row(measurement.name, &value);
+            if let Some(note) = &measurement.note {
+                pdf.row("", &format!("Note: {note}"));
+            }
+        }
+
+        if !summary.timed_out.is_empty() {
+            pdf.heading("Timed out");
+            pdf.text(&format!(
+                "These sections did not load in time, and are left out: {}",
+                summary.timed_out.join(", ")
+            ));
+        }
+
+        pdf.finish().map_err(|e| {
+            error!("Saving PDF document failed with error: {:?}", e);
+            AppError::Internal(String::from("Failed to render the summary as PDF."))
+        })
+    }
+
+    fn filename(&self, summary: &PatientSummary) -> Option<String> {
+        // FHIR IDs are limited to letters, digits, dashes, and periods, but we do not
+        // trust the server to enforce this
+        let patient_id: String = summary
+            .patient_id
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.'))
+            .collect();
+        Some(format!("patient-summary-{patient_id}.pdf"))
+    }
+}
+
+// The width of an A4 page.
+const PDF_PAGE_WIDTH: Mm = Mm(210.0);
+
+// The height of an A4 page.
+const PDF_PAGE_HEIGHT: Mm = Mm(297.0);
+
+// The margin around the text on each page, in millimeters.
+const PDF_MARGIN: f32 = 20.0;
+
+// Where the values of rows start, in millimeters from the left margin.
+const PDF_VALUE_OFFSET: f32 = 55.0;
+
+// Writes lines of text into a PDF document, from the top of the page down, starting
+// a new page when the current one is full.
+struct PdfWriter {
+    document: PdfDocumentReference,
+    layer: PdfLayerReference,
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+
+    // The position of the next line, in millimeters from the bottom of the page.
+    y: f32,
+}
+
+impl PdfWriter {
+    // Creates a document with a single, empty page.
+    //
+    // # Arguments
+    // * `title` The title of the document.
+    fn new(title: &str) -> Result<PdfWriter, printpdf::Error> {
+        let (document, page, layer) =
+            PdfDocument::new(title, PDF_PAGE_WIDTH, PDF_PAGE_HEIGHT, "Summary");
+        let regular = document.add_builtin_font(BuiltinFont::Helvetica)?;
+        let bold = document.add_builtin_font(BuiltinFont::HelveticaBold)?;
+        let layer = document.get_page(page).get_layer(layer);
+
+        Ok(PdfWriter {
+            document,
+            layer,
+            regular,
+            bold,
+            y: PDF_PAGE_HEIGHT.0 - PDF_MARGIN,
+        })
+    }
+
+    // Moves down by a line, starting a new page if the line does not fit.
+    //
+    // # Arguments
+    // * `height` The height of the line, in millimeters.
+    fn advance(&mut self, height: f32) -> Mm {
+        if self.y - height < PDF_MARGIN {
+            let (page, layer) = self
+                .document
+                .add_page(PDF_PAGE_WIDTH, PDF_PAGE_HEIGHT, "Summary");
+            self.layer = self.document.get_page(page).get_layer(layer);
+            self.y = PDF_PAGE_HEIGHT.0 - PDF_MARGIN;
+        }
+        self.y -= height;
+        Mm(self.y)
+    }
+
+    // Writes the title of the document.
+    fn title(&mut self, text: &str) {
+        let y = self.advance(10.0);
+        self.layer
+            .use_text(text, 18.0, Mm(PDF_MARGIN), y, &self.bold);
+    }
+
+    // Writes the heading of a section, with space above it.
+    fn heading(&mut self, text: &str) {
+        self.advance(4.0);
+        let y = self.advance(8.0);
+        self.layer
+            .use_text(text, 14.0, Mm(PDF_MARGIN), y, &self.bold);
+    }
+
+    // Writes a line of text.
+    fn text(&mut self, text: &str) {
+        let y = self.advance(6.0);
+        self.layer
+            .use_text(text, 10.0, Mm(PDF_MARGIN), y, &self.regular);
+    }
+
+    // Writes a row of a two column table.
+    //
+    // # Arguments
+    // * `name` The name of the field, in the first column.
+    // * `value` The value of the field, in the second column.
+    fn row(&mut self, name: &str, value: &str) {
+        let y = self.advance(6.0);
+        self.layer
+            .use_text(name, 10.0, Mm(PDF_MARGIN), y, &self.bold);
+        self.layer.use_text(
+            value,
+            10.0,
+            Mm(PDF_MARGIN + PDF_VALUE_OFFSET),
+            y,
+            &self.regular,
+        );
+    }
+
+    // Serializes the document.
+    fn finish(self) -> Result<Vec<u8>, printpdf::Error> {
+        self.document.save_to_bytes()
+    }
+}
+
+// Selects a renderer by the extension of the requested path.
+//
+// Returns an empty option if we cannot render summaries in the format.
+//
+// # Arguments
+// * `extension` The extension, e.g. "json".
+// * `pretty` Whether to indent JSON documents.
+pub fn renderer_for_extension(extension: &str, pretty: bool) -> Option<Box<dyn SummaryRenderer>> {
+    match extension {
+        "html" => Some(Box::new(HtmlRenderer)),
+        "json" => Some(Box::new(JsonRenderer { pretty })),
+        "csv" => Some(Box::new(CsvRenderer)),
+        "pdf" => Some(Box::new(PdfRenderer)),
+        _ => None,
+    }
+}
+
+// Selects a renderer using the `Accept` header of a request.
+//
+// Media ranges are considered in the order they are listed, and the first one that we
+// can render is used. We fall back to HTML if we cannot render any of them.
+//
+// # Arguments
+// * `accept` The value of the `Accept` header, if one was sent.
+// * `pretty` Whether to indent JSON documents.
+pub fn renderer_for_accept(accept: Option<&str>, pretty: bool) -> Box<dyn SummaryRenderer> {
+    accept
+        .into_iter()
+        .flat_map(|accept| accept.split(','))
+        .filter_map(|media_range| media_range.split(';').next())
+        .find_map(|media_type| match media_type.trim() {
+            "text/html" => Some(Box::new(HtmlRenderer) as Box<dyn SummaryRenderer>),
+            "application/json" => Some(Box::new(JsonRenderer { pretty })),
+            "text/csv" => Some(Box::new(CsvRenderer)),
+            "application/pdf" => Some(Box::new(PdfRenderer)),
+            _ => None,
+        })
+        .unwrap_or_else(|| Box::new(HtmlRenderer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fhir_sdk::r4b::resources::Observation;
+    use serde_json::{json, Value};
+
+    use crate::observation::default_value_precision;
+    use crate::smart::brand::Brand;
+
+    // Builds a summary for a patient with a height measurement, a medication, and
+    // a goal.
+    fn summary() -> PatientSummary {
+        let patient: Patient = serde_json::from_value(json!({
+            "resourceType": "Patient",
+            "id": "123",
+            "name": [{ "family": "Chalmers", "given": ["Peter"] }]
+        }))
+        .unwrap();
+        let height: Observation = serde_json::from_value(json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "8302-2" }]
+            },
+            "valueQuantity": { "value": 180, "unit": "cm" }
+        }))
+        .unwrap();
+
+        PatientSummary {
+            patient_id: String::from("123"),
+            patient,
+            general_practitioners: vec![],
+            practitioner: None,
+            blood_pressure: Ok((vec![], None)),
+            height: Ok((vec![height], None)),
+            ldl: Ok((vec![], None)),
+            hdl: Ok((vec![], None)),
+            medications: vec![String::from("Aspirin 81 MG")],
+            diagnostic_reports: vec![],
+            goals: vec![GoalSummary {
+                description: String::from("Walk daily"),
+                status: String::from("active"),
+            }],
+            care_plans: vec![],
+            timed_out: vec![],
+            value_precision: default_value_precision(),
+        }
+    }
+
+    // Builds the context of an EHR launch for patient 123.
+    fn context() -> LaunchContext {
+        LaunchContext {
+            session_key: String::from("123"),
+            iss: String::from("https://ehr.example.com/fhir"),
+            patient: Some(String::from("123")),
+            encounter: None,
+            fhir_context: vec![],
+            user_name: None,
+            practitioner: None,
+            scopes: vec![String::from("patient/*.read")],
+            brand: None,
+            need_patient_banner: false,
+            style_url: None,
+            style: None,
+            ehr_launch: true,
+        }
+    }
+
+    // Renders the summary with a renderer, as text.
+    //
+    // # Arguments
+    // * `renderer` The renderer to use.
+    fn render(renderer: &dyn SummaryRenderer) -> String {
+        String::from_utf8(renderer.render(&summary(), &context()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn formats_render_the_same_summary() {
+        let height = summary().measurements().remove(0).value;
+        let html = render(&HtmlRenderer);
+        let json: Value = serde_json::from_str(&render(&JsonRenderer { pretty: false })).unwrap();
+        let csv = render(&CsvRenderer);
+
+        for expected in ["Peter", "Chalmers", &height, "Aspirin 81 MG", "Walk daily"] {
+            assert!(html.contains(expected), "HTML is missing {expected}");
+            assert!(csv.contains(expected), "CSV is missing {expected}");
+        }
+        assert_eq!(json["patient"]["first_name"], "Peter");
+        assert_eq!(json["patient"]["last_name"], "Chalmers");
+        assert_eq!(json["measurements"][0]["value"], height.as_str());
+        assert_eq!(json["medications"][0], "Aspirin 81 MG");
+        assert_eq!(json["goals"][0]["description"], "Walk daily");
+        assert!(csv.contains(&format!("observation,Height,{height}")));
+    }
+
+    #[test]
+    fn renderer_is_selected_by_extension_or_accept_header() {
+        assert_eq!(
+            renderer_for_extension("csv", false).unwrap().content_type(),
+            "text/csv; charset=utf-8"
+        );
+        assert!(renderer_for_extension("xml", false).is_none());
+        assert_eq!(
+            renderer_for_accept(Some("application/xml, application/json;q=0.9"), false)
+                .content_type(),
+            "application/json"
+        );
+        assert_eq!(
+            renderer_for_accept(None, false).content_type(),
+            "text/html; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn csv_fields_cannot_start_formulas() {
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\t=1"), "'\t=1");
+        assert_eq!(csv_field("\r=1"), "\"'\r=1\"");
+        assert_eq!(csv_field("120 mg/dL"), "120 mg/dL");
+    }
+
+    #[test]
+    fn pdf_renders_summary_without_measurements() {
+        let pdf = PdfRenderer.render(&summary(), &context()).unwrap();
+
+        assert!(pdf.starts_with(b"%PDF-"));
+        assert!(pdf.len() > 1000);
+    }
+
+    #[test]
+    fn page_renders_launch_context() {
+        let context = LaunchContext {
+            user_name: Some(String::from("Dr. Jane Smith")),
+            brand: Some(Brand {
+                name: String::from("Example Health"),
+                logo: Some(String::from("https://ehr.example.com/logo.png")),
+            }),
+            need_patient_banner: true,
+            ..context()
+        };
+
+        let page = render_page(&summary(), &context).into_string();
+
+        assert!(page.contains("Connected to Example Health"));
+        assert!(page.contains(r#"<img src="https://ehr.example.com/logo.png""#));
+        assert!(page.contains("Logged in as Dr. Jane Smith"));
+        assert!(page.contains(r#"<a id="logout" href="/logout/123">"#));
+        assert!(page.contains(r#"id="patient-banner""#));
+    }
+
+    #[test]
+    fn page_falls_back_to_issuer_without_brand() {
+        let page = render_page(&summary(), &context()).into_string();
+
+        assert!(page.contains("Connected to https://ehr.example.com/fhir"));
+        assert!(!page.contains(r#"id="user""#));
+        assert!(!page.contains(r#"id="patient-banner""#));
+    }
+
+    #[test]
+    fn measurement_note_renders_escaped_beneath_value() {
+        let mut summary = summary();
+        let mut height: Observation = serde_json::from_value(json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "8302-2" }]
+            },
+            "valueQuantity": { "value": 180, "unit": "cm" },
+            "note": [{ "text": "Measured <standing>" }]
+        }))
+        .unwrap();
+        summary.height = Ok((vec![height.clone()], None));
+
+        let with_note = render_page(&summary, &context()).into_string();
+        height.note = vec![];
+        summary.height = Ok((vec![height], None));
+        let without_note = render_page(&summary, &context()).into_string();
+
+        assert!(with_note.contains(r#"<br><small class="note">Measured &lt;standing&gt;</small>"#));
+        assert!(!without_note.contains(r#"class="note""#));
+    }
+}
@@ -0,0 +1,124 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage};
+use uuid::Uuid;
+
+// The header used to propagate request IDs.
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// The ID of a request, stored in the request's extensions.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+// Checks whether a client-provided request ID is safe to log and echo back.
+//
+// We only accept short IDs made up of alphanumeric characters and '-', '_',
+// and '.', so that clients cannot inject content into our logs.
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= 128
+        && request_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// Middleware that assigns an ID to every request.
+//
+// If the client provided a valid `X-Request-Id` header, we reuse that ID; otherwise,
+// we generate a new UUID. The ID is stored in the request's extensions as a
+// `RequestId`, and is echoed back in the `X-Request-Id` response header, so that
+// it can be included in our access logs and correlated with client reports.
+pub async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_valid_request_id(value))
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let mut res = next.call(req).await?;
+
+    // the request ID is either a UUID or has been validated, so it is always a valid header
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(X_REQUEST_ID, value);
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::middleware::from_fn;
+    use actix_web::{test, web, App, HttpResponse};
+
+    // Sends a request through the middleware, returning the `X-Request-Id` response
+    // header.
+    //
+    // # Arguments
+    // * `supplied` The `X-Request-Id` request header to send, if any.
+    async fn echoed_request_id(supplied: Option<&str>) -> String {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(request_id))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let mut req = test::TestRequest::get().uri("/");
+        if let Some(supplied) = supplied {
+            req = req.insert_header((X_REQUEST_ID, supplied));
+        }
+
+        let resp = test::call_service(&app, req.to_request()).await;
+        resp.headers()
+            .get(&X_REQUEST_ID)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[actix_web::test]
+    async fn supplied_request_id_is_echoed() {
+        assert_eq!(echoed_request_id(Some("abc-123")).await, "abc-123");
+    }
+
+    #[actix_web::test]
+    async fn missing_request_id_is_generated() {
+        let generated = echoed_request_id(None).await;
+
+        assert!(Uuid::parse_str(&generated).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn unsafe_request_id_is_replaced() {
+        let generated = echoed_request_id(Some("abc\tinjected")).await;
+
+        assert!(Uuid::parse_str(&generated).is_ok());
+    }
+}
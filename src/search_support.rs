@@ -0,0 +1,179 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fhir_sdk::client::Client as FhirClient;
+use fhir_sdk::client::{Error, FhirR4B};
+use fhir_sdk::r4b::resources::CapabilityStatement;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+// The search parameters that a FHIR server supports, per resource type.
+//
+// Taken from the server's [capability statement](http://hl7.org/fhir/R4B/capabilitystatement.html),
+// which lists the search parameters for each resource type under
+// `rest.resource.searchParam`.
+#[derive(Clone, Debug, Default)]
+pub struct SearchSupport {
+    search_params: HashMap<String, HashSet<String>>,
+}
+
+impl SearchSupport {
+    // Collects the supported search parameters from a capability statement.
+    //
+    // # Arguments
+    // * `statement` The server's capability statement.
+    pub fn from_statement(statement: &CapabilityStatement) -> SearchSupport {
+        let mut search_params: HashMap<String, HashSet<String>> = HashMap::new();
+
+        for rest in statement.rest.iter().flatten() {
+            for resource in rest.resource.iter().flatten() {
+                search_params
+                    .entry(resource.r#type.to_string())
+                    .or_default()
+                    .extend(
+                        resource
+                            .search_param
+                            .iter()
+                            .flatten()
+                            .map(|param| param.name.clone()),
+                    );
+            }
+        }
+
+        SearchSupport { search_params }
+    }
+
+    // Checks whether the server supports searching a resource type by a parameter.
+    //
+    // Servers are not required to list the search parameters that they support, so
+    // if the capability statement does not list any parameters for a resource type,
+    // we assume that the parameter is supported.
+    //
+    // # Arguments
+    // * `resource_type` The type of resource to search, e.g. "Observation".
+    // * `param` The name of the search parameter, e.g. "code".
+    pub fn supports(&self, resource_type: &str, param: &str) -> bool {
+        match self.search_params.get(resource_type) {
+            Some(params) if !params.is_empty() => params.contains(param),
+            _ => true,
+        }
+    }
+
+    // Lists the parameters that the server does not support searching a resource
+    // type by.
+    //
+    // # Arguments
+    // * `resource_type` The type of resource to search, e.g. "Observation".
+    // * `params` The names of the search parameters.
+    pub fn unsupported<'a>(&self, resource_type: &str, params: &[&'a str]) -> Vec<&'a str> {
+        params
+            .iter()
+            .copied()
+            .filter(|param| !self.supports(resource_type, param))
+            .collect()
+    }
+}
+
+// A cache of the search parameters supported by FHIR servers, keyed by base URL.
+//
+// Failures to fetch a capability statement are not cached.
+#[derive(Clone, Default)]
+pub struct SearchSupportCache(Arc<Mutex<HashMap<String, SearchSupport>>>);
+
+impl SearchSupportCache {
+    // Gets the search parameters supported by a FHIR server, fetching its capability
+    // statement if it is not cached.
+    //
+    // # Arguments
+    // * `client` The FHIR client to fetch the capability statement with.
+    // * `base_url` The base URL of the FHIR server.
+    pub async fn get(
+        &self,
+        client: &FhirClient<FhirR4B>,
+        base_url: &str,
+    ) -> Result<SearchSupport, Error> {
+        if let Some(support) = self.0.lock().unwrap().get(base_url) {
+            return Ok(support.clone());
+        }
+
+        let support = SearchSupport::from_statement(&client.capabilities().await?);
+        self.0
+            .lock()
+            .unwrap()
+            .insert(base_url.to_string(), support.clone());
+        Ok(support)
+    }
+
+    // Checks whether a thread panicked while holding the lock on the cache.
+    pub fn is_poisoned(&self) -> bool {
+        self.0.is_poisoned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    // Builds the search support of a server whose capability statement lists search
+    // parameters for observations.
+    //
+    // # Arguments
+    // * `params` The names of the search parameters listed for observations.
+    fn observation_support(params: &[&str]) -> SearchSupport {
+        let search_params: Vec<_> = params
+            .iter()
+            .map(|name| json!({ "name": name, "type": "token" }))
+            .collect();
+        let statement: CapabilityStatement = serde_json::from_value(json!({
+            "resourceType": "CapabilityStatement",
+            "status": "active",
+            "date": "2024-01-01",
+            "kind": "instance",
+            "fhirVersion": "4.3.0",
+            "format": ["json"],
+            "rest": [{
+                "mode": "server",
+                "resource": [
+                    { "type": "Observation", "searchParam": search_params },
+                    { "type": "Patient" }
+                ]
+            }]
+        }))
+        .unwrap();
+        SearchSupport::from_statement(&statement)
+    }
+
+    #[test]
+    fn unlisted_search_param_is_unsupported() {
+        let support = observation_support(&["code", "subject"]);
+
+        assert_eq!(
+            support.unsupported("Observation", &["code", "subject", "date"]),
+            vec!["date"]
+        );
+    }
+
+    #[test]
+    fn resource_without_listed_params_supports_all() {
+        let support = observation_support(&["code"]);
+
+        assert!(support.supports("Patient", "birthdate"));
+        assert!(support.supports("Condition", "code"));
+    }
+}
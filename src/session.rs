@@ -0,0 +1,83 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::HttpRequest;
+use uuid::Uuid;
+
+use std::collections::VecDeque;
+
+// The name of the cookie identifying a browser session.
+pub const SESSION_COOKIE: &str = "rust_smart_fhir_session";
+
+// The maximum number of recently viewed patients we remember per browser session.
+pub const RECENT_PATIENTS_LIMIT: usize = 10;
+
+// A patient whose summary was viewed in a browser session.
+#[derive(Clone, Debug)]
+pub struct RecentPatient {
+    // The ID of the patient.
+    pub id: String,
+
+    // The name of the patient, if it is known.
+    pub name: Option<String>,
+}
+
+// The patients viewed in a browser session, most recent first.
+#[derive(Clone, Debug, Default)]
+pub struct RecentPatients(VecDeque<RecentPatient>);
+
+impl RecentPatients {
+    // Records that a patient was viewed.
+    //
+    // Moves the patient to the front of the list if they were viewed before, and
+    // forgets the least recently viewed patient if the list is full.
+    //
+    // # Arguments
+    // * `patient` The patient that was viewed.
+    pub fn push(&mut self, patient: RecentPatient) {
+        self.0.retain(|recent| recent.id != patient.id);
+        self.0.push_front(patient);
+        self.0.truncate(RECENT_PATIENTS_LIMIT);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RecentPatient> {
+        self.0.iter()
+    }
+}
+
+// Gets the ID of the browser session that sent a request, if it has one.
+//
+// # Arguments
+// * `req` The request.
+pub fn session_id(req: &HttpRequest) -> Option<Uuid> {
+    req.cookie(SESSION_COOKIE)
+        .and_then(|cookie| Uuid::parse_str(cookie.value()).ok())
+}
+
+// Builds the cookie identifying a browser session.
+//
+// # Arguments
+// * `session` The ID of the browser session.
+// * `secure` Whether the cookie should only be sent over HTTPS.
+pub fn session_cookie(session: &Uuid, secure: bool) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, session.to_string())
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .finish()
+}
@@ -14,5 +14,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod brand;
+pub mod capability;
+pub mod client_auth;
 pub mod configuration;
+pub mod id_token;
+pub mod jwks;
+pub mod revocation;
+pub mod style;
 pub mod token;
@@ -0,0 +1,278 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fhir_sdk::r4b::resources::{Bundle, Organization, Resource};
+use fhir_sdk::r4b::types::{Extension, ExtensionValue};
+use reqwest::Client;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::smart::configuration::SmartConfiguration;
+
+// The URL of the extension carrying an organization's brand, e.g. its logo.
+const ORGANIZATION_BRAND_EXTENSION: &str =
+    "http://hl7.org/fhir/StructureDefinition/organization-brand";
+
+// An identifier for the primary entry in a Brand Bundle, as a FHIR Identifier.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BrandIdentifier {
+    pub system: Option<String>,
+    pub value: Option<String>,
+}
+
+// How an EHR presents itself to users, as described by its
+// [User-access Brand](https://build.fhir.org/ig/HL7/smart-app-launch/brands.html).
+#[derive(Clone, Debug)]
+pub struct Brand {
+    // The name of the organization, e.g. "Example Health".
+    pub name: String,
+
+    // The URL of the organization's logo, if it provides one.
+    pub logo: Option<String>,
+}
+
+impl Brand {
+    // Fetches the brand for an EHR from the Brand Bundle in its SMART configuration.
+    //
+    // Returns an empty option if the EHR does not provide a Brand Bundle, or if the
+    // bundle does not name an organization.
+    //
+    // # Arguments
+    // * `client` The HTTP client to fetch the Brand Bundle with.
+    // * `config` The SMART configuration for the EHR.
+    pub async fn fetch(
+        client: &Client,
+        config: &SmartConfiguration,
+    ) -> Result<Option<Brand>, reqwest::Error> {
+        let Some(brand_bundle) = &config.user_access_brand_bundle else {
+            return Ok(None);
+        };
+
+        let bundle = client
+            .get(brand_bundle)
+            .header("Accept", "application/fhir+json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Bundle>()
+            .await?;
+
+        Ok(Self::from_bundle(
+            &bundle,
+            config.user_access_brand_identifier.as_ref(),
+        ))
+    }
+
+    // Gets the brand for the primary organization in a Brand Bundle.
+    //
+    // The primary organization is the one carrying the brand identifier from the
+    // SMART configuration. If there is no identifier, or no organization carries it,
+    // we use the first organization in the bundle.
+    //
+    // # Arguments
+    // * `bundle` The Brand Bundle.
+    // * `identifier` The identifier for the primary organization, if any.
+    fn from_bundle(bundle: &Bundle, identifier: Option<&BrandIdentifier>) -> Option<Brand> {
+        let organizations: Vec<&Organization> = bundle
+            .entry
+            .iter()
+            .flatten()
+            .filter_map(|entry| match &entry.resource {
+                Some(Resource::Organization(organization)) => Some(organization),
+                _ => None,
+            })
+            .collect();
+
+        let primary = identifier
+            .and_then(|identifier| {
+                organizations.iter().find(|organization| {
+                    organization.identifier.iter().flatten().any(|candidate| {
+                        candidate.system == identifier.system && candidate.value == identifier.value
+                    })
+                })
+            })
+            .or_else(|| organizations.first())?;
+
+        primary.name.clone().map(|name| Brand {
+            name,
+            logo: Self::logo(&primary.extension),
+        })
+    }
+
+    // Gets the logo URL from an organization's brand extension.
+    //
+    // # Arguments
+    // * `extensions` The extensions on the organization.
+    fn logo(extensions: &[Extension]) -> Option<String> {
+        extensions
+            .iter()
+            .filter(|extension| extension.url == ORGANIZATION_BRAND_EXTENSION)
+            .flat_map(|extension| extension.extension.iter())
+            .filter(|extension| extension.url == "brandLogo")
+            .find_map(|extension| match &extension.value {
+                Some(ExtensionValue::Url(url)) => Some(url.clone()),
+                _ => None,
+            })
+    }
+}
+
+// A cache of the brands of EHRs, keyed by issuer URL.
+//
+// EHRs without a brand are cached as well, so that we do not refetch their
+// configuration on every launch. Failures to fetch a brand are not cached.
+#[derive(Clone, Default)]
+pub struct BrandCache(Arc<Mutex<HashMap<String, Option<Brand>>>>);
+
+impl BrandCache {
+    // Gets the brand for an EHR, fetching it if it is not cached.
+    //
+    // # Arguments
+    // * `client` The HTTP client to fetch the Brand Bundle with.
+    // * `iss` The URL of the EHR.
+    // * `config` The SMART configuration for the EHR.
+    pub async fn get(
+        &self,
+        client: &Client,
+        iss: &str,
+        config: &SmartConfiguration,
+    ) -> Result<Option<Brand>, reqwest::Error> {
+        if let Some(brand) = self.0.lock().unwrap().get(iss) {
+            return Ok(brand.clone());
+        }
+
+        let brand = Brand::fetch(client, config).await?;
+        self.0
+            .lock()
+            .unwrap()
+            .insert(iss.to_string(), brand.clone());
+        Ok(brand)
+    }
+
+    // Checks whether a thread panicked while holding the lock on the cache.
+    pub fn is_poisoned(&self) -> bool {
+        self.0.is_poisoned()
+    }
+}
+
+#[cfg(test)]
+impl BrandCache {
+    // Poisons the lock on the cache, as if a thread panicked while holding it.
+    pub fn poison(&self) {
+        let cache = self.0.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = cache.lock().unwrap();
+            panic!("poisoning the brand cache");
+        })
+        .join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::test_support::smart_configuration;
+
+    // Builds the SMART configuration of an EHR with a Brand Bundle.
+    //
+    // # Arguments
+    // * `server` The mock EHR, which serves the Brand Bundle at `/brands.json`.
+    fn branded_configuration(server: &MockServer) -> SmartConfiguration {
+        let mut config = smart_configuration(&server.uri());
+        config["user_access_brand_bundle"] = json!(format!("{}/brands.json", server.uri()));
+        config["user_access_brand_identifier"] =
+            json!({ "system": "urn:ietf:rfc:3986", "value": "https://example.org/main" });
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn brand_is_resolved_from_bundle() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/brands.json"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Bundle",
+                "type": "collection",
+                "entry": [
+                    {
+                        "resource": {
+                            "resourceType": "Organization",
+                            "id": "affiliate",
+                            "name": "Example Affiliate"
+                        }
+                    },
+                    {
+                        "resource": {
+                            "resourceType": "Organization",
+                            "id": "main",
+                            "identifier": [{
+                                "system": "urn:ietf:rfc:3986",
+                                "value": "https://example.org/main"
+                            }],
+                            "name": "Example Health",
+                            "extension": [{
+                                "url": ORGANIZATION_BRAND_EXTENSION,
+                                "extension": [{
+                                    "url": "brandLogo",
+                                    "valueUrl": "https://example.org/logo.svg"
+                                }]
+                            }]
+                        }
+                    }
+                ]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let config = branded_configuration(&server);
+        let cache = BrandCache::default();
+
+        let brand = cache
+            .get(&Client::new(), &server.uri(), &config)
+            .await
+            .unwrap()
+            .unwrap();
+        let cached = cache
+            .get(&Client::new(), &server.uri(), &config)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(brand.name, "Example Health");
+        assert_eq!(brand.logo.as_deref(), Some("https://example.org/logo.svg"));
+        assert_eq!(cached.name, "Example Health");
+    }
+
+    #[actix_web::test]
+    async fn unreachable_brand_bundle_is_an_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/brands.json"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&server)
+            .await;
+
+        let brand = Brand::fetch(&Client::new(), &branded_configuration(&server)).await;
+
+        assert!(brand.is_err());
+    }
+}
@@ -0,0 +1,121 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+// A capability that a SMART-on-FHIR server can advertise in its SMART configuration.
+//
+// The well-known capabilities are documented
+// [here](https://build.fhir.org/ig/HL7/smart-app-launch/conformance.html#capabilities).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    // Launch modes
+    LaunchEhr,
+    LaunchStandalone,
+
+    // Authorization methods
+    AuthorizePost,
+
+    // Client types
+    ClientPublic,
+    ClientConfidentialSymmetric,
+    ClientConfidentialAsymmetric,
+
+    // Single sign-on
+    SsoOpenidConnect,
+
+    // Launch context
+    ContextPassthroughBanner,
+    ContextPassthroughStyle,
+    ContextEhrPatient,
+    ContextEhrEncounter,
+    ContextStandalonePatient,
+    ContextStandaloneEncounter,
+
+    // Permissions
+    PermissionOffline,
+    PermissionOnline,
+    PermissionPatient,
+    PermissionUser,
+    PermissionV1,
+    PermissionV2,
+
+    // App state
+    SmartAppState,
+
+    // A capability that is not in the list of well-known capabilities.
+    Other(String),
+}
+
+impl Capability {
+    pub fn parse(capability: &str) -> Capability {
+        match capability {
+            "launch-ehr" => Capability::LaunchEhr,
+            "launch-standalone" => Capability::LaunchStandalone,
+            "authorize-post" => Capability::AuthorizePost,
+            "client-public" => Capability::ClientPublic,
+            "client-confidential-symmetric" => Capability::ClientConfidentialSymmetric,
+            "client-confidential-asymmetric" => Capability::ClientConfidentialAsymmetric,
+            "sso-openid-connect" => Capability::SsoOpenidConnect,
+            "context-passthrough-banner" => Capability::ContextPassthroughBanner,
+            "context-passthrough-style" => Capability::ContextPassthroughStyle,
+            "context-ehr-patient" => Capability::ContextEhrPatient,
+            "context-ehr-encounter" => Capability::ContextEhrEncounter,
+            "context-standalone-patient" => Capability::ContextStandalonePatient,
+            "context-standalone-encounter" => Capability::ContextStandaloneEncounter,
+            "permission-offline" => Capability::PermissionOffline,
+            "permission-online" => Capability::PermissionOnline,
+            "permission-patient" => Capability::PermissionPatient,
+            "permission-user" => Capability::PermissionUser,
+            "permission-v1" => Capability::PermissionV1,
+            "permission-v2" => Capability::PermissionV2,
+            "smart-app-state" => Capability::SmartAppState,
+            other => Capability::Other(other.to_string()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Capability::LaunchEhr => "launch-ehr",
+            Capability::LaunchStandalone => "launch-standalone",
+            Capability::AuthorizePost => "authorize-post",
+            Capability::ClientPublic => "client-public",
+            Capability::ClientConfidentialSymmetric => "client-confidential-symmetric",
+            Capability::ClientConfidentialAsymmetric => "client-confidential-asymmetric",
+            Capability::SsoOpenidConnect => "sso-openid-connect",
+            Capability::ContextPassthroughBanner => "context-passthrough-banner",
+            Capability::ContextPassthroughStyle => "context-passthrough-style",
+            Capability::ContextEhrPatient => "context-ehr-patient",
+            Capability::ContextEhrEncounter => "context-ehr-encounter",
+            Capability::ContextStandalonePatient => "context-standalone-patient",
+            Capability::ContextStandaloneEncounter => "context-standalone-encounter",
+            Capability::PermissionOffline => "permission-offline",
+            Capability::PermissionOnline => "permission-online",
+            Capability::PermissionPatient => "permission-patient",
+            Capability::PermissionUser => "permission-user",
+            Capability::PermissionV1 => "permission-v1",
+            Capability::PermissionV2 => "permission-v2",
+            Capability::SmartAppState => "smart-app-state",
+            Capability::Other(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
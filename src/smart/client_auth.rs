@@ -0,0 +1,186 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use reqwest::RequestBuilder;
+use serde::Serialize;
+
+use std::fmt;
+
+// A method that a client can use to authenticate with a token endpoint.
+//
+// These are described in the SMART-on-FHIR
+// [docs](https://build.fhir.org/ig/HL7/smart-app-launch/client-authentication.html),
+// and are advertised by a server in the `token_endpoint_auth_methods_supported` field
+// of its SMART configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAuthMethod {
+    // Authenticates with a JWT signed by the client's private key.
+    PrivateKeyJwt,
+
+    // Authenticates with the client ID and secret in a HTTP Basic Authorization header.
+    ClientSecretBasic,
+
+    // Authenticates with the client ID and secret in the form body.
+    ClientSecretPost,
+
+    // Does not authenticate, as is the case for public clients. The client ID is
+    // sent in the form body.
+    None,
+}
+
+// The order in which we try client authentication methods, by default.
+pub const DEFAULT_CLIENT_AUTH_METHODS: [ClientAuthMethod; 3] = [
+    ClientAuthMethod::PrivateKeyJwt,
+    ClientAuthMethod::ClientSecretBasic,
+    ClientAuthMethod::ClientSecretPost,
+];
+
+impl ClientAuthMethod {
+    pub fn parse(method: &str) -> Option<ClientAuthMethod> {
+        match method {
+            "private_key_jwt" => Some(ClientAuthMethod::PrivateKeyJwt),
+            "client_secret_basic" => Some(ClientAuthMethod::ClientSecretBasic),
+            "client_secret_post" => Some(ClientAuthMethod::ClientSecretPost),
+            "none" => Some(ClientAuthMethod::None),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientAuthMethod::PrivateKeyJwt => "private_key_jwt",
+            ClientAuthMethod::ClientSecretBasic => "client_secret_basic",
+            ClientAuthMethod::ClientSecretPost => "client_secret_post",
+            ClientAuthMethod::None => "none",
+        }
+    }
+}
+
+// Whether the app can keep a client secret confidential.
+//
+// Confidential clients (e.g., apps with a backend, like this one) authenticate with
+// the token endpoint. Public clients (e.g., apps running entirely in the browser)
+// cannot keep a secret, so they do not authenticate, and only identify themselves
+// with their client ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientType {
+    Confidential,
+    Public,
+}
+
+impl ClientType {
+    pub fn parse(client_type: &str) -> Option<ClientType> {
+        match client_type {
+            "confidential" => Some(ClientType::Confidential),
+            "public" => Some(ClientType::Public),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientAuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// A form body, with the client credentials added for `client_secret_post`, or the
+// client ID added for public clients.
+//
+// NOTE: client_secret is a secret and should not be printed
+// As such, we do not support debug on this struct
+#[derive(Serialize)]
+struct AuthenticatedForm<'a, F: Serialize> {
+    #[serde(flatten)]
+    form: &'a F,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<&'a str>,
+}
+
+// The credentials that this app uses to authenticate with token endpoints.
+//
+// NOTE: client_secret is a secret and should not be printed
+// As such, we do not support debug on this struct
+#[derive(Clone)]
+pub struct ClientCredentials {
+    pub client_id: String,
+    client_secret: String,
+}
+
+impl ClientCredentials {
+    pub fn new(client_id: String, client_secret: String) -> ClientCredentials {
+        ClientCredentials {
+            client_id,
+            client_secret,
+        }
+    }
+
+    // Checks whether we have the credentials needed for an authentication method.
+    //
+    // We do not currently support configuring a private key, so we cannot use
+    // `private_key_jwt`.
+    pub fn supports(&self, method: ClientAuthMethod) -> bool {
+        match method {
+            ClientAuthMethod::PrivateKeyJwt => false,
+            ClientAuthMethod::ClientSecretBasic
+            | ClientAuthMethod::ClientSecretPost
+            | ClientAuthMethod::None => true,
+        }
+    }
+
+    /// Provides a secret usable with the SMART-on-FHIR symmetric authorization flow.
+    ///
+    /// Base64 encodes "client_id:client_secret", as described in the SMART-on-FHIR
+    /// [docs](https://build.fhir.org/ig/HL7/smart-app-launch/client-confidential-symmetric.html).
+    fn base64_secret(&self) -> String {
+        BASE64_STANDARD.encode(format!("{}:{}", self.client_id, self.client_secret))
+    }
+
+    // Adds a form body to a token endpoint request, authenticated with a given method.
+    //
+    // # Arguments
+    // * `request` The request to the token endpoint.
+    // * `method` The authentication method to use. Must be supported by these credentials.
+    // * `form` The form body to send.
+    pub fn authenticate<F: Serialize>(
+        &self,
+        request: RequestBuilder,
+        method: ClientAuthMethod,
+        form: &F,
+    ) -> RequestBuilder {
+        match method {
+            ClientAuthMethod::ClientSecretBasic => request
+                .form(form)
+                .header("Authorization", format!("Basic {}", self.base64_secret())),
+            ClientAuthMethod::ClientSecretPost => request.form(&AuthenticatedForm {
+                form,
+                client_id: Some(&self.client_id),
+                client_secret: Some(&self.client_secret),
+            }),
+            ClientAuthMethod::None => request.form(&AuthenticatedForm {
+                form,
+                client_id: Some(&self.client_id),
+                client_secret: None,
+            }),
+            ClientAuthMethod::PrivateKeyJwt => {
+                unreachable!("Tried to authenticate with an unsupported method.")
+            }
+        }
+    }
+}
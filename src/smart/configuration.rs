@@ -14,14 +14,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use reqwest::Client;
+use log::debug;
+use reqwest::{Client, Response, StatusCode};
 use serde::Deserialize;
+use url::Url;
 
-#[allow(dead_code)]
+use std::collections::{HashMap, HashSet};
+
+use crate::smart::brand::BrandIdentifier;
+use crate::smart::capability::Capability;
+
+// An endpoint that shares the same authorization mechanism as the FHIR endpoint.
 #[derive(Clone, Debug, Deserialize)]
 pub struct Endpoint {
-    url: String,
-    capabilities: Vec<String>,
+    // The base URL of the endpoint.
+    pub url: String,
+
+    // The capabilities of the endpoint. We treat a capability naming a FHIR resource
+    // type (e.g., "Observation") as meaning that the endpoint serves that resource type.
+    pub capabilities: Vec<String>,
 }
 
 #[allow(dead_code)]
@@ -33,6 +44,7 @@ pub struct SmartConfiguration {
 
     // CONDITIONAL, String conveying this system’s JSON Web Key Set URL.
     // Required if the server’s capabilities include sso-openid-connect; otherwise, optional.
+    #[serde(alias = "jwks_uri")]
     pub jwks_url: Option<String>,
 
     // CONDITIONAL, URL to the OAuth2 authorization endpoint.
@@ -42,6 +54,7 @@ pub struct SmartConfiguration {
     // REQUIRED, Array of grant types supported at the token endpoint.
     // The options are “authorization_code” (when SMART App Launch is supported) and
     // “client_credentials” (when SMART Backend Services is supported).
+    #[serde(default)]
     pub grant_types_supported: Vec<String>,
 
     // REQUIRED, URL to the OAuth2 token endpoint.
@@ -49,6 +62,7 @@ pub struct SmartConfiguration {
 
     // OPTIONAL, array of client authentication methods supported by the token endpoint.
     // The options are “client_secret_post”, “client_secret_basic”, and “private_key_jwt”.
+    #[serde(default)]
     pub token_endpoint_auth_methods_supported: Vec<String>,
 
     // OPTIONAL, If available, URL to the OAuth2 dynamic registration endpoint for this FHIR server.
@@ -61,19 +75,22 @@ pub struct SmartConfiguration {
 
     // OPTIONAL, Array of objects for endpoints that share the same authorization mechanism as this FHIR endpoint, each with a “url” and “capabilities” array.
     // This property is deemed experimental.
-    pub associated_endpoints: Option<Endpoint>,
+    #[serde(default)]
+    pub associated_endpoints: Vec<Endpoint>,
 
     // RECOMMENDED, URL for a Brand Bundle.
     pub user_access_brand_bundle: Option<String>,
 
     // RECOMMENDED, Identifier for the primary entry in a Brand Bundle.
-    pub user_access_brand_identifier: Option<String>,
+    pub user_access_brand_identifier: Option<BrandIdentifier>,
 
     // RECOMMENDED, Array of scopes a client may request.
     // The server SHALL support all scopes listed here; additional scopes MAY be supported (so clients should not consider this an exhaustive list).
+    #[serde(default)]
     pub scopes_supported: Vec<String>,
 
     // RECOMMENDED, Array of OAuth2 response_type values that are supported.
+    #[serde(default)]
     pub response_types_supported: Vec<String>,
 
     // RECOMMENDED, URL where an end-user can view which applications currently have access to data and can make adjustments to these access rights.
@@ -85,35 +102,459 @@ pub struct SmartConfiguration {
     // RECOMMENDED, URL to a server’s revoke endpoint that can be used to revoke a token.
     pub revocation_endpoint: Option<String>,
 
+    // OPTIONAL, URL to the OpenID Connect end-session endpoint, used for
+    // [RP-initiated logout](https://openid.net/specs/openid-connect-rpinitiated-1_0.html).
+    // Not part of the SMART configuration specification, but provided by some EHRs.
+    pub end_session_endpoint: Option<String>,
+
     // REQUIRED, Array of strings representing SMART capabilities (e.g., sso-openid-connect or launch-standalone) that the server supports.
     pub capabilities: Vec<String>,
 
     // REQUIRED, Array of PKCE code challenge methods supported. The S256 method SHALL be included in this list, and the plain method SHALL NOT be included in this list.
     pub code_challenge_methods_supported: Vec<String>,
+
+    // Any top-level fields that we do not recognize, e.g. because they were added in a
+    // newer version of the specification.
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, serde_json::Value>,
 }
 
 impl SmartConfiguration {
+    // Gets the set of capabilities that the server advertises.
+    pub fn capabilities_set(&self) -> HashSet<Capability> {
+        self.capabilities
+            .iter()
+            .map(|capability| Capability::parse(capability))
+            .collect()
+    }
+
+    // Checks whether the server lists a scope in `scopes_supported`.
+    //
+    // Wildcard scopes (e.g., `patient/*.read`) cover the scopes for every resource
+    // type, and SMART v2 scopes are compared without their search parameters. If the
+    // server does not list any scopes, every scope is assumed to be supported.
+    //
+    // # Arguments
+    // * `scope` The scope to check, e.g. "patient/Observation.read".
+    pub fn supports_scope(&self, scope: &str) -> bool {
+        if self.scopes_supported.is_empty() {
+            return true;
+        }
+
+        let scope = scope.split_once('?').map_or(scope, |(scope, _)| scope);
+        let wildcard = scope.split_once('/').and_then(|(context, resource)| {
+            resource
+                .split_once('.')
+                .map(|(_, permissions)| format!("{context}/*.{permissions}"))
+        });
+        self.scopes_supported
+            .iter()
+            .any(|supported| supported == scope || Some(supported) == wildcard.as_ref())
+    }
+
+    // Gets the scope that grants refresh tokens.
+    //
+    // Requests `offline_access` from servers that list it in `scopes_supported`, so
+    // that sessions can be refreshed for as long as the grant lasts, and falls back
+    // to `online_access`, which only grants refresh tokens while the user is logged
+    // in to the EHR.
+    pub fn refresh_scope(&self) -> &'static str {
+        if self
+            .scopes_supported
+            .iter()
+            .any(|scope| scope == "offline_access")
+        {
+            "offline_access"
+        } else {
+            "online_access"
+        }
+    }
+
+    // Gets the base URL to use for requests for a type of resource.
+    //
+    // Large systems may serve some resources from associated endpoints that share
+    // our authorization. If an associated endpoint lists the resource type among its
+    // capabilities, we use its URL; otherwise, we fall back to the primary base URL.
+    //
+    // # Arguments
+    // * `resource_type` The type of resource, e.g. "Observation".
+    // * `primary` The base URL of the primary FHIR endpoint.
+    pub fn resolve_endpoint_for<'a>(&'a self, resource_type: &str, primary: &'a str) -> &'a str {
+        self.associated_endpoints
+            .iter()
+            .find(|endpoint| {
+                endpoint
+                    .capabilities
+                    .iter()
+                    .any(|capability| capability == resource_type)
+            })
+            .map(|endpoint| endpoint.url.as_str())
+            .unwrap_or(primary)
+    }
+
+    // Resolves relative endpoint URLs against the URL the configuration was found under.
+    //
+    // Some servers return endpoints as paths (e.g., "/auth/token") rather than absolute
+    // URLs. Absolute endpoints, and endpoints that cannot be resolved, are left as is.
+    //
+    // # Arguments
+    // * `base_url` The URL whose `.well-known/smart-configuration` we fetched.
+    fn resolve_relative_endpoints(mut self, base_url: &str) -> SmartConfiguration {
+        // a trailing slash makes relative paths resolve under the base URL's path,
+        // rather than replacing its last segment
+        let Ok(base) = Url::parse(&format!("{}/", base_url.trim_end_matches('/'))) else {
+            return self;
+        };
+        let resolve = |endpoint: &mut String| {
+            if Url::parse(endpoint).is_err() {
+                if let Ok(resolved) = base.join(endpoint) {
+                    *endpoint = resolved.to_string();
+                }
+            }
+        };
+
+        resolve(&mut self.token_endpoint);
+        for endpoint in [
+            &mut self.authorization_endpoint,
+            &mut self.registration_endpoint,
+            &mut self.management_endpoint,
+            &mut self.introspection_endpoint,
+            &mut self.revocation_endpoint,
+            &mut self.end_session_endpoint,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            resolve(endpoint);
+        }
+
+        self
+    }
+
+    // Fetches the SMART configuration of an issuer.
+    //
+    // The SMART configuration is served at `.well-known/smart-configuration` under the
+    // issuer's base URL. If the base URL has a path (e.g., `https://ehr.example.com/fhir/r4`),
+    // some servers serve the document at the root of the host instead. If permitted,
+    // and the base-relative location is not found, we fall back to the host root.
+    // Relative endpoints are resolved against the location the document was found
+    // under.
+    //
+    // # Arguments
+    // * `base_url` The base URL of the issuer.
+    // * `client` The HTTP client to fetch the configuration with.
+    // * `allow_host_root` If true, falls back to the host root; see
+    //   `State::allows_host_root_discovery`.
     pub async fn get(
-        base_url: &String,
+        base_url: &str,
         client: &Client,
+        allow_host_root: bool,
     ) -> Result<SmartConfiguration, reqwest::Error> {
-        let request = client
-            .get(format!("{}/.well-known/smart-configuration", base_url))
-            .header("Accept", "application/json")
-            .build();
-
-        match request {
-            Ok(req) => {
-                let smart_configuration = client.execute(req).await;
-
-                match smart_configuration {
-                    Ok(smart_configuration) => {
-                        smart_configuration.json::<SmartConfiguration>().await
-                    }
-                    Err(e) => Err(e),
-                }
+        // a trailing slash would produce a `//.well-known` path, which some servers reject
+        let mut fetched_url = base_url.trim_end_matches('/').to_string();
+        let mut response = fetch_well_known(client, &fetched_url).await?;
+
+        if response.status() == StatusCode::NOT_FOUND && allow_host_root {
+            if let Some(host_root) = host_root(&fetched_url) {
+                debug!(
+                    "No SMART configuration found under {fetched_url}; trying the host root {host_root}"
+                );
+                response = fetch_well_known(client, &host_root).await?;
+                fetched_url = host_root;
             }
-            Err(e) => Err(e),
         }
+
+        response
+            .json::<SmartConfiguration>()
+            .await
+            .map(|config| config.resolve_relative_endpoints(&fetched_url))
+    }
+}
+
+// Requests the `.well-known/smart-configuration` document under a URL.
+//
+// # Arguments
+// * `client` The HTTP client to send the request with.
+// * `base_url` The URL to request the document under, without a trailing slash.
+async fn fetch_well_known(client: &Client, base_url: &str) -> Result<Response, reqwest::Error> {
+    client
+        .get(format!("{}/.well-known/smart-configuration", base_url))
+        .header("Accept", "application/json")
+        .send()
+        .await
+}
+
+// Gets the root of the host that serves a base URL, e.g. "https://ehr.example.com"
+// for "https://ehr.example.com/fhir/r4".
+//
+// Returns an empty option if the base URL is already at the host root, or cannot be
+// parsed.
+//
+// # Arguments
+// * `base_url` The base URL, without a trailing slash.
+pub fn host_root(base_url: &str) -> Option<String> {
+    let url = Url::parse(base_url).ok()?;
+    if url.path().trim_matches('/').is_empty() {
+        return None;
+    }
+
+    Some(url.origin().ascii_serialization())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::test_support::smart_configuration;
+
+    #[test]
+    fn capabilities_set_parses_known_and_unknown_capabilities() {
+        let mut config = smart_configuration("https://ehr.example.com");
+        config["capabilities"] = json!([
+            "launch-ehr",
+            "launch-standalone",
+            "client-confidential-symmetric",
+            "sso-openid-connect",
+            "permission-v2",
+            "vendor-extension"
+        ]);
+        let config: SmartConfiguration = serde_json::from_value(config).unwrap();
+
+        let capabilities = config.capabilities_set();
+
+        assert_eq!(
+            capabilities,
+            HashSet::from([
+                Capability::LaunchEhr,
+                Capability::LaunchStandalone,
+                Capability::ClientConfidentialSymmetric,
+                Capability::SsoOpenidConnect,
+                Capability::PermissionV2,
+                Capability::Other(String::from("vendor-extension")),
+            ])
+        );
+    }
+
+    // Parses an entry of the fuzzing corpus, and uses the parsed configuration as the
+    // fuzz target does.
+    //
+    // Returns an empty option if the entry is not a valid configuration.
+    //
+    // # Arguments
+    // * `entry` The contents of the corpus entry.
+    fn parse_corpus_entry(entry: &[u8]) -> Option<SmartConfiguration> {
+        let config = serde_json::from_slice::<SmartConfiguration>(entry).ok()?;
+        config.capabilities_set();
+        config.resolve_endpoint_for("Observation", "https://fhir.example.com/r4");
+        config.supports_scope("patient/Observation.read");
+        Some(config)
+    }
+
+    #[test]
+    fn fuzzing_seeds_parse() {
+        let parsed = [
+            &include_bytes!("../../fuzz/corpus/smart_configuration/seed-conformant.json")[..],
+            include_bytes!("../../fuzz/corpus/smart_configuration/seed-missing-issuer.json"),
+            include_bytes!("../../fuzz/corpus/smart_configuration/seed-relative-endpoints.json"),
+        ];
+        let rejected = [
+            &include_bytes!("../../fuzz/corpus/smart_configuration/seed-empty.json")[..],
+            include_bytes!("../../fuzz/corpus/smart_configuration/seed-wrong-types.json"),
+        ];
+
+        for entry in parsed {
+            assert!(parse_corpus_entry(entry).is_some());
+        }
+        for entry in rejected {
+            assert!(parse_corpus_entry(entry).is_none());
+        }
+    }
+
+    #[test]
+    fn fuzzing_regressions_do_not_panic() {
+        let missing_issuer = parse_corpus_entry(include_bytes!(
+            "../../fuzz/corpus/smart_configuration/regression-missing-issuer.json"
+        ));
+        let rejected = [
+            &include_bytes!(
+                "../../fuzz/corpus/smart_configuration/regression-null-endpoint-url.json"
+            )[..],
+            include_bytes!("../../fuzz/corpus/smart_configuration/regression-deep-nesting.json"),
+            include_bytes!("../../fuzz/corpus/smart_configuration/regression-invalid-utf8.json"),
+            include_bytes!("../../fuzz/corpus/smart_configuration/regression-number-overflow.json"),
+        ];
+
+        assert!(missing_issuer.unwrap().issuer.is_none());
+        for entry in rejected {
+            assert!(parse_corpus_entry(entry).is_none());
+        }
+    }
+
+    #[test]
+    fn recommended_fields_default_to_empty() {
+        let config: SmartConfiguration = serde_json::from_value(json!({
+            "token_endpoint": "https://ehr.example.com/token",
+            "capabilities": [],
+            "code_challenge_methods_supported": ["S256"]
+        }))
+        .unwrap();
+
+        assert!(config.grant_types_supported.is_empty());
+        assert!(config.token_endpoint_auth_methods_supported.is_empty());
+        assert!(config.scopes_supported.is_empty());
+        assert!(config.response_types_supported.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn relative_endpoints_resolve_against_base_url() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/fhir/.well-known/smart-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "authorization_endpoint": "/auth/authorize",
+                "token_endpoint": "auth/token",
+                "revocation_endpoint": "https://auth.example.com/revoke",
+                "capabilities": ["launch-ehr"],
+                "code_challenge_methods_supported": ["S256"]
+            })))
+            .mount(&ehr)
+            .await;
+
+        let config =
+            SmartConfiguration::get(&format!("{}/fhir/", ehr.uri()), &Client::new(), false)
+                .await
+                .unwrap();
+
+        assert_eq!(
+            config.authorization_endpoint.unwrap(),
+            format!("{}/auth/authorize", ehr.uri())
+        );
+        assert_eq!(
+            config.token_endpoint,
+            format!("{}/fhir/auth/token", ehr.uri())
+        );
+        assert_eq!(
+            config.revocation_endpoint.unwrap(),
+            "https://auth.example.com/revoke"
+        );
+    }
+
+    #[actix_web::test]
+    async fn trailing_slash_issuer_has_well_formed_discovery_url() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/fhir/.well-known/smart-configuration"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(smart_configuration(&ehr.uri())))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+
+        let config =
+            SmartConfiguration::get(&format!("{}/fhir/", ehr.uri()), &Client::new(), false).await;
+
+        assert!(config.is_ok());
+        let requests = ehr.received_requests().await.unwrap();
+        assert_eq!(
+            requests[0].url.path(),
+            "/fhir/.well-known/smart-configuration"
+        );
+    }
+
+    // Starts a mock EHR serving a SMART configuration at a path, with a relative token
+    // endpoint.
+    //
+    // # Arguments
+    // * `well_known_path` The path of the SMART configuration.
+    async fn ehr_serving_configuration_at(well_known_path: &str) -> MockServer {
+        let ehr = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path(well_known_path))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "token_endpoint": "auth/token",
+                "capabilities": ["launch-ehr"],
+                "code_challenge_methods_supported": ["S256"]
+            })))
+            .mount(&ehr)
+            .await;
+        ehr
+    }
+
+    #[actix_web::test]
+    async fn base_relative_configuration_is_discovered() {
+        let ehr = ehr_serving_configuration_at("/fhir/r4/.well-known/smart-configuration").await;
+
+        let config =
+            SmartConfiguration::get(&format!("{}/fhir/r4", ehr.uri()), &Client::new(), true)
+                .await
+                .unwrap();
+
+        assert_eq!(
+            config.token_endpoint,
+            format!("{}/fhir/r4/auth/token", ehr.uri())
+        );
+        assert_eq!(ehr.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn host_root_configuration_is_discovered_when_allowed() {
+        let ehr = ehr_serving_configuration_at("/.well-known/smart-configuration").await;
+
+        let config =
+            SmartConfiguration::get(&format!("{}/fhir/r4", ehr.uri()), &Client::new(), true)
+                .await
+                .unwrap();
+
+        // relative endpoints resolve against the host root, where the document was found
+        assert_eq!(config.token_endpoint, format!("{}/auth/token", ehr.uri()));
+    }
+
+    #[actix_web::test]
+    async fn host_root_is_not_tried_unless_allowed() {
+        let ehr = ehr_serving_configuration_at("/.well-known/smart-configuration").await;
+
+        let config =
+            SmartConfiguration::get(&format!("{}/fhir/r4", ehr.uri()), &Client::new(), false).await;
+
+        assert!(config.is_err());
+        let requests = ehr.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].url.path(),
+            "/fhir/r4/.well-known/smart-configuration"
+        );
+    }
+
+    // Builds a SMART configuration listing supported scopes.
+    //
+    // # Arguments
+    // * `scopes_supported` The supported scopes.
+    fn with_scopes(scopes_supported: &[&str]) -> SmartConfiguration {
+        let mut config = smart_configuration("https://ehr.example.com");
+        config["scopes_supported"] = json!(scopes_supported);
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn supports_scope_matches_listed_and_wildcard_scopes() {
+        let config = with_scopes(&["launch", "patient/*.read", "user/Patient.rs"]);
+
+        assert!(config.supports_scope("launch"));
+        assert!(config.supports_scope("patient/Observation.read"));
+        assert!(config.supports_scope("user/Patient.rs?_id=123"));
+        assert!(!config.supports_scope("user/Observation.rs"));
+        assert!(!config.supports_scope("patient/Observation.write"));
+        assert!(!config.supports_scope("offline_access"));
+    }
+
+    #[test]
+    fn supports_every_scope_when_none_are_listed() {
+        let config = with_scopes(&[]);
+
+        assert!(config.supports_scope("offline_access"));
+        assert!(config.supports_scope("patient/Observation.rs?category=laboratory"));
     }
 }
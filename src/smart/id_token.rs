@@ -0,0 +1,76 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use serde::Deserialize;
+
+// The claims from an OpenID Connect id_token that we use to identify the user.
+//
+// The id_token is returned from the token endpoint when the `openid` scope is
+// granted, as described in the SMART-on-FHIR
+// [docs](https://build.fhir.org/ig/HL7/smart-app-launch/scopes-and-launch-context.html#scopes-for-requesting-identity-data).
+//
+// NOTE: we only validate the id_token's signature if the EHR publishes its keys (see
+// `KeyStore`), so these claims must only be used for display purposes, and never for
+// authorization decisions.
+#[derive(Clone, Debug, Deserialize)]
+pub struct IdTokenClaims {
+    // The identifier for the user.
+    pub sub: String,
+
+    // The user's full name, if requested via the `profile` scope.
+    pub name: Option<String>,
+
+    // The user's preferred username, if requested via the `profile` scope.
+    pub preferred_username: Option<String>,
+
+    // A FHIR resource URL representing the user, e.g. a Practitioner or Patient.
+    #[serde(rename = "fhirUser")]
+    pub fhir_user: Option<String>,
+
+    // The nonce that we passed to the authorization endpoint, echoed back by the EHR.
+    pub nonce: Option<String>,
+}
+
+impl IdTokenClaims {
+    // Decodes the claims from an id_token.
+    //
+    // An id_token is a [JSON Web Token](https://datatracker.ietf.org/doc/html/rfc7519),
+    // whose claims are encoded as base64url JSON in the second of its three
+    // dot-separated segments. Returns an empty option if the token is malformed.
+    //
+    // # Arguments
+    // * `id_token` The encoded id_token.
+    pub fn decode(id_token: &str) -> Option<IdTokenClaims> {
+        let mut segments = id_token.split('.');
+        let payload = match (segments.next(), segments.next(), segments.next()) {
+            (Some(_header), Some(payload), Some(_signature)) => payload,
+            _ => return None,
+        };
+
+        let payload = BASE64_URL_SAFE_NO_PAD
+            .decode(payload.trim_end_matches('='))
+            .ok()?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    // Gets the name to display for the user.
+    //
+    // Prefers the user's full name, falling back to their preferred username.
+    pub fn display_name(&self) -> Option<&str> {
+        self.name.as_deref().or(self.preferred_username.as_deref())
+    }
+}
@@ -0,0 +1,378 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use reqwest::Client;
+use ring::signature::{
+    RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, RSA_PKCS1_2048_8192_SHA256,
+};
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// A public key that an EHR signs id_tokens with, as a
+// [JSON Web Key](https://datatracker.ietf.org/doc/html/rfc7517).
+//
+// We support RSA keys for RS256, which SMART requires EHRs to support, and P-256
+// keys for ES256.
+#[derive(Clone, Debug, Deserialize)]
+pub struct JsonWebKey {
+    pub kty: String,
+    pub kid: Option<String>,
+
+    // the modulus and exponent of an RSA key
+    pub n: Option<String>,
+    pub e: Option<String>,
+
+    // the curve and coordinates of an elliptic curve key
+    pub crv: Option<String>,
+    pub x: Option<String>,
+    pub y: Option<String>,
+}
+
+impl JsonWebKey {
+    // Checks a signature made with this key.
+    //
+    // Returns false if the algorithm does not match the key, or if the key is malformed.
+    //
+    // # Arguments
+    // * `alg` The algorithm from the JWT header, e.g. "RS256".
+    // * `message` The signed message, i.e. the encoded header and payload of the JWT.
+    // * `signature` The decoded signature.
+    fn verify(&self, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        let decode = |value: &Option<String>| {
+            value
+                .as_deref()
+                .and_then(|value| BASE64_URL_SAFE_NO_PAD.decode(value).ok())
+        };
+        match (alg, self.kty.as_str()) {
+            ("RS256", "RSA") => {
+                let (Some(n), Some(e)) = (decode(&self.n), decode(&self.e)) else {
+                    return false;
+                };
+                RsaPublicKeyComponents { n, e }
+                    .verify(&RSA_PKCS1_2048_8192_SHA256, message, signature)
+                    .is_ok()
+            }
+            ("ES256", "EC") if self.crv.as_deref() == Some("P-256") => {
+                let (Some(x), Some(y)) = (decode(&self.x), decode(&self.y)) else {
+                    return false;
+                };
+                // an uncompressed point
+                let point = [&[0x04][..], &x, &y].concat();
+                UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+// The keys that an EHR signs id_tokens with, published at the `jwks_uri` in its SMART
+// configuration.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct JsonWebKeySet {
+    pub keys: Vec<JsonWebKey>,
+}
+
+impl JsonWebKeySet {
+    // Fetches the key set for an EHR.
+    //
+    // # Arguments
+    // * `client` The HTTP client to fetch the key set with.
+    // * `url` The `jwks_uri` from the SMART configuration.
+    pub async fn fetch(client: &Client, url: &str) -> Result<JsonWebKeySet, reqwest::Error> {
+        client
+            .get(url)
+            .header("Accept", "application/json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<JsonWebKeySet>()
+            .await
+    }
+
+    // Checks whether the key set has a key with an ID, or any key if the JWT does not
+    // name one.
+    //
+    // # Arguments
+    // * `kid` The key ID from the JWT header.
+    fn has_key(&self, kid: Option<&str>) -> bool {
+        self.keys
+            .iter()
+            .any(|key| kid.is_none() || key.kid.as_deref() == kid)
+    }
+
+    // Checks a signature against the keys with an ID, or against every key if the JWT
+    // does not name one.
+    //
+    // # Arguments
+    // * `kid` The key ID from the JWT header.
+    // * `alg` The algorithm from the JWT header.
+    // * `message` The signed message.
+    // * `signature` The decoded signature.
+    fn verify(&self, kid: Option<&str>, alg: &str, message: &[u8], signature: &[u8]) -> bool {
+        self.keys
+            .iter()
+            .filter(|key| kid.is_none() || key.kid.as_deref() == kid)
+            .any(|key| key.verify(alg, message, signature))
+    }
+}
+
+// The header of a JWT, naming the algorithm and key it was signed with.
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+// The cached key sets, keyed by `jwks_uri`, with when they were fetched.
+type KeySets = HashMap<String, (Arc<JsonWebKeySet>, Instant)>;
+
+// A cache of the key sets of EHRs, keyed by `jwks_uri`.
+//
+// Key sets are reused until they reach the maximum age. EHRs rotate their keys, so
+// a token signed with a key that is not in the cached set makes us fetch the set
+// again. We keep at most a configured number of key sets, evicting expired sets, and
+// then the oldest, to make room. Failures to fetch key sets are not cached.
+#[derive(Clone)]
+pub struct KeyStore {
+    key_sets: Arc<Mutex<KeySets>>,
+    max_key_sets: usize,
+    max_age: Duration,
+}
+
+impl KeyStore {
+    // Creates an empty key store.
+    //
+    // # Arguments
+    // * `max_key_sets` The most key sets to keep.
+    // * `max_age` How long to reuse fetched key sets for.
+    pub fn new(max_key_sets: usize, max_age: Duration) -> KeyStore {
+        KeyStore {
+            key_sets: Arc::new(Mutex::new(HashMap::new())),
+            max_key_sets,
+            max_age,
+        }
+    }
+
+    // Checks the signature of a JWT, such as an id_token, against an EHR's keys.
+    //
+    // Returns false if the JWT is malformed, names a key that the EHR does not
+    // publish, or has an invalid signature. Returns an error if the key set cannot
+    // be fetched.
+    //
+    // # Arguments
+    // * `client` The HTTP client to fetch the key set with.
+    // * `url` The `jwks_uri` from the SMART configuration.
+    // * `jwt` The encoded JWT.
+    pub async fn verify(
+        &self,
+        client: &Client,
+        url: &str,
+        jwt: &str,
+    ) -> Result<bool, reqwest::Error> {
+        let Some((header, message, signature)) = split_jwt(jwt) else {
+            return Ok(false);
+        };
+        let kid = header.kid.as_deref();
+
+        let cached = self.cached(url).filter(|key_set| key_set.has_key(kid));
+        let key_set = match cached {
+            Some(key_set) => key_set,
+            None => self.fetch(client, url).await?,
+        };
+        Ok(key_set.verify(kid, &header.alg, message.as_bytes(), &signature))
+    }
+
+    // Gets a cached key set, if it is younger than the maximum age.
+    //
+    // # Arguments
+    // * `url` The `jwks_uri` of the key set.
+    fn cached(&self, url: &str) -> Option<Arc<JsonWebKeySet>> {
+        self.key_sets
+            .lock()
+            .unwrap()
+            .get(url)
+            .filter(|(_, fetched_at)| fetched_at.elapsed() < self.max_age)
+            .map(|(key_set, _)| key_set.clone())
+    }
+
+    // Fetches a key set and caches it, making room if the store is full.
+    //
+    // # Arguments
+    // * `client` The HTTP client to fetch the key set with.
+    // * `url` The `jwks_uri` of the key set.
+    async fn fetch(
+        &self,
+        client: &Client,
+        url: &str,
+    ) -> Result<Arc<JsonWebKeySet>, reqwest::Error> {
+        let key_set = Arc::new(JsonWebKeySet::fetch(client, url).await?);
+
+        let mut key_sets = self.key_sets.lock().unwrap();
+        if !key_sets.contains_key(url) && key_sets.len() >= self.max_key_sets {
+            key_sets.retain(|_, (_, fetched_at)| fetched_at.elapsed() < self.max_age);
+            if key_sets.len() >= self.max_key_sets {
+                let oldest = key_sets
+                    .iter()
+                    .min_by_key(|(_, (_, fetched_at))| *fetched_at)
+                    .map(|(url, _)| url.clone());
+                if let Some(oldest) = oldest {
+                    key_sets.remove(&oldest);
+                }
+            }
+        }
+        key_sets.insert(url.to_string(), (key_set.clone(), Instant::now()));
+        Ok(key_set)
+    }
+
+    // Checks whether a thread panicked while holding the lock on the store.
+    pub fn is_poisoned(&self) -> bool {
+        self.key_sets.is_poisoned()
+    }
+}
+
+// Splits a JWT into its decoded header, the signed message, and the decoded signature.
+//
+// Returns an empty option if the JWT is malformed.
+//
+// # Arguments
+// * `jwt` The encoded JWT.
+fn split_jwt(jwt: &str) -> Option<(JwtHeader, &str, Vec<u8>)> {
+    let (message, signature) = jwt.rsplit_once('.')?;
+    let (header, _payload) = message.split_once('.')?;
+    let header = BASE64_URL_SAFE_NO_PAD.decode(header).ok()?;
+    let header = serde_json::from_slice(&header).ok()?;
+    let signature = BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?;
+    Some((header, message, signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{json, Value};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::test_support::SigningKey;
+
+    // Serves a key set from a mock EHR, a given number of times.
+    //
+    // # Arguments
+    // * `server` The mock EHR.
+    // * `keys` The keys to publish.
+    // * `times` How many times to serve the key set.
+    async fn mock_jwks(server: &MockServer, keys: &[&SigningKey], times: u64) {
+        let keys: Vec<Value> = keys.iter().map(|key| key.jwk()).collect();
+        Mock::given(method("GET"))
+            .and(path("/jwks"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "keys": keys })))
+            .up_to_n_times(times)
+            .expect(times)
+            .mount(server)
+            .await;
+    }
+
+    #[actix_web::test]
+    async fn signed_token_is_verified() {
+        let server = MockServer::start().await;
+        let key = SigningKey::generate("k1");
+        mock_jwks(&server, &[&key], 1).await;
+        let store = KeyStore::new(8, Duration::from_secs(60));
+        let client = Client::new();
+        let url = format!("{}/jwks", server.uri());
+
+        let token = key.sign(json!({ "sub": "u1" }));
+        assert!(store.verify(&client, &url, &token).await.unwrap());
+        // the key set is cached
+        assert!(store.verify(&client, &url, &token).await.unwrap());
+
+        let other = SigningKey::generate("k1").sign(json!({ "sub": "u1" }));
+        assert!(!store.verify(&client, &url, &other).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn unknown_kid_refetches_key_set() {
+        let server = MockServer::start().await;
+        let old_key = SigningKey::generate("old");
+        let new_key = SigningKey::generate("new");
+        mock_jwks(&server, &[&old_key], 1).await;
+        mock_jwks(&server, &[&old_key, &new_key], 1).await;
+        let store = KeyStore::new(8, Duration::from_secs(60));
+        let client = Client::new();
+        let url = format!("{}/jwks", server.uri());
+
+        assert!(store
+            .verify(&client, &url, &old_key.sign(json!({ "sub": "u1" })))
+            .await
+            .unwrap());
+        // the EHR rotated its keys since we cached the key set
+        assert!(store
+            .verify(&client, &url, &new_key.sign(json!({ "sub": "u1" })))
+            .await
+            .unwrap());
+    }
+
+    #[actix_web::test]
+    async fn expired_key_set_is_refetched() {
+        let server = MockServer::start().await;
+        let key = SigningKey::generate("k1");
+        mock_jwks(&server, &[&key], 2).await;
+        let store = KeyStore::new(8, Duration::ZERO);
+        let client = Client::new();
+        let url = format!("{}/jwks", server.uri());
+
+        let token = key.sign(json!({ "sub": "u1" }));
+        assert!(store.verify(&client, &url, &token).await.unwrap());
+        assert!(store.verify(&client, &url, &token).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn oldest_key_set_is_evicted_when_full() {
+        let first = MockServer::start().await;
+        let second = MockServer::start().await;
+        let key = SigningKey::generate("k1");
+        mock_jwks(&first, &[&key], 2).await;
+        mock_jwks(&second, &[&key], 1).await;
+        let store = KeyStore::new(1, Duration::from_secs(60));
+        let client = Client::new();
+        let token = key.sign(json!({ "sub": "u1" }));
+
+        for server in [&first, &second, &first] {
+            let url = format!("{}/jwks", server.uri());
+            assert!(store.verify(&client, &url, &token).await.unwrap());
+        }
+        assert_eq!(store.key_sets.lock().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn malformed_token_is_not_verified() {
+        let server = MockServer::start().await;
+        let store = KeyStore::new(8, Duration::from_secs(60));
+        let url = format!("{}/jwks", server.uri());
+
+        assert!(!store
+            .verify(&Client::new(), &url, "not-a-jwt")
+            .await
+            .unwrap());
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use reqwest::Client;
+use serde::Serialize;
+
+use crate::smart::client_auth::{ClientAuthMethod, ClientCredentials};
+
+#[derive(Serialize)]
+struct RevocationRequest<'a> {
+    token: &'a str,
+    token_type_hint: &'a str,
+}
+
+// A token that can be revoked at an EHR's revocation endpoint.
+#[derive(Clone)]
+pub struct Revocation {
+    // The URL of the revocation endpoint.
+    endpoint: String,
+
+    // The token to revoke.
+    token: String,
+
+    // The type of the token to revoke, either "refresh_token" or "access_token".
+    token_type_hint: &'static str,
+
+    // The credentials for the app, used to authenticate with the revocation endpoint.
+    credentials: ClientCredentials,
+
+    // The client authentication method that the token endpoint accepted.
+    auth_method: ClientAuthMethod,
+}
+
+impl Revocation {
+    pub fn new(
+        endpoint: String,
+        token: String,
+        token_type_hint: &'static str,
+        credentials: ClientCredentials,
+        auth_method: ClientAuthMethod,
+    ) -> Revocation {
+        Revocation {
+            endpoint,
+            token,
+            token_type_hint,
+            credentials,
+            auth_method,
+        }
+    }
+
+    // Revokes the token, as described in [RFC 7009](https://www.rfc-editor.org/rfc/rfc7009).
+    //
+    // # Arguments
+    // * `client` The HTTP client to send the revocation request with.
+    pub async fn revoke(&self, client: &Client) -> Result<(), reqwest::Error> {
+        // NOTE: the token is a secret and should not be printed
+        let request_arguments = RevocationRequest {
+            token: &self.token,
+            token_type_hint: self.token_type_hint,
+        };
+
+        self.credentials
+            .authenticate(
+                client.post(&self.endpoint),
+                self.auth_method,
+                &request_arguments,
+            )
+            .send()
+            .await?
+            .error_for_status()
+            .map(|_| ())
+    }
+}
@@ -0,0 +1,283 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use reqwest::Client;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// The styles an EHR asks apps to use, so that they blend in with the EHR, as described
+// by the [`smart_style_url`](https://build.fhir.org/ig/HL7/smart-app-launch/scopes-and-launch-context.html#styling)
+// launch context parameter.
+//
+// We only use the colors and fonts. Every field is optional, and values that could
+// escape the CSS property they are used in are dropped when rendering.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SmartStyle {
+    pub color_background: Option<String>,
+    pub color_error: Option<String>,
+    pub color_highlight: Option<String>,
+    pub color_text: Option<String>,
+    pub font_family_body: Option<String>,
+    pub font_family_heading: Option<String>,
+}
+
+impl SmartStyle {
+    // Fetches the styles for an EHR.
+    //
+    // # Arguments
+    // * `client` The HTTP client to fetch the styles with.
+    // * `url` The `smart_style_url` from the token response.
+    pub async fn fetch(client: &Client, url: &str) -> Result<SmartStyle, reqwest::Error> {
+        client
+            .get(url)
+            .header("Accept", "application/json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<SmartStyle>()
+            .await
+    }
+
+    // Renders the styles as a CSS stylesheet for our pages.
+    //
+    // Returns an empty option if none of the styles can be used.
+    pub fn stylesheet(&self) -> Option<String> {
+        let rules = [
+            ("body", "background-color", &self.color_background),
+            ("body", "color", &self.color_text),
+            ("body", "font-family", &self.font_family_body),
+            ("h1, h2", "font-family", &self.font_family_heading),
+            ("a", "color", &self.color_highlight),
+            ("#timed-out", "color", &self.color_error),
+        ];
+
+        let css: Vec<String> = rules
+            .iter()
+            .filter_map(|(selector, property, value)| {
+                value
+                    .as_deref()
+                    .filter(|value| is_safe_value(value))
+                    .map(|value| format!("{selector} {{ {property}: {value}; }}"))
+            })
+            .collect();
+        if css.is_empty() {
+            None
+        } else {
+            Some(css.join("\n"))
+        }
+    }
+}
+
+// Checks whether a style value can be used as a CSS property value.
+//
+// The styles come from the EHR, so we only accept the characters needed for colors
+// (e.g. "#1a2b3c", "rgb(0, 0, 0)") and font lists (e.g. "'Open Sans', sans-serif"),
+// which rules out ending the property or rule early.
+//
+// # Arguments
+// * `value` The style value to check.
+fn is_safe_value(value: &str) -> bool {
+    !value.is_empty()
+        && value.chars().all(|c| {
+            c.is_ascii_alphanumeric()
+                || matches!(c, ' ' | '#' | '(' | ')' | ',' | '.' | '%' | '-' | '\'')
+        })
+}
+
+// How long we remember that fetching styles failed, before trying again. This is
+// short, so that an EHR recovers quickly, but saves every page render from waiting
+// on an EHR that is down.
+const FAILED_STYLE_MAX_AGE: Duration = Duration::from_secs(60);
+
+// The cached styles, keyed by `smart_style_url`, with when they were fetched. Failed
+// fetches are cached as empty options.
+type Styles = HashMap<String, (Option<SmartStyle>, Instant)>;
+
+// A cache of the styles of EHRs, keyed by `smart_style_url`.
+//
+// Pages are rendered from the styles on every request, so we reuse fetched styles
+// until they reach the maximum age. Failures to fetch styles are cached briefly, so
+// that we do not try to fetch them on every render. Expired entries are evicted
+// whenever we fetch styles.
+#[derive(Clone, Default)]
+pub struct StyleCache(Arc<Mutex<Styles>>);
+
+impl StyleCache {
+    // Gets the styles at a URL, fetching them if they are not cached, or if the cached
+    // styles are older than the maximum age.
+    //
+    // Returns an empty option if fetching the styles failed recently, and an error if
+    // fetching them fails now.
+    //
+    // # Arguments
+    // * `client` The HTTP client to fetch the styles with.
+    // * `url` The `smart_style_url` from the token response.
+    // * `max_age` How long to reuse fetched styles for.
+    pub async fn get(
+        &self,
+        client: &Client,
+        url: &str,
+        max_age: Duration,
+    ) -> Result<Option<SmartStyle>, reqwest::Error> {
+        if let Some((style, fetched_at)) = self.0.lock().unwrap().get(url) {
+            if !is_expired(style, fetched_at, max_age) {
+                return Ok(style.clone());
+            }
+        }
+
+        let style = SmartStyle::fetch(client, url).await;
+        let mut styles = self.0.lock().unwrap();
+        styles.retain(|_, (style, fetched_at)| !is_expired(style, fetched_at, max_age));
+        styles.insert(
+            url.to_string(),
+            (style.as_ref().ok().cloned(), Instant::now()),
+        );
+        style.map(Some)
+    }
+
+    // Checks whether a thread panicked while holding the lock on the cache.
+    pub fn is_poisoned(&self) -> bool {
+        self.0.is_poisoned()
+    }
+}
+
+// Checks whether a cached entry should be fetched again.
+//
+// # Arguments
+// * `style` The cached styles, or an empty option if fetching them failed.
+// * `fetched_at` When the entry was cached.
+// * `max_age` How long to reuse fetched styles for.
+fn is_expired(style: &Option<SmartStyle>, fetched_at: &Instant, max_age: Duration) -> bool {
+    let max_age = match style {
+        Some(_) => max_age,
+        None => FAILED_STYLE_MAX_AGE.min(max_age),
+    };
+    fetched_at.elapsed() >= max_age
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    // Serves styles from a mock EHR, expecting a number of fetches.
+    //
+    // # Arguments
+    // * `server` The mock EHR.
+    // * `status` The status to respond with.
+    // * `fetches` How many times the styles should be fetched.
+    async fn mock_styles(server: &MockServer, status: u16, fetches: u64) -> String {
+        Mock::given(method("GET"))
+            .and(path("/style.json"))
+            .respond_with(
+                ResponseTemplate::new(status).set_body_json(json!({ "color_text": "#123456" })),
+            )
+            .expect(fetches)
+            .mount(server)
+            .await;
+        format!("{}/style.json", server.uri())
+    }
+
+    #[actix_web::test]
+    async fn styles_are_fetched_once_within_max_age() {
+        let server = MockServer::start().await;
+        let url = mock_styles(&server, 200, 1).await;
+        let cache = StyleCache::default();
+        let client = Client::new();
+
+        for _ in 0..2 {
+            let style = cache
+                .get(&client, &url, Duration::from_secs(60))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(style.color_text.as_deref(), Some("#123456"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn expired_styles_are_fetched_again() {
+        let server = MockServer::start().await;
+        let url = mock_styles(&server, 200, 2).await;
+        let cache = StyleCache::default();
+        let client = Client::new();
+
+        for _ in 0..2 {
+            assert!(cache
+                .get(&client, &url, Duration::ZERO)
+                .await
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[actix_web::test]
+    async fn failed_fetch_is_cached_briefly() {
+        let server = MockServer::start().await;
+        let url = mock_styles(&server, 500, 1).await;
+        let cache = StyleCache::default();
+        let client = Client::new();
+
+        assert!(cache
+            .get(&client, &url, Duration::from_secs(60 * 60))
+            .await
+            .is_err());
+        assert!(cache
+            .get(&client, &url, Duration::from_secs(60 * 60))
+            .await
+            .unwrap()
+            .is_none());
+
+        let (_, fetched_at) = cache.0.lock().unwrap()[&url].clone();
+        assert!(!is_expired(
+            &None,
+            &fetched_at,
+            Duration::from_secs(60 * 60)
+        ));
+        let long_ago = Instant::now() - FAILED_STYLE_MAX_AGE;
+        assert!(is_expired(&None, &long_ago, Duration::from_secs(60 * 60)));
+        assert!(!is_expired(
+            &Some(SmartStyle::default()),
+            &long_ago,
+            Duration::from_secs(60 * 60)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn expired_entries_are_evicted() {
+        let server = MockServer::start().await;
+        let url = mock_styles(&server, 200, 1).await;
+        let cache = StyleCache::default();
+        cache.0.lock().unwrap().insert(
+            String::from("https://gone.example.com/style.json"),
+            (None, Instant::now() - FAILED_STYLE_MAX_AGE),
+        );
+
+        cache
+            .get(&Client::new(), &url, Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        let styles = cache.0.lock().unwrap();
+        assert_eq!(styles.keys().collect::<Vec<_>>(), vec![&url]);
+    }
+}
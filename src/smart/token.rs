@@ -14,36 +14,123 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use actix_web::rt::time::{sleep, timeout};
 use fhir_sdk::client::Client as FhirClient;
 use fhir_sdk::client::{Error, FhirR4B, LoginManager};
 use fhir_sdk::header::InvalidHeaderValue;
 use fhir_sdk::{HeaderValue, HttpClient};
+use log::{debug, info, warn};
 use oauth2::PkceCodeVerifier;
-use reqwest::Client as ReqwestClient;
+use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client as ReqwestClient, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
+use crate::context::{practitioner_reference, LaunchContext};
+use crate::limit::RequestLimiter;
+use crate::medication::MedicationCache;
+use crate::practitioner::PractitionerCache;
+use crate::smart::brand::Brand;
+use crate::smart::client_auth::{ClientAuthMethod, ClientCredentials};
 use crate::smart::configuration::SmartConfiguration;
+use crate::smart::id_token::IdTokenClaims;
+use crate::smart::revocation::Revocation;
 use crate::state::State;
 
+// The number of times we try to exchange an authorization code for a token.
+const TOKEN_EXCHANGE_ATTEMPTS: u32 = 2;
+
+// How long we wait before retrying a failed token exchange. Authorization codes
+// typically expire within a minute or so, so we keep this short.
+const TOKEN_EXCHANGE_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+// How much of an unexpected response from the token endpoint we keep in errors.
+const MAX_ERROR_BODY_CHARS: usize = 500;
+
 // Represents a Bearer token that can be used to access FHIR APIs.
+#[derive(Clone)]
 pub struct Token {
     // The SMART Configuration for the FHIR server this token was
     // requested from. Used for refreshing the token.
     smart_configuration: SmartConfiguration,
 
-    // The BASE64 secret for the app. Used for refreshing the token.
-    base64_secret: String,
+    // The credentials for the app. Used for refreshing the token.
+    credentials: ClientCredentials,
+
+    // The client authentication method that the token endpoint accepted. Used
+    // for refreshing the token.
+    auth_method: ClientAuthMethod,
+
+    // the core token fields. Clones of a token share these, so that the clients for
+    // a session's associated endpoints use the same access token and refresh token
+    // as its primary client, and see each other's refreshes.
+    token: Arc<RwLock<TokenContents>>,
+
+    // Held while refreshing the token, so that clients sharing it do not refresh it
+    // at the same time. If the EHR rotates refresh tokens, a second refresh with
+    // the same refresh token would fail.
+    refreshing: Arc<tokio::sync::Mutex<()>>,
+
+    // The access token that the client holding this clone last sent. Not shared
+    // between clones, as each client sends its own authorization header; if the
+    // FHIR server rejects this access token, it needs a refresh.
+    sent_access_token: Option<String>,
+
+    // The ID for the selected patient, requested via `launch/patient` scope. Absent
+    // for administrative launches, which do not request patient context.
+    pub patient: Option<String>,
+
+    // The ID for the encounter in context, if the EHR provided one.
+    pub encounter: Option<String>,
+
+    // References to other resources in context, e.g. an order that the user
+    // launched the app from.
+    pub fhir_context: Vec<String>,
 
-    // the core token fields
-    token: TokenContents,
+    // The claims identifying the authenticated user, decoded from the id_token
+    // if the `openid` scope was granted.
+    pub user: Option<IdTokenClaims>,
 
-    // The ID for the selected patient, requested via `launch/patient` scope.
-    pub patient: String,
+    // A FHIR resource URL representing the user, e.g. a Practitioner. Taken from
+    // the id_token if it has one, and otherwise from the token response, which some
+    // EHRs use to identify the user without OpenID Connect.
+    pub fhir_user: Option<String>,
+
+    // How the EHR that issued this token presents itself to users, if known.
+    pub brand: Option<Brand>,
+
+    // The raw id_token, if the `openid` scope was granted. Used as a hint when
+    // ending the session at the EHR.
+    id_token: Option<String>,
+
+    // Whether the app needs to display a patient banner, because the EHR does not.
+    // Defaults to true if the EHR did not say.
+    pub need_patient_banner: bool,
+
+    // The URL of the styles the EHR asks apps to use, if it provided one.
+    pub style_url: Option<String>,
+
+    // Whether the EHR launched the app with a `launch` parameter, in which case the
+    // app is likely displayed in a frame within the EHR.
+    pub ehr_launch: bool,
 
     // The URL that issued this Token.
     iss: String,
+
+    // How long we wait for the token endpoint when refreshing this token.
+    token_timeout: Duration,
+
+    // How far our clock may drift from the authorization server's before we treat
+    // this token as expired.
+    clock_skew: Duration,
+
+    // How long before its expiry we refresh this token.
+    refresh_lead: Duration,
 }
 
 #[derive(Clone)]
@@ -53,7 +140,6 @@ struct TokenContents {
 
     // Scope of access authorized.
     // Note that this can be different from the scopes requested by the app.
-    #[allow(dead_code)]
     scopes: Vec<String>,
 
     // The point when the token expires,
@@ -63,10 +149,6 @@ struct TokenContents {
     // Token that can be used to obtain a new access token, using the same or a
     // subset of the original authorization grants
     refresh_token: Option<String>,
-
-    // Authenticated user identity and user details, if requested.
-    #[allow(dead_code)]
-    id_token: Option<String>,
 }
 
 // NOTE: code_verifier is a secret and should not be printed
@@ -89,52 +171,367 @@ struct TokenRefreshRequest {
     // original token.
 }
 
+// An error response from the token endpoint, as described in
+// [RFC 6749](https://datatracker.ietf.org/doc/html/rfc6749#section-5.2).
+#[derive(Debug, Deserialize)]
+pub struct TokenErrorResponse {
+    pub error: String,
+    pub error_description: Option<String>,
+}
+
+impl TokenErrorResponse {
+    // Checks whether the token endpoint rejected the `aud` that we sent to the
+    // authorization endpoint.
+    //
+    // There is no dedicated error code for this, so we look for the audience in the
+    // error description.
+    pub fn is_audience_mismatch(&self) -> bool {
+        matches!(self.error.as_str(), "invalid_grant" | "invalid_request")
+            && self
+                .error_description
+                .as_ref()
+                .is_some_and(|description| description.to_lowercase().contains("aud"))
+    }
+}
+
+// An error that occurred while requesting a token.
+#[derive(Debug)]
+pub enum TokenError {
+    // Sending the request or parsing the response failed.
+    Request(reqwest::Error),
+
+    // The token endpoint returned an error response.
+    Response(TokenErrorResponse),
+
+    // We do not have credentials for any of the client authentication methods
+    // that we are allowed to try.
+    NoClientAuthMethod,
+
+    // The token endpoint issued a token of a type that we do not support.
+    UnsupportedTokenType(String),
+
+    // The token endpoint returned a response that is neither a token nor an error
+    // response, e.g. an HTML error page from a gateway in front of it.
+    UnexpectedResponse {
+        status: StatusCode,
+        content_type: Option<String>,
+        body: String,
+    },
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::Request(e) => write!(f, "token request failed: {e}"),
+            TokenError::Response(response) => match &response.error_description {
+                Some(description) => write!(
+                    f,
+                    "token endpoint returned {}: {}",
+                    response.error, description
+                ),
+                None => write!(f, "token endpoint returned {}", response.error),
+            },
+            TokenError::NoClientAuthMethod => {
+                write!(f, "no usable client authentication method")
+            }
+            TokenError::UnsupportedTokenType(token_type) => {
+                write!(f, "unsupported token type {token_type}")
+            }
+            TokenError::UnexpectedResponse {
+                status,
+                content_type,
+                body,
+            } => write!(
+                f,
+                "token endpoint returned an unexpected HTTP {status} response ({}): {body}",
+                content_type.as_deref().unwrap_or("no content type")
+            ),
+        }
+    }
+}
+
+impl From<reqwest::Error> for TokenError {
+    fn from(e: reqwest::Error) -> TokenError {
+        TokenError::Request(e)
+    }
+}
+
+// An error that occurred while creating the authorization header for a FHIR request.
+#[derive(Debug)]
+pub enum AuthError {
+    // The access token has expired, and we could not refresh it.
+    Expired,
+
+    // The access token cannot be sent in a header.
+    InvalidHeader(InvalidHeaderValue),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::Expired => write!(f, "access token has expired and cannot be refreshed"),
+            AuthError::InvalidHeader(e) => write!(f, "invalid authorization header: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
 #[derive(Deserialize)]
 struct TokenResponse {
     access_token: String,
-    #[allow(dead_code)]
     token_type: String,
     expires_in: u64,
     scope: String,
     refresh_token: Option<String>,
     id_token: Option<String>,
-    patient: String,
+    patient: Option<String>,
+    encounter: Option<String>,
+    #[serde(rename = "fhirContext", default)]
+    fhir_context: Vec<FhirContextEntry>,
+    #[serde(rename = "fhirUser")]
+    fhir_user: Option<String>,
+    need_patient_banner: Option<bool>,
+    smart_style_url: Option<String>,
     #[allow(dead_code)]
     authorization_details: Option<String>,
 }
 
+// A resource in the launch context, beyond the patient and encounter.
+//
+// SMART App Launch 2.0 lists these as references (e.g., "DiagnosticReport/123"),
+// while 2.1 lists objects holding a reference, canonical URL, or identifier.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FhirContextEntry {
+    Reference(String),
+    Object { reference: Option<String> },
+}
+
+impl FhirContextEntry {
+    // Gets the reference to the resource, if the entry has one.
+    fn reference(self) -> Option<String> {
+        match self {
+            FhirContextEntry::Reference(reference) => Some(reference),
+            FhirContextEntry::Object { reference } => reference,
+        }
+    }
+}
+
+// What we need to end a session with the EHR when the user logs out.
+#[derive(Clone)]
+pub struct Logout {
+    // The token to revoke, if the EHR has a revocation endpoint.
+    pub revocation: Option<Revocation>,
+
+    // The OpenID Connect end-session endpoint, if the EHR supports RP-initiated logout.
+    pub end_session_endpoint: Option<String>,
+
+    // The raw id_token, passed to the end-session endpoint as `id_token_hint`.
+    pub id_token: Option<String>,
+
+    // The client ID we are registered with at the EHR.
+    pub client_id: String,
+}
+
 #[derive(Clone)]
 pub struct TokenClient {
-    pub patient: String,
+    // The key the session is stored under: the patient ID, if the launch has patient
+    // context, or a random ID otherwise.
+    pub session_key: String,
+    pub patient: Option<String>,
+    pub context: LaunchContext,
+    pub logout: Logout,
     pub client: FhirClient<FhirR4B>,
+    // The base URL of the FHIR API: the issuer, with a FHIR version path segment
+    // appended if the server requires one.
+    base_url: String,
+    smart_configuration: SmartConfiguration,
+    endpoint_clients: HashMap<String, FhirClient<FhirR4B>>,
+    // The HTTP client and token, for requests whose responses we stream rather than
+    // parse; see `get_streamed`.
+    http_client: ReqwestClient,
+    token: Token,
+    pub limiter: RequestLimiter,
+    pub medications: MedicationCache,
+    pub practitioners: PractitionerCache,
 }
 
 impl TokenClient {
-    pub async fn new(client: ReqwestClient, token: Token) -> Result<TokenClient, Error> {
+    // Builds a client for the FHIR API that issued a token.
+    //
+    // # Arguments
+    // * `client` The Reqwest client that we will use for sending HTTP requests.
+    // * `token` The token to use for authorization.
+    // * `base_url` The base URL of the FHIR API, which is usually the issuer.
+    pub async fn new(
+        client: ReqwestClient,
+        token: Token,
+        base_url: &str,
+    ) -> Result<TokenClient, Error> {
         let patient = token.patient.clone();
-        match Self::build_client(client, token).await {
-            Ok(client) => Ok(TokenClient { patient, client }),
+        let session_key = patient
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let context = LaunchContext {
+            session_key: session_key.clone(),
+            iss: token.iss.clone(),
+            patient: patient.clone(),
+            encounter: token.encounter.clone(),
+            fhir_context: token.fhir_context.clone(),
+            user_name: token
+                .user
+                .as_ref()
+                .and_then(|user| user.display_name())
+                .map(str::to_string),
+            practitioner: token.fhir_user.as_deref().and_then(practitioner_reference),
+            scopes: token.contents().scopes.clone(),
+            brand: token.brand.clone(),
+            need_patient_banner: token.need_patient_banner,
+            style_url: token.style_url.clone(),
+            style: None,
+            ehr_launch: token.ehr_launch,
+        };
+        let logout = token.logout();
+        let smart_configuration = token.smart_configuration.clone();
+
+        // build a client for each associated endpoint. the clients share the token,
+        // so a refresh by any of them is used by all
+        let mut endpoint_clients = HashMap::new();
+        for endpoint in &smart_configuration.associated_endpoints {
+            if !endpoint_clients.contains_key(&endpoint.url) {
+                let endpoint_client =
+                    Self::build_client(client.clone(), &endpoint.url, token.clone()).await?;
+                endpoint_clients.insert(endpoint.url.clone(), endpoint_client);
+            }
+        }
+
+        match Self::build_client(client.clone(), base_url, token.clone()).await {
+            Ok(fhir_client) => Ok(TokenClient {
+                session_key,
+                patient,
+                context,
+                logout,
+                client: fhir_client,
+                base_url: base_url.to_string(),
+                smart_configuration,
+                endpoint_clients,
+                http_client: client,
+                token,
+                limiter: RequestLimiter::default(),
+                medications: MedicationCache::default(),
+                practitioners: PractitionerCache::default(),
+            }),
             Err(e) => Err(e),
         }
     }
 
+    // Checks whether the granted scopes allow reading a type of resource.
+    //
+    // See `LaunchContext::can_read`.
+    //
+    // # Arguments
+    // * `resource_type` The type of resource to read, e.g. "Patient".
+    pub fn can_read(&self, resource_type: &str) -> bool {
+        self.context.can_read(resource_type)
+    }
+
+    // Gets the base URL of the FHIR API.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    // Builds a client for the same session under another base URL of the FHIR API.
+    //
+    // The new client shares this client's request limiter.
+    //
+    // # Arguments
+    // * `base_url` The base URL of the FHIR API.
+    pub async fn with_base_url(&self, base_url: &str) -> Result<TokenClient, Error> {
+        let mut client =
+            TokenClient::new(self.http_client.clone(), self.token.clone(), base_url).await?;
+        client.session_key = self.session_key.clone();
+        client.context = self.context.clone();
+        client.limiter = self.limiter.clone();
+        Ok(client)
+    }
+
+    // Gets the base URL to use for requests for a type of resource.
+    //
+    // See `SmartConfiguration::resolve_endpoint_for`.
+    //
+    // # Arguments
+    // * `resource_type` The type of resource, e.g. "Observation".
+    pub fn base_url_for(&self, resource_type: &str) -> &str {
+        self.smart_configuration
+            .resolve_endpoint_for(resource_type, &self.base_url)
+    }
+
+    // Gets the FHIR API client to use for requests for a type of resource.
+    //
+    // # Arguments
+    // * `resource_type` The type of resource, e.g. "Observation".
+    pub fn client_for(&self, resource_type: &str) -> &FhirClient<FhirR4B> {
+        self.endpoint_clients
+            .get(self.base_url_for(resource_type))
+            .unwrap_or(&self.client)
+    }
+
+    // Sends a GET request to a URL under the FHIR API, returning the response before
+    // its body is read, so that the body can be streamed.
+    //
+    // Authorizes the request with our token like the FHIR client does: if the server
+    // rejects the token, we refresh it if we can, and retry once. A response that
+    // rejects the retry is returned as is.
+    //
+    // # Arguments
+    // * `url` The absolute URL to request.
+    pub async fn get_streamed(&self, url: &str) -> Result<Response, Error> {
+        let mut token = self.token.clone();
+        let mut retried = false;
+        loop {
+            let header = token
+                .authenticate(self.http_client.clone())
+                .await
+                .map_err(|e| Error::AuthCallback(e.to_string()))?;
+            let response = self
+                .http_client
+                .get(url)
+                .header(AUTHORIZATION, header)
+                .header(ACCEPT, "application/fhir+json")
+                .send()
+                .await
+                .map_err(Error::Request)?;
+            if response.status() != StatusCode::UNAUTHORIZED || retried {
+                return Ok(response);
+            }
+            retried = true;
+        }
+    }
+
     // Builds a FHIR API client.
     //
-    // Configures a FHIR API client that targets the FHIR API that issued our
+    // Configures a FHIR API client that targets a FHIR API that accepts our
     // token, with the bearer token set in the authorization header.
     //
     // # Arguments
     // * `client` The Reqwest client that we will use for sending HTTP requests.
+    // * `base_url` The base URL of the FHIR API.
     // * `token` The token to use for authorization.
     async fn build_client(
         client: ReqwestClient,
+        base_url: &str,
         token: Token,
     ) -> Result<FhirClient<FhirR4B>, Error> {
         {
             // TODO: ideally we should preserve the client?
             FhirClient::<FhirR4B>::builder()
                 .client(client)
-                .base_url(token.iss.parse().unwrap())
+                .base_url(
+                    base_url
+                        .parse()
+                        .map_err(|_| Error::UrlParse(base_url.to_string()))?,
+                )
                 .auth_callback(token)
                 .build()
         }
@@ -144,43 +541,55 @@ impl TokenClient {
 // Extends trait from fhir_sdk, used to create authorization headers for
 // FHIR Client requests.
 impl LoginManager for Token {
-    type Error = InvalidHeaderValue;
+    type Error = AuthError;
 
     async fn authenticate(
         &mut self,
         client: HttpClient,
     ) -> Result<HeaderValue, <Token as LoginManager>::Error> {
-        // Here, we read lock the token to see if it is still valid, or whether
-        // it needs a refresh.
-        let token_needing_refresh = if self.needs_refresh() {
-            Some((
-                self.token.clone(),
-                self.smart_configuration.clone(),
-                self.base64_secret.clone(),
-            ))
-        } else {
-            None
-        };
-
-        // If the token needs to be refreshed, we issue the refresh API call.
-        //
-        // If the API call succeeds and we get a refreshed token, we write lock
-        // the token and insert the updated token.
-        //
-        // TODO: ideally the read / write pattern here would be a single transaction.
-        // However, we cannot hold a std::sync::RwLock across an async function call,
-        // hence the lock / unlock / relock pattern. This could arguably lead to errors.
-        if let Some((inner_token, smart_configuration, base64_secret)) = token_needing_refresh {
-            let refreshed_token = inner_token
-                .refresh(&client, &smart_configuration, &base64_secret)
+        // we are only called once the FHIR server rejected a request, so if it
+        // rejected the access token that we sent, the token needs a refresh even if
+        // it has not expired by our clock, e.g. because the EHR revoked it partway
+        // through a paged search. clients sharing this token refresh it one at a
+        // time. once we hold the lock, we check again whether the token needs a
+        // refresh, as another client may have refreshed it while we waited.
+        if self.needs_refresh() {
+            let _refreshing = self.refreshing.lock().await;
+            if self.needs_refresh() {
+                let contents = self.contents();
+                let refreshed_token = timeout(
+                    self.token_timeout,
+                    contents.refresh(
+                        &client,
+                        &self.smart_configuration,
+                        &self.credentials,
+                        self.auth_method,
+                    ),
+                )
                 .await;
 
-            if let Ok(refreshed_token) = refreshed_token {
-                self.refresh_token(refreshed_token)
+                match refreshed_token {
+                    Ok(Ok(refreshed_token)) => self.refresh_token(refreshed_token),
+                    Ok(Err(e)) => warn!(
+                        "Refreshing token with {} failed: {e}",
+                        self.smart_configuration.token_endpoint
+                    ),
+                    Err(_) => warn!(
+                        "Refreshing token with {} timed out after {:?}",
+                        self.smart_configuration.token_endpoint, self.token_timeout
+                    ),
+                }
             }
         }
 
-        self.auth_header()
+        // the server will reject an expired token, so we report that the session
+        // has expired rather than sending it
+        if self.contents().has_expired(self.clock_skew) {
+            return Err(AuthError::Expired);
+        }
+
+        self.sent_access_token = Some(self.contents().access_token);
+        self.auth_header().map_err(AuthError::InvalidHeader)
     }
 }
 
@@ -199,12 +608,30 @@ impl TokenContents {
             scopes: Self::split_scopes(response.scope),
             expires_at: Self::expiration(response.expires_in),
             refresh_token: response.refresh_token,
-            id_token: response.id_token,
         }
     }
 
-    fn has_expired(&self) -> bool {
-        Instant::now() > self.expires_at
+    // Checks whether the token has expired.
+    //
+    // The token's lifetime is measured with our clock, which may drift from the
+    // authorization server's, so we only treat the token as expired once it is past
+    // its expiry by more than the allowed clock skew.
+    //
+    // # Arguments
+    // * `clock_skew` The allowed clock skew.
+    fn has_expired(&self, clock_skew: Duration) -> bool {
+        Instant::now() > self.expires_at + clock_skew
+    }
+
+    // Checks whether the token is due for a refresh.
+    //
+    // We refresh the token once it is within the refresh lead of its expiry, so that
+    // we do not send a token that the server is about to treat as expired.
+    //
+    // # Arguments
+    // * `refresh_lead` How long before its expiry the token is refreshed.
+    fn is_due_for_refresh(&self, refresh_lead: Duration) -> bool {
+        Instant::now() + refresh_lead >= self.expires_at
     }
 
     fn can_refresh(&self) -> bool {
@@ -214,13 +641,16 @@ impl TokenContents {
     // Refreshes the token by calling to the FHIR server's token endpoint
     // with token refresh arguments.
     //
-    // Does not update in place, rather this method returns a new token.
+    // Does not update in place, rather this method returns a new token. If the
+    // response does not include a refresh token, the new token keeps our current
+    // refresh token.
     async fn refresh(
         &self,
         reqwest_client: &HttpClient,
         smart_configuration: &SmartConfiguration,
-        base64_secret: &str,
-    ) -> Result<TokenContents, reqwest::Error> {
+        credentials: &ClientCredentials,
+        auth_method: ClientAuthMethod,
+    ) -> Result<TokenContents, TokenError> {
         let refresh_token = self
             .refresh_token
             .as_ref()
@@ -232,44 +662,200 @@ impl TokenContents {
             refresh_token: refresh_token.clone(),
         };
 
-        let request = reqwest_client
-            .post(&smart_configuration.token_endpoint)
-            .form(&request_arguments)
-            .header("Authorization", format!("Basic {}", base64_secret))
+        // we reuse the authentication method that succeeded for the original token
+        let (response, _) = request_token(
+            reqwest_client,
+            &smart_configuration.token_endpoint,
+            &request_arguments,
+            credentials,
+            &[auth_method],
+        )
+        .await?;
+
+        // marshall token response. servers that rotate refresh tokens send a new
+        // refresh token, which replaces ours; servers that do not may omit it, in
+        // which case we keep using the one we have.
+        let mut refreshed = TokenContents::from_response(response);
+        if refreshed.refresh_token.is_none() {
+            refreshed.refresh_token = Some(refresh_token.clone());
+        }
+        Ok(refreshed)
+    }
+}
+
+// Checks whether a token request failed before reaching the token endpoint, e.g.
+// because the connection was refused.
+//
+// Only connection errors qualify: if a request timed out or failed after connecting,
+// the token endpoint may have received it and redeemed the authorization code, in
+// which case a retry would be rejected anyway.
+//
+// # Arguments
+// * `e` The error that the request failed with.
+fn is_transport_error(e: &reqwest::Error) -> bool {
+    e.is_connect()
+}
+
+// Shortens the body of an unexpected response from a token endpoint, for logging.
+//
+// Error pages can be long, so we keep the first `MAX_ERROR_BODY_CHARS` characters.
+//
+// # Arguments
+// * `body` The body of the response.
+fn truncate_body(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(MAX_ERROR_BODY_CHARS) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+// Sends a request to a token endpoint, trying client authentication methods in order.
+//
+// Methods that we do not have credentials for are skipped. If the token endpoint
+// rejects our credentials with an `invalid_client` error, we fall back to the next
+// method; any other error is returned immediately.
+//
+// Returns the token response, along with the authentication method that succeeded.
+//
+// # Arguments
+// * `reqwest_client` The HTTP client to send requests with.
+// * `token_endpoint` The URL of the token endpoint.
+// * `form` The form body to send.
+// * `credentials` The app's client credentials.
+// * `methods` The authentication methods to try, in order of preference.
+async fn request_token<F: Serialize>(
+    reqwest_client: &ReqwestClient,
+    token_endpoint: &str,
+    form: &F,
+    credentials: &ClientCredentials,
+    methods: &[ClientAuthMethod],
+) -> Result<(TokenResponse, ClientAuthMethod), TokenError> {
+    let mut last_error = TokenError::NoClientAuthMethod;
+
+    for method in methods.iter().copied() {
+        if !credentials.supports(method) {
+            debug!("Skipping client authentication method {method}, as we lack credentials for it");
+            continue;
+        }
+
+        let response = credentials
+            .authenticate(reqwest_client.post(token_endpoint), method, form)
             .send()
-            .await;
+            .await?;
 
-        match request {
-            Ok(request) => {
-                let response = request.json::<TokenResponse>().await;
+        // we read the body as text before parsing it, so that a response that is not
+        // JSON, e.g. an HTML error page from a gateway, is reported as it is
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(str::to_string);
+        let body = response.text().await?;
+        let unexpected = || TokenError::UnexpectedResponse {
+            status,
+            content_type: content_type.clone(),
+            body: truncate_body(&body),
+        };
 
-                match response {
-                    Ok(response) => {
-                        // marshall token response
-                        Ok(TokenContents::from_response(response))
-                    }
-                    Err(e) => Err(e),
-                }
-            }
-            Err(e) => Err(e),
+        if status.is_success() {
+            return match serde_json::from_str::<TokenResponse>(&body) {
+                Ok(token) => Ok((token, method)),
+                Err(_) => Err(unexpected()),
+            };
+        }
+
+        let Ok(error) = serde_json::from_str::<TokenErrorResponse>(&body) else {
+            return Err(unexpected());
+        };
+        if error.error == "invalid_client" {
+            warn!("Token endpoint {token_endpoint} rejected client authentication method {method}");
+            last_error = TokenError::Response(error);
+        } else {
+            return Err(TokenError::Response(error));
         }
     }
+
+    Err(last_error)
 }
 
 impl Token {
+    // Gets a copy of the core token fields.
+    //
+    // We copy the fields rather than hold the lock, as the lock cannot be held
+    // across an await.
+    fn contents(&self) -> TokenContents {
+        self.token.read().unwrap().clone()
+    }
+
     fn auth_header(&self) -> Result<HeaderValue, InvalidHeaderValue> {
         HeaderValue::from_str(&format!(
             "AUTHORIZATION: Bearer {}",
-            self.token.access_token
+            self.contents().access_token
         ))
     }
 
+    // Checks whether the token needs a refresh, because it is about to expire or the
+    // FHIR server rejected the access token that we last sent.
     fn needs_refresh(&self) -> bool {
-        self.token.has_expired() && self.token.can_refresh()
+        let contents = self.contents();
+        let rejected = self.sent_access_token.as_ref() == Some(&contents.access_token);
+        (contents.is_due_for_refresh(self.refresh_lead) || rejected) && contents.can_refresh()
+    }
+
+    fn refresh_token(&self, contents: TokenContents) {
+        *self.token.write().unwrap() = contents;
     }
 
-    fn refresh_token(&mut self, contents: TokenContents) {
-        self.token = contents;
+    // Gets the URL that issued this token.
+    pub fn iss(&self) -> &str {
+        &self.iss
+    }
+
+    // Gets the raw id_token, if the EHR returned one.
+    pub fn id_token(&self) -> Option<&str> {
+        self.id_token.as_deref()
+    }
+
+    // Checks whether the EHR should have identified the user with an id_token,
+    // because it returned one or granted the `openid` scope.
+    pub fn expects_id_token(&self) -> bool {
+        self.id_token.is_some() || self.contents().scopes.iter().any(|scope| scope == "openid")
+    }
+
+    // Gets what we need to end the session with the EHR when the user logs out.
+    //
+    // We revoke the refresh token if we have one, as this revokes the whole
+    // grant; otherwise, we revoke the access token. Note that if the EHR rotates
+    // refresh tokens, the token captured here may be stale by the time we log out.
+    fn logout(&self) -> Logout {
+        let revocation = self
+            .smart_configuration
+            .revocation_endpoint
+            .as_ref()
+            .map(|endpoint| {
+                let contents = self.contents();
+                let (token, token_type_hint) = match contents.refresh_token {
+                    Some(refresh_token) => (refresh_token, "refresh_token"),
+                    None => (contents.access_token, "access_token"),
+                };
+
+                Revocation::new(
+                    endpoint.clone(),
+                    token,
+                    token_type_hint,
+                    self.credentials.clone(),
+                    self.auth_method,
+                )
+            });
+
+        Logout {
+            revocation,
+            end_session_endpoint: self.smart_configuration.end_session_endpoint.clone(),
+            id_token: self.id_token.clone(),
+            client_id: self.credentials.client_id.clone(),
+        }
     }
 
     // Requests a token from the token endpoint of a SMART-on-FHIR server.
@@ -281,52 +867,845 @@ impl Token {
     // exchange.
     //
     // # Arguments
+    // * `iss` The URL of the server that issued the launch.
     // * `smart_configuration` The SMART configuration for the server we are requesting
     //   a token from.
     // * `code` The code received from the authorization server.
     // * `verifier` The PKCE verifier that we are exchanging.
     // * `data` The application state.
     pub async fn post(
+        iss: &str,
         smart_configuration: &SmartConfiguration,
         code: &str,
         verifier: &PkceCodeVerifier,
         data: &State,
-    ) -> Result<Token, reqwest::Error> {
+    ) -> Result<Token, TokenError> {
         // NOTE: verifier.secret is a secret and should not be printed
         let request_arguments = TokenRequest {
             grant_type: String::from("authorization_code"),
             code: code.to_string(),
-            redirect_uri: data.callback(),
+            redirect_uri: data.redirect_uri_for(iss),
             code_verifier: verifier.secret().clone(),
         };
 
-        let request = data
-            .reqwest_client
-            .post(&smart_configuration.token_endpoint)
-            .form(&request_arguments)
-            .header("Authorization", format!("Basic {}", data.base64_secret()))
-            .send()
+        let credentials = data.credentials_for(iss);
+        let methods = data.client_auth_methods(smart_configuration);
+
+        // the authorization code is single use and short lived, so if the exchange
+        // fails before reaching the token endpoint, we retry quickly rather than
+        // losing the launch; errors returned by the token endpoint are not retried
+        let mut attempt = 1;
+        let (mut response, auth_method) = loop {
+            let result = request_token(
+                &data.reqwest_client,
+                &smart_configuration.token_endpoint,
+                &request_arguments,
+                &credentials,
+                &methods,
+            )
             .await;
 
-        match request {
-            Ok(request) => {
-                let response = request.json::<TokenResponse>().await;
-
-                match response {
-                    Ok(response) => {
-                        // marshall token response
-                        Ok(Token {
-                            smart_configuration: smart_configuration.clone(),
-                            base64_secret: data.base64_secret(),
-                            patient: response.patient.clone(),
-                            iss: smart_configuration.issuer.clone().unwrap(),
-                            token: TokenContents::from_response(response),
-                        })
-                    }
-                    Err(e) => Err(e),
+            match result {
+                Err(TokenError::Request(e))
+                    if attempt < TOKEN_EXCHANGE_ATTEMPTS && is_transport_error(&e) =>
+                {
+                    warn!(
+                        "Token exchange with {} failed ({e}), retrying",
+                        smart_configuration.token_endpoint
+                    );
+                    attempt += 1;
+                    sleep(TOKEN_EXCHANGE_RETRY_DELAY).await;
                 }
+                result => break result?,
+            }
+        };
+
+        info!(
+            "Authenticated with token endpoint {} using {auth_method}",
+            smart_configuration.token_endpoint
+        );
+
+        // we always send the token as a Bearer token; the token type is case
+        // insensitive, per RFC 6749
+        if !response.token_type.eq_ignore_ascii_case("bearer") {
+            if data.allow_unsupported_token_types {
+                warn!(
+                    "Token endpoint {} issued an unsupported {} token, which we will use as a Bearer token",
+                    smart_configuration.token_endpoint, response.token_type
+                );
+            } else {
+                return Err(TokenError::UnsupportedTokenType(response.token_type));
+            }
+        }
+
+        // marshall token response
+        let user = response.id_token.as_deref().and_then(IdTokenClaims::decode);
+        Ok(Token {
+            smart_configuration: smart_configuration.clone(),
+            credentials,
+            auth_method,
+            patient: response.patient.clone(),
+            encounter: response.encounter.clone(),
+            fhir_context: response
+                .fhir_context
+                .drain(..)
+                .filter_map(FhirContextEntry::reference)
+                .collect(),
+            user: user.clone(),
+            fhir_user: user
+                .and_then(|user| user.fhir_user)
+                .or(response.fhir_user.clone()),
+            brand: None,
+            id_token: response.id_token.clone(),
+            need_patient_banner: response.need_patient_banner.unwrap_or(true),
+            style_url: response.smart_style_url.clone(),
+            ehr_launch: false,
+            // the issuer is optional in SMART configurations, so we use the URL
+            // that issued the launch
+            iss: iss.to_string(),
+            token_timeout: data.token_timeout,
+            clock_skew: data.token_clock_skew,
+            refresh_lead: data.token_refresh_lead,
+            token: Arc::new(RwLock::new(TokenContents::from_response(response))),
+            refreshing: Arc::default(),
+            sent_access_token: None,
+        })
+    }
+}
+
+#[cfg(test)]
+impl Token {
+    // Creates a token issued by a mock EHR, for tests.
+    //
+    // The token is valid for an hour, and cannot be refreshed.
+    //
+    // # Arguments
+    // * `iss` The URL of the mock EHR.
+    // * `patient` The ID of the patient in context, if any.
+    // * `scopes` The granted scopes.
+    pub fn for_test(iss: &str, patient: Option<&str>, scopes: &[&str]) -> Token {
+        Token {
+            smart_configuration: serde_json::from_value(crate::test_support::smart_configuration(
+                iss,
+            ))
+            .unwrap(),
+            credentials: ClientCredentials::new(
+                String::from("test-client"),
+                String::from("test-secret"),
+            ),
+            auth_method: ClientAuthMethod::ClientSecretBasic,
+            token: Arc::new(RwLock::new(TokenContents {
+                access_token: String::from("test-access-token"),
+                scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+                expires_at: TokenContents::expiration(3600),
+                refresh_token: None,
+            })),
+            refreshing: Arc::default(),
+            sent_access_token: None,
+            patient: patient.map(str::to_string),
+            encounter: None,
+            fhir_context: Vec::new(),
+            user: None,
+            fhir_user: None,
+            brand: None,
+            id_token: None,
+            need_patient_banner: true,
+            style_url: None,
+            ehr_launch: true,
+            iss: iss.to_string(),
+            token_timeout: Duration::from_secs(30),
+            clock_skew: Duration::ZERO,
+            refresh_lead: Duration::ZERO,
+        }
+    }
+
+    // Replaces the SMART configuration of a test token.
+    //
+    // # Arguments
+    // * `smart_configuration` The SMART configuration of the mock EHR.
+    pub fn with_smart_configuration(mut self, smart_configuration: SmartConfiguration) -> Token {
+        self.smart_configuration = smart_configuration;
+        self
+    }
+
+    // Sets the id_token of a test token, along with the user claims decoded from it.
+    //
+    // # Arguments
+    // * `id_token` The encoded id_token.
+    pub fn with_id_token(mut self, id_token: &str) -> Token {
+        self.user = IdTokenClaims::decode(id_token);
+        self.fhir_user = self.user.as_ref().and_then(|user| user.fhir_user.clone());
+        self.id_token = Some(id_token.to_string());
+        self
+    }
+
+    // Expires a test token, as if its lifetime had passed.
+    pub fn expired(self) -> Token {
+        self.token.write().unwrap().expires_at = Instant::now() - Duration::from_secs(1);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fhir_sdk::client::SearchParameters;
+    use fhir_sdk::r4b::resources::{Condition, Observation, Patient};
+    use serde_json::{json, Value};
+    use wiremock::matchers::{
+        body_string_contains, header_exists, header_regex, method, path, query_param,
+    };
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::fetch::fetch_for_patient;
+    use crate::smart::client_auth::ClientType;
+    use crate::test_support::{
+        capture_warnings, mock_token_endpoint, search_bundle, smart_configuration, take_warnings,
+        test_state, token_response,
+    };
+
+    // Exchanges an authorization code with a mock EHR.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `smart_configuration` The SMART configuration of the mock EHR.
+    async fn exchange_code(
+        ehr: &MockServer,
+        smart_configuration: Value,
+    ) -> Result<Token, TokenError> {
+        Token::post(
+            &ehr.uri(),
+            &serde_json::from_value(smart_configuration).unwrap(),
+            "test-code",
+            &PkceCodeVerifier::new(String::from("test-verifier")),
+            &test_state(),
+        )
+        .await
+    }
+
+    #[actix_web::test]
+    async fn exchange_falls_back_to_next_auth_method_on_invalid_client() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(header_exists("authorization"))
+            .respond_with(
+                ResponseTemplate::new(401).set_body_json(json!({ "error": "invalid_client" })),
+            )
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("client_secret=test-secret"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(token_response()))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        let mut config = smart_configuration(&ehr.uri());
+        config["token_endpoint_auth_methods_supported"] = json!([
+            "private_key_jwt",
+            "client_secret_basic",
+            "client_secret_post"
+        ]);
+
+        let token = exchange_code(&ehr, config).await.unwrap();
+
+        assert_eq!(token.auth_method, ClientAuthMethod::ClientSecretPost);
+        assert_eq!(token.contents().access_token, "exchanged-access-token");
+    }
+
+    // Creates a token that has expired, but can be refreshed.
+    //
+    // # Arguments
+    // * `iss` The URL of the mock EHR.
+    // * `smart_configuration` The SMART configuration of the mock EHR.
+    fn expired_token(iss: &str, smart_configuration: Value) -> Token {
+        let token = Token::for_test(iss, Some("123"), &["patient/*.read"])
+            .with_smart_configuration(serde_json::from_value(smart_configuration).unwrap())
+            .expired();
+        token.token.write().unwrap().refresh_token = Some(String::from("original-refresh-token"));
+        token
+    }
+
+    // Serves a resource from a mock FHIR server, which rejects requests that do not
+    // carry an access token.
+    //
+    // # Arguments
+    // * `server` The mock FHIR server.
+    // * `resource_path` The path of the resource, e.g. "/Patient/123".
+    // * `access_token` The access token that the server accepts.
+    // * `resource` The resource, as FHIR JSON.
+    async fn mock_protected_resource(
+        server: &MockServer,
+        resource_path: &str,
+        access_token: &str,
+        resource: Value,
+    ) {
+        Mock::given(method("GET"))
+            .and(path(resource_path))
+            .and(header_regex(
+                "authorization",
+                &format!("Bearer {access_token}$"),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(resource))
+            .mount(server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(resource_path))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(server)
+            .await;
+    }
+
+    #[actix_web::test]
+    async fn endpoint_clients_share_refreshed_token() {
+        let ehr = MockServer::start().await;
+        let endpoint = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("refresh_token=original-refresh-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "refreshed-access-token",
+                "token_type": "Bearer",
+                "expires_in": 3600,
+                "scope": "patient/*.read",
+                "refresh_token": "rotated-refresh-token"
+            })))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        mock_protected_resource(
+            &ehr,
+            "/Patient/123",
+            "refreshed-access-token",
+            json!({ "resourceType": "Patient", "id": "123" }),
+        )
+        .await;
+        mock_protected_resource(
+            &endpoint,
+            "/Observation/o1",
+            "refreshed-access-token",
+            json!({
+                "resourceType": "Observation",
+                "id": "o1",
+                "status": "final",
+                "code": { "text": "Height" }
+            }),
+        )
+        .await;
+        let mut config = smart_configuration(&ehr.uri());
+        config["associated_endpoints"] =
+            json!([{ "url": endpoint.uri(), "capabilities": ["Observation"] }]);
+        let token = expired_token(&ehr.uri(), config);
+
+        let client = TokenClient::new(ReqwestClient::new(), token.clone(), &ehr.uri())
+            .await
+            .unwrap();
+        let patient = client.client_for("Patient").read::<Patient>("123").await;
+        let observation = client
+            .client_for("Observation")
+            .read::<Observation>("o1")
+            .await;
+
+        assert!(patient.unwrap().is_some());
+        assert!(observation.unwrap().is_some());
+        assert_eq!(
+            token.contents().refresh_token.as_deref(),
+            Some("rotated-refresh-token")
+        );
+    }
+
+    #[actix_web::test]
+    async fn exchange_uses_credentials_registered_for_issuer() {
+        let first = MockServer::start().await;
+        let second = MockServer::start().await;
+        // both mock EHRs listen on the loopback address, so address the second by
+        // name to give it a host of its own
+        let first_iss = first.uri();
+        let second_iss = second.uri().replace("127.0.0.1", "localhost");
+        for (server, secret) in [(&first, "first-secret"), (&second, "second-secret")] {
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .and(body_string_contains(format!("client_secret={secret}")))
+                .respond_with(ResponseTemplate::new(200).set_body_json(token_response()))
+                .expect(1)
+                .mount(server)
+                .await;
+        }
+        let data = test_state().with_credentials(HashMap::from([
+            (
+                String::from("127.0.0.1"),
+                (String::from("first-client"), String::from("first-secret")),
+            ),
+            (
+                String::from("localhost"),
+                (String::from("second-client"), String::from("second-secret")),
+            ),
+        ]));
+
+        for iss in [&first_iss, &second_iss] {
+            let mut config = smart_configuration(iss);
+            config["token_endpoint_auth_methods_supported"] = json!(["client_secret_post"]);
+            let token = Token::post(
+                iss,
+                &serde_json::from_value(config).unwrap(),
+                "test-code",
+                &PkceCodeVerifier::new(String::from("test-verifier")),
+                &data,
+            )
+            .await;
+
+            assert!(token.is_ok(), "exchange with {iss} failed");
+        }
+    }
+
+    // Refreshes an expired token against a mock token endpoint.
+    //
+    // Returns the refresh token held after the refresh.
+    //
+    // # Arguments
+    // * `refresh_response` The response of the token endpoint to the refresh.
+    async fn refresh_with(refresh_response: Value) -> Option<String> {
+        let ehr = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(refresh_response))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        let mut token = expired_token(&ehr.uri(), smart_configuration(&ehr.uri()));
+
+        token.authenticate(ReqwestClient::new()).await.unwrap();
+
+        token.contents().refresh_token
+    }
+
+    #[actix_web::test]
+    async fn refresh_adopts_rotated_refresh_token() {
+        let refresh_token = refresh_with(json!({
+            "access_token": "refreshed-access-token",
+            "token_type": "Bearer",
+            "expires_in": 3600,
+            "scope": "patient/*.read",
+            "refresh_token": "rotated-refresh-token"
+        }))
+        .await;
+
+        assert_eq!(refresh_token.as_deref(), Some("rotated-refresh-token"));
+    }
+
+    #[actix_web::test]
+    async fn refresh_keeps_refresh_token_when_response_omits_it() {
+        let refresh_token = refresh_with(json!({
+            "access_token": "refreshed-access-token",
+            "token_type": "Bearer",
+            "expires_in": 3600,
+            "scope": "patient/*.read"
+        }))
+        .await;
+
+        assert_eq!(refresh_token.as_deref(), Some("original-refresh-token"));
+    }
+
+    #[actix_web::test]
+    async fn public_client_sends_client_id_without_secret() {
+        let ehr = MockServer::start().await;
+        let mut response = token_response();
+        response["refresh_token"] = json!("original-refresh-token");
+        mock_token_endpoint(&ehr, response).await;
+        let data = test_state().with_client_type(ClientType::Public);
+
+        let token = Token::post(
+            &ehr.uri(),
+            &serde_json::from_value(smart_configuration(&ehr.uri())).unwrap(),
+            "test-code",
+            &PkceCodeVerifier::new(String::from("test-verifier")),
+            &data,
+        )
+        .await
+        .unwrap();
+        token.token.write().unwrap().expires_at = Instant::now() - Duration::from_secs(1);
+        token
+            .clone()
+            .authenticate(ReqwestClient::new())
+            .await
+            .unwrap();
+
+        let requests = ehr.received_requests().await.unwrap();
+        let grant_types: Vec<_> = requests
+            .iter()
+            .map(|request| {
+                let form: HashMap<String, String> = url::form_urlencoded::parse(&request.body)
+                    .into_owned()
+                    .collect();
+                assert_eq!(form["client_id"], "test-client");
+                assert!(!form.contains_key("client_secret"));
+                assert!(!request.headers.contains_key("authorization"));
+                form["grant_type"].clone()
+            })
+            .collect();
+        assert_eq!(grant_types, vec!["authorization_code", "refresh_token"]);
+    }
+
+    #[actix_web::test]
+    async fn exchange_is_retried_after_connection_error() {
+        // reserve a port, and only start the mock EHR on it after the first attempt
+        // has been refused
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let ehr = actix_web::rt::spawn(async move {
+            actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+            let ehr = MockServer::builder()
+                .listener(std::net::TcpListener::bind(address).unwrap())
+                .start()
+                .await;
+            mock_token_endpoint(&ehr, token_response()).await;
+            ehr
+        });
+        let iss = format!("http://{address}");
+
+        let token = Token::post(
+            &iss,
+            &serde_json::from_value(smart_configuration(&iss)).unwrap(),
+            "test-code",
+            &PkceCodeVerifier::new(String::from("test-verifier")),
+            &test_state(),
+        )
+        .await;
+
+        assert!(token.is_ok());
+        assert_eq!(
+            ehr.await.unwrap().received_requests().await.unwrap().len(),
+            1
+        );
+    }
+
+    #[actix_web::test]
+    async fn exchange_is_not_retried_on_invalid_grant() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(
+                ResponseTemplate::new(400).set_body_json(json!({ "error": "invalid_grant" })),
+            )
+            .expect(1)
+            .mount(&ehr)
+            .await;
+
+        let token = exchange_code(&ehr, smart_configuration(&ehr.uri())).await;
+
+        assert!(token.is_err());
+    }
+
+    // Exchanges an authorization code at a mock EHR that issues tokens of a type.
+    //
+    // # Arguments
+    // * `token_type` The type of the issued tokens, e.g. "Bearer".
+    // * `data` The application state.
+    async fn exchange_for_token_type(token_type: &str, data: &State) -> Result<Token, TokenError> {
+        let ehr = MockServer::start().await;
+        let mut response = token_response();
+        response["token_type"] = json!(token_type);
+        mock_token_endpoint(&ehr, response).await;
+
+        Token::post(
+            &ehr.uri(),
+            &serde_json::from_value(smart_configuration(&ehr.uri())).unwrap(),
+            "test-code",
+            &PkceCodeVerifier::new(String::from("test-verifier")),
+            data,
+        )
+        .await
+    }
+
+    #[actix_web::test]
+    async fn bearer_token_type_is_case_insensitive() {
+        let token = exchange_for_token_type("bearer", &test_state()).await;
+
+        assert!(token.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn unsupported_token_type_is_rejected() {
+        let token = exchange_for_token_type("DPoP", &test_state()).await;
+
+        assert!(matches!(
+            token,
+            Err(TokenError::UnsupportedTokenType(token_type)) if token_type == "DPoP"
+        ));
+    }
+
+    #[actix_web::test]
+    async fn unsupported_token_type_is_allowed_with_warning() {
+        let data = test_state().with_allow_unsupported_token_types(true);
+        capture_warnings();
+
+        let token = exchange_for_token_type("DPoP", &data).await;
+
+        assert!(token.is_ok());
+        assert!(take_warnings()
+            .iter()
+            .any(|warning| warning.contains("unsupported DPoP token")));
+    }
+
+    #[actix_web::test]
+    async fn paged_search_survives_refresh_between_pages() {
+        let ehr = MockServer::start().await;
+        let condition = |id: &str| {
+            json!({
+                "resourceType": "Condition",
+                "id": id,
+                "subject": { "reference": "Patient/123" }
+            })
+        };
+        let mut first_page = search_bundle(vec![condition("c1")]);
+        first_page["link"] = json!([{
+            "relation": "next",
+            "url": format!("{}/Condition?page=2", ehr.uri())
+        }]);
+        // the server stops accepting the original token after the first page
+        Mock::given(method("GET"))
+            .and(path("/Condition"))
+            .and(query_param("subject", "Patient/123"))
+            .and(header_regex("authorization", "Bearer test-access-token$"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(first_page))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/Condition"))
+            .and(query_param("page", "2"))
+            .and(header_regex(
+                "authorization",
+                "Bearer refreshed-access-token$",
+            ))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(search_bundle(vec![condition("c2")])),
+            )
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&ehr)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("refresh_token=original-refresh-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "refreshed-access-token",
+                "token_type": "Bearer",
+                "expires_in": 3600,
+                "scope": "patient/*.read"
+            })))
+            .expect(1)
+            .mount(&ehr)
+            .await;
+        let token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]);
+        token.token.write().unwrap().refresh_token = Some(String::from("original-refresh-token"));
+
+        let client = TokenClient::new(ReqwestClient::new(), token, &ehr.uri())
+            .await
+            .unwrap();
+        let conditions: Vec<Condition> =
+            fetch_for_patient(&client.client, "123", SearchParameters::empty(), 10)
+                .await
+                .unwrap();
+
+        let ids: Vec<_> = conditions
+            .iter()
+            .map(|condition| condition.id.as_deref().unwrap())
+            .collect();
+        assert_eq!(ids, ["c1", "c2"]);
+    }
+
+    // Creates a client for a mock EHR with a token that expires some time from now.
+    //
+    // The token allows a clock skew of 30 seconds, and is refreshed 60 seconds before
+    // its expiry if it can be.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `expires_in` How long until the token expires, in seconds; negative if it
+    //   has already expired.
+    // * `refreshable` Whether the token has a refresh token.
+    async fn client_with_token_expiring_in(
+        ehr: &MockServer,
+        expires_in: i64,
+        refreshable: bool,
+    ) -> TokenClient {
+        let mut token = Token::for_test(&ehr.uri(), Some("123"), &["patient/*.read"]);
+        token.clock_skew = Duration::from_secs(30);
+        token.refresh_lead = Duration::from_secs(60);
+        let offset = Duration::from_secs(expires_in.unsigned_abs());
+        {
+            let mut contents = token.token.write().unwrap();
+            contents.expires_at = if expires_in >= 0 {
+                Instant::now() + offset
+            } else {
+                Instant::now() - offset
+            };
+            if refreshable {
+                contents.refresh_token = Some(String::from("refresh-token"));
             }
-            Err(e) => Err(e),
         }
+        TokenClient::new(ReqwestClient::new(), token, &ehr.uri())
+            .await
+            .unwrap()
+    }
+
+    // Mounts a patient that the mock EHR only returns for an access token, rejecting
+    // all other requests, and a token endpoint that refreshes tokens.
+    //
+    // # Arguments
+    // * `ehr` The mock EHR.
+    // * `access_token` The access token that the patient is returned for.
+    // * `refreshes` How many refreshes we expect.
+    async fn mock_patient_for_token(ehr: &MockServer, access_token: &str, refreshes: u64) {
+        Mock::given(method("GET"))
+            .and(path("/Patient/123"))
+            .and(header_regex(
+                "authorization",
+                &format!("Bearer {access_token}$"),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "resourceType": "Patient",
+                "id": "123"
+            })))
+            .mount(ehr)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(ehr)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "refreshed-access-token",
+                "token_type": "Bearer",
+                "expires_in": 3600,
+                "scope": "patient/*.read"
+            })))
+            .expect(refreshes)
+            .mount(ehr)
+            .await;
+    }
+
+    #[actix_web::test]
+    async fn token_just_inside_refresh_lead_is_refreshed_before_use() {
+        let ehr = MockServer::start().await;
+        mock_patient_for_token(&ehr, "refreshed-access-token", 1).await;
+        let client = client_with_token_expiring_in(&ehr, 59, true).await;
+
+        let patient = client.client.read::<Patient>("123").await;
+
+        assert!(patient.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn token_just_outside_refresh_lead_is_used_as_is() {
+        let ehr = MockServer::start().await;
+        mock_patient_for_token(&ehr, "test-access-token", 0).await;
+        let client = client_with_token_expiring_in(&ehr, 61, true).await;
+
+        let patient = client.client.read::<Patient>("123").await;
+
+        assert!(patient.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn token_just_inside_skew_past_expiry_is_still_sent() {
+        let ehr = MockServer::start().await;
+        mock_patient_for_token(&ehr, "test-access-token", 0).await;
+        let client = client_with_token_expiring_in(&ehr, -29, false).await;
+
+        let patient = client.client.read::<Patient>("123").await;
+
+        assert!(patient.unwrap().is_some());
+    }
+
+    #[actix_web::test]
+    async fn token_just_outside_skew_past_expiry_has_expired() {
+        let ehr = MockServer::start().await;
+        mock_patient_for_token(&ehr, "test-access-token", 0).await;
+        let client = client_with_token_expiring_in(&ehr, -31, false).await;
+
+        let patient = client.client.read::<Patient>("123").await;
+
+        assert!(matches!(patient, Err(Error::AuthCallback(_))));
+        let sent_token = ehr
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .any(|request| request.headers.contains_key("authorization"));
+        assert!(!sent_token);
+    }
+
+    #[actix_web::test]
+    async fn html_error_from_token_endpoint_is_reported() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(
+                ResponseTemplate::new(502)
+                    .set_body_raw("<html><body>Bad Gateway</body></html>", "text/html"),
+            )
+            .mount(&ehr)
+            .await;
+
+        let error = exchange_code(&ehr, smart_configuration(&ehr.uri()))
+            .await
+            .err()
+            .unwrap();
+
+        let TokenError::UnexpectedResponse {
+            status,
+            content_type,
+            body,
+        } = &error
+        else {
+            panic!("Expected an unexpected response error, got: {error}");
+        };
+        assert_eq!(*status, StatusCode::BAD_GATEWAY);
+        assert_eq!(content_type.as_deref(), Some("text/html"));
+        assert_eq!(body, "<html><body>Bad Gateway</body></html>");
+        assert!(error.to_string().contains("HTTP 502 Bad Gateway"));
+    }
+
+    #[actix_web::test]
+    async fn successful_non_json_token_response_is_reported() {
+        let ehr = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw("OK", "text/plain"))
+            .mount(&ehr)
+            .await;
+
+        let error = exchange_code(&ehr, smart_configuration(&ehr.uri()))
+            .await
+            .err()
+            .unwrap();
+
+        assert!(matches!(
+            error,
+            TokenError::UnexpectedResponse { status: StatusCode::OK, ref body, .. } if body == "OK"
+        ));
+    }
+
+    #[test]
+    fn long_error_bodies_are_truncated() {
+        let body = "x".repeat(MAX_ERROR_BODY_CHARS + 10);
+
+        let truncated = truncate_body(&body);
+
+        assert_eq!(truncated.len(), MAX_ERROR_BODY_CHARS + 3);
+        assert!(truncated.ends_with("..."));
+        assert_eq!(truncate_body("  short  "), "short");
     }
 }
@@ -14,49 +14,984 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono_tz::Tz;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use log::warn;
 use oauth2::{PkceCodeChallenge, PkceCodeVerifier};
 use reqwest::Client;
+use ring::digest::{digest, SHA256};
+use url::Url;
 use uuid::Uuid;
 
-use crate::smart::configuration::SmartConfiguration;
+use crate::allowlist::IssuerAllowlist;
+use crate::context::LaunchContext;
+use crate::context_cookie::ContextCookieCipher;
+use crate::error::{AppError, ErrorPage, ErrorPageText};
+use crate::fetch::fetch_patient;
+use crate::http::HttpClientConfig;
+use crate::launch::{LaunchMode, ScopeVersion};
+use crate::limit::{AuthBreaker, RequestLimiter};
+use crate::metrics::ConnectionMetrics;
+use crate::observation::{default_value_precision, ValuePrecision};
+use crate::pkce::{StoredVerifier, VerifierCipher};
+use crate::search_support::{SearchSupport, SearchSupportCache};
+use crate::session::{RecentPatient, RecentPatients};
+use crate::smart::brand::{Brand, BrandCache};
+use crate::smart::client_auth::{
+    ClientAuthMethod, ClientCredentials, ClientType, DEFAULT_CLIENT_AUTH_METHODS,
+};
+use crate::smart::configuration::{host_root, SmartConfiguration};
+use crate::smart::jwks::KeyStore;
+use crate::smart::style::{SmartStyle, StyleCache};
 use crate::smart::token::{Token, TokenClient};
 
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How long we remember that an expired launch was started, so that a user
+// returning to it is told that it expired rather than that it is unknown.
+const EXPIRED_LAUNCH_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How long we remember that a launch completed, so that a duplicate delivery of
+// its callback can be redirected to the patient summary.
+const COMPLETED_LAUNCH_RETENTION: Duration = Duration::from_secs(60);
+
+// How long we remember that an authorization code was exchanged. RFC 6749 recommends
+// that codes expire within 10 minutes, so a replayed code is rejected by the EHR
+// after that.
+const USED_CODE_RETENTION: Duration = Duration::from_secs(10 * 60);
+
+// The FHIR version path segment we try if the FHIR server does not find the patient in
+// context under the issuer URL.
+const DETECTED_FHIR_VERSION: &str = "R4";
+
+// A launch that is waiting for the EHR to redirect back to our callback.
+pub struct PendingLaunch {
+    // The URL of the server that issued the launch.
+    pub iss: String,
+
+    // The unique launch ID received from the launching EHR, if the EHR launched us.
+    pub launch_id: Option<String>,
+
+    // The number of times the launch has been restarted to re-authenticate the user.
+    pub reauth_attempts: u32,
+
+    // The `aud` we sent in place of the issuer, if the launch was restarted because
+    // the EHR rejected the issuer as the audience.
+    pub aud: Option<String>,
+
+    // The ID of the patient to preselect, if a standalone launch asked for one.
+    pub patient_hint: Option<String>,
+}
+
+// A FHIR client for a patient, along with when it was last used.
+struct Session {
+    client: TokenClient,
+    last_accessed: Instant,
+}
+
+impl Session {
+    fn is_idle(&self, idle_timeout: Duration) -> bool {
+        self.last_accessed.elapsed() > idle_timeout
+    }
+}
+
+// The result of looking up the session for a patient.
+pub enum SessionLookup {
+    // There is exactly one matching session.
+    Found(Box<TokenClient>),
+
+    // The patient has sessions from more than one issuer; holds the context of each.
+    Ambiguous(Vec<LaunchContext>),
+
+    // There is no matching session.
+    Missing,
+}
 
 pub struct State {
     pub app_domain: String,
     pub client_id: String,
     pub client_secret: String,
+    pub credentials: HashMap<String, (String, String)>,
+    pub issuer_names: HashMap<String, String>,
+    pub observation_codes: HashMap<String, Vec<String>>,
+    pub value_precision: ValuePrecision,
+    pub issuer_scopes: HashMap<String, Vec<String>>,
+    pub issuer_redirect_uris: HashMap<String, String>,
+    pub issuer_fhir_versions: HashMap<String, String>,
+    pub proxy_resource_types: Vec<String>,
     pub reqwest_client: Client,
+    pub connection_metrics: ConnectionMetrics,
+    pub iss_allowlist: IssuerAllowlist,
+    pub strict_schemes: bool,
+    pub dev_mode: bool,
+    pub trim_iss_trailing_slash: bool,
+    pub strict_smart_configuration: bool,
+    pub host_root_discovery: bool,
+    pub allow_unsupported_token_types: bool,
+    pub allow_ehr_framing: bool,
+    pub show_granted_scopes: bool,
+    pub pretty_json: bool,
+    pub relaunch_url: String,
+    pub error_page_text: ErrorPageText,
+    pub post_logout_url: Option<String>,
+    pub smart_configuration_max_age: Duration,
+    pub style_max_age: Duration,
+    pub admin_key: Option<String>,
+    pub idle_timeout: Duration,
+    pub launch_timeout: Duration,
+    pub discovery_timeout: Duration,
+    pub token_timeout: Duration,
+    pub token_clock_skew: Duration,
+    pub token_refresh_lead: Duration,
+    pub resource_timeout: Duration,
+    pub summary_timeout: Duration,
+    pub timezone: Tz,
+    pub search_limit: usize,
+    pub max_concurrent_requests: Option<usize>,
+    pub max_sessions: Option<usize>,
+    pub auth_failure_threshold: u32,
+    pub auth_failure_cooldown: Duration,
+    pub client_auth_methods: Vec<ClientAuthMethod>,
+    pub client_type: ClientType,
+    pub launch_mode: LaunchMode,
+    pub scope_version: ScopeVersion,
+    pub check_search_support: bool,
+
+    verifier_cipher: Option<VerifierCipher>,
+    pub context_cookie_cipher: Option<ContextCookieCipher>,
+    // the maps below are sharded, so that requests for different launches and
+    // sessions do not wait on each other
+    pkce: DashMap<Uuid, (PkceCodeChallenge, StoredVerifier)>,
+    nonces: DashMap<Uuid, String>,
+    smart_configurations: DashMap<String, (Arc<SmartConfiguration>, Instant)>,
+    // the FHIR version path segments that issuers turned out to need, keyed by issuer
+    detected_fhir_versions: DashMap<String, String>,
+    // each launch keeps the configuration it started with, so that invalidating the
+    // cached configuration does not break launches in flight
+    iss: DashMap<Uuid, (String, Arc<SmartConfiguration>)>,
+    launch_started: DashMap<Uuid, Instant>,
+    pending_launches: DashMap<Uuid, PendingLaunch>,
+    completed_launches: DashMap<Uuid, (String, Instant)>,
+    used_codes: DashMap<Vec<u8>, Instant>,
+    brands: BrandCache,
+    styles: StyleCache,
+    keys: KeyStore,
+    search_support: SearchSupportCache,
+    recent_patients: DashMap<Uuid, (RecentPatients, Instant)>,
+    // sessions, keyed by session key; patient IDs are only unique within an issuer,
+    // so a key can hold one session per issuer
+    tokens: DashMap<String, Vec<Session>>,
+}
+
+impl State {
+    pub fn new(app_domain: String, client_id: String, client_secret: String) -> State {
+        let connection_metrics = ConnectionMetrics::default();
+
+        State {
+            app_domain,
+            client_id,
+            client_secret,
+            credentials: HashMap::new(),
+            issuer_names: HashMap::new(),
+            observation_codes: HashMap::new(),
+            value_precision: default_value_precision(),
+            issuer_scopes: HashMap::new(),
+            issuer_redirect_uris: HashMap::new(),
+            issuer_fhir_versions: HashMap::new(),
+            proxy_resource_types: Vec::new(),
+            reqwest_client: HttpClientConfig::default()
+                .build(&connection_metrics)
+                .expect("Failed to build HTTP client."),
+            connection_metrics,
+            iss_allowlist: IssuerAllowlist::default(),
+            strict_schemes: false,
+            dev_mode: false,
+            trim_iss_trailing_slash: true,
+            strict_smart_configuration: false,
+            host_root_discovery: false,
+            allow_unsupported_token_types: false,
+            allow_ehr_framing: true,
+            show_granted_scopes: false,
+            pretty_json: false,
+            relaunch_url: String::from("https://launch.smarthealthit.org/"),
+            error_page_text: ErrorPageText::default(),
+            post_logout_url: None,
+            smart_configuration_max_age: Duration::ZERO,
+            style_max_age: Duration::from_secs(60 * 60),
+            admin_key: None,
+            idle_timeout: Duration::from_secs(30 * 60),
+            launch_timeout: Duration::from_secs(10 * 60),
+            discovery_timeout: Duration::from_secs(10),
+            token_timeout: Duration::from_secs(30),
+            token_clock_skew: Duration::ZERO,
+            token_refresh_lead: Duration::ZERO,
+            resource_timeout: Duration::from_secs(60),
+            summary_timeout: Duration::from_secs(15),
+            timezone: Tz::UTC,
+            search_limit: 1000,
+            max_concurrent_requests: None,
+            max_sessions: None,
+            auth_failure_threshold: 3,
+            auth_failure_cooldown: Duration::from_secs(60),
+            client_auth_methods: DEFAULT_CLIENT_AUTH_METHODS.to_vec(),
+            client_type: ClientType::Confidential,
+            launch_mode: LaunchMode::Patient,
+            scope_version: ScopeVersion::V1,
+            check_search_support: false,
+            verifier_cipher: None,
+            context_cookie_cipher: None,
+            pkce: DashMap::new(),
+            nonces: DashMap::new(),
+            smart_configurations: DashMap::new(),
+            detected_fhir_versions: DashMap::new(),
+            iss: DashMap::new(),
+            launch_started: DashMap::new(),
+            pending_launches: DashMap::new(),
+            completed_launches: DashMap::new(),
+            used_codes: DashMap::new(),
+            brands: BrandCache::default(),
+            styles: StyleCache::default(),
+            keys: KeyStore::new(64, Duration::from_secs(60 * 60)),
+            search_support: SearchSupportCache::default(),
+            recent_patients: DashMap::new(),
+            tokens: DashMap::new(),
+        }
+    }
+
+    // Sets the configuration of the HTTP client used to call EHR and FHIR servers.
+    //
+    // # Arguments
+    // * `config` The HTTP client configuration.
+    pub fn with_http_client_config(mut self, config: &HttpClientConfig) -> State {
+        self.reqwest_client = config
+            .build(&self.connection_metrics)
+            .expect("Failed to build HTTP client.");
+        self
+    }
+
+    // Sets the issuers that are allowed to launch this app.
+    //
+    // By default, all issuers are allowed.
+    //
+    // # Arguments
+    // * `iss_allowlist` The allowed issuers.
+    pub fn with_iss_allowlist(mut self, iss_allowlist: IssuerAllowlist) -> State {
+        self.iss_allowlist = iss_allowlist;
+        self
+    }
+
+    // Sets whether EHR endpoints using an insecure `http://` scheme are rejected.
+    //
+    // By default, insecure endpoints are allowed, but a warning is logged.
+    //
+    // # Arguments
+    // * `strict_schemes` If true, rejects insecure endpoints.
+    pub fn with_strict_schemes(mut self, strict_schemes: bool) -> State {
+        self.strict_schemes = strict_schemes;
+        self
+    }
+
+    // Sets whether the app runs in dev mode, which enables debugging aids that must
+    // not be exposed in production, such as dry run launches.
+    //
+    // # Arguments
+    // * `dev_mode` If true, enables dev mode.
+    pub fn with_dev_mode(mut self, dev_mode: bool) -> State {
+        self.dev_mode = dev_mode;
+        self
+    }
+
+    // Sets whether trailing slashes are trimmed from issuer URLs.
+    //
+    // By default, an issuer that launches us as "https://ehr.example.com/fhir/" is
+    // treated as "https://ehr.example.com/fhir", so that sessions, cached
+    // configurations, and the `aud` we send use one form of the URL.
+    //
+    // # Arguments
+    // * `trim_iss_trailing_slash` If false, issuer URLs are used exactly as received.
+    pub fn with_trim_iss_trailing_slash(mut self, trim_iss_trailing_slash: bool) -> State {
+        self.trim_iss_trailing_slash = trim_iss_trailing_slash;
+        self
+    }
+
+    // Gets the form of an issuer URL that we store and compare; see
+    // `with_trim_iss_trailing_slash`.
+    //
+    // # Arguments
+    // * `iss` The issuer URL, as received.
+    pub fn normalize_iss(&self, iss: &str) -> String {
+        if self.trim_iss_trailing_slash {
+            iss.trim_end_matches('/').to_string()
+        } else {
+            iss.to_string()
+        }
+    }
+
+    // Sets whether unrecognized fields in SMART configurations are reported.
+    //
+    // By default, unrecognized fields are silently ignored.
+    //
+    // # Arguments
+    // * `strict_smart_configuration` If true, logs a warning for each unrecognized field.
+    pub fn with_strict_smart_configuration(mut self, strict_smart_configuration: bool) -> State {
+        self.strict_smart_configuration = strict_smart_configuration;
+        self
+    }
+
+    // Sets whether we look for SMART configurations at the root of issuers' hosts.
+    //
+    // By default, we only do so for issuers whose host root is itself allowed by the
+    // issuer allowlist; see `allows_host_root_discovery`.
+    //
+    // # Arguments
+    // * `host_root_discovery` If true, falls back to the host root for all issuers.
+    pub fn with_host_root_discovery(mut self, host_root_discovery: bool) -> State {
+        self.host_root_discovery = host_root_discovery;
+        self
+    }
+
+    // Checks whether we may fetch an issuer's SMART configuration from the root of its
+    // host, if it is not found under the issuer's base URL.
+    //
+    // The host root may be served by a different application than the FHIR server, so
+    // we only trust it if configured to, or if an allowlist entry allows the host root
+    // as an issuer in its own right (e.g., a host entry rather than a URL prefix).
+    //
+    // # Arguments
+    // * `iss` The issuer.
+    pub fn allows_host_root_discovery(&self, iss: &str) -> bool {
+        self.host_root_discovery
+            || (!self.iss_allowlist.is_empty()
+                && host_root(iss.trim_end_matches('/'))
+                    .is_some_and(|host_root| self.iss_allowlist.allows(&host_root)))
+    }
+
+    // Sets whether we accept tokens of types that we do not support.
+    //
+    // We only support `Bearer` tokens. By default, token responses with any other
+    // token type (e.g., `DPoP`) are rejected, as the FHIR server would reject our
+    // requests anyway.
+    //
+    // # Arguments
+    // * `allow_unsupported_token_types` If true, logs a warning for unsupported
+    //   token types, and uses the tokens as `Bearer` tokens.
+    pub fn with_allow_unsupported_token_types(
+        mut self,
+        allow_unsupported_token_types: bool,
+    ) -> State {
+        self.allow_unsupported_token_types = allow_unsupported_token_types;
+        self
+    }
+
+    // Sets whether EHRs may display the app that they launched in a frame.
+    //
+    // By default, pages for EHR launches may be framed by the issuer's origin. If
+    // disabled, all pages deny framing, which breaks EHRs that embed apps.
+    //
+    // # Arguments
+    // * `allow_ehr_framing` If true, permits the issuer to frame EHR launches.
+    pub fn with_allow_ehr_framing(mut self, allow_ehr_framing: bool) -> State {
+        self.allow_ehr_framing = allow_ehr_framing;
+        self
+    }
+
+    // Sets whether a successful launch shows the scopes that the EHR granted, before
+    // continuing to the landing page.
+    //
+    // By default, launches redirect straight to the landing page.
+    //
+    // # Arguments
+    // * `show_granted_scopes` If true, shows the granted scopes after launching.
+    pub fn with_show_granted_scopes(mut self, show_granted_scopes: bool) -> State {
+        self.show_granted_scopes = show_granted_scopes;
+        self
+    }
+
+    // Sets whether JSON endpoints indent their output by default.
+    //
+    // By default, output is compact. Requests can override this with the `pretty`
+    // query parameter.
+    //
+    // # Arguments
+    // * `pretty_json` If true, indents JSON output.
+    pub fn with_pretty_json(mut self, pretty_json: bool) -> State {
+        self.pretty_json = pretty_json;
+        self
+    }
+
+    // Sets the URL that users are sent to when they need to relaunch the app.
+    //
+    // By default, this is the [SMART Sandbox Launcher](https://launch.smarthealthit.org/).
+    //
+    // # Arguments
+    // * `relaunch_url` The URL to relaunch the app from.
+    pub fn with_relaunch_url(mut self, relaunch_url: String) -> State {
+        self.relaunch_url = relaunch_url;
+        self
+    }
+
+    // Sets the text shown on error pages.
+    //
+    // # Arguments
+    // * `error_page_text` The text for each kind of error page.
+    pub fn with_error_page_text(mut self, error_page_text: ErrorPageText) -> State {
+        self.error_page_text = error_page_text;
+        self
+    }
+
+    // Sets the URL that users are redirected to after logging out.
+    //
+    // By default, there is no post-logout redirect.
+    //
+    // # Arguments
+    // * `post_logout_url` The URL to redirect to after logging out.
+    pub fn with_post_logout_url(mut self, post_logout_url: Option<String>) -> State {
+        self.post_logout_url = post_logout_url;
+        self
+    }
+
+    // Sets how long a SMART configuration fetched for a launch is reused for later
+    // launches from the same issuer.
+    //
+    // By default, this is zero, so that each launch fetches the configuration.
+    //
+    // # Arguments
+    // * `smart_configuration_max_age` How long to reuse configurations for.
+    pub fn with_smart_configuration_max_age(
+        mut self,
+        smart_configuration_max_age: Duration,
+    ) -> State {
+        self.smart_configuration_max_age = smart_configuration_max_age;
+        self
+    }
+
+    // Sets how long styles fetched from an EHR's `smart_style_url` are reused for.
+    //
+    // By default, styles are reused for an hour.
+    //
+    // # Arguments
+    // * `style_max_age` How long to reuse styles for.
+    pub fn with_style_max_age(mut self, style_max_age: Duration) -> State {
+        self.style_max_age = style_max_age;
+        self
+    }
+
+    // Sets how many EHR key sets we cache for checking id_token signatures, and for
+    // how long.
+    //
+    // By default, we keep the key sets of up to 64 EHRs, and reuse each for an hour.
+    //
+    // # Arguments
+    // * `max_key_sets` The most key sets to keep.
+    // * `max_age` How long to reuse a key set for.
+    pub fn with_key_set_cache(mut self, max_key_sets: usize, max_age: Duration) -> State {
+        self.keys = KeyStore::new(max_key_sets, max_age);
+        self
+    }
+
+    // Sets the key that guards administrative endpoints, e.g. invalidating cached
+    // SMART configurations.
+    //
+    // By default, there is no key, and administrative endpoints are disabled.
+    //
+    // # Arguments
+    // * `admin_key` The key that requests must present as a `Bearer` token.
+    pub fn with_admin_key(mut self, admin_key: Option<String>) -> State {
+        self.admin_key = admin_key;
+        self
+    }
+
+    // Sets how long a session can go unused before it is dropped.
+    //
+    // Idle sessions are dropped regardless of whether their token could be
+    // refreshed. By default, sessions are dropped after 30 minutes.
+    //
+    // # Arguments
+    // * `idle_timeout` The maximum time a session can go unused.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> State {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    // Sets how long a launch can take to complete before it expires.
+    //
+    // A launch is complete once the EHR redirects the browser to our callback.
+    // By default, launches expire after 10 minutes.
+    //
+    // # Arguments
+    // * `launch_timeout` The maximum time between a launch and its callback.
+    pub fn with_launch_timeout(mut self, launch_timeout: Duration) -> State {
+        self.launch_timeout = launch_timeout;
+        self
+    }
+
+    // Sets how long we wait for an EHR's SMART configuration during a launch.
+    //
+    // Discovery is a single small document, so by default we wait for 10 seconds.
+    //
+    // # Arguments
+    // * `discovery_timeout` The maximum time to wait for a SMART configuration.
+    pub fn with_discovery_timeout(mut self, discovery_timeout: Duration) -> State {
+        self.discovery_timeout = discovery_timeout;
+        self
+    }
+
+    // Sets how long we wait for the token endpoint, when exchanging a code for a
+    // token or refreshing a token.
+    //
+    // By default, we wait for 30 seconds.
+    //
+    // # Arguments
+    // * `token_timeout` The maximum time to wait for a token.
+    pub fn with_token_timeout(mut self, token_timeout: Duration) -> State {
+        self.token_timeout = token_timeout;
+        self
+    }
+
+    // Sets how far our clock may drift from the authorization server's before we treat
+    // a token as expired.
+    //
+    // Tokens are only rejected as expired once they are past their expiry by more than
+    // the skew. By default, there is no allowance for skew.
+    //
+    // # Arguments
+    // * `token_clock_skew` The allowed clock skew.
+    pub fn with_token_clock_skew(mut self, token_clock_skew: Duration) -> State {
+        self.token_clock_skew = token_clock_skew;
+        self
+    }
+
+    // Sets how long before their expiry tokens are refreshed.
+    //
+    // By default, tokens are refreshed once they have expired.
+    //
+    // # Arguments
+    // * `token_refresh_lead` How long before its expiry a token is refreshed.
+    pub fn with_token_refresh_lead(mut self, token_refresh_lead: Duration) -> State {
+        self.token_refresh_lead = token_refresh_lead;
+        self
+    }
+
+    // Sets how long a single request for FHIR resources may take.
+    //
+    // Searches may page through large bundles, so by default we wait for 60 seconds.
+    // The time spent waiting for the concurrent request limit does not count.
+    //
+    // # Arguments
+    // * `resource_timeout` The maximum time for a resource request.
+    pub fn with_resource_timeout(mut self, resource_timeout: Duration) -> State {
+        self.resource_timeout = resource_timeout;
+        self
+    }
+
+    // Sets how long we wait for the searches behind a patient summary.
+    //
+    // Sections whose searches have not completed in time are left out of the
+    // summary, and marked as timed out. By default, we wait for 15 seconds.
+    //
+    // # Arguments
+    // * `summary_timeout` The maximum time to wait for a summary's searches.
+    pub fn with_summary_timeout(mut self, summary_timeout: Duration) -> State {
+        self.summary_timeout = summary_timeout;
+        self
+    }
+
+    // Sets the timezone that times are displayed in.
+    //
+    // Dates without a time component are displayed as is. By default, times are
+    // displayed in UTC.
+    //
+    // # Arguments
+    // * `timezone` The timezone to display times in.
+    pub fn with_timezone(mut self, timezone: Tz) -> State {
+        self.timezone = timezone;
+        self
+    }
+
+    // Sets the maximum number of resources collected by a single FHIR search.
+    //
+    // By default, at most 1000 resources are collected.
+    //
+    // # Arguments
+    // * `search_limit` The maximum number of resources to collect.
+    pub fn with_search_limit(mut self, search_limit: usize) -> State {
+        self.search_limit = search_limit;
+        self
+    }
+
+    // Sets the maximum number of requests a session sends to the FHIR server at once.
+    //
+    // By default, requests are not limited.
+    //
+    // # Arguments
+    // * `max_concurrent_requests` The maximum number of concurrent requests, if any.
+    pub fn with_max_concurrent_requests(mut self, max_concurrent_requests: Option<usize>) -> State {
+        self.max_concurrent_requests = max_concurrent_requests;
+        self
+    }
+
+    // Sets the maximum number of sessions that we store tokens for.
+    //
+    // By default, sessions are not limited. When storing a token would exceed the
+    // limit, the least recently accessed session is dropped first.
+    //
+    // # Arguments
+    // * `max_sessions` The maximum number of sessions, if any.
+    pub fn with_max_sessions(mut self, max_sessions: Option<usize>) -> State {
+        self.max_sessions = max_sessions;
+        self
+    }
+
+    // Sets how many shards the maps of launches and sessions are split into.
+    //
+    // Each shard has its own lock, so requests for launches and sessions in different
+    // shards do not wait on each other. By default, the number of shards scales with
+    // the number of CPUs. Must be called before any launches or sessions are stored,
+    // as the maps are replaced.
+    //
+    // # Arguments
+    // * `shards` The number of shards, if any, which must be a power of two greater
+    //   than one.
+    pub fn with_state_shards(mut self, shards: Option<usize>) -> State {
+        let Some(shards) = shards else {
+            return self;
+        };
+        self.pkce = DashMap::with_shard_amount(shards);
+        self.nonces = DashMap::with_shard_amount(shards);
+        self.smart_configurations = DashMap::with_shard_amount(shards);
+        self.detected_fhir_versions = DashMap::with_shard_amount(shards);
+        self.iss = DashMap::with_shard_amount(shards);
+        self.launch_started = DashMap::with_shard_amount(shards);
+        self.pending_launches = DashMap::with_shard_amount(shards);
+        self.completed_launches = DashMap::with_shard_amount(shards);
+        self.used_codes = DashMap::with_shard_amount(shards);
+        self.recent_patients = DashMap::with_shard_amount(shards);
+        self.tokens = DashMap::with_shard_amount(shards);
+        self
+    }
+
+    // Sets how many consecutive times the FHIR server may reject a session's token
+    // before we stop sending requests for the session.
+    //
+    // By default, we stop after 3 rejections. Zero disables this.
+    //
+    // # Arguments
+    // * `auth_failure_threshold` The number of consecutive rejections.
+    pub fn with_auth_failure_threshold(mut self, auth_failure_threshold: u32) -> State {
+        self.auth_failure_threshold = auth_failure_threshold;
+        self
+    }
+
+    // Sets how long we stop sending requests for a session whose token the FHIR
+    // server repeatedly rejected.
+    //
+    // By default, this is 1 minute.
+    //
+    // # Arguments
+    // * `auth_failure_cooldown` How long to stop sending requests for.
+    pub fn with_auth_failure_cooldown(mut self, auth_failure_cooldown: Duration) -> State {
+        self.auth_failure_cooldown = auth_failure_cooldown;
+        self
+    }
+
+    // Sets the key used to encrypt PKCE verifiers while they are in the state store.
+    //
+    // By default, verifiers are stored in plaintext.
+    //
+    // # Arguments
+    // * `cipher` The cipher to encrypt verifiers with.
+    pub fn with_verifier_cipher(mut self, cipher: VerifierCipher) -> State {
+        self.verifier_cipher = Some(cipher);
+        self
+    }
+
+    // Sets the key used to encrypt the launch context cookie.
+    //
+    // By default, we do not hand the launch context to the browser.
+    //
+    // # Arguments
+    // * `cipher` The cipher to encrypt the launch context with.
+    pub fn with_context_cookie_cipher(mut self, cipher: ContextCookieCipher) -> State {
+        self.context_cookie_cipher = Some(cipher);
+        self
+    }
+
+    // Sets the order in which client authentication methods are tried at the token endpoint.
+    //
+    // By default, we try `private_key_jwt`, then `client_secret_basic`, and then
+    // `client_secret_post`.
+    //
+    // # Arguments
+    // * `client_auth_methods` The authentication methods to try, in order of preference.
+    pub fn with_client_auth_methods(mut self, client_auth_methods: Vec<ClientAuthMethod>) -> State {
+        self.client_auth_methods = client_auth_methods;
+        self
+    }
+
+    // Sets whether the app is a confidential or public client.
+    //
+    // Public clients do not authenticate with the token endpoint, and ignore the
+    // configured client authentication methods. By default, the app is a
+    // confidential client.
+    //
+    // # Arguments
+    // * `client_type` The client type.
+    pub fn with_client_type(mut self, client_type: ClientType) -> State {
+        self.client_type = client_type;
+        self
+    }
+
+    // Sets whether launches request patient context.
+    //
+    // By default, launches request patient context.
+    //
+    // # Arguments
+    // * `launch_mode` The launch mode.
+    pub fn with_launch_mode(mut self, launch_mode: LaunchMode) -> State {
+        self.launch_mode = launch_mode;
+        self
+    }
+
+    // Sets which syntax the scopes that launches request use.
+    //
+    // By default, launches request SMART v1 scopes, which older servers understand.
+    //
+    // # Arguments
+    // * `scope_version` The scope version.
+    pub fn with_scope_version(mut self, scope_version: ScopeVersion) -> State {
+        self.scope_version = scope_version;
+        self
+    }
+
+    // Sets whether we check that FHIR servers support the search parameters we use.
+    //
+    // If enabled, we fetch each server's capability statement before searching it.
+    // By default, we do not check.
+    //
+    // # Arguments
+    // * `check_search_support` Whether to check search parameter support.
+    pub fn with_check_search_support(mut self, check_search_support: bool) -> State {
+        self.check_search_support = check_search_support;
+        self
+    }
+
+    // Sets the client credentials to use with specific issuers.
+    //
+    // By default, the global client ID and secret are used with all issuers.
+    //
+    // # Arguments
+    // * `credentials` The client ID and secret to use, keyed by issuer host.
+    pub fn with_credentials(mut self, credentials: HashMap<String, (String, String)>) -> State {
+        self.credentials = credentials;
+        self
+    }
+
+    // Sets the names to display for specific issuers.
+    //
+    // By default, issuers are displayed by the name in their Brand Bundle, or else by URL.
+    //
+    // # Arguments
+    // * `issuer_names` The display names, keyed by issuer URL.
+    pub fn with_issuer_names(mut self, issuer_names: HashMap<String, String>) -> State {
+        self.issuer_names = issuer_names;
+        self
+    }
+
+    // Sets additional codes to search for when summarizing measurements.
+    //
+    // By default, we only search for the built-in LOINC and SNOMED CT codes of each
+    // measurement.
+    //
+    // # Arguments
+    // * `observation_codes` The codes, qualified with their code system, keyed by
+    //   measurement name (e.g., "height").
+    pub fn with_observation_codes(
+        mut self,
+        observation_codes: HashMap<String, Vec<String>>,
+    ) -> State {
+        self.observation_codes = observation_codes;
+        self
+    }
+
+    // Sets the number of decimal places to display observation values with, for
+    // additional codes or in place of the defaults.
+    //
+    // By default, vital signs are displayed with one decimal place, and counts and
+    // measurements recorded in whole units without decimals; see
+    // `default_value_precision`. Values for other codes are displayed as reported.
+    //
+    // # Arguments
+    // * `value_precision` The number of decimal places, keyed by code qualified with
+    //   its code system (e.g., "http://loinc.org|8302-2").
+    pub fn with_value_precision(mut self, value_precision: ValuePrecision) -> State {
+        self.value_precision.extend(value_precision);
+        self
+    }
+
+    // Sets the scopes to request from specific issuers.
+    //
+    // By default, all issuers are asked for the scopes of our launch mode.
+    //
+    // # Arguments
+    // * `issuer_scopes` The scopes to request, keyed by issuer host.
+    pub fn with_issuer_scopes(mut self, issuer_scopes: HashMap<String, Vec<String>>) -> State {
+        self.issuer_scopes = issuer_scopes;
+        self
+    }
+
+    // Gets the scopes configured for an issuer's host, if any.
+    //
+    // # Arguments
+    // * `iss` The issuer.
+    pub fn scopes_for(&self, iss: &str) -> Option<Vec<String>> {
+        Url::parse(iss).ok().and_then(|url| {
+            url.host_str()
+                .and_then(|host| self.issuer_scopes.get(host))
+                .cloned()
+        })
+    }
+
+    // Sets the redirect URIs to send to specific issuers.
+    //
+    // By default, all issuers are sent our `/callback` URL.
+    //
+    // # Arguments
+    // * `issuer_redirect_uris` The redirect URIs, keyed by issuer host.
+    pub fn with_issuer_redirect_uris(
+        mut self,
+        issuer_redirect_uris: HashMap<String, String>,
+    ) -> State {
+        self.issuer_redirect_uris = issuer_redirect_uris;
+        self
+    }
+
+    // Sets the FHIR version path segments to append to the base URLs of specific
+    // issuers.
+    //
+    // Some FHIR servers serve discovery documents from the issuer URL, but only serve
+    // resources under a version segment, e.g. `/R4`. By default, the issuer URL is used
+    // as the base URL, and we detect a missing version segment when the summary does not
+    // find the patient (see `detect_fhir_version`).
+    //
+    // # Arguments
+    // * `issuer_fhir_versions` The version path segments, e.g. "R4", keyed by issuer
+    //   host.
+    pub fn with_issuer_fhir_versions(
+        mut self,
+        issuer_fhir_versions: HashMap<String, String>,
+    ) -> State {
+        self.issuer_fhir_versions = issuer_fhir_versions;
+        self
+    }
+
+    // Sets the resource types that may be read through the FHIR proxy.
+    //
+    // By default, no resource types may be proxied, and the proxy is disabled.
+    //
+    // # Arguments
+    // * `proxy_resource_types` The resource types, e.g. "Observation".
+    pub fn with_proxy_resource_types(mut self, proxy_resource_types: Vec<String>) -> State {
+        self.proxy_resource_types = proxy_resource_types;
+        self
+    }
 
-    pkce: Mutex<HashMap<Uuid, (PkceCodeChallenge, PkceCodeVerifier)>>,
-    smart_configurations: Mutex<HashMap<String, SmartConfiguration>>,
-    iss: Mutex<HashMap<Uuid, String>>,
-    tokens: Mutex<HashMap<String, TokenClient>>,
-}
+    // Gets the redirect URI this app uses with an issuer.
+    //
+    // Looks up the redirect URI registered for the issuer's host, falling back to our
+    // `/callback` URL. The authorization request and the token exchange must send the
+    // same redirect URI, so both look it up here.
+    //
+    // # Arguments
+    // * `iss` The issuer.
+    pub fn redirect_uri_for(&self, iss: &str) -> String {
+        Url::parse(iss)
+            .ok()
+            .and_then(|url| {
+                url.host_str()
+                    .and_then(|host| self.issuer_redirect_uris.get(host))
+                    .cloned()
+            })
+            .unwrap_or_else(|| self.callback())
+    }
 
-impl State {
-    pub fn new(app_domain: String, client_id: String, client_secret: String) -> State {
-        State {
-            app_domain,
-            client_id,
-            client_secret,
-            reqwest_client: Client::new(),
-            pkce: Mutex::new(HashMap::new()),
-            smart_configurations: Mutex::new(HashMap::new()),
-            iss: Mutex::new(HashMap::new()),
-            tokens: Mutex::new(HashMap::new()),
+    // Gets the FHIR version path segment configured for an issuer, if any.
+    //
+    // # Arguments
+    // * `iss` The issuer.
+    pub fn fhir_version_for(&self, iss: &str) -> Option<&str> {
+        let url = Url::parse(iss).ok()?;
+        self.issuer_fhir_versions
+            .get(url.host_str()?)
+            .map(String::as_str)
+    }
+
+    // Gets the client credentials this app uses with an issuer.
+    //
+    // Looks up the credentials registered for the issuer's host, falling back to the
+    // global client ID and secret.
+    //
+    // # Arguments
+    // * `iss` The issuer.
+    pub fn credentials_for(&self, iss: &str) -> ClientCredentials {
+        let registered = Url::parse(iss).ok().and_then(|url| {
+            url.host_str()
+                .and_then(|host| self.credentials.get(host))
+                .cloned()
+        });
+
+        match registered {
+            Some((client_id, client_secret)) => ClientCredentials::new(client_id, client_secret),
+            None => ClientCredentials::new(self.client_id.clone(), self.client_secret.clone()),
+        }
+    }
+
+    // Gets the client authentication methods to try with a server, in order of preference.
+    //
+    // Filters our preferred methods down to those the server advertises. If the server
+    // does not advertise any methods, we try all of our preferred methods. Public
+    // clients do not authenticate, and only send their client ID.
+    //
+    // # Arguments
+    // * `config` The SMART configuration for the server.
+    pub fn client_auth_methods(&self, config: &SmartConfiguration) -> Vec<ClientAuthMethod> {
+        // public clients have no secret to authenticate with
+        if self.client_type == ClientType::Public {
+            return vec![ClientAuthMethod::None];
+        }
+
+        if config.token_endpoint_auth_methods_supported.is_empty() {
+            return self.client_auth_methods.clone();
         }
+
+        self.client_auth_methods
+            .iter()
+            .copied()
+            .filter(|method| {
+                config
+                    .token_endpoint_auth_methods_supported
+                    .iter()
+                    .any(|supported| supported == method.as_str())
+            })
+            .collect()
     }
 
-    /// Provides a secret usable with the SMART-on-FHIR symmetric authorization flow.
-    ///
-    /// Base64 encodes "client_id:client_secret", as described in the SMART-on-FHIR
-    /// [docs](https://build.fhir.org/ig/HL7/smart-app-launch/client-confidential-symmetric.html).
-    pub fn base64_secret(&self) -> String {
-        BASE64_STANDARD.encode(format!("{}:{}", self.client_id, self.client_secret))
+    // Gets the scheme (e.g., "https") that this app is served over.
+    pub fn app_scheme(&self) -> &str {
+        self.app_domain
+            .split_once("://")
+            .map(|(scheme, _)| scheme)
+            .unwrap_or("http")
+    }
+
+    // Creates the page to show the user for an error.
+    //
+    // # Arguments
+    // * `error` The error to show.
+    pub fn error_page(&self, error: AppError) -> ErrorPage {
+        ErrorPage::new(error, &self.error_page_text, &self.relaunch_url)
     }
 
     // Generates the callback URL for this app.
@@ -64,35 +999,169 @@ impl State {
         format!("{}/callback", self.app_domain)
     }
 
+    // Gets the brand for an EHR, from the Brand Bundle in its SMART configuration.
+    //
+    // Brands are cached per issuer. If the Brand Bundle cannot be fetched, we log a
+    // warning and return an empty option. If a display name is configured for the
+    // issuer, it takes precedence over the name in the Brand Bundle.
+    //
+    // # Arguments
+    // * `iss` The URL of the EHR.
+    // * `config` The SMART configuration for the EHR.
+    pub async fn get_brand(&self, iss: &str, config: &SmartConfiguration) -> Option<Brand> {
+        let brand = match self.brands.get(&self.reqwest_client, iss, config).await {
+            Ok(brand) => brand,
+            Err(e) => {
+                warn!("Fetching brand bundle for issuer {iss} failed with error: {e}");
+                None
+            }
+        };
+
+        match self.issuer_names.get(iss) {
+            Some(name) => Some(Brand {
+                name: name.clone(),
+                logo: brand.and_then(|brand| brand.logo),
+            }),
+            None => brand,
+        }
+    }
+
+    // Gets the styles that the EHR asks apps to use for a launch.
+    //
+    // Returns an empty option if the EHR did not provide a `smart_style_url`, or if the
+    // styles cannot be fetched, in which case we log a warning. Styles are cached per
+    // URL (see `with_style_max_age`), and failures to fetch them are cached briefly.
+    //
+    // # Arguments
+    // * `context` The context of the launch.
+    pub async fn get_style(&self, context: &LaunchContext) -> Option<SmartStyle> {
+        let url = context.style_url.as_deref()?;
+        match self
+            .styles
+            .get(&self.reqwest_client, url, self.style_max_age)
+            .await
+        {
+            Ok(style) => style,
+            Err(e) => {
+                warn!("Fetching styles from {url} failed with error: {e:?}");
+                None
+            }
+        }
+    }
+
+    // Checks the signature of the id_token that came with a token against the EHR's keys.
+    //
+    // We do not reject tokens whose id_token we cannot verify; we only log a warning,
+    // as the id_token's claims are only used for display. Tokens without an id_token,
+    // and EHRs that do not publish a `jwks_uri`, are not checked.
+    //
+    // # Arguments
+    // * `token` The token returned from the token endpoint.
+    // * `config` The SMART configuration for the EHR.
+    pub async fn check_id_token_signature(&self, token: &Token, config: &SmartConfiguration) {
+        let (Some(id_token), Some(url)) = (token.id_token(), config.jwks_url.as_deref()) else {
+            return;
+        };
+        match self.keys.verify(&self.reqwest_client, url, id_token).await {
+            Ok(true) => {}
+            Ok(false) => warn!("The id_token is not signed with any of the keys at {url}"),
+            Err(e) => warn!("Fetching keys from {url} failed with error: {e:?}"),
+        }
+    }
+
+    // Gets the search parameters that a FHIR server supports for a resource type.
+    //
+    // Returns an empty option if we are not configured to check search parameter
+    // support, or if the server's capability statement cannot be fetched, in which
+    // case we log a warning. Capability statements are cached per base URL.
+    //
+    // # Arguments
+    // * `client` The FHIR client for the session.
+    // * `resource_type` The type of resource that will be searched, e.g. "Observation".
+    pub async fn get_search_support(
+        &self,
+        client: &TokenClient,
+        resource_type: &str,
+    ) -> Option<SearchSupport> {
+        if !self.check_search_support {
+            return None;
+        }
+
+        let base_url = client.base_url_for(resource_type);
+        match client
+            .limiter
+            .run_request(
+                self.search_support
+                    .get(client.client_for(resource_type), base_url),
+            )
+            .await
+        {
+            Ok(support) => Some(support),
+            Err(e) => {
+                warn!("Fetching capability statement for {base_url} failed with error: {e:?}");
+                None
+            }
+        }
+    }
+
     // Adds the issuer and SMART configuration into the state store.
     //
     // At the start of a SMART launch, we collect a SMART configuration from the
     // SMART-on-FHIR server that issued the launch. This method stores the
-    // issuer, keyed by the launch UUID (`state`), and the configuration (keyed
-    // by the issuer URL).
+    // issuer and configuration, keyed by the launch UUID (`state`), and caches the
+    // configuration (keyed by the issuer URL) for later launches.
     //
     // # Arguments
     // * `state` The UUID for the launch.
     // * `iss` The URL of the server that issued the launch.
     // * `config` The SMART Configuration for the server.
     pub fn put_iss_and_config(&self, state: &Uuid, iss: &str, config: &SmartConfiguration) {
-        self.put_iss(state, iss);
-        self.put_config(iss, config);
+        let config = Arc::new(config.clone());
+        self.iss
+            .insert(*state, (iss.to_string(), Arc::clone(&config)));
+        self.smart_configurations
+            .insert(iss.to_string(), (config, Instant::now()));
     }
 
-    fn put_iss(&self, state: &Uuid, iss: &str) {
-        let mut map = self.iss.lock().unwrap();
-        map.insert(*state, iss.to_string());
+    // Gets the SMART configuration fetched for an earlier launch from an issuer.
+    //
+    // Returns an empty option if we have not fetched the issuer's configuration
+    // within the maximum age (see `with_smart_configuration_max_age`).
+    //
+    // # Arguments
+    // * `iss` The URL of the issuer.
+    pub fn get_cached_config(&self, iss: &str) -> Option<SmartConfiguration> {
+        self.smart_configurations
+            .get(iss)
+            .filter(|entry| entry.1.elapsed() < self.smart_configuration_max_age)
+            .map(|entry| SmartConfiguration::clone(&entry.0))
     }
 
-    fn get_iss(&self, state: &Uuid) -> Option<String> {
-        let mut map = self.iss.lock().unwrap();
-        map.remove(state)
+    // Drops the cached SMART configuration for an issuer, so that the next launch
+    // from the issuer fetches it again.
+    //
+    // Returns whether a configuration was cached for the issuer.
+    //
+    // # Arguments
+    // * `iss` The URL of the issuer.
+    pub fn invalidate_config(&self, iss: &str) -> bool {
+        self.smart_configurations
+            .remove(&self.normalize_iss(iss))
+            .is_some()
     }
 
-    fn put_config(&self, iss: &str, config: &SmartConfiguration) {
-        let mut map = self.smart_configurations.lock().unwrap();
-        map.insert(iss.to_string(), config.clone());
+    // Checks whether a key matches the admin key.
+    //
+    // We compare digests of the keys, so that the comparison does not leak how much
+    // of the key matched. Always fails if no admin key is configured.
+    //
+    // # Arguments
+    // * `key` The key presented by a request.
+    pub fn is_admin_key(&self, key: &str) -> bool {
+        self.admin_key.as_deref().is_some_and(|admin_key| {
+            digest(&SHA256, admin_key.as_bytes()).as_ref()
+                == digest(&SHA256, key.as_bytes()).as_ref()
+        })
     }
 
     // Gets the issuer and SMART configuration from the state store.
@@ -105,15 +1174,163 @@ impl State {
     // # Arguments
     // * `state` The UUID for the launch.
     pub fn get_iss_and_config(&self, state: &Uuid) -> Option<(String, SmartConfiguration)> {
-        let iss = self.get_iss(state);
-        if let Some(iss) = iss {
-            let map = self.smart_configurations.lock().unwrap();
-            map.get(&iss).map(|config| (iss, config.clone()))
+        self.iss
+            .remove(state)
+            .map(|(_, (iss, config))| (iss, SmartConfiguration::clone(&config)))
+    }
+
+    // Records that a launch has started.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch.
+    pub fn put_launch_started(&self, state: &Uuid) {
+        self.launch_started.insert(*state, Instant::now());
+    }
+
+    // Checks whether a launch has expired.
+    //
+    // A launch has expired if it was started longer ago than the launch timeout.
+    // The PKCE pair and issuer for an expired launch are dropped; we remember that
+    // the launch expired, so that repeated calls keep returning true. A launch that
+    // has not expired is left alone until it is finished.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch.
+    pub fn launch_expired(&self, state: &Uuid) -> bool {
+        // copy the start time out, so that we do not hold the entry while removing it
+        let started = self.launch_started.get(state).map(|started| *started);
+        match started {
+            Some(started) if started.elapsed() > self.launch_timeout => {
+                self.remove_launch(state);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Forgets a launch that the EHR called back for, whether or not it succeeded.
+    //
+    // Drops everything we stored for the launch, so that a failed launch does not
+    // linger until it expires.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch.
+    pub fn finish_launch(&self, state: &Uuid) {
+        self.remove_launch(state);
+        self.launch_started.remove(state);
+    }
+
+    // Records that a launch has completed, and which session it created.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch.
+    // * `session_key` The key the launch's token is stored under.
+    pub fn put_completed_launch(&self, state: &Uuid, session_key: &str) {
+        self.completed_launches
+            .insert(*state, (session_key.to_string(), Instant::now()));
+    }
+
+    // Gets the key of the session created by a launch that recently completed.
+    //
+    // Returns an empty option if the launch did not complete, completed too long
+    // ago, or if the session it created has since ended.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch.
+    pub fn get_completed_launch(&self, state: &Uuid) -> Option<String> {
+        let session_key = self
+            .completed_launches
+            .get(state)
+            .filter(|entry| entry.1.elapsed() <= COMPLETED_LAUNCH_RETENTION)
+            .map(|entry| entry.0.clone())?;
+
+        if self.tokens.contains_key(&session_key) {
+            Some(session_key)
         } else {
             None
         }
     }
 
+    // Records that an authorization code is being exchanged for a token.
+    //
+    // Returns false if the code was already exchanged, in which case it must not be
+    // exchanged again. This guards against replayed codes regardless of whether the
+    // launch's PKCE pair was consumed. We only keep a digest of each code, as codes
+    // are secrets.
+    //
+    // # Arguments
+    // * `code` The authorization code.
+    pub fn claim_code(&self, code: &str) -> bool {
+        let key = digest(&SHA256, code.as_bytes()).as_ref().to_vec();
+        // the entry holds its shard's lock, so that concurrent exchanges of the same
+        // code cannot both claim it
+        match self.used_codes.entry(key) {
+            Entry::Occupied(used) if used.get().elapsed() <= USED_CODE_RETENTION => false,
+            entry => {
+                entry.insert(Instant::now());
+                true
+            }
+        }
+    }
+
+    // Drops the PKCE pair and issuer for all launches that have expired.
+    //
+    // Also forgets launches that completed too long ago for us to recognize a
+    // duplicate callback, and authorization codes that were exchanged too long ago
+    // to be replayed. Returns the number of expired launches that were dropped.
+    pub fn evict_expired_launches(&self) -> usize {
+        self.completed_launches
+            .retain(|_, (_, completed)| completed.elapsed() <= COMPLETED_LAUNCH_RETENTION);
+        self.used_codes
+            .retain(|_, used| used.elapsed() <= USED_CODE_RETENTION);
+
+        self.launch_started
+            .retain(|_, started| started.elapsed() <= EXPIRED_LAUNCH_RETENTION);
+        let expired: Vec<Uuid> = self
+            .launch_started
+            .iter()
+            .filter(|started| started.value().elapsed() > self.launch_timeout)
+            .map(|started| *started.key())
+            .collect();
+
+        expired
+            .iter()
+            .filter(|state| self.remove_launch(state))
+            .count()
+    }
+
+    // Drops the PKCE pair, nonce, and issuer for a launch.
+    //
+    // Returns true if the launch had not already been dropped.
+    fn remove_launch(&self, state: &Uuid) -> bool {
+        let had_pkce = self.pkce.remove(state).is_some();
+        let had_nonce = self.nonces.remove(state).is_some();
+        let had_iss = self.iss.remove(state).is_some();
+        let had_pending = self.pending_launches.remove(state).is_some();
+        had_pkce || had_nonce || had_iss || had_pending
+    }
+
+    // Records what we need to restart a launch.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch.
+    // * `launch` The launch.
+    pub fn put_pending_launch(&self, state: &Uuid, launch: PendingLaunch) {
+        self.pending_launches.insert(*state, launch);
+    }
+
+    // Gets what we need to restart a launch from the state store.
+    //
+    // This method can only be called once for a given `state` UUID.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch.
+    pub fn get_pending_launch(&self, state: &Uuid) -> Option<PendingLaunch> {
+        self.pending_launches
+            .remove(state)
+            .map(|(_, launch)| launch)
+    }
+
     // Adds the PKCE challenge/verifier pair for a launch to the state store.
     //
     // The SMART-on-FHIR confidential launch flow depends on a [PKCE
@@ -126,9 +1343,21 @@ impl State {
     // * `state` The UUID for the launch.
     // * `challenge` The PKCE challenge code.
     // * `verifier` The PKCE verifier code.
-    pub fn put_pkce(&self, state: &Uuid, challenge: PkceCodeChallenge, verifier: PkceCodeVerifier) {
-        let mut map = self.pkce.lock().unwrap();
-        map.insert(*state, (challenge, verifier));
+    //
+    // Returns an error if verifiers are encrypted and we fail to encrypt this one.
+    pub fn put_pkce(
+        &self,
+        state: &Uuid,
+        challenge: PkceCodeChallenge,
+        verifier: PkceCodeVerifier,
+    ) -> Result<(), String> {
+        let verifier = match &self.verifier_cipher {
+            Some(cipher) => cipher.seal(state, &verifier)?,
+            None => StoredVerifier::Plain(verifier),
+        };
+
+        self.pkce.insert(*state, (challenge, verifier));
+        Ok(())
     }
 
     // Gets the PKCE challenge/verifier pair for a launch from the state store.
@@ -140,38 +1369,554 @@ impl State {
     // # Arguments
     // * `state` The UUID for the launch.
     pub fn get_pkce(&self, state: &Uuid) -> Option<(PkceCodeChallenge, PkceCodeVerifier)> {
-        let mut map = self.pkce.lock().unwrap();
-        map.remove(state)
+        let (_, (challenge, verifier)) = self.pkce.remove(state)?;
+
+        let verifier = match (&self.verifier_cipher, verifier) {
+            (Some(cipher), verifier) => cipher.open(state, verifier)?,
+            (None, StoredVerifier::Plain(verifier)) => verifier,
+            (None, StoredVerifier::Encrypted(_)) => return None,
+        };
+
+        Some((challenge, verifier))
+    }
+
+    // Adds the OpenID Connect nonce for a launch to the state store.
+    //
+    // The nonce is passed to the authorization endpoint, and the EHR echoes it in the
+    // id_token, which binds the id_token to this launch. The callback takes it together
+    // with the PKCE pair, before the token exchange, and only checks it afterwards.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch.
+    // * `nonce` The nonce.
+    pub fn put_nonce(&self, state: &Uuid, nonce: &str) {
+        self.nonces.insert(*state, nonce.to_string());
+    }
+
+    // Gets the OpenID Connect nonce for a launch from the state store.
+    //
+    // This method can only be called once for a given `state` UUID.
+    //
+    // # Arguments
+    // * `state` The UUID for the launch.
+    pub fn get_nonce(&self, state: &Uuid) -> Option<String> {
+        self.nonces.remove(state).map(|(_, nonce)| nonce)
+    }
+
+    // Checks whether the state store is consistent.
+    //
+    // If a thread panics while holding a lock on one of the caches, the lock is
+    // poisoned, and every later request that takes it will fail. We report the state
+    // store as unhealthy if any of these locks are poisoned. The sharded maps of
+    // launches and sessions release their locks when a thread panics, and cannot be
+    // poisoned.
+    pub fn is_healthy(&self) -> bool {
+        ![
+            self.brands.is_poisoned(),
+            self.styles.is_poisoned(),
+            self.keys.is_poisoned(),
+            self.search_support.is_poisoned(),
+        ]
+        .contains(&true)
     }
 
     // Puts a FHIR Bearer token into the state store.
     //
+    // Replaces any session for the same session key and issuer. If we already hold the
+    // maximum number of sessions, the least recently accessed session is dropped to make
+    // room. Returns the context of the launch, which holds the key the token is stored
+    // under (see `TokenClient::session_key`), or an empty option if we could not build
+    // a FHIR client for the token.
+    //
+    // The FHIR API's base URL is the issuer, with the version path segment configured
+    // for the issuer appended, if any, or the segment we detected for the issuer (see
+    // `detect_fhir_version`).
+    //
     // # Arguments
-    // * `iss` The URL of the issuer of the token.
     // * `token` The Bearer token.
-    pub async fn put_token(&self, token: Token) -> bool {
-        let patient = token.patient.clone();
-        match TokenClient::new(self.reqwest_client.clone(), token).await {
-            Ok(client) => {
-                let mut map = self.tokens.lock().unwrap();
-                map.insert(patient, client);
-                true
+    pub async fn put_token(&self, token: Token) -> Option<LaunchContext> {
+        let iss = token.iss().to_string();
+        let version = self.fhir_version_for(&iss).map(str::to_string).or_else(|| {
+            self.detected_fhir_versions
+                .get(&iss)
+                .map(|version| version.clone())
+        });
+        let base_url = match version {
+            Some(version) => with_version_segment(&iss, &version),
+            None => iss.clone(),
+        };
+
+        match TokenClient::new(self.reqwest_client.clone(), token, &base_url).await {
+            Ok(mut client) => {
+                client.limiter = RequestLimiter::new(
+                    self.max_concurrent_requests,
+                    AuthBreaker::new(self.auth_failure_threshold, self.auth_failure_cooldown),
+                    self.resource_timeout,
+                );
+
+                let context = client.context.clone();
+                if let Some(mut sessions) = self.tokens.get_mut(&client.session_key) {
+                    sessions.retain(|session| session.client.context.iss != context.iss);
+                }
+                self.tokens
+                    .remove_if(&client.session_key, |_, sessions| sessions.is_empty());
+                // the limit is checked without locking every shard at once, so
+                // concurrent launches may briefly exceed it
+                if let Some(max_sessions) = self.max_sessions {
+                    while self
+                        .tokens
+                        .iter()
+                        .map(|sessions| sessions.len())
+                        .sum::<usize>()
+                        >= max_sessions
+                    {
+                        if !evict_least_recent_session(&self.tokens) {
+                            break;
+                        }
+                    }
+                }
+
+                self.tokens
+                    .entry(client.session_key.clone())
+                    .or_default()
+                    .push(Session {
+                        client,
+                        last_accessed: Instant::now(),
+                    });
+                Some(context)
+            }
+            Err(_) => None,
+        }
+    }
+
+    // Checks whether a FHIR server requires a version path segment in its base URL,
+    // after it did not find the patient in context of a session.
+    //
+    // Retries the read of the patient under the `R4` segment. If the retry finds the
+    // patient, we remember the segment for the issuer, so that later sessions use it
+    // from the start, replace the session's client with one for the versioned base URL,
+    // and return that client. Sessions whose base URL is configured or already has a
+    // version segment, or that may not read patients, are not checked.
+    //
+    // # Arguments
+    // * `client` The client for the session.
+    pub async fn detect_fhir_version(&self, client: &TokenClient) -> Option<TokenClient> {
+        let iss = &client.context.iss;
+        let patient = client.patient.as_deref()?;
+        if !client.can_read("Patient")
+            || self.fhir_version_for(iss).is_some()
+            || has_version_segment(client.base_url())
+        {
+            return None;
+        }
+
+        let base_url = with_version_segment(client.base_url(), DETECTED_FHIR_VERSION);
+        let versioned = client.with_base_url(&base_url).await.ok()?;
+        let found = versioned
+            .limiter
+            .run_request(fetch_patient(versioned.client_for("Patient"), patient))
+            .await;
+        if !matches!(found, Ok(Some(_))) {
+            return None;
+        }
+
+        warn!(
+            "Patient {patient} was only found under {base_url}; configure the FHIR version for {iss} to skip this check"
+        );
+        self.detected_fhir_versions
+            .insert(iss.clone(), DETECTED_FHIR_VERSION.to_string());
+        if let Some(mut sessions) = self.tokens.get_mut(&client.session_key) {
+            for session in sessions
+                .iter_mut()
+                .filter(|session| session.client.context.iss == *iss)
+            {
+                session.client = versioned.clone();
             }
-            Err(_) => false,
         }
+        Some(versioned)
+    }
+
+    // Looks up the session for a patient, from a specific issuer if given.
+    //
+    // Sessions that have been idle for longer than the idle timeout are dropped. If
+    // no issuer is given, and the patient has sessions from more than one issuer, the
+    // lookup is ambiguous. A session that is found is marked as accessed.
+    //
+    // # Arguments
+    // * `patient_id` The patient ID to look up, or the session key for a launch
+    //   without patient context.
+    // * `iss` The issuer of the session, if known.
+    pub fn lookup_token(&self, patient_id: &str, iss: Option<&str>) -> SessionLookup {
+        let iss = iss.map(|iss| self.normalize_iss(iss));
+        let iss = iss.as_deref();
+        let Some(mut sessions) = self.tokens.get_mut(patient_id) else {
+            return SessionLookup::Missing;
+        };
+        sessions.retain(|session| !session.is_idle(self.idle_timeout));
+
+        let mut matching: Vec<&mut Session> = sessions
+            .iter_mut()
+            .filter(|session| iss.is_none_or(|iss| session.client.context.iss == iss))
+            .collect();
+        let lookup = match matching.len() {
+            0 => SessionLookup::Missing,
+            1 => {
+                let session = &mut matching[0];
+                session.last_accessed = Instant::now();
+                SessionLookup::Found(Box::new(session.client.clone()))
+            }
+            _ => SessionLookup::Ambiguous(
+                matching
+                    .iter()
+                    .map(|session| session.client.context.clone())
+                    .collect(),
+            ),
+        };
+
+        drop(sessions);
+        self.tokens
+            .remove_if(patient_id, |_, sessions| sessions.is_empty());
+        lookup
     }
 
     // Gets an issuer URL and FHIR Bearer token from the state store.
     //
-    // This function can be called multiple times.
+    // This function can be called multiple times. Each call marks the session as
+    // accessed. If the session has been idle for longer than the idle timeout, it
+    // is dropped and an empty option is returned. If the patient has sessions from
+    // more than one issuer, the most recently accessed session is returned; use
+    // `lookup_token` to pick a session by issuer.
     //
     // # Arguments
-    // * `patient_id` The patient ID to return a token for.
+    // * `patient_id` The patient ID to return a token for, or the session key for a
+    //   launch without patient context.
     pub fn get_token(&self, patient_id: &str) -> Option<TokenClient> {
-        // TODO: here we assume that patient_ids are globally unique
-        // this is a faulty assumption that we should fix at a later date.
+        let mut sessions = self.tokens.get_mut(patient_id)?;
+        sessions.retain(|session| !session.is_idle(self.idle_timeout));
+
+        let client = sessions
+            .iter_mut()
+            .max_by_key(|session| session.last_accessed)
+            .map(|session| {
+                session.last_accessed = Instant::now();
+                session.client.clone()
+            });
+
+        drop(sessions);
+        self.tokens
+            .remove_if(patient_id, |_, sessions| sessions.is_empty());
+        client
+    }
+
+    // Removes a FHIR Bearer token from the state store.
+    //
+    // If the patient has sessions from more than one issuer, the most recently
+    // accessed session is removed. Returns the removed token, if there was one.
+    //
+    // # Arguments
+    // * `patient_id` The patient ID to remove the token for, or the session key for a
+    //   launch without patient context.
+    pub fn remove_token(&self, patient_id: &str) -> Option<TokenClient> {
+        let mut sessions = self.tokens.get_mut(patient_id)?;
+
+        let client = sessions
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, session)| session.last_accessed)
+            .map(|(i, _)| i)
+            .map(|i| sessions.remove(i).client);
+
+        drop(sessions);
+        self.tokens
+            .remove_if(patient_id, |_, sessions| sessions.is_empty());
+        client
+    }
+
+    // Records that a patient was viewed in a browser session.
+    //
+    // # Arguments
+    // * `session` The ID of the browser session.
+    // * `patient` The patient that was viewed.
+    pub fn put_recent_patient(&self, session: &Uuid, patient: RecentPatient) {
+        let mut entry = self
+            .recent_patients
+            .entry(*session)
+            .or_insert_with(|| (RecentPatients::default(), Instant::now()));
+        let (recent_patients, last_accessed) = entry.value_mut();
+        recent_patients.push(patient);
+        *last_accessed = Instant::now();
+    }
+
+    // Gets the patients recently viewed in a browser session, most recent first.
+    //
+    // Only returns patients that we still hold a token for.
+    //
+    // # Arguments
+    // * `session` The ID of the browser session.
+    pub fn get_recent_patients(&self, session: &Uuid) -> Vec<RecentPatient> {
+        let recent_patients = match self.recent_patients.get(session) {
+            Some(entry) => entry.0.clone(),
+            None => return Vec::new(),
+        };
+
+        recent_patients
+            .iter()
+            .filter(|patient| self.tokens.contains_key(&patient.id))
+            .cloned()
+            .collect()
+    }
+
+    // Drops all sessions that have been idle for longer than the idle timeout.
+    //
+    // Also forgets the recently viewed patients for browser sessions that have been
+    // idle for longer than the idle timeout. Returns the number of sessions that
+    // were dropped.
+    pub fn evict_idle_sessions(&self) -> usize {
+        self.recent_patients
+            .retain(|_, (_, last_accessed)| last_accessed.elapsed() <= self.idle_timeout);
+
+        let mut dropped = 0;
+        self.tokens.retain(|_, sessions| {
+            let count = sessions.len();
+            sessions.retain(|session| !session.is_idle(self.idle_timeout));
+            dropped += count - sessions.len();
+            !sessions.is_empty()
+        });
+        dropped
+    }
+}
+
+// Drops the least recently accessed session from a token map.
+//
+// Returns false if the map holds no sessions.
+//
+// # Arguments
+// * `map` The sessions, keyed by patient ID or session key.
+fn evict_least_recent_session(map: &DashMap<String, Vec<Session>>) -> bool {
+    let least_recent = map
+        .iter()
+        .flat_map(|entry| {
+            entry
+                .value()
+                .iter()
+                .map(|session| (entry.key().clone(), session.last_accessed))
+                .collect::<Vec<_>>()
+        })
+        .min_by_key(|(_, last_accessed)| *last_accessed);
+
+    let Some((key, last_accessed)) = least_recent else {
+        return false;
+    };
+    // the session may have been accessed or removed since we found it, in which case
+    // we leave it be, and the caller looks again
+    if let Some(mut sessions) = map.get_mut(&key) {
+        sessions.retain(|session| session.last_accessed != last_accessed);
+    }
+    map.remove_if(&key, |_, sessions| sessions.is_empty());
+    true
+}
+
+// Appends a FHIR version path segment to a base URL.
+//
+// # Arguments
+// * `base_url` The base URL.
+// * `version` The version path segment, e.g. "R4".
+fn with_version_segment(base_url: &str, version: &str) -> String {
+    format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        version.trim_matches('/')
+    )
+}
+
+// Checks whether a base URL already ends with a FHIR version path segment, e.g. "R4"
+// or "r4b".
+//
+// # Arguments
+// * `base_url` The base URL.
+fn has_version_segment(base_url: &str) -> bool {
+    base_url
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .is_some_and(|segment| {
+            let mut chars = segment.chars();
+            matches!(chars.next(), Some('R' | 'r'))
+                && chars.next().is_some_and(|c| c.is_ascii_digit())
+        })
+}
+
+#[cfg(test)]
+impl State {
+    // Leaves the state store inconsistent, as if a thread panicked while holding a
+    // lock on one of its caches.
+    pub fn poison(&self) {
+        self.brands.poison();
+    }
+
+    // Counts the entries we hold for launches that have not finished.
+    pub fn launch_entries(&self) -> usize {
+        self.pkce.len()
+            + self.nonces.len()
+            + self.iss.len()
+            + self.launch_started.len()
+            + self.pending_launches.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use wiremock::MockServer;
+
+    use crate::test_support::{put_session, test_state};
+
+    // Moves the last access of a patient's sessions into the past, as if the clock
+    // had advanced.
+    //
+    // # Arguments
+    // * `state` The application state.
+    // * `patient_id` The patient whose sessions to age.
+    // * `by` How far to move the last access back.
+    fn age_sessions(state: &State, patient_id: &str, by: Duration) {
+        for session in state.tokens.get_mut(patient_id).unwrap().iter_mut() {
+            session.last_accessed -= by;
+        }
+    }
+
+    #[actix_web::test]
+    async fn idle_sessions_are_evicted() {
+        let ehr = MockServer::start().await;
+        let state = test_state().with_idle_timeout(Duration::from_secs(30 * 60));
+        put_session(&state, &ehr, "idle", &["patient/*.read"]).await;
+        put_session(&state, &ehr, "active", &["patient/*.read"]).await;
+
+        age_sessions(&state, "idle", Duration::from_secs(31 * 60));
+        age_sessions(&state, "active", Duration::from_secs(29 * 60));
+        assert!(state.get_token("active").is_some());
+        age_sessions(&state, "active", Duration::from_secs(29 * 60));
+
+        assert_eq!(state.evict_idle_sessions(), 1);
+        assert!(state.get_token("idle").is_none());
+        assert!(state.get_token("active").is_some());
+    }
+
+    #[actix_web::test]
+    async fn session_beyond_cap_evicts_least_recently_used() {
+        let ehr = MockServer::start().await;
+        let state = test_state().with_max_sessions(Some(2));
+        put_session(&state, &ehr, "first", &["patient/*.read"]).await;
+        put_session(&state, &ehr, "second", &["patient/*.read"]).await;
+        age_sessions(&state, "first", Duration::from_secs(60));
+        age_sessions(&state, "second", Duration::from_secs(60));
+
+        // reading the first session makes the second the least recently used
+        assert!(state.get_token("first").is_some());
+        put_session(&state, &ehr, "third", &["patient/*.read"]).await;
+
+        assert!(state.get_token("first").is_some());
+        assert!(state.get_token("second").is_none());
+        assert!(state.get_token("third").is_some());
+    }
+
+    #[test]
+    fn nonce_outlives_consumed_pkce() {
+        let state = test_state();
+        let launch = Uuid::new_v4();
+        let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+        state.put_pkce(&launch, challenge, verifier).unwrap();
+        state.put_nonce(&launch, "launch-nonce");
+
+        assert!(state.get_pkce(&launch).is_some());
+        assert!(state.get_pkce(&launch).is_none());
+        assert_eq!(state.get_nonce(&launch).as_deref(), Some("launch-nonce"));
+        assert_eq!(state.get_nonce(&launch), None);
+    }
+
+    #[test]
+    fn code_can_be_claimed_once() {
+        let state = test_state();
+
+        assert!(state.claim_code("first-code"));
+        assert!(!state.claim_code("first-code"));
+        assert!(state.claim_code("second-code"));
+    }
+
+    #[test]
+    fn client_auth_methods_follow_advertised_methods() {
+        let state = test_state().with_client_auth_methods(vec![
+            ClientAuthMethod::ClientSecretBasic,
+            ClientAuthMethod::ClientSecretPost,
+        ]);
+        let mut config: SmartConfiguration = serde_json::from_value(
+            crate::test_support::smart_configuration("https://ehr.example.com"),
+        )
+        .unwrap();
+        config.token_endpoint_auth_methods_supported = vec![String::from("client_secret_post")];
+
+        assert_eq!(
+            state.client_auth_methods(&config),
+            [ClientAuthMethod::ClientSecretPost]
+        );
+
+        config.token_endpoint_auth_methods_supported = Vec::new();
+
+        assert_eq!(
+            state.client_auth_methods(&config),
+            [
+                ClientAuthMethod::ClientSecretBasic,
+                ClientAuthMethod::ClientSecretPost
+            ]
+        );
+    }
+
+    #[test]
+    fn concurrent_launches_in_different_shards_complete() {
+        let state = test_state().with_state_shards(Some(8));
+        let launches: Vec<Vec<Uuid>> = (0..8)
+            .map(|_| (0..200).map(|_| Uuid::new_v4()).collect())
+            .collect();
+
+        // each thread stores its own launches, then consumes every other one
+        std::thread::scope(|scope| {
+            for states in &launches {
+                let state = &state;
+                scope.spawn(move || {
+                    for launch_state in states {
+                        let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+                        state.put_pkce(launch_state, challenge, verifier).unwrap();
+                        state.put_nonce(launch_state, &launch_state.to_string());
+                    }
+                    for launch_state in states.iter().step_by(2) {
+                        assert!(state.get_pkce(launch_state).is_some());
+                        assert_eq!(
+                            state.get_nonce(launch_state),
+                            Some(launch_state.to_string())
+                        );
+                    }
+                });
+            }
+        });
+
+        for states in &launches {
+            for (i, launch_state) in states.iter().enumerate() {
+                assert_eq!(state.get_pkce(launch_state).is_some(), i % 2 == 1);
+            }
+        }
+    }
+
+    #[test]
+    fn host_root_discovery_needs_allowlist_entry_or_configuration() {
+        let iss = "https://ehr.example.com/fhir/r4";
+        let allowing =
+            |entry: &str| test_state().with_iss_allowlist(IssuerAllowlist::parse([entry]).unwrap());
 
-        let map = self.tokens.lock().unwrap();
-        map.get(patient_id).cloned()
+        assert!(!test_state().allows_host_root_discovery(iss));
+        assert!(!allowing("https://ehr.example.com/fhir").allows_host_root_discovery(iss));
+        assert!(allowing("ehr.example.com").allows_host_root_discovery(iss));
+        assert!(test_state()
+            .with_host_root_discovery(true)
+            .allows_host_root_discovery(iss));
     }
 }
@@ -0,0 +1,170 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use actix_files::{Files, NamedFile};
+use actix_web::dev::HttpServiceFactory;
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::middleware::DefaultHeaders;
+use actix_web::web;
+
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct StaticFilesConfig {
+    // How long browsers may cache static files before fetching them again.
+    pub max_age: Duration,
+
+    // Whether directory listings are shown. Only enabled in dev mode.
+    pub show_files_listing: bool,
+
+    // The path of the icon served at `/favicon.ico`.
+    pub favicon_path: String,
+}
+
+impl Default for StaticFilesConfig {
+    fn default() -> StaticFilesConfig {
+        StaticFilesConfig {
+            max_age: Duration::from_secs(24 * 60 * 60),
+            show_files_listing: false,
+            favicon_path: "./resources/favicon.ico".to_string(),
+        }
+    }
+}
+
+impl StaticFilesConfig {
+    // Builds a service serving the static files in a directory.
+    //
+    // Responses carry a `Cache-Control` header with the configured max age.
+    //
+    // # Arguments
+    // * `mount_path` The path the files are served under, e.g. "/resources".
+    // * `serve_from` The directory to serve the files from.
+    pub fn service(&self, mount_path: &str, serve_from: &str) -> impl HttpServiceFactory {
+        let files = Files::new("", serve_from);
+        let files = if self.show_files_listing {
+            files.show_files_listing()
+        } else {
+            files
+        };
+
+        web::scope(mount_path)
+            .wrap(self.cache_headers())
+            .service(files)
+    }
+
+    // Builds a service serving the configured icon at `/favicon.ico`.
+    //
+    // Responses carry a `Cache-Control` header with the configured max age.
+    pub fn favicon_service(&self) -> impl HttpServiceFactory {
+        let favicon_path = self.favicon_path.clone();
+
+        web::resource("/favicon.ico")
+            .wrap(self.cache_headers())
+            .route(web::get().to(move || {
+                let favicon_path = favicon_path.clone();
+                async move { NamedFile::open(favicon_path) }
+            }))
+    }
+
+    // Builds the middleware adding the `Cache-Control` header to static files.
+    fn cache_headers(&self) -> DefaultHeaders {
+        DefaultHeaders::new().add(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(self.max_age.as_secs() as u32),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::http::header::CACHE_CONTROL;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    // Requests a path from an app serving `./resources` under `/resources`.
+    //
+    // # Arguments
+    // * `config` The configuration for static files.
+    // * `path` The path to request.
+    async fn get(config: StaticFilesConfig, path: &str) -> actix_web::dev::ServiceResponse {
+        let app =
+            test::init_service(App::new().service(config.service("/resources", "./resources")))
+                .await;
+        let req = test::TestRequest::get().uri(path).to_request();
+        test::call_service(&app, req).await
+    }
+
+    #[actix_web::test]
+    async fn static_files_have_cache_headers() {
+        let resp = get(
+            StaticFilesConfig::default(),
+            "/resources/example-smart-app.css",
+        )
+        .await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(CACHE_CONTROL).unwrap(),
+            "public, max-age=86400"
+        );
+    }
+
+    #[actix_web::test]
+    async fn directory_listing_is_off_by_default() {
+        let resp = get(StaticFilesConfig::default(), "/resources/").await;
+
+        assert_ne!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn directory_listing_is_shown_in_dev_mode() {
+        let config = StaticFilesConfig {
+            show_files_listing: true,
+            ..StaticFilesConfig::default()
+        };
+
+        let resp = get(config, "/resources/").await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("example-smart-app.css"));
+    }
+
+    #[actix_web::test]
+    async fn favicon_is_served_from_configured_path() {
+        let config = StaticFilesConfig {
+            favicon_path: "./resources/example-smart-app.css".to_string(),
+            ..StaticFilesConfig::default()
+        };
+        let app = test::init_service(App::new().service(config.favicon_service())).await;
+        let req = test::TestRequest::get().uri("/favicon.ico").to_request();
+
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(CACHE_CONTROL).unwrap(),
+            "public, max-age=86400"
+        );
+        let body = test::read_body(resp).await;
+        assert_eq!(
+            body,
+            std::fs::read("./resources/example-smart-app.css").unwrap()
+        );
+    }
+}
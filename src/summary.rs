@@ -0,0 +1,597 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fhir_sdk::client::Error;
+use fhir_sdk::r4b::codes::{AddressUse, ContactPointSystem, ContactPointUse};
+use fhir_sdk::r4b::resources::{Observation, Patient};
+use fhir_sdk::r4b::types::Address;
+use log::error;
+use serde::Serialize;
+
+use crate::care_plan::{CarePlanSummary, GoalSummary};
+use crate::diagnostic_report::ReportSummary;
+use crate::display::display_date;
+use crate::loinc::LoincCode;
+use crate::observation::{
+    observation_component_value, observation_note, observation_value, ValuePrecision,
+};
+use crate::practitioner::LoggedInPractitioner;
+
+// The data displayed in a patient summary.
+pub struct PatientSummary {
+    pub patient_id: String,
+    pub patient: Patient,
+    pub general_practitioners: Vec<String>,
+
+    // The practitioner who is logged in, if the EHR identified one.
+    pub practitioner: Option<LoggedInPractitioner>,
+
+    pub blood_pressure: ObservationSearch,
+    pub height: ObservationSearch,
+    pub ldl: ObservationSearch,
+    pub hdl: ObservationSearch,
+    pub medications: Vec<String>,
+    pub diagnostic_reports: Vec<ReportSummary>,
+    pub goals: Vec<GoalSummary>,
+    pub care_plans: Vec<CarePlanSummary>,
+
+    // The names of the sections whose searches did not complete in time, and
+    // which are therefore missing from the summary.
+    pub timed_out: Vec<&'static str>,
+
+    // The number of decimal places to display measurements with, keyed by code.
+    pub value_precision: ValuePrecision,
+}
+
+// A measurement displayed in a patient summary, extracted from observations.
+#[derive(Serialize)]
+pub struct Measurement {
+    // A stable identifier for the measurement, e.g. "height".
+    pub id: &'static str,
+
+    // The name of the measurement, for display.
+    pub name: &'static str,
+
+    // The observed value, including its unit.
+    pub value: String,
+
+    // The latest note on the observation that the value was taken from, if any.
+    pub note: Option<String>,
+
+    // The total number of observations that matched, if the FHIR server reported it.
+    pub total: Option<u32>,
+}
+
+impl Measurement {
+    // Describes how many of the matching observations we are displaying.
+    //
+    // We display a single value for each measurement, so if the FHIR server reports
+    // that more than one observation matched, we let the user know that the data is
+    // truncated.
+    pub fn display_total(&self) -> Option<String> {
+        match self.total {
+            Some(total) if total > 1 => Some(format!("(showing 1 of {total} measurements)")),
+            _ => None,
+        }
+    }
+}
+
+// The patient details displayed in a patient summary.
+#[derive(Serialize)]
+pub struct PatientDetails {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub gender: Option<String>,
+    pub phone: Option<String>,
+    pub email: Option<String>,
+    pub address: Vec<String>,
+    pub birth_date: Option<String>,
+}
+
+impl PatientSummary {
+    // Gets the details about the patient that we display.
+    pub fn details(&self) -> PatientDetails {
+        let name = self.patient.name.iter().flatten().next();
+
+        PatientDetails {
+            first_name: name.and_then(|name| name.given.iter().flatten().next().cloned()),
+            last_name: name.and_then(|name| name.family.clone()),
+            gender: self.patient.gender.map(|gender| gender.to_string()),
+            phone: select_telecom(&self.patient, ContactPointSystem::Phone).map(str::to_string),
+            email: select_telecom(&self.patient, ContactPointSystem::Email).map(str::to_string),
+            address: primary_address(&self.patient)
+                .map(format_address)
+                .unwrap_or_default(),
+            birth_date: self.patient.birth_date.as_ref().map(display_date),
+        }
+    }
+
+    // Gets the measurements that we display, in display order.
+    //
+    // Measurements that none of the matching observations have a value for are omitted.
+    pub fn measurements(&self) -> Vec<Measurement> {
+        let systolic_loinc = LoincCode::bare("8480-6");
+        let diastolic_loinc = LoincCode::bare("8462-4");
+
+        [
+            (
+                "height",
+                "Height",
+                &self.height,
+                extract_observation(&self.height, &self.value_precision),
+            ),
+            (
+                "systolicbp",
+                "Systolic blood pressure",
+                &self.blood_pressure,
+                extract_observation_or_component(
+                    &self.blood_pressure,
+                    &systolic_loinc,
+                    &self.value_precision,
+                ),
+            ),
+            (
+                "disatolicbp",
+                "Diastolic blood pressure",
+                &self.blood_pressure,
+                extract_observation_or_component(
+                    &self.blood_pressure,
+                    &diastolic_loinc,
+                    &self.value_precision,
+                ),
+            ),
+            (
+                "ldl",
+                "LDL",
+                &self.ldl,
+                extract_observation(&self.ldl, &self.value_precision),
+            ),
+            (
+                "hdl",
+                "HDL",
+                &self.hdl,
+                extract_observation(&self.hdl, &self.value_precision),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(id, name, search_query, value)| {
+            let (value, note) = value?;
+            Some(Measurement {
+                id,
+                name,
+                value,
+                note,
+                total: search_total(search_query),
+            })
+        })
+        .collect()
+    }
+}
+
+// The result of a query searching for observations, along with the total number of
+// observations that matched the query, if the FHIR server reported it.
+pub type ObservationSearch = Result<(Vec<Observation>, Option<u32>), Error>;
+
+// Extracts a value from the observations returned by a query.
+//
+// Applies `extract` to each observation, and returns one of the extracted values, along
+// with the latest note on the observation it was extracted from. If no observations
+// yield a value, an empty option is returned.
+//
+// If the query returned multiple valid Observation resources, we select one of the results.
+// We do not use any specific logic to choose what to return.
+//
+// # Arguments
+// * `search_query` The result of a query searching for observations.
+// * `extract` The function to use to extract a value from an observation.
+fn extract_from_observations<F>(
+    search_query: &ObservationSearch,
+    extract: F,
+) -> Option<(String, Option<String>)>
+where
+    F: Fn(&Observation) -> Option<String>,
+{
+    match search_query {
+        Ok((observations, _total)) => {
+            // TODO: have smarter logic for selecting an entry to return (e.g., sort
+            // and return latest entry)
+            observations.iter().rev().find_map(|observation| {
+                extract(observation).map(|value| (value, observation_note(observation)))
+            })
+        }
+        Err(e) => {
+            error!("Fetching observation failed with error: {:?}", e);
+            None
+        }
+    }
+}
+
+// Extracts the observed value for an observation from a query.
+//
+// Only considers the top-level value of each observation; see `observation_value`. Data
+// carried in observation components is not inspected, and should be extracted with
+// `extract_observation_or_component`.
+//
+// # Arguments
+// * `search_query` The result of a query searching for observations.
+// * `precision` The number of decimal places to display values with, keyed by code.
+fn extract_observation(
+    search_query: &ObservationSearch,
+    precision: &ValuePrecision,
+) -> Option<(String, Option<String>)> {
+    extract_from_observations(search_query, |observation| {
+        observation_value(observation, precision)
+    })
+}
+
+// Extracts the observed value for an observation, falling back to a named component.
+//
+// Handles observations that bundle multiple measurement components together. For example,
+// observations corresponding to the blood pressure [LOINC 55284-4](https://loinc.org/55284-4)
+// code SHOULD contain separate observations of systolic ([LOINC 8480-6](https://loinc.org/8480-6)
+// and diastolic ([LOINC 8462-4](https://loinc.org/8462-4)) pressure measurements, nested under
+// the [Observation.component](http://hl7.org/fhir/R4B/observation-definitions.html#Observation.component)
+// field, and leave the top-level value empty.
+//
+// For each observation, tries the top-level value first. If the top-level value is absent,
+// falls back to the component matching `code`. Otherwise, behaves akin to
+// `extract_observation`.
+//
+// # Arguments
+// * `search_query` The result of a query searching for observations.
+// * `code` The LOINC code to use to filter observation components.
+// * `precision` The number of decimal places to display values with, keyed by code.
+fn extract_observation_or_component(
+    search_query: &ObservationSearch,
+    code: &LoincCode,
+    precision: &ValuePrecision,
+) -> Option<(String, Option<String>)> {
+    extract_from_observations(search_query, |observation| {
+        observation_value(observation, precision)
+            .or_else(|| observation_component_value(observation, code, precision))
+    })
+}
+
+// Gets the total number of observations that matched a query, if the FHIR server
+// reported it.
+//
+// # Arguments
+// * `search_query` The result of a query searching for observations.
+fn search_total(search_query: &ObservationSearch) -> Option<u32> {
+    match search_query {
+        Ok((_observations, total)) => *total,
+        Err(_) => None,
+    }
+}
+
+// Selects the contact point a patient would prefer to be reached at, for a given system.
+//
+// Contact points that are marked as old are ignored. For phones, we prefer mobile
+// numbers, and then home numbers; for other systems (e.g., email), we prefer home
+// contact points. Contact points without a use come next, and work or temporary
+// contact points last. Ties are broken using the contact point's rank.
+//
+// # Arguments
+// * `patient` The patient to select a contact point for.
+// * `system` The kind of contact point to select, e.g. phone or email.
+pub fn select_telecom(patient: &Patient, system: ContactPointSystem) -> Option<&str> {
+    let preference = |contact_use: Option<ContactPointUse>| match (system, contact_use) {
+        (ContactPointSystem::Phone, Some(ContactPointUse::Mobile)) => Some(0),
+        (_, Some(ContactPointUse::Home)) => Some(1),
+        (_, Some(ContactPointUse::Mobile)) => Some(1),
+        (_, None) => Some(2),
+        (_, Some(ContactPointUse::Work | ContactPointUse::Temp)) => Some(3),
+        (_, Some(ContactPointUse::Old)) => None,
+    };
+
+    patient
+        .telecom
+        .iter()
+        .flatten()
+        .filter(|contact| contact.system == Some(system))
+        .filter_map(|contact| {
+            let value = contact.value.as_deref()?;
+            let preference = preference(contact.r#use)?;
+            // contact points without a rank come after ranked ones
+            let rank = contact.rank.map_or(u32::MAX, |rank| rank.get());
+            Some(((preference, rank), value))
+        })
+        .min_by_key(|(key, _)| *key)
+        .map(|(_, value)| value)
+}
+
+// Selects the primary address for a patient.
+//
+// Prefers home addresses, and ignores addresses that are marked as old.
+//
+// # Arguments
+// * `patient` The patient to select an address for.
+pub fn primary_address(patient: &Patient) -> Option<&Address> {
+    let addresses = || {
+        patient
+            .address
+            .iter()
+            .flatten()
+            .filter(|address| address.r#use != Some(AddressUse::Old))
+    };
+
+    addresses()
+        .find(|address| address.r#use == Some(AddressUse::Home))
+        .or_else(|| addresses().next())
+}
+
+// Formats an address as a list of lines, for display.
+//
+// Uses the structured parts of the address if it has any, e.g.:
+//
+// ```
+// 123 Main St
+// Springfield, IL 62701
+// USA
+// ```
+//
+// Otherwise, falls back to the address text, split into lines. Returns an empty list
+// if the address has neither.
+//
+// # Arguments
+// * `address` The address to format.
+pub fn format_address(address: &Address) -> Vec<String> {
+    let mut lines: Vec<String> = address.line.iter().flatten().cloned().collect();
+
+    let city_state = [&address.city, &address.state]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .join(", ");
+    let locality = [Some(&city_state), address.postal_code.as_ref()]
+        .into_iter()
+        .flatten()
+        .filter(|part| !part.is_empty())
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .join(" ");
+    if !locality.is_empty() {
+        lines.push(locality);
+    }
+    lines.extend(address.country.clone());
+
+    if lines.is_empty() {
+        if let Some(text) = &address.text {
+            lines = text.lines().map(str::to_string).collect();
+        }
+    }
+
+    lines
+}
+
+// Gets the medical record number for a patient, if one is available.
+//
+// Looks for an identifier whose type is coded as "MR", using the
+// [identifier type](http://hl7.org/fhir/R4B/valueset-identifier-type.html) value set.
+//
+// # Arguments
+// * `patient` The patient to get the medical record number for.
+pub fn medical_record_number(patient: &Patient) -> Option<&str> {
+    patient.identifier.iter().flatten().find_map(|identifier| {
+        let is_mrn = identifier.r#type.iter().any(|concept| {
+            concept
+                .coding
+                .iter()
+                .flatten()
+                .any(|coding| coding.code.as_deref() == Some("MR"))
+        });
+
+        if is_mrn {
+            identifier.value.as_deref()
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::observation::default_value_precision;
+
+    use fhir_sdk::r4b::resources::ObservationValue;
+    use serde_json::json;
+
+    // Builds a blood pressure observation with systolic and diastolic components, and
+    // no top-level value.
+    fn blood_pressure() -> Observation {
+        serde_json::from_value(json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "55284-4" }]
+            },
+            "component": [
+                {
+                    "code": {
+                        "coding": [{ "system": "http://loinc.org", "code": "8480-6" }]
+                    },
+                    "valueQuantity": { "value": 120, "unit": "mm[Hg]" }
+                },
+                {
+                    "code": {
+                        "coding": [{ "system": "http://loinc.org", "code": "8462-4" }]
+                    },
+                    "valueQuantity": { "value": 80, "unit": "mm[Hg]" }
+                }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn extract_observation_ignores_components() {
+        let search: ObservationSearch = Ok((vec![blood_pressure()], Some(1)));
+
+        assert_eq!(
+            extract_observation(&search, &default_value_precision()),
+            None
+        );
+    }
+
+    #[test]
+    fn extract_observation_or_component_falls_back_to_component() {
+        let search: ObservationSearch = Ok((vec![blood_pressure()], Some(1)));
+
+        let systolic = extract_observation_or_component(
+            &search,
+            &LoincCode::bare("8480-6"),
+            &default_value_precision(),
+        );
+        let diastolic = extract_observation_or_component(
+            &search,
+            &LoincCode::bare("8462-4"),
+            &default_value_precision(),
+        );
+
+        assert_eq!(systolic, Some((String::from("120 mmHg"), None)));
+        assert_eq!(diastolic, Some((String::from("80 mmHg"), None)));
+    }
+
+    #[test]
+    fn extract_observation_or_component_prefers_top_level_value() {
+        let mut observation = blood_pressure();
+        observation.value = Some(ObservationValue::Quantity(
+            serde_json::from_value(json!({ "value": 100, "unit": "mmHg" })).unwrap(),
+        ));
+        let search: ObservationSearch = Ok((vec![observation], Some(1)));
+
+        let value = extract_observation_or_component(
+            &search,
+            &LoincCode::bare("8480-6"),
+            &default_value_precision(),
+        );
+
+        assert_eq!(value, Some((String::from("100 mmHg"), None)));
+    }
+
+    #[test]
+    fn unitless_quantity_renders_value_alone() {
+        let score: Observation = serde_json::from_value(json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": {
+                "coding": [{ "system": "http://loinc.org", "code": "72514-3" }]
+            },
+            "valueQuantity": { "value": 4 }
+        }))
+        .unwrap();
+        let search: ObservationSearch = Ok((vec![score], Some(1)));
+
+        assert_eq!(
+            extract_observation(&search, &default_value_precision()),
+            Some((String::from("4"), None))
+        );
+    }
+
+    // Builds a patient from their contact details.
+    //
+    // # Arguments
+    // * `telecom` The patient's contact points, as FHIR JSON.
+    // * `address` The patient's addresses, as FHIR JSON.
+    fn patient_with_contacts(telecom: serde_json::Value, address: serde_json::Value) -> Patient {
+        serde_json::from_value(json!({
+            "resourceType": "Patient",
+            "telecom": telecom,
+            "address": address
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn select_telecom_prefers_mobile_phone_and_home_email() {
+        let patient = patient_with_contacts(
+            json!([
+                { "system": "phone", "value": "555-0100", "use": "work" },
+                { "system": "phone", "value": "555-0101", "use": "home" },
+                { "system": "phone", "value": "555-0102", "use": "mobile" },
+                { "system": "email", "value": "old@example.com", "use": "old" },
+                { "system": "email", "value": "work@example.com", "use": "work" },
+                { "system": "email", "value": "home@example.com", "use": "home" }
+            ]),
+            json!([]),
+        );
+
+        assert_eq!(
+            select_telecom(&patient, ContactPointSystem::Phone),
+            Some("555-0102")
+        );
+        assert_eq!(
+            select_telecom(&patient, ContactPointSystem::Email),
+            Some("home@example.com")
+        );
+    }
+
+    #[test]
+    fn select_telecom_ignores_old_contacts() {
+        let patient = patient_with_contacts(
+            json!([{ "system": "phone", "value": "555-0100", "use": "old" }]),
+            json!([]),
+        );
+
+        assert_eq!(select_telecom(&patient, ContactPointSystem::Phone), None);
+        assert_eq!(select_telecom(&patient, ContactPointSystem::Email), None);
+    }
+
+    #[test]
+    fn format_address_puts_each_part_on_its_own_line() {
+        let patient = patient_with_contacts(
+            json!([]),
+            json!([
+                { "use": "work", "line": ["1 Office Park"], "city": "Elsewhere" },
+                {
+                    "use": "home",
+                    "line": ["123 Main St", "Apt 4"],
+                    "city": "Springfield",
+                    "state": "IL",
+                    "postalCode": "62701",
+                    "country": "USA"
+                }
+            ]),
+        );
+
+        let address = primary_address(&patient).unwrap();
+
+        assert_eq!(
+            format_address(address),
+            vec!["123 Main St", "Apt 4", "Springfield, IL 62701", "USA"]
+        );
+    }
+
+    #[test]
+    fn format_address_falls_back_to_text() {
+        let patient =
+            patient_with_contacts(json!([]), json!([{ "text": "123 Main St\nSpringfield" }]));
+
+        let address = primary_address(&patient).unwrap();
+
+        assert_eq!(format_address(address), vec!["123 Main St", "Springfield"]);
+    }
+
+    #[test]
+    fn patient_without_contacts_has_no_address() {
+        let patient = patient_with_contacts(json!([]), json!([]));
+
+        assert!(primary_address(&patient).is_none());
+    }
+}
@@ -0,0 +1,262 @@
+// Licensed to Translating Science PBC under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  Translating Science PBC licenses
+// this file to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Helpers shared by the tests of several modules, e.g. for standing up a mock EHR.
+
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use fhir_sdk::client::Client as FhirClient;
+use fhir_sdk::client::FhirR4B;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde_json::{json, Value};
+use url::form_urlencoded::byte_serialize;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::context::LaunchContext;
+use crate::smart::token::Token;
+use crate::state::State;
+
+use std::cell::RefCell;
+use std::sync::Once;
+
+// The domain that the app is served from in tests.
+pub const APP_DOMAIN: &str = "https://app.example.com";
+
+// Creates the application state used in tests, with default settings.
+pub fn test_state() -> State {
+    State::new(
+        APP_DOMAIN.to_string(),
+        String::from("test-client"),
+        String::from("test-secret"),
+    )
+}
+
+// Builds a SMART configuration for an EHR whose endpoints are under a base URL.
+//
+// # Arguments
+// * `base_url` The base URL of the EHR.
+pub fn smart_configuration(base_url: &str) -> Value {
+    json!({
+        "authorization_endpoint": format!("{base_url}/authorize"),
+        "token_endpoint": format!("{base_url}/token"),
+        "grant_types_supported": ["authorization_code"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic"],
+        "scopes_supported": [],
+        "response_types_supported": ["code"],
+        "capabilities": ["launch-ehr", "client-confidential-symmetric"],
+        "code_challenge_methods_supported": ["S256"]
+    })
+}
+
+// Serves a SMART configuration from a mock EHR, at its base URL.
+//
+// # Arguments
+// * `server` The mock EHR.
+pub async fn mock_smart_configuration(server: &MockServer) {
+    Mock::given(method("GET"))
+        .and(path("/.well-known/smart-configuration"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(smart_configuration(&server.uri())))
+        .mount(server)
+        .await;
+}
+
+// Builds a successful response from a token endpoint, for a launch with patient 123.
+pub fn token_response() -> Value {
+    json!({
+        "access_token": "exchanged-access-token",
+        "token_type": "Bearer",
+        "expires_in": 3600,
+        "scope": "launch patient/*.read",
+        "patient": "123"
+    })
+}
+
+// Serves a token endpoint from a mock EHR, which issues a token for every request.
+//
+// # Arguments
+// * `server` The mock EHR.
+// * `response` The token response.
+pub async fn mock_token_endpoint(server: &MockServer, response: Value) {
+    Mock::given(method("POST"))
+        .and(path("/token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(response))
+        .mount(server)
+        .await;
+}
+
+// Percent-encodes a value for use in a query string.
+//
+// # Arguments
+// * `value` The value to encode.
+pub fn encode(value: &str) -> String {
+    byte_serialize(value.as_bytes()).collect()
+}
+
+// Encodes claims as an unsigned id_token.
+//
+// # Arguments
+// * `claims` The claims of the id_token.
+pub fn id_token(claims: Value) -> String {
+    format!(
+        "{}.{}.",
+        BASE64_URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+        BASE64_URL_SAFE_NO_PAD.encode(claims.to_string())
+    )
+}
+
+// A P-256 key pair that a mock EHR signs id_tokens with.
+pub struct SigningKey {
+    kid: String,
+    key_pair: EcdsaKeyPair,
+}
+
+impl SigningKey {
+    // Generates a key pair.
+    //
+    // # Arguments
+    // * `kid` The ID of the key.
+    pub fn generate(kid: &str) -> SigningKey {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap();
+        SigningKey {
+            kid: kid.to_string(),
+            key_pair,
+        }
+    }
+
+    // Gets the public key as a JSON Web Key.
+    pub fn jwk(&self) -> Value {
+        // skip the leading byte of the uncompressed point
+        let point = &self.key_pair.public_key().as_ref()[1..];
+        json!({
+            "kty": "EC",
+            "kid": self.kid,
+            "crv": "P-256",
+            "x": BASE64_URL_SAFE_NO_PAD.encode(&point[..32]),
+            "y": BASE64_URL_SAFE_NO_PAD.encode(&point[32..]),
+        })
+    }
+
+    // Signs a JWT with some claims.
+    //
+    // # Arguments
+    // * `claims` The claims of the JWT.
+    pub fn sign(&self, claims: Value) -> String {
+        let header = json!({ "alg": "ES256", "kid": self.kid });
+        let message = format!(
+            "{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(header.to_string()),
+            BASE64_URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = self
+            .key_pair
+            .sign(&SystemRandom::new(), message.as_bytes())
+            .unwrap();
+        format!("{message}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature))
+    }
+}
+
+// Creates a FHIR client for a mock FHIR server, without authorization.
+//
+// # Arguments
+// * `server` The mock FHIR server.
+pub fn fhir_client(server: &MockServer) -> FhirClient<FhirR4B> {
+    FhirClient::<FhirR4B>::builder()
+        .base_url(server.uri().parse().unwrap())
+        .build()
+        .unwrap()
+}
+
+// Builds a search set Bundle holding resources.
+//
+// # Arguments
+// * `resources` The resources, as FHIR JSON.
+pub fn search_bundle(resources: Vec<Value>) -> Value {
+    json!({
+        "resourceType": "Bundle",
+        "type": "searchset",
+        "total": resources.len(),
+        "entry": resources
+            .into_iter()
+            .map(|resource| json!({ "resource": resource }))
+            .collect::<Vec<Value>>()
+    })
+}
+
+// Stores a session for a patient, as if the mock EHR had launched the app.
+//
+// Returns the context of the launch.
+//
+// # Arguments
+// * `state` The application state.
+// * `server` The mock EHR.
+// * `patient` The ID of the patient in context.
+// * `scopes` The granted scopes.
+pub async fn put_session(
+    state: &State,
+    server: &MockServer,
+    patient: &str,
+    scopes: &[&str],
+) -> LaunchContext {
+    state
+        .put_token(Token::for_test(&server.uri(), Some(patient), scopes))
+        .await
+        .unwrap()
+}
+
+thread_local! {
+    // The warnings logged on this thread since they were last taken.
+    static WARNINGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+// A logger that keeps the warnings logged on each thread, so that tests can check them.
+//
+// Each test runs on its own thread, so tests do not see each other's warnings.
+struct WarningCapture;
+
+impl Log for WarningCapture {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            WARNINGS.with(|warnings| warnings.borrow_mut().push(record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Starts capturing the warnings logged on this thread, dropping any captured so far.
+pub fn capture_warnings() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&WarningCapture).expect("Another logger was already set.");
+        log::set_max_level(LevelFilter::Warn);
+    });
+    WARNINGS.with(|warnings| warnings.borrow_mut().clear());
+}
+
+// Takes the warnings logged on this thread since `capture_warnings` was called.
+pub fn take_warnings() -> Vec<String> {
+    WARNINGS.with(|warnings| warnings.take())
+}